@@ -0,0 +1,61 @@
+//! Building the From, To and envelope of a message from the configured From
+//! mailboxes, cloning them up front as `build_smtp_email` used to and
+//! borrowing them until the From header needs its own copy as it does now.
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use lettre::{
+    address::{Address, Envelope},
+    message::{header, Mailbox, Mailboxes},
+    Message,
+};
+
+fn from_mailboxes() -> Mailboxes {
+    "Notifications <notify@example.com>"
+        .parse()
+        .expect("valid from mailboxes")
+}
+
+/// Before: the From mailboxes, the primary mailbox and the envelope
+/// originator were each cloned from the configured From.
+fn build_cloned(from: &Mailboxes, to: &Mailbox) -> Message {
+    let from = from.clone();
+    let primary = from.iter().next().cloned().expect("non-empty");
+    let originator = primary.clone();
+    finish(header::From::from(from), originator.email, to)
+}
+
+/// After: only the From header's copy and the originator address.
+fn build_borrowed(from: &Mailboxes, to: &Mailbox) -> Message {
+    let primary = from.iter().next().expect("non-empty");
+    let originator = primary.email.clone();
+    finish(header::From::from(from.clone()), originator, to)
+}
+
+fn finish(from: header::From, originator: Address, to: &Mailbox) -> Message {
+    let envelope = Envelope::new(Some(originator), vec![to.email.clone()]).expect("valid envelope");
+    Message::builder()
+        .mailbox(from)
+        .to(to.clone())
+        .subject("deploy finished")
+        .envelope(envelope)
+        .body("build 1234 is live".to_string())
+        .expect("valid message")
+}
+
+fn bench_from(c: &mut Criterion) {
+    let from = from_mailboxes();
+    let to: Mailbox = "ops@example.com".parse().expect("valid recipient");
+    let mut group = c.benchmark_group("from_mailbox");
+    group.bench_function("clone", |b| {
+        b.iter(|| build_cloned(black_box(&from), black_box(&to)))
+    });
+    group.bench_function("borrow", |b| {
+        b.iter(|| build_borrowed(black_box(&from), black_box(&to)))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_from);
+criterion_main!(benches);
@@ -0,0 +1,887 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex, TryLockError},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use arc_swap::ArcSwap;
+use futures::{future::BoxFuture, FutureExt};
+use lettre::message::{Mailbox, Mailboxes};
+use reqwest::header::CONTENT_TYPE;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+const SECS_PER_DAY: u64 = 86_400;
+
+/// Longest wait for the introspection endpoint before the key is refused.
+const INTROSPECTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Cached introspection answers past which expired ones are dropped.
+const MAX_CACHED_TOKENS: usize = 10_000;
+
+/// Tenants whose daily usage is tracked at once. Past it, earlier days'
+/// counts are dropped first, then the least recently used tenant's, whose
+/// count then starts over.
+const MAX_TRACKED_QUOTAS: usize = 10_000;
+
+/// Shortest gap between checks of `API_KEYS_FILE` for changes, so busy
+/// servers do not stat the file on every request.
+const KEYS_FILE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Tenant of the key from `API_KEY`.
+const DEFAULT_TENANT: &str = "default";
+
+/// Per-key sending policy. The key from `API_KEY` gets the default policy:
+/// configured From, no quota, any recipient domain.
+#[derive(Debug, Clone, Default)]
+pub struct KeyPolicy {
+    /// Name the key's sends are counted under in `/metrics`; never the key
+    /// itself.
+    pub tenant: String,
+    /// Kept in the form the From header takes, so sends borrow it.
+    pub from: Option<Mailboxes>,
+    pub daily_quota: Option<u64>,
+    pub allowed_domains: Vec<String>,
+    /// Requests must carry an `X-Signature` HMAC of the body made with it.
+    pub signing_secret: Option<String>,
+    /// May call the admin endpoints that act on the whole server rather
+    /// than on the caller's own sends.
+    pub admin: bool,
+    /// Requests may set `bypass_suppression` to reach `SUPPRESSION_FILE`
+    /// addresses.
+    pub bypass_suppression: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct KeyPolicyEntry {
+    tenant: Option<String>,
+    from: Option<String>,
+    daily_quota: Option<u64>,
+    #[serde(default)]
+    allowed_domains: Vec<String>,
+    signing_secret: Option<String>,
+    #[serde(default)]
+    admin: bool,
+    #[serde(default)]
+    bypass_suppression: bool,
+}
+
+impl KeyPolicyEntry {
+    fn into_policy(self, key: &str) -> Result<KeyPolicy> {
+        let from = self
+            .from
+            .map(|from| Mailbox::from_str(&from).map(Mailboxes::from))
+            .transpose()
+            .context("invalid from address")?;
+        anyhow::ensure!(
+            self.signing_secret.as_deref() != Some(""),
+            "empty signing_secret"
+        );
+        let tenant = match self.tenant.as_deref().map(str::trim) {
+            Some("") => anyhow::bail!("empty tenant"),
+            Some(tenant) => tenant.to_string(),
+            None => key_fingerprint(key),
+        };
+        Ok(KeyPolicy {
+            tenant,
+            from,
+            daily_quota: self.daily_quota,
+            allowed_domains: self.allowed_domains,
+            signing_secret: self.signing_secret,
+            admin: self.admin,
+            bypass_suppression: self.bypass_suppression,
+        })
+    }
+}
+
+impl KeyPolicy {
+    /// Whether the policy permits sending to the given recipient domain.
+    pub fn allows_domain(&self, domain: &str) -> bool {
+        self.allowed_domains.is_empty()
+            || self
+                .allowed_domains
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(domain))
+    }
+}
+
+/// Authenticated caller resolved from the request's API key.
+pub struct Caller<'a> {
+    pub key: &'a str,
+    pub policy: Arc<KeyPolicy>,
+}
+
+impl Caller<'_> {
+    /// What the daily quota is counted under: the tenant, so its keys share
+    /// one budget, or the key itself for a policy without one.
+    fn quota_key(&self) -> &str {
+        match self.policy.tenant.as_str() {
+            "" => self.key,
+            tenant => tenant,
+        }
+    }
+}
+
+/// Where API keys come from, from `AUTH_BACKEND`.
+#[derive(Debug, Clone)]
+pub enum AuthConfig {
+    /// `API_KEY` and `API_KEYS_FILE`, read once at startup.
+    Env,
+    /// `API_KEYS_FILE` alone, re-read whenever it changes.
+    File,
+    /// Keys checked against a token introspection endpoint.
+    Http(IntrospectionConfig),
+}
+
+#[derive(Debug, Clone)]
+pub struct IntrospectionConfig {
+    pub url: String,
+    /// Bearer token this server presents to the endpoint.
+    pub token: Option<String>,
+    /// How long an answer, accepted or refused, is reused; zero asks every
+    /// time.
+    pub cache_ttl: Duration,
+}
+
+/// Resolves an API key to its policy. `ApiKeys` asks it on every request,
+/// so the source of keys is chosen once at startup by `AUTH_BACKEND`.
+pub trait AuthBackend: Send + Sync {
+    /// The key's policy, or `None` when the key is not valid.
+    fn resolve<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Option<Arc<KeyPolicy>>>;
+
+    /// The valid key whose [`key_id`] is `id`, for work stored under the id
+    /// alone; `None` when no such key is known.
+    fn key_for_id(&self, id: &str) -> Option<String>;
+}
+
+/// Where a caller's daily quota stands, as sent in `X-RateLimit-*`.
+#[derive(Debug, Clone, Copy)]
+pub struct QuotaState {
+    pub limit: u64,
+    pub remaining: u64,
+    /// Seconds until the quota resets at midnight UTC.
+    pub reset_secs: u64,
+}
+
+#[derive(Debug, Default)]
+struct DailyUsage {
+    day: u64,
+    count: u64,
+    /// Unix seconds of the last send counted, for eviction.
+    last_used: u64,
+}
+
+/// API keys resolved through the configured backend, and the daily usage
+/// counted against their quotas.
+pub struct ApiKeys {
+    backend: Box<dyn AuthBackend>,
+    usage: Mutex<HashMap<String, DailyUsage>>,
+}
+
+impl ApiKeys {
+    pub fn load(auth: &AuthConfig, default_key: Option<&str>, file: Option<&Path>) -> Result<Self> {
+        let backend: Box<dyn AuthBackend> = match auth {
+            AuthConfig::Env => Box::new(StaticKeys::load(default_key, file)?),
+            AuthConfig::File => Box::new(FileKeys::load(
+                file.context("AUTH_BACKEND=file requires API_KEYS_FILE")?,
+            )?),
+            AuthConfig::Http(cfg) => Box::new(Introspection::new(cfg.clone())?),
+        };
+        Ok(Self {
+            backend,
+            usage: Mutex::default(),
+        })
+    }
+
+    pub async fn lookup<'a>(&self, key: &'a str) -> Option<Caller<'a>> {
+        let policy = self.backend.resolve(key).await?;
+        Some(Caller { key, policy })
+    }
+
+    /// The key stored as `id` by [`key_id`], to look its caller up again.
+    pub fn key_for_id(&self, id: &str) -> Option<String> {
+        self.backend.key_for_id(id)
+    }
+
+    /// Counts one send against the caller's tenant's daily quota, which
+    /// resets at midnight UTC. Returns `false` when the quota is already
+    /// used up.
+    pub fn try_consume(&self, caller: &Caller<'_>) -> bool {
+        let Some(quota) = caller.policy.daily_quota else {
+            return true;
+        };
+
+        let now = now_secs();
+        let today = now / SECS_PER_DAY;
+
+        let mut usage = self.usage.lock().expect("quota lock poisoned");
+        if usage.len() >= MAX_TRACKED_QUOTAS && !usage.contains_key(caller.quota_key()) {
+            usage.retain(|_, entry| entry.day == today);
+            if usage.len() >= MAX_TRACKED_QUOTAS {
+                let oldest = usage
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.last_used)
+                    .map(|(tenant, _)| tenant.clone());
+                if let Some(oldest) = oldest {
+                    usage.remove(&oldest);
+                }
+            }
+        }
+        let entry = usage.entry(caller.quota_key().to_string()).or_default();
+        if entry.day != today {
+            *entry = DailyUsage {
+                day: today,
+                ..DailyUsage::default()
+            };
+        }
+        entry.last_used = now;
+        if entry.count >= quota {
+            return false;
+        }
+        entry.count += 1;
+        true
+    }
+
+    /// Gives back a send counted by `try_consume` that never went out.
+    pub fn refund(&self, caller: &Caller<'_>) {
+        if caller.policy.daily_quota.is_none() {
+            return;
+        }
+        let today = now_secs() / SECS_PER_DAY;
+        let mut usage = self.usage.lock().expect("quota lock poisoned");
+        if let Some(entry) = usage
+            .get_mut(caller.quota_key())
+            .filter(|entry| entry.day == today)
+        {
+            entry.count = entry.count.saturating_sub(1);
+        }
+    }
+
+    /// The caller's quota budget right now; `None` for keys without one.
+    pub fn quota_state(&self, caller: &Caller<'_>) -> Option<QuotaState> {
+        let quota = caller.policy.daily_quota?;
+        let now = now_secs();
+        let usage = self.usage.lock().expect("quota lock poisoned");
+        let used = usage
+            .get(caller.quota_key())
+            .filter(|entry| entry.day == now / SECS_PER_DAY)
+            .map_or(0, |entry| entry.count);
+        Some(QuotaState {
+            limit: quota,
+            remaining: quota.saturating_sub(used),
+            reset_secs: SECS_PER_DAY - now % SECS_PER_DAY,
+        })
+    }
+}
+
+/// `AUTH_BACKEND=env`: the key from `API_KEY` and those in `API_KEYS_FILE`.
+struct StaticKeys {
+    policies: HashMap<String, Arc<KeyPolicy>>,
+}
+
+impl StaticKeys {
+    fn load(default_key: Option<&str>, file: Option<&Path>) -> Result<Self> {
+        let mut policies = match file {
+            Some(path) => read_keys_file(path)?,
+            None => HashMap::new(),
+        };
+        if let Some(key) = default_key {
+            policies.insert(
+                key.to_string(),
+                Arc::new(KeyPolicy {
+                    tenant: DEFAULT_TENANT.to_string(),
+                    // The operator's own key.
+                    admin: true,
+                    bypass_suppression: true,
+                    ..KeyPolicy::default()
+                }),
+            );
+        }
+        anyhow::ensure!(
+            !policies.is_empty(),
+            "either API_KEY or API_KEYS_FILE must be set"
+        );
+        Ok(Self { policies })
+    }
+}
+
+impl AuthBackend for StaticKeys {
+    fn resolve<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Option<Arc<KeyPolicy>>> {
+        futures::future::ready(self.policies.get(key).cloned()).boxed()
+    }
+
+    fn key_for_id(&self, id: &str) -> Option<String> {
+        find_key(self.policies.keys(), id)
+    }
+}
+
+/// `AUTH_BACKEND=file`: `API_KEYS_FILE`, checked for changes on lookups at
+/// most once per [`KEYS_FILE_CHECK_INTERVAL`] so keys can be added or
+/// revoked without a restart.
+struct FileKeys {
+    path: PathBuf,
+    policies: ArcSwap<HashMap<String, Arc<KeyPolicy>>>,
+    reload: Mutex<ReloadState>,
+}
+
+struct ReloadState {
+    checked_at: Instant,
+    /// Modification time of the file the current keys came from.
+    loaded_mtime: Option<SystemTime>,
+}
+
+impl FileKeys {
+    fn load(path: &Path) -> Result<Self> {
+        let mtime = modified(path);
+        let policies = read_keys_file(path)?;
+        anyhow::ensure!(
+            !policies.is_empty(),
+            "api keys file {} has no keys",
+            path.display()
+        );
+        Ok(Self {
+            path: path.to_path_buf(),
+            policies: ArcSwap::from_pointee(policies),
+            reload: Mutex::new(ReloadState {
+                checked_at: Instant::now(),
+                loaded_mtime: mtime,
+            }),
+        })
+    }
+
+    /// Swaps in the file's new keys if it changed since the last load. A
+    /// file that fails to parse is logged and the current keys kept. Skipped
+    /// when checked within the interval or while another lookup checks, so
+    /// a lookup only waits on the file about once a second.
+    fn reload_if_changed(&self) {
+        let mut reload = match self.reload.try_lock() {
+            Ok(reload) => reload,
+            Err(TryLockError::WouldBlock) => return,
+            Err(TryLockError::Poisoned(_)) => panic!("api keys lock poisoned"),
+        };
+        if reload.checked_at.elapsed() < KEYS_FILE_CHECK_INTERVAL {
+            return;
+        }
+        reload.checked_at = Instant::now();
+        let mtime = modified(&self.path);
+        if mtime.is_none() || mtime == reload.loaded_mtime {
+            return;
+        }
+
+        match read_keys_file(&self.path) {
+            Ok(policies) => {
+                info!(path = %self.path.display(), keys = policies.len(), "api keys reloaded");
+                self.policies.store(Arc::new(policies));
+            }
+            Err(err) => {
+                warn!(
+                    path = %self.path.display(),
+                    error = %format!("{err:#}"),
+                    "rejected api keys reload, keeping previous keys"
+                );
+            }
+        }
+        reload.loaded_mtime = mtime;
+    }
+}
+
+impl AuthBackend for FileKeys {
+    fn resolve<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Option<Arc<KeyPolicy>>> {
+        self.reload_if_changed();
+        futures::future::ready(self.policies.load().get(key).cloned()).boxed()
+    }
+
+    fn key_for_id(&self, id: &str) -> Option<String> {
+        self.reload_if_changed();
+        find_key(self.policies.load().keys(), id)
+    }
+}
+
+/// `AUTH_BACKEND=http`: asks an RFC 7662 style endpoint about each key,
+/// posted as `token=<key>`. An `active: true` answer may carry the same
+/// policy fields as an `API_KEYS_FILE` entry. Answers are cached for
+/// `AUTH_CACHE_SECS`; an unreachable endpoint refuses the key uncached.
+struct Introspection {
+    client: reqwest::Client,
+    cfg: IntrospectionConfig,
+    cache: Mutex<HashMap<String, CachedAnswer>>,
+}
+
+struct CachedAnswer {
+    at: Instant,
+    /// `None` caches a refusal.
+    policy: Option<Arc<KeyPolicy>>,
+}
+
+#[derive(Deserialize)]
+struct IntrospectionResponse {
+    active: bool,
+    #[serde(flatten)]
+    policy: KeyPolicyEntry,
+}
+
+impl Introspection {
+    fn new(cfg: IntrospectionConfig) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(INTROSPECTION_TIMEOUT)
+            .build()
+            .context("failed to create introspection client")?;
+        Ok(Self {
+            client,
+            cfg,
+            cache: Mutex::default(),
+        })
+    }
+
+    fn cached(&self, key: &str) -> Option<Option<Arc<KeyPolicy>>> {
+        let cache = self
+            .cache
+            .lock()
+            .expect("introspection cache lock poisoned");
+        cache
+            .get(key)
+            .filter(|answer| answer.at.elapsed() < self.cfg.cache_ttl)
+            .map(|answer| answer.policy.clone())
+    }
+
+    fn remember(&self, key: &str, policy: Option<Arc<KeyPolicy>>) {
+        if self.cfg.cache_ttl.is_zero() {
+            return;
+        }
+        let mut cache = self
+            .cache
+            .lock()
+            .expect("introspection cache lock poisoned");
+        if cache.len() >= MAX_CACHED_TOKENS {
+            let ttl = self.cfg.cache_ttl;
+            cache.retain(|_, answer| answer.at.elapsed() < ttl);
+        }
+        cache.insert(
+            key.to_string(),
+            CachedAnswer {
+                at: Instant::now(),
+                policy,
+            },
+        );
+    }
+
+    async fn introspect(&self, key: &str) -> Result<Option<Arc<KeyPolicy>>> {
+        let body = form_urlencoded::Serializer::new(String::new())
+            .append_pair("token", key)
+            .finish();
+        let mut request = self
+            .client
+            .post(&self.cfg.url)
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(body);
+        if let Some(token) = &self.cfg.token {
+            request = request.bearer_auth(token);
+        }
+        let response: IntrospectionResponse = request
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .context("introspection request failed")?
+            .json()
+            .await
+            .context("invalid introspection response")?;
+        if !response.active {
+            return Ok(None);
+        }
+        let policy = response
+            .policy
+            .into_policy(key)
+            .context("invalid policy in introspection response")?;
+        Ok(Some(Arc::new(policy)))
+    }
+}
+
+impl AuthBackend for Introspection {
+    fn resolve<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Option<Arc<KeyPolicy>>> {
+        async move {
+            if let Some(policy) = self.cached(key) {
+                return policy;
+            }
+            match self.introspect(key).await {
+                Ok(policy) => {
+                    self.remember(key, policy.clone());
+                    policy
+                }
+                Err(err) => {
+                    warn!(error = %format!("{err:#}"), "api key introspection failed");
+                    None
+                }
+            }
+        }
+        .boxed()
+    }
+
+    /// Only keys still in the cache can be found: the endpoint is asked
+    /// about raw keys, never ids.
+    fn key_for_id(&self, id: &str) -> Option<String> {
+        let cache = self
+            .cache
+            .lock()
+            .expect("introspection cache lock poisoned");
+        find_key(cache.keys(), id)
+    }
+}
+
+fn read_keys_file(path: &Path) -> Result<HashMap<String, Arc<KeyPolicy>>> {
+    let raw = fs::read_to_string(path)
+        .with_context(|| format!("failed to read api keys file {}", path.display()))?;
+    let entries: HashMap<String, KeyPolicyEntry> = serde_json::from_str(&raw)
+        .with_context(|| format!("invalid api keys file {}", path.display()))?;
+    entries
+        .into_iter()
+        .map(|(key, entry)| {
+            let policy = entry
+                .into_policy(&key)
+                .with_context(|| format!("invalid entry in api keys file {}", path.display()))?;
+            Ok((key, Arc::new(policy)))
+        })
+        .collect()
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs())
+        .unwrap_or_default()
+}
+
+/// Stand-in tenant for a key without one: a short SHA-256 prefix, enough to
+/// tell keys apart without revealing them.
+/// SHA-256 of the key in hex: names the key in stored jobs without
+/// revealing it.
+pub fn key_id(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+fn find_key<'a>(keys: impl IntoIterator<Item = &'a String>, id: &str) -> Option<String> {
+    keys.into_iter().find(|key| key_id(key) == id).cloned()
+}
+
+fn key_fingerprint(key: &str) -> String {
+    let digest = Sha256::digest(key.as_bytes());
+    let hex: String = digest[..4]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    format!("key-{hex}")
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        http::{header::CONTENT_TYPE, Request, StatusCode},
+    };
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::test_support::{self, TempDir};
+
+    fn keys() -> ApiKeys {
+        ApiKeys::load(&AuthConfig::Env, Some("test-key"), None).expect("default key loads")
+    }
+
+    fn caller<'a>(key: &'a str, tenant: &str, daily_quota: u64) -> Caller<'a> {
+        Caller {
+            key,
+            policy: Arc::new(KeyPolicy {
+                tenant: tenant.to_string(),
+                daily_quota: Some(daily_quota),
+                ..KeyPolicy::default()
+            }),
+        }
+    }
+
+    #[test]
+    fn tracked_quotas_stay_bounded() {
+        let keys = keys();
+        let busy = caller("busy-key", "busy", 2);
+        assert!(keys.try_consume(&busy));
+        for n in 0..MAX_TRACKED_QUOTAS + 50 {
+            let key = format!("key-{n}");
+            assert!(keys.try_consume(&caller(&key, &format!("tenant-{n}"), 1)));
+        }
+        let usage = keys.usage.lock().expect("quota lock poisoned");
+        assert_eq!(usage.len(), MAX_TRACKED_QUOTAS);
+        assert!(usage.contains_key(&format!("tenant-{}", MAX_TRACKED_QUOTAS + 49)));
+    }
+
+    #[test]
+    fn tracked_tenant_is_not_evicted_by_its_own_sends() {
+        let keys = keys();
+        for n in 0..MAX_TRACKED_QUOTAS {
+            let key = format!("key-{n}");
+            assert!(keys.try_consume(&caller(&key, &format!("tenant-{n}"), 2)));
+        }
+        let tracked = caller("key-0", "tenant-0", 2);
+        assert!(keys.try_consume(&tracked));
+        assert!(!keys.try_consume(&tracked));
+        assert_eq!(
+            keys.usage.lock().expect("quota lock poisoned").len(),
+            MAX_TRACKED_QUOTAS
+        );
+    }
+
+    /// An `API_KEYS_FILE` with the given policies, kept alive by the dir.
+    fn keys_file(policies: Value) -> (TempDir, std::path::PathBuf) {
+        let dir = TempDir::new("api-keys");
+        let path = dir.path().join("keys.json");
+        fs::write(&path, policies.to_string()).expect("keys written");
+        (dir, path)
+    }
+
+    #[tokio::test]
+    async fn tenants_have_independent_quotas() {
+        let (_dir, path) = keys_file(json!({
+            "alpha-key": {"tenant": "alpha", "daily_quota": 1},
+            "beta-key": {"tenant": "beta", "daily_quota": 1},
+        }));
+        let keys = ApiKeys::load(&AuthConfig::Env, None, Some(&path)).expect("keys load");
+        let alpha = keys.lookup("alpha-key").await.expect("known key");
+        let beta = keys.lookup("beta-key").await.expect("known key");
+
+        assert!(keys.try_consume(&alpha));
+        assert!(!keys.try_consume(&alpha), "alpha's quota is used up");
+        assert!(keys.try_consume(&beta), "beta is not held back by alpha");
+        assert!(!keys.try_consume(&beta));
+    }
+
+    #[tokio::test]
+    async fn keys_of_one_tenant_share_its_quota() {
+        let (_dir, path) = keys_file(json!({
+            "first-key": {"tenant": "acme", "daily_quota": 2},
+            "second-key": {"tenant": "acme", "daily_quota": 2},
+        }));
+        let keys = ApiKeys::load(&AuthConfig::Env, None, Some(&path)).expect("keys load");
+        let first = keys.lookup("first-key").await.expect("known key");
+        let second = keys.lookup("second-key").await.expect("known key");
+
+        assert!(keys.try_consume(&first));
+        assert!(keys.try_consume(&second));
+        assert!(!keys.try_consume(&first));
+        keys.refund(&second);
+        assert!(
+            keys.try_consume(&first),
+            "the refund went back to the tenant"
+        );
+    }
+
+    #[tokio::test]
+    async fn keys_without_a_tenant_get_a_fingerprint() {
+        let (_dir, path) = keys_file(json!({"secret-key": {}}));
+        let keys =
+            ApiKeys::load(&AuthConfig::Env, Some("test-key"), Some(&path)).expect("keys load");
+
+        let tenant = keys
+            .lookup("secret-key")
+            .await
+            .expect("known key")
+            .policy
+            .tenant
+            .clone();
+        assert_eq!(tenant, key_fingerprint("secret-key"));
+        assert!(
+            tenant.starts_with("key-") && !tenant.contains("secret"),
+            "{tenant}"
+        );
+        let default = keys.lookup("test-key").await.expect("default key");
+        assert_eq!(default.policy.tenant, DEFAULT_TENANT);
+    }
+
+    #[test]
+    fn an_empty_tenant_is_refused() {
+        let (_dir, path) = keys_file(json!({"alpha-key": {"tenant": "  "}}));
+        let err = ApiKeys::load(&AuthConfig::Env, None, Some(&path))
+            .err()
+            .map(|err| format!("{err:#}"))
+            .expect("keys are refused");
+        assert!(err.contains("empty tenant"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn sends_are_counted_per_tenant_in_metrics() {
+        let (_dir, path) = keys_file(json!({
+            "alpha-key": {"tenant": "alpha", "daily_quota": 1},
+            "beta-key": {"tenant": "beta"},
+        }));
+        let state = test_support::state(&[
+            ("API_KEY", ""),
+            ("API_KEYS_FILE", path.to_str().expect("utf-8 path")),
+            ("METRICS_TENANT_LABEL", "true"),
+        ])
+        .await;
+        let app = test_support::app(&state);
+        let send = |key: &str| {
+            let request = Request::post("/notify")
+                .header("authorization", format!("Bearer {key}"))
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b"})
+                        .to_string(),
+                ))
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+
+        for (key, expected) in [
+            ("alpha-key", StatusCode::OK),
+            ("alpha-key", StatusCode::TOO_MANY_REQUESTS),
+            ("beta-key", StatusCode::OK),
+            ("beta-key", StatusCode::OK),
+        ] {
+            let response = send(key).await.unwrap();
+            assert_eq!(response.status(), expected, "{key}");
+        }
+
+        let request = Request::get("/metrics")
+            .header("authorization", "Bearer beta-key")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let metrics = String::from_utf8_lossy(&bytes);
+        assert!(
+            metrics
+                .contains(r#"notifications_total{outcome="sent",service="smtp",tenant="alpha"} 1"#),
+            "{metrics}"
+        );
+        assert!(
+            metrics
+                .contains(r#"notifications_total{outcome="sent",service="smtp",tenant="beta"} 2"#),
+            "{metrics}"
+        );
+        assert!(
+            !metrics.contains("alpha-key") && !metrics.contains("beta-key"),
+            "{metrics}"
+        );
+    }
+
+    #[test]
+    fn quota_state_counts_down_and_resets_the_next_day() {
+        let keys = keys();
+        let limited = caller("limited-key", "limited", 2);
+        let state = keys.quota_state(&limited).expect("a quota");
+        assert_eq!((state.limit, state.remaining), (2, 2));
+        assert!((1..=SECS_PER_DAY).contains(&state.reset_secs));
+
+        assert!(keys.try_consume(&limited));
+        assert_eq!(keys.quota_state(&limited).unwrap().remaining, 1);
+        assert!(keys.try_consume(&limited));
+        assert!(!keys.try_consume(&limited));
+        assert_eq!(keys.quota_state(&limited).unwrap().remaining, 0);
+
+        // As if the sends were counted yesterday.
+        keys.usage
+            .lock()
+            .expect("quota lock poisoned")
+            .get_mut("limited")
+            .expect("tracked")
+            .day -= 1;
+        assert_eq!(keys.quota_state(&limited).unwrap().remaining, 2);
+        assert!(keys.try_consume(&limited));
+
+        let unlimited = Caller {
+            key: "free-key",
+            policy: Arc::new(KeyPolicy::default()),
+        };
+        assert!(keys.quota_state(&unlimited).is_none());
+    }
+
+    #[tokio::test]
+    async fn notify_responses_carry_the_rate_limit_headers() {
+        let (_dir, path) = keys_file(json!({
+            "limited-key": {"daily_quota": 2},
+            "free-key": {},
+        }));
+        let state = test_support::state(&[
+            ("API_KEY", ""),
+            ("API_KEYS_FILE", path.to_str().expect("utf-8 path")),
+        ])
+        .await;
+        let app = test_support::app(&state);
+        let send = |key: &'static str| {
+            let app = app.clone();
+            async move {
+                let body =
+                    json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b"});
+                let request = Request::post("/notify")
+                    .header("authorization", format!("Bearer {key}"))
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(body.to_string()))
+                    .unwrap();
+                let response = app.oneshot(request).await.unwrap();
+                let header = |name: &str| {
+                    response
+                        .headers()
+                        .get(name)
+                        .map(|value| value.to_str().unwrap().parse::<u64>().unwrap())
+                };
+                let limits = (
+                    header("x-ratelimit-limit"),
+                    header("x-ratelimit-remaining"),
+                    header("x-ratelimit-reset"),
+                );
+                (response.status(), limits)
+            }
+        };
+
+        let mut remaining = Vec::new();
+        for _ in 0..3 {
+            let (status, (limit, left, reset)) = send("limited-key").await;
+            assert_eq!(limit, Some(2));
+            assert!(reset.is_some_and(|reset| reset <= SECS_PER_DAY));
+            remaining.push((status, left));
+        }
+        assert_eq!(
+            remaining,
+            [
+                (StatusCode::OK, Some(1)),
+                (StatusCode::OK, Some(0)),
+                (StatusCode::TOO_MANY_REQUESTS, Some(0)),
+            ]
+        );
+
+        let (status, limits) = send("free-key").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(limits, (None, None, None));
+    }
+
+    #[tokio::test]
+    async fn the_keys_file_is_checked_at_most_once_per_interval() {
+        let (_dir, path) = keys_file(json!({"old-key": {}}));
+        let keys = FileKeys::load(&path).unwrap();
+        fs::write(&path, json!({"new-key": {}}).to_string()).unwrap();
+        let later = SystemTime::now() + Duration::from_secs(10);
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|file| file.set_modified(later))
+            .unwrap();
+
+        // Checked at load, so the change waits for the interval to pass.
+        assert!(keys.resolve("new-key").await.is_none());
+        assert!(keys.resolve("old-key").await.is_some());
+
+        keys.reload.lock().unwrap().checked_at -= KEYS_FILE_CHECK_INTERVAL;
+        assert!(keys.resolve("new-key").await.is_some());
+        assert!(keys.resolve("old-key").await.is_none());
+    }
+}
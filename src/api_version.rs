@@ -0,0 +1,165 @@
+use axum::{
+    body::{to_bytes, Body},
+    extract::Request,
+    http::{
+        header::{CONTENT_LENGTH, CONTENT_TYPE},
+        StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::{Map, Value};
+
+use crate::error_response;
+
+/// `ApiResponse` keys that did not exist in v1. v1 clients get these
+/// stripped so they only ever see `{ ok, message }`.
+const V2_ONLY_FIELDS: &[&str] = &["request_id", "field"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiVersion {
+    V1,
+    V2,
+}
+
+/// Picks the response schema from `Accept-Version` or `?api_version=`,
+/// defaulting to v1.
+fn negotiate(req: &Request) -> Option<ApiVersion> {
+    let from_header = req
+        .headers()
+        .get("accept-version")
+        .and_then(|value| value.to_str().ok());
+    let from_query = req.uri().query().and_then(|query| {
+        query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("api_version="))
+    });
+
+    match from_header.or(from_query).map(str::trim) {
+        None => Some(ApiVersion::V1),
+        Some(raw) => match raw.trim_start_matches(['v', 'V']) {
+            "1" => Some(ApiVersion::V1),
+            "2" => Some(ApiVersion::V2),
+            _ => None,
+        },
+    }
+}
+
+/// Response-shaping layer: rewrites JSON object bodies to match the
+/// negotiated schema version. Non-JSON responses pass through untouched.
+pub async fn shape_response(req: Request, next: Next) -> Response {
+    let Some(version) = negotiate(&req) else {
+        return error_response(StatusCode::BAD_REQUEST, "unsupported api version").into_response();
+    };
+
+    let response = next.run(req).await;
+    let is_json = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+    if !is_json {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let Ok(bytes) = to_bytes(body, usize::MAX).await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    let Ok(Value::Object(mut object)) = serde_json::from_slice::<Value>(&bytes) else {
+        return Response::from_parts(parts, Body::from(bytes));
+    };
+
+    shape(&mut object, version);
+    parts.headers.remove(CONTENT_LENGTH);
+    (parts, Json(Value::Object(object))).into_response()
+}
+
+fn shape(object: &mut Map<String, Value>, version: ApiVersion) {
+    match version {
+        ApiVersion::V1 => {
+            for key in V2_ONLY_FIELDS {
+                object.remove(*key);
+            }
+        }
+        ApiVersion::V2 => {
+            let request_id = format!("{:032x}", rand::random::<u128>());
+            object.insert("request_id".to_string(), Value::String(request_id));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Method;
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::test_support::{self, call};
+
+    fn invalid_recipient() -> Value {
+        json!({"service": "smtp", "to": "not an address", "title": "t", "body": "b"})
+    }
+
+    #[test]
+    fn v1_strips_the_newer_fields() {
+        let Value::Object(mut object) =
+            json!({"ok": false, "message": "m", "field": "to", "request_id": "r"})
+        else {
+            unreachable!()
+        };
+        shape(&mut object, ApiVersion::V1);
+        assert_eq!(Value::Object(object), json!({"ok": false, "message": "m"}));
+    }
+
+    #[tokio::test]
+    async fn v1_is_the_default() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let (status, body) = call(&app, Method::POST, "/notify", Some(invalid_recipient())).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["message"], "invalid recipient email");
+        assert!(body.get("field").is_none(), "{body}");
+        assert!(body.get("request_id").is_none(), "{body}");
+    }
+
+    #[tokio::test]
+    async fn v2_adds_request_id_and_field() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let (status, body) = call(
+            &app,
+            Method::POST,
+            "/notify?api_version=2",
+            Some(invalid_recipient()),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["field"], "to");
+        assert_eq!(
+            body["request_id"].as_str().map(str::len),
+            Some(32),
+            "{body}"
+        );
+
+        let request = Request::post("/notify")
+            .header("authorization", format!("Bearer {}", test_support::API_KEY))
+            .header("accept-version", "v2")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(invalid_recipient().to_string()))
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["field"], "to");
+        assert!(body.get("request_id").is_some(), "{body}");
+    }
+
+    #[tokio::test]
+    async fn unknown_versions_are_refused() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let (status, body) = call(&app, Method::GET, "/healthz?api_version=3", None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["message"], "unsupported api version");
+    }
+}
@@ -0,0 +1,387 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use lettre::message::{
+    header::{ContentDisposition, ContentTransferEncoding, ContentType},
+    Attachment, Body, SinglePart,
+};
+use serde::{Deserialize, Serialize};
+
+/// Fallback when the type is neither given nor recognisable.
+pub const OCTET_STREAM: &str = "application/octet-stream";
+
+/// A file attached to the message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AttachmentRequest {
+    pub filename: String,
+    /// Base64 of the file contents.
+    pub content: String,
+    /// MIME type; sniffed from the contents when omitted.
+    #[serde(default)]
+    pub content_type: Option<String>,
+    #[serde(default)]
+    pub disposition: Disposition,
+    /// Content-ID of an inline attachment, referenced from the html body as
+    /// `cid:<cid>`.
+    #[serde(default)]
+    pub cid: Option<String>,
+    /// Set for multipart uploads, whose contents are on disk instead of in
+    /// `content`.
+    #[serde(skip)]
+    pub spooled: Option<Arc<SpooledFile>>,
+}
+
+/// How mail clients should present an attachment.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Disposition {
+    /// Offered for download.
+    #[default]
+    Attachment,
+    /// Shown in the message body.
+    Inline,
+}
+
+/// An uploaded file spooled to the temp directory; removed once the last
+/// copy of the request holding it is dropped.
+#[derive(Debug)]
+pub struct SpooledFile {
+    path: PathBuf,
+    /// SHA-256 of the contents, standing in for them in the dedupe hash.
+    pub digest: [u8; 32],
+}
+
+impl SpooledFile {
+    pub fn new() -> Self {
+        let path = env::temp_dir().join(format!(
+            "notification-upload-{:032x}",
+            rand::random::<u128>()
+        ));
+        Self {
+            path,
+            digest: [0; 32],
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for SpooledFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl AttachmentRequest {
+    /// Decoded size in bytes, 0 when unreadable.
+    pub fn size(&self) -> usize {
+        match &self.spooled {
+            Some(file) => fs::metadata(file.path()).map_or(0, |meta| meta.len() as usize),
+            None => BASE64_STANDARD
+                .decode(self.content.trim())
+                .map_or(0, |bytes| bytes.len()),
+        }
+    }
+
+    /// Decodes the contents into a MIME part. With `sniff` off, a missing
+    /// type is always `application/octet-stream`.
+    pub fn into_part(self, sniff: bool) -> Result<SinglePart, String> {
+        let filename = self.filename.trim();
+        if filename.is_empty() {
+            return Err("attachment filename cannot be empty".to_string());
+        }
+        let bytes = match &self.spooled {
+            Some(file) => fs::read(file.path())
+                .map_err(|err| format!("attachment {filename} could not be read: {err}"))?,
+            None => BASE64_STANDARD
+                .decode(self.content.trim())
+                .map_err(|_| format!("attachment {filename} is not valid base64"))?,
+        };
+
+        let mime = match self.content_type.as_deref().map(str::trim) {
+            Some(content_type) if !content_type.is_empty() => content_type.to_string(),
+            _ if sniff => sniff_content_type(&bytes),
+            _ => OCTET_STREAM.to_string(),
+        };
+        let content_type = ContentType::parse(&mime)
+            .map_err(|_| format!("attachment {filename} has an invalid content_type"))?;
+
+        // lettre would pick 7bit for ASCII-range binaries such as NUL bytes.
+        let body = if mime.to_ascii_lowercase().starts_with("text/") {
+            Body::new(bytes)
+        } else {
+            Body::new_with_encoding(bytes, ContentTransferEncoding::Base64)
+                .expect("base64 can encode any body")
+        };
+        let filename = filename.to_string();
+        Ok(match (self.disposition, self.cid) {
+            (Disposition::Attachment, Some(_)) => {
+                return Err(format!("attachment {filename} has a cid but is not inline"));
+            }
+            (Disposition::Attachment, None) => Attachment::new(filename).body(body, content_type),
+            (Disposition::Inline, Some(cid)) => {
+                let cid = cid.trim().trim_start_matches('<').trim_end_matches('>');
+                if cid.is_empty() || !cid.bytes().all(is_cid_byte) {
+                    return Err(format!("attachment {filename} has an invalid cid"));
+                }
+                Attachment::new_inline_with_name(cid.to_string(), filename).body(body, content_type)
+            }
+            (Disposition::Inline, None) => SinglePart::builder()
+                .header(ContentDisposition::inline_with_name(&filename))
+                .header(content_type)
+                .body(body),
+        })
+    }
+
+    /// Inline parts with a cid belong in multipart/related beside the body
+    /// that references them.
+    pub fn is_related(&self) -> bool {
+        self.disposition == Disposition::Inline && self.cid.is_some()
+    }
+}
+
+/// Known binary formats by magic bytes, then UTF-8 text without control
+/// characters as `text/plain`, else `application/octet-stream`.
+fn sniff_content_type(bytes: &[u8]) -> String {
+    if let Some(kind) = infer::get(bytes) {
+        return kind.mime_type().to_string();
+    }
+    match std::str::from_utf8(bytes) {
+        Ok(text) if !text.is_empty() && !text.chars().any(is_binary_control) => {
+            "text/plain; charset=utf-8".to_string()
+        }
+        _ => OCTET_STREAM.to_string(),
+    }
+}
+
+/// Printable ASCII other than the brackets and whitespace that would break
+/// the `Content-ID` header.
+fn is_cid_byte(byte: u8) -> bool {
+    byte.is_ascii_graphic() && !matches!(byte, b'<' | b'>')
+}
+
+fn is_binary_control(ch: char) -> bool {
+    ch.is_control() && !matches!(ch, '\t' | '\n' | '\r' | '\x0c')
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::test_support::{self, notify};
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR\0\0\0\x01\0\0\0\x01\x08\x06\0\0\0";
+    const PDF: &[u8] = b"%PDF-1.7\n%\xe2\xe3\xcf\xd3\n1 0 obj\n<<>>\nendobj\n";
+
+    fn attachment(filename: &str, bytes: &[u8]) -> AttachmentRequest {
+        AttachmentRequest {
+            filename: filename.to_string(),
+            content: BASE64_STANDARD.encode(bytes),
+            content_type: None,
+            disposition: Disposition::Attachment,
+            cid: None,
+            spooled: None,
+        }
+    }
+
+    /// The part's `Content-Type` header value.
+    fn content_type(part: &SinglePart) -> String {
+        let formatted = String::from_utf8(part.formatted()).unwrap();
+        formatted
+            .lines()
+            .find_map(|line| line.strip_prefix("Content-Type: "))
+            .expect("a content type")
+            .to_string()
+    }
+
+    #[test]
+    fn binary_formats_are_sniffed_by_magic_bytes() {
+        assert_eq!(sniff_content_type(PNG), "image/png");
+        assert_eq!(sniff_content_type(PDF), "application/pdf");
+    }
+
+    #[test]
+    fn text_is_sniffed_as_utf8_plain_text() {
+        assert_eq!(
+            sniff_content_type("timestamp,level\n2025-07-01,übel\tok\r\n".as_bytes()),
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(sniff_content_type(b"\0\x01\x02binary"), OCTET_STREAM);
+        assert_eq!(sniff_content_type(b"\xff\xfe not utf-8"), OCTET_STREAM);
+        assert_eq!(sniff_content_type(b""), OCTET_STREAM);
+    }
+
+    #[test]
+    fn missing_content_type_is_sniffed_unless_disabled() {
+        let png = attachment("chart.png", PNG).into_part(true).unwrap();
+        assert_eq!(content_type(&png), "image/png");
+        let pdf = attachment("report.pdf", PDF).into_part(true).unwrap();
+        assert_eq!(content_type(&pdf), "application/pdf");
+        let text = attachment("notes.txt", b"all good")
+            .into_part(true)
+            .unwrap();
+        assert_eq!(content_type(&text), "text/plain; charset=utf-8");
+
+        let unsniffed = attachment("chart.png", PNG).into_part(false).unwrap();
+        assert_eq!(content_type(&unsniffed), OCTET_STREAM);
+        let given = AttachmentRequest {
+            content_type: Some("image/x-custom".to_string()),
+            ..attachment("chart.png", PNG)
+        };
+        assert_eq!(
+            content_type(&given.into_part(true).unwrap()),
+            "image/x-custom"
+        );
+    }
+
+    #[test]
+    fn malformed_attachments_are_refused() {
+        let err = |attachment: AttachmentRequest| attachment.into_part(true).unwrap_err();
+
+        assert_eq!(
+            err(attachment(" ", b"x")),
+            "attachment filename cannot be empty"
+        );
+        assert_eq!(
+            err(AttachmentRequest {
+                content: "not base64!".to_string(),
+                ..attachment("a.txt", b"")
+            }),
+            "attachment a.txt is not valid base64"
+        );
+        assert_eq!(
+            err(AttachmentRequest {
+                content_type: Some("not a type".to_string()),
+                ..attachment("a.txt", b"x")
+            }),
+            "attachment a.txt has an invalid content_type"
+        );
+        assert_eq!(
+            err(AttachmentRequest {
+                cid: Some("logo".to_string()),
+                ..attachment("logo.png", PNG)
+            }),
+            "attachment logo.png has a cid but is not inline"
+        );
+        assert_eq!(
+            err(AttachmentRequest {
+                cid: Some("<lo go>".to_string()),
+                disposition: Disposition::Inline,
+                ..attachment("logo.png", PNG)
+            }),
+            "attachment logo.png has an invalid cid"
+        );
+    }
+
+    /// The formatted part's `name` header value.
+    fn part_header(part: &SinglePart, name: &str) -> Option<String> {
+        let formatted = String::from_utf8(part.formatted()).unwrap();
+        crate::test_support::header_value(&formatted, name)
+    }
+
+    fn with_disposition(disposition: Disposition, cid: Option<&str>) -> AttachmentRequest {
+        AttachmentRequest {
+            disposition,
+            cid: cid.map(str::to_string),
+            ..attachment("logo.png", PNG)
+        }
+    }
+
+    #[test]
+    fn attachments_default_to_a_download() {
+        let request: AttachmentRequest =
+            serde_json::from_str(r#"{"filename": "logo.png", "content": "AA=="}"#).unwrap();
+        assert_eq!(request.disposition, Disposition::Attachment);
+
+        let part = with_disposition(Disposition::Attachment, None)
+            .into_part(true)
+            .unwrap();
+        assert_eq!(
+            part_header(&part, "Content-Disposition").as_deref(),
+            Some("attachment; filename=\"logo.png\"")
+        );
+        assert_eq!(part_header(&part, "Content-ID"), None);
+    }
+
+    #[test]
+    fn inline_attachments_are_shown_in_the_body() {
+        let part = with_disposition(Disposition::Inline, None)
+            .into_part(true)
+            .unwrap();
+        assert_eq!(
+            part_header(&part, "Content-Disposition").as_deref(),
+            Some("inline; filename=\"logo.png\"")
+        );
+        assert_eq!(part_header(&part, "Content-ID"), None);
+        assert!(!with_disposition(Disposition::Inline, None).is_related());
+    }
+
+    #[test]
+    fn inline_attachments_with_a_cid_get_a_content_id() {
+        let request = with_disposition(Disposition::Inline, Some("<logo@example.com>"));
+        assert!(request.is_related());
+        let part = request.into_part(true).unwrap();
+        assert_eq!(
+            part_header(&part, "Content-ID").as_deref(),
+            Some("<logo@example.com>")
+        );
+        assert!(part_header(&part, "Content-Disposition")
+            .expect("a disposition")
+            .starts_with("inline"));
+    }
+
+    #[test]
+    fn a_cid_needs_an_inline_disposition_and_valid_characters() {
+        assert_eq!(
+            with_disposition(Disposition::Attachment, Some("logo"))
+                .into_part(true)
+                .unwrap_err(),
+            "attachment logo.png has a cid but is not inline"
+        );
+        for cid in ["", "<>", "logo image", "logo\r\nBcc: x@example.com"] {
+            assert_eq!(
+                with_disposition(Disposition::Inline, Some(cid))
+                    .into_part(true)
+                    .unwrap_err(),
+                "attachment logo.png has an invalid cid",
+                "{cid:?}"
+            );
+        }
+    }
+
+    fn with_attachments(count: usize) -> Value {
+        let attachments: Vec<_> = (0..count)
+            .map(|n| json!({"filename": format!("file-{n}.txt"), "content": BASE64_STANDARD.encode("x")}))
+            .collect();
+        json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b", "attachments": attachments})
+    }
+
+    #[tokio::test]
+    async fn max_attachments_caps_the_attachment_count() {
+        for (vars, allowed) in [(vec![], 10), (vec![("MAX_ATTACHMENTS", "2")], 2)] {
+            let app = test_support::app(&test_support::state(&vars).await);
+            let (status, body) = notify(&app, with_attachments(allowed)).await;
+            assert_eq!(status, StatusCode::OK, "{vars:?}: {body}");
+
+            let (status, body) = notify(&app, with_attachments(allowed + 1)).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{vars:?}");
+            assert_eq!(body["message"], "too many attachments");
+            assert_eq!(test_support::sent(&app).await.len(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn max_attachments_of_zero_is_unlimited() {
+        let app = test_support::app(&test_support::state(&[("MAX_ATTACHMENTS", "0")]).await);
+        let (status, body) = notify(&app, with_attachments(25)).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+    }
+}
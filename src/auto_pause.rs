@@ -0,0 +1,260 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use tracing::{error, info};
+
+use crate::{authenticate_admin, error_response, ApiResponse, AppState};
+
+/// Stops sending once the failure rate over `AUTO_PAUSE_WINDOW_SECS` reaches
+/// `AUTO_PAUSE_FAILURE_RATE`, so a broken provider is not fed every request
+/// in the meantime. Sends are refused with 503 until `POST /admin/resume`
+/// or, with `AUTO_PAUSE_RESUME_SECS`, until that long has passed.
+#[derive(Debug)]
+pub struct AutoPause {
+    window: Duration,
+    failure_rate: f64,
+    /// Sends the window must hold before its rate counts.
+    min_sends: usize,
+    resume_after: Option<Duration>,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// When each send in the window finished, and whether it failed.
+    outcomes: VecDeque<(Instant, bool)>,
+    paused_at: Option<Instant>,
+}
+
+impl AutoPause {
+    pub fn new(
+        window: Duration,
+        failure_rate: f64,
+        min_sends: usize,
+        resume_after: Option<Duration>,
+    ) -> Self {
+        Self {
+            window,
+            failure_rate,
+            min_sends,
+            resume_after,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    /// Whether sends are paused, resuming first if the pause has timed out.
+    pub fn is_paused(&self, now: Instant) -> bool {
+        let mut inner = self.inner.lock().expect("auto-pause lock poisoned");
+        let Some(paused_at) = inner.paused_at else {
+            return false;
+        };
+        match self.resume_after {
+            Some(after) if now.duration_since(paused_at) >= after => {
+                *inner = Inner::default();
+                info!(
+                    paused_secs = after.as_secs(),
+                    "sending resumed after auto-pause"
+                );
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Counts a finished send and pauses once the window's failure rate
+    /// reaches the threshold.
+    pub fn record(&self, failed: bool, now: Instant) {
+        let mut inner = self.inner.lock().expect("auto-pause lock poisoned");
+        if inner.paused_at.is_some() {
+            return;
+        }
+        while inner
+            .outcomes
+            .front()
+            .is_some_and(|(at, _)| now.duration_since(*at) > self.window)
+        {
+            inner.outcomes.pop_front();
+        }
+        inner.outcomes.push_back((now, failed));
+
+        let sends = inner.outcomes.len();
+        let failures = inner.outcomes.iter().filter(|(_, failed)| *failed).count();
+        let rate = failures as f64 / sends as f64;
+        if sends >= self.min_sends && rate >= self.failure_rate {
+            inner.paused_at = Some(now);
+            error!(
+                alert = true,
+                failures,
+                sends,
+                window_secs = self.window.as_secs(),
+                "failure rate over threshold, sending auto-paused"
+            );
+        }
+    }
+
+    /// Lifts a pause. Returns `false` when sending was not paused.
+    pub fn resume(&self) -> bool {
+        let mut inner = self.inner.lock().expect("auto-pause lock poisoned");
+        let was_paused = inner.paused_at.is_some();
+        *inner = Inner::default();
+        was_paused
+    }
+}
+
+/// `POST /admin/resume`: lifts an auto-pause.
+pub async fn resume(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> (StatusCode, Json<ApiResponse>) {
+    if let Err(resp) = authenticate_admin(&state, &headers).await {
+        return resp.into_inner();
+    }
+    let Some(auto_pause) = &state.auto_pause else {
+        return error_response(StatusCode::NOT_FOUND, "auto-pause is not configured");
+    };
+    let message = if auto_pause.resume() {
+        info!("sending resumed by admin");
+        "sending resumed"
+    } else {
+        "sending was not paused"
+    };
+    (StatusCode::OK, Json(ApiResponse::ok(message)))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Method;
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_support::{self, call};
+
+    const SEC: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn pauses_once_the_window_failure_rate_reaches_the_threshold() {
+        let pause = AutoPause::new(10 * SEC, 0.5, 4, None);
+        let start = Instant::now();
+        pause.record(true, start);
+        pause.record(true, start);
+        pause.record(true, start);
+        // Too few sends for the rate to count yet.
+        assert!(!pause.is_paused(start));
+
+        pause.record(false, start);
+        assert!(pause.is_paused(start));
+        assert!(pause.is_paused(start + 3600 * SEC), "resumes by hand only");
+    }
+
+    #[test]
+    fn failures_leave_the_window_as_it_slides() {
+        let pause = AutoPause::new(10 * SEC, 0.5, 4, None);
+        let start = Instant::now();
+        pause.record(true, start);
+        pause.record(true, start);
+        for _ in 0..3 {
+            pause.record(false, start + 11 * SEC);
+        }
+        pause.record(true, start + 11 * SEC);
+        // 1 failure in the last 4 sends once the first two slide out.
+        assert!(!pause.is_paused(start + 11 * SEC));
+    }
+
+    #[test]
+    fn a_timed_pause_resumes_on_its_own() {
+        let pause = AutoPause::new(10 * SEC, 1.0, 1, Some(30 * SEC));
+        let start = Instant::now();
+        pause.record(true, start);
+        assert!(pause.is_paused(start + 29 * SEC));
+        assert!(!pause.is_paused(start + 30 * SEC));
+
+        // The window starts over after a resume.
+        pause.record(false, start + 31 * SEC);
+        assert!(!pause.is_paused(start + 31 * SEC));
+    }
+
+    #[test]
+    fn resume_lifts_a_pause() {
+        let pause = AutoPause::new(10 * SEC, 1.0, 1, None);
+        assert!(!pause.resume());
+        pause.record(true, Instant::now());
+        assert!(pause.resume());
+        assert!(!pause.is_paused(Instant::now()));
+    }
+
+    #[tokio::test]
+    async fn a_failing_provider_pauses_sending_until_resumed() {
+        let smtp = test_support::MockSmtp::start().await;
+        for _ in 0..3 {
+            smtp.reply("MAIL", "451 4.3.0 try again later");
+        }
+        let state = smtp
+            .state(&[
+                ("AUTO_PAUSE_FAILURE_RATE", "0.6"),
+                ("AUTO_PAUSE_MIN_SENDS", "4"),
+            ])
+            .await;
+        let app = test_support::app(&state);
+        let body = json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b"});
+
+        let mut statuses = Vec::new();
+        for _ in 0..5 {
+            statuses.push(test_support::notify(&app, body.clone()).await.0);
+        }
+        // Three failures in four sends cross 0.6; the fifth is not attempted.
+        assert_eq!(statuses[..3], [StatusCode::INTERNAL_SERVER_ERROR; 3]);
+        assert_eq!(statuses[3], StatusCode::OK);
+        assert_eq!(statuses[4], StatusCode::SERVICE_UNAVAILABLE);
+        let (_, response) = test_support::notify(&app, body.clone()).await;
+        assert_eq!(
+            response["message"],
+            "sending paused after sustained failures"
+        );
+        assert_eq!(smtp.messages().len(), 1);
+
+        let (status, response) = call(&app, Method::POST, "/admin/resume", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response["message"], "sending resumed");
+        let (status, response) = test_support::notify(&app, body).await;
+        assert_eq!(status, StatusCode::OK, "{response}");
+        assert_eq!(smtp.messages().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn permanent_refusals_do_not_count() {
+        let smtp = test_support::MockSmtp::start().await;
+        for _ in 0..3 {
+            smtp.reply("RCPT", "550 5.1.1 no such user");
+        }
+        let state = smtp
+            .state(&[
+                ("AUTO_PAUSE_FAILURE_RATE", "0.5"),
+                ("AUTO_PAUSE_MIN_SENDS", "2"),
+            ])
+            .await;
+        let app = test_support::app(&state);
+        let body = json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b"});
+
+        for _ in 0..3 {
+            test_support::notify(&app, body.clone()).await;
+        }
+        let (status, response) = test_support::notify(&app, body).await;
+        assert_eq!(status, StatusCode::OK, "{response}");
+    }
+
+    #[tokio::test]
+    async fn resume_answers_404_without_auto_pause() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let (status, response) = call(&app, Method::POST, "/admin/resume", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(response["message"], "auto-pause is not configured");
+    }
+}
@@ -0,0 +1,518 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api_keys::Caller, attachments::AttachmentRequest, authenticate, dispatch, error_response,
+    field_error, queue, ApiError, AppState, JsonBody, NotifyRequest,
+};
+
+/// How a batch's messages share SMTP connections, from
+/// `BATCH_CONNECTION_MODE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchConnectionMode {
+    /// Up to `BATCH_CONCURRENCY` sends at once, each on its own pooled
+    /// connection.
+    Pooled,
+    /// One send at a time, so every message reuses the same pooled
+    /// connection.
+    Single,
+}
+
+/// What happens to a batch of more than `MAX_BATCH_SIZE` messages, from
+/// `BATCH_OVERFLOW`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchOverflow {
+    /// Refuse it with 400.
+    Reject,
+    /// Accept it with 202 and feed it to the queue one chunk of
+    /// `MAX_BATCH_SIZE` at a time.
+    Enqueue,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BatchRequest {
+    messages: Vec<NotifyRequest>,
+    /// Attachments added to every message after its own, e.g. terms shared
+    /// by personal invoices.
+    #[serde(default)]
+    attachments: Vec<AttachmentRequest>,
+    /// Stop dispatching new messages after the first failure.
+    #[serde(default)]
+    fail_fast: bool,
+}
+
+#[derive(Serialize)]
+pub struct BatchResponse {
+    ok: bool,
+    message: String,
+    results: Vec<BatchItemResult>,
+}
+
+#[derive(Serialize)]
+pub struct BatchQueuedResponse {
+    ok: bool,
+    message: String,
+    batch_id: String,
+    /// One per message, in order; each can be followed on `/jobs/{id}`.
+    job_ids: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct BatchItemResult {
+    index: usize,
+    status: ItemStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ItemStatus {
+    Sent,
+    Failed,
+    Skipped,
+}
+
+/// Sends every message in the batch with bounded concurrency, or one after
+/// another in [`BatchConnectionMode::Single`].
+///
+/// In fail-fast mode a failure only stops messages that have not started
+/// yet; sends already talking to the SMTP server run to completion so no
+/// message is left half-delivered.
+///
+/// Batches over `MAX_BATCH_SIZE` are refused or, with `BATCH_OVERFLOW`
+/// set to `enqueue`, handed to the async queue in chunks.
+pub async fn notify_batch(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    JsonBody(req): JsonBody<BatchRequest>,
+) -> Result<Response, ApiError> {
+    let Some(caller) = authenticate(&state, &headers).await else {
+        return Err(error_response(StatusCode::UNAUTHORIZED, "invalid api key").into());
+    };
+    if req.messages.is_empty() {
+        return Err(field_error("messages", "messages cannot be empty").into());
+    }
+    if let Some(max) = state.max_batch_size.filter(|max| req.messages.len() > *max) {
+        if state.batch_overflow == BatchOverflow::Reject {
+            return Err(field_error(
+                "messages",
+                &format!("batch has {} messages, more than {max}", req.messages.len()),
+            )
+            .into());
+        }
+        return enqueue_in_chunks(&state, &caller, &headers, req, max)
+            .map(IntoResponse::into_response);
+    }
+
+    let fail_fast = req.fail_fast;
+    let concurrency = match state.batch_connection_mode {
+        BatchConnectionMode::Pooled => state.batch_concurrency,
+        BatchConnectionMode::Single => 1,
+    };
+    let stop = AtomicBool::new(false);
+    let shared = req.attachments;
+    let shared_size: usize = shared.iter().map(AttachmentRequest::size).sum();
+    let (state, caller, headers, stop, shared) = (&state, &caller, &headers, &stop, &shared);
+
+    let mut results: Vec<BatchItemResult> = stream::iter(req.messages.into_iter().enumerate())
+        .map(|(index, mut message)| async move {
+            if stop.load(Ordering::Acquire) {
+                return BatchItemResult {
+                    index,
+                    status: ItemStatus::Skipped,
+                    error: None,
+                };
+            }
+
+            let error = match merge_shared(state, &mut message, shared, shared_size) {
+                Err(error) => error,
+                Ok(()) => {
+                    let (status, Json(body)) = dispatch(state, caller, headers, message).await;
+                    if concurrency == 1 {
+                        // lettre hands the connection back to its pool on a
+                        // spawned task; let it run so the next message finds
+                        // it idle.
+                        tokio::task::yield_now().await;
+                    }
+                    if status.is_success() {
+                        return BatchItemResult {
+                            index,
+                            status: ItemStatus::Sent,
+                            error: None,
+                        };
+                    }
+                    body.message
+                }
+            };
+
+            if fail_fast {
+                stop.store(true, Ordering::Release);
+            }
+            BatchItemResult {
+                index,
+                status: ItemStatus::Failed,
+                error: Some(error),
+            }
+        })
+        .buffer_unordered(concurrency)
+        .collect()
+        .await;
+    results.sort_by_key(|result| result.index);
+
+    let all_sent = results.iter().all(|r| r.status == ItemStatus::Sent);
+    let message = if all_sent {
+        "sent"
+    } else if results.iter().any(|r| r.status == ItemStatus::Skipped) {
+        "aborted after first failure"
+    } else {
+        "partially failed"
+    };
+
+    Ok(Json(BatchResponse {
+        ok: all_sent,
+        message: message.to_string(),
+        results,
+    })
+    .into_response())
+}
+
+/// Merges the shared attachments into every message, then queues the batch
+/// `chunk` messages at a time. Nothing is queued if any message is invalid.
+fn enqueue_in_chunks(
+    state: &Arc<AppState>,
+    caller: &Caller<'_>,
+    headers: &HeaderMap,
+    req: BatchRequest,
+    chunk: usize,
+) -> Result<(StatusCode, Json<BatchQueuedResponse>), ApiError> {
+    let shared_size: usize = req.attachments.iter().map(AttachmentRequest::size).sum();
+    let mut messages = req.messages;
+    for (index, message) in messages.iter_mut().enumerate() {
+        merge_shared(state, message, &req.attachments, shared_size).map_err(|error| {
+            error_response(
+                StatusCode::BAD_REQUEST,
+                &format!("message {index}: {error}"),
+            )
+        })?;
+    }
+    let (batch_id, job_ids) = queue::enqueue_batch(state, caller, headers, messages, chunk)?;
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(BatchQueuedResponse {
+            ok: true,
+            message: "batch queued".to_string(),
+            batch_id,
+            job_ids,
+        }),
+    ))
+}
+
+/// Appends the batch's shared attachments to `message`, holding the result
+/// to `MAX_UPLOAD_BYTES` of attachments like an upload.
+fn merge_shared(
+    state: &AppState,
+    message: &mut NotifyRequest,
+    shared: &[AttachmentRequest],
+    shared_size: usize,
+) -> Result<(), String> {
+    if shared.is_empty() {
+        return Ok(());
+    }
+    let own_size: usize = message
+        .attachments
+        .iter()
+        .map(AttachmentRequest::size)
+        .sum();
+    if own_size + shared_size > state.max_upload_bytes {
+        return Err(format!(
+            "attachments exceed {} bytes with the shared attachments",
+            state.max_upload_bytes
+        ));
+    }
+    message.attachments.extend(shared.iter().cloned());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use crate::queue::spawn_workers;
+    use base64::{prelude::BASE64_STANDARD, Engine};
+    use std::time::Duration;
+
+    use axum::http::Method;
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::test_support::{self, call};
+
+    fn messages() -> Value {
+        let message = |to: &str| json!({"service": "smtp", "to": to, "title": "t", "body": "b"});
+        json!([
+            message("first@example.com"),
+            message("not an address"),
+            message("third@example.com"),
+            message("fourth@example.com"),
+        ])
+    }
+
+    #[tokio::test]
+    async fn best_effort_attempts_every_message() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let (status, body) = call(
+            &app,
+            Method::POST,
+            "/notify/batch",
+            Some(json!({"messages": messages()})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["ok"], false);
+        assert_eq!(body["message"], "partially failed");
+        let statuses: Vec<_> = body["results"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|result| result["status"].clone())
+            .collect();
+        assert_eq!(statuses, ["sent", "failed", "sent", "sent"]);
+        assert_eq!(body["results"][1]["error"], "invalid recipient email");
+        assert_eq!(test_support::sent(&app).await.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn fail_fast_skips_what_has_not_started() {
+        let state = test_support::state(&[("BATCH_CONNECTION_MODE", "single")]).await;
+        let app = test_support::app(&state);
+        let (status, body) = call(
+            &app,
+            Method::POST,
+            "/notify/batch",
+            Some(json!({"messages": messages(), "fail_fast": true})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["ok"], false);
+        assert_eq!(body["message"], "aborted after first failure");
+        let statuses: Vec<_> = body["results"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|result| result["status"].clone())
+            .collect();
+        assert_eq!(statuses, ["sent", "failed", "skipped", "skipped"]);
+        let sent = test_support::sent(&app).await;
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0]["to"], json!(["first@example.com"]));
+    }
+
+    fn batch(count: usize) -> Value {
+        let messages: Vec<_> = (0..count)
+            .map(|n| json!({"service": "smtp", "to": format!("user{n}@example.com"), "title": "t", "body": "b"}))
+            .collect();
+        json!({ "messages": messages })
+    }
+
+    #[tokio::test]
+    async fn single_mode_sends_the_batch_over_one_connection() {
+        let smtp = test_support::MockSmtp::start().await;
+        smtp.delay("DATA", Duration::from_millis(50));
+        let state = smtp.state(&[("BATCH_CONNECTION_MODE", "single")]).await;
+        let app = test_support::app(&state);
+
+        let (status, body) = call(&app, Method::POST, "/notify/batch", Some(batch(5))).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["message"], "sent");
+        assert_eq!(smtp.messages().len(), 5);
+        assert_eq!(smtp.connections(), 1);
+    }
+
+    #[tokio::test]
+    async fn pooled_mode_sends_the_batch_concurrently() {
+        let smtp = test_support::MockSmtp::start().await;
+        smtp.delay("DATA", Duration::from_millis(50));
+        let state = smtp.state(&[("BATCH_CONNECTION_MODE", "pooled")]).await;
+        let app = test_support::app(&state);
+
+        let (status, body) = call(&app, Method::POST, "/notify/batch", Some(batch(5))).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(smtp.messages().len(), 5);
+        assert!(smtp.connections() > 1, "{} connections", smtp.connections());
+    }
+
+    #[test]
+    fn unknown_batch_connection_modes_are_refused() {
+        let err = test_support::config(&[("BATCH_CONNECTION_MODE", "pipelined")]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unsupported BATCH_CONNECTION_MODE: pipelined"
+        );
+    }
+
+    fn attachment(filename: &str, content: &str) -> Value {
+        json!({"filename": filename, "content": BASE64_STANDARD.encode(content), "content_type": "text/plain"})
+    }
+
+    fn personal(to: &str, invoice: &str) -> Value {
+        json!({"service": "smtp", "to": to, "title": "t", "body": "b",
+            "attachments": [attachment(&format!("{invoice}.txt"), &format!("total for {invoice}"))]})
+    }
+
+    #[tokio::test]
+    async fn each_message_gets_its_own_attachments_and_the_shared_ones() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let (status, body) = call(
+            &app,
+            Method::POST,
+            "/notify/batch",
+            Some(json!({
+                "messages": [personal("alice@example.com", "INV-1"), personal("bob@example.com", "INV-2")],
+                "attachments": [attachment("terms.txt", "the terms")],
+            })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["message"], "sent");
+
+        let sent = test_support::sent(&app).await;
+        let raw = |to: &str| {
+            sent.iter()
+                .find(|message| message["to"] == json!([to]))
+                .and_then(|message| message["raw"].as_str())
+                .unwrap_or_else(|| panic!("no message to {to}"))
+                .to_string()
+        };
+        let (alice, bob) = (raw("alice@example.com"), raw("bob@example.com"));
+        assert!(
+            alice.contains("INV-1.txt") && !alice.contains("INV-2.txt"),
+            "{alice}"
+        );
+        assert!(
+            bob.contains("INV-2.txt") && !bob.contains("INV-1.txt"),
+            "{bob}"
+        );
+        assert!(alice.contains("total for INV-1"), "{alice}");
+        assert!(bob.contains("total for INV-2"), "{bob}");
+        for raw in [&alice, &bob] {
+            assert!(
+                raw.contains("filename=\"terms.txt\"") && raw.contains("the terms"),
+                "{raw}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn the_size_cap_covers_own_and_shared_attachments_together() {
+        let app = test_support::app(&test_support::state(&[("MAX_UPLOAD_BYTES", "40")]).await);
+        let mut large = personal("bob@example.com", "INV-2");
+        large["attachments"][0] = attachment("INV-2.txt", &"x".repeat(35));
+        let (status, body) = call(
+            &app,
+            Method::POST,
+            "/notify/batch",
+            Some(json!({
+                "messages": [personal("alice@example.com", "INV-1"), large],
+                "attachments": [attachment("terms.txt", "the terms")],
+            })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let results = body["results"].as_array().unwrap();
+        assert_eq!(results[0]["status"], "sent");
+        assert_eq!(results[1]["status"], "failed");
+        assert_eq!(
+            results[1]["error"],
+            "attachments exceed 40 bytes with the shared attachments"
+        );
+        assert_eq!(test_support::sent(&app).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn oversized_batches_are_refused_by_default() {
+        let app = test_support::app(&test_support::state(&[("MAX_BATCH_SIZE", "4")]).await);
+        let (status, body) = call(&app, Method::POST, "/notify/batch", Some(batch(4))).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        let (status, body) = call(&app, Method::POST, "/notify/batch", Some(batch(5))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["message"], "batch has 5 messages, more than 4");
+        assert_eq!(test_support::sent(&app).await.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn oversized_batches_can_be_queued_in_chunks() {
+        let state =
+            test_support::state(&[("MAX_BATCH_SIZE", "2"), ("BATCH_OVERFLOW", "enqueue")]).await;
+        let app = test_support::app(&state);
+        spawn_workers(&state, 2);
+
+        let (status, body) = call(&app, Method::POST, "/notify/batch", Some(batch(5))).await;
+        assert_eq!(status, StatusCode::ACCEPTED, "{body}");
+        assert_eq!(body["message"], "batch queued");
+        assert!(body["batch_id"].is_string(), "{body}");
+        let job_ids = body["job_ids"].as_array().expect("job ids");
+        assert_eq!(job_ids.len(), 5);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        for id in job_ids {
+            let uri = format!("/jobs/{}", id.as_str().unwrap());
+            loop {
+                let (status, job) = call(&app, Method::GET, &uri, None).await;
+                assert_eq!(status, StatusCode::OK, "{job}");
+                if job["message"] == "sent" {
+                    break;
+                }
+                assert!(Instant::now() < deadline, "job never sent: {job}");
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        }
+        let mut to: Vec<_> = test_support::sent(&app)
+            .await
+            .iter()
+            .map(|message| message["to"][0].as_str().unwrap().to_string())
+            .collect();
+        to.sort();
+        let expected: Vec<_> = (0..5).map(|n| format!("user{n}@example.com")).collect();
+        assert_eq!(to, expected);
+    }
+
+    #[tokio::test]
+    async fn a_queued_batch_is_validated_whole_up_front() {
+        let state =
+            test_support::state(&[("MAX_BATCH_SIZE", "2"), ("BATCH_OVERFLOW", "enqueue")]).await;
+        let app = test_support::app(&state);
+        spawn_workers(&state, 1);
+
+        let (status, body) = call(
+            &app,
+            Method::POST,
+            "/notify/batch",
+            Some(json!({"messages": messages()})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["message"], "message 1: invalid recipient email");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(test_support::sent(&app).await.is_empty());
+    }
+
+    #[test]
+    fn unknown_batch_overflow_modes_are_refused() {
+        let err = test_support::config(&[("BATCH_OVERFLOW", "split")]).unwrap_err();
+        assert_eq!(err.to_string(), "unsupported BATCH_OVERFLOW: split");
+    }
+}
@@ -0,0 +1,210 @@
+use serde::Serialize;
+
+use crate::{build_bound_transport, parse_bool_env, AppState, BackendConfig, SmtpConfig};
+
+/// What `CONFIG_CHECK=true` prints instead of serving: the configuration the
+/// server would run with, or why it would not start. Printed as JSON on
+/// stdout, after any startup logs; the exit status is 0 only when `ok`.
+#[derive(Serialize)]
+pub struct Report {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    configured: Option<Configured>,
+    problems: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct Configured {
+    environment: Option<String>,
+    http_bind: String,
+    backend: &'static str,
+    /// `host:port` of the primary SMTP server, then the fallbacks.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    smtp_servers: Vec<String>,
+    from: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    redirect_all_to: Option<String>,
+    /// Templates loaded from `TEMPLATES_DIR`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    templates: Option<usize>,
+    dkim: bool,
+    pgp: bool,
+    spool: bool,
+    dead_letters: bool,
+    outbox: bool,
+    tracking: bool,
+    verify_recipients: bool,
+    /// Whether each SMTP server was dialled and authenticated against
+    /// (`CONFIG_CHECK_CONNECT`).
+    connection_tested: bool,
+}
+
+impl Report {
+    /// Startup got as far as a running configuration. With
+    /// `CONFIG_CHECK_CONNECT=true` every SMTP server is also dialled and
+    /// logged in to, and a failure is reported as a problem.
+    pub async fn inspect(
+        state: &AppState,
+        backend: &BackendConfig,
+        http_bind: &str,
+        environment: Option<&str>,
+    ) -> Self {
+        let servers: Vec<&SmtpConfig> = match backend {
+            BackendConfig::Smtp(smtp) => std::iter::once(smtp.as_ref())
+                .chain(smtp.fallbacks.iter())
+                .collect(),
+            BackendConfig::Ses {
+                smtp_fallback: Some(smtp),
+                ..
+            } => vec![smtp.as_ref()],
+            BackendConfig::Ses { .. } | BackendConfig::Memory => Vec::new(),
+        };
+        let connect = parse_bool_env("CONFIG_CHECK_CONNECT").unwrap_or(false);
+        let mut problems = Vec::new();
+        if connect {
+            for smtp in &servers {
+                if let Err(problem) = test_connection(smtp).await {
+                    problems.push(format!(
+                        "smtp server {}:{}: {problem}",
+                        smtp.host, smtp.port
+                    ));
+                }
+            }
+        }
+
+        Self {
+            ok: problems.is_empty(),
+            configured: Some(Configured {
+                environment: environment.map(str::to_string),
+                http_bind: http_bind.to_string(),
+                backend: state.transport.name(),
+                smtp_servers: servers
+                    .iter()
+                    .map(|smtp| format!("{}:{}", smtp.host, smtp.port))
+                    .collect(),
+                from: state.from.iter().map(ToString::to_string).collect(),
+                redirect_all_to: state.redirect_all_to.as_ref().map(ToString::to_string),
+                templates: state.templates.as_ref().map(|templates| templates.count()),
+                dkim: state.dkim.is_some(),
+                pgp: state.pgp.is_some(),
+                spool: state.spool.is_some(),
+                dead_letters: state.dead_letters.is_some(),
+                outbox: state.outbox.is_some(),
+                tracking: state.tracking.is_some(),
+                verify_recipients: state.recipient_probe.is_some(),
+                connection_tested: connect && !servers.is_empty(),
+            }),
+            problems,
+        }
+    }
+
+    /// Startup failed with `err`.
+    pub fn failed(err: &anyhow::Error) -> Self {
+        Self {
+            ok: false,
+            configured: None,
+            problems: vec![format!("{err:#}")],
+        }
+    }
+
+    pub fn print_and_exit(self) -> ! {
+        let report = serde_json::to_string_pretty(&self).expect("report serializes");
+        println!("{report}");
+        std::process::exit(if self.ok { 0 } else { 1 })
+    }
+}
+
+async fn test_connection(smtp: &SmtpConfig) -> Result<(), String> {
+    let check_cfg = SmtpConfig {
+        chunking: false,
+        warmup: false,
+        ..smtp.clone()
+    };
+    let transport = build_bound_transport(&check_cfg).map_err(|err| format!("{err:#}"))?;
+    transport
+        .test_connection()
+        .await
+        .map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::test_support;
+
+    fn report_json(report: &Report) -> Value {
+        serde_json::to_value(report).expect("report serializes")
+    }
+
+    #[test]
+    fn a_missing_required_var_is_reported() {
+        let err = test_support::config(&[("BACKEND", "smtp")]).unwrap_err();
+        let report = report_json(&Report::failed(&err));
+        assert_eq!(report["ok"], false);
+        assert_eq!(
+            report["problems"],
+            json!(["missing env var: SMTP_HOST: environment variable not found"])
+        );
+        assert!(report.get("configured").is_none(), "{report}");
+    }
+
+    #[tokio::test]
+    async fn a_complete_config_passes() {
+        let smtp = test_support::MockSmtp::start().await;
+        let port = smtp.port();
+        let cfg = test_support::config(&[
+            ("BACKEND", "smtp"),
+            ("SMTP_HOST", "127.0.0.1"),
+            ("SMTP_PORT", &port),
+            ("SMTP_TLS", "false"),
+        ])
+        .expect("config loads");
+        let (state, cfg) = crate::build_state(cfg).await.expect("state builds");
+        let report = report_json(
+            &Report::inspect(&state, &cfg.backend, &cfg.http_bind, Some("staging")).await,
+        );
+        assert_eq!(report["ok"], true, "{report}");
+        assert_eq!(report["problems"], json!([]));
+        let configured = &report["configured"];
+        assert_eq!(configured["environment"], "staging");
+        assert_eq!(configured["backend"], "smtp");
+        assert_eq!(
+            configured["smtp_servers"],
+            json!([format!("127.0.0.1:{port}")])
+        );
+        assert_eq!(
+            configured["from"],
+            json!(["Notifications <notify@example.com>"])
+        );
+        assert_eq!(configured["connection_tested"], false);
+    }
+
+    fn smtp_config(port: &str) -> SmtpConfig {
+        let cfg = test_support::config(&[
+            ("BACKEND", "smtp"),
+            ("SMTP_HOST", "127.0.0.1"),
+            ("SMTP_PORT", port),
+            ("SMTP_TLS", "false"),
+        ])
+        .expect("config loads");
+        match cfg.backend {
+            BackendConfig::Smtp(smtp) => *smtp,
+            other => panic!("not an smtp backend: {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn the_connection_test_dials_the_server() {
+        let smtp = test_support::MockSmtp::start().await;
+        assert_eq!(test_connection(&smtp_config(&smtp.port())).await, Ok(()));
+        assert_eq!(smtp.connections(), 1);
+
+        // Nothing listens on the port of a dropped listener.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        drop(listener);
+        assert!(test_connection(&smtp_config(&port)).await.is_err());
+    }
+}
@@ -0,0 +1,393 @@
+use std::{fs, io::ErrorKind, path::PathBuf, sync::Arc, time::SystemTime};
+
+use anyhow::{Context, Result};
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    authenticate, dispatch, error_response,
+    failures::AttachmentSummary,
+    queue::header_map,
+    spool::{self, SpooledJob},
+    ApiError, ApiResponse, AppState, NotifyRequest,
+};
+
+/// A background job that failed or expired, as written to the store.
+#[derive(Debug, Serialize, Deserialize)]
+struct DeadLetter {
+    /// Unix timestamp in seconds of the latest failure.
+    failed_at: u64,
+    error: String,
+    job: SpooledJob,
+}
+
+/// A dead letter as listed: the request without attachment contents.
+#[derive(Serialize)]
+struct DeadLetterView {
+    id: String,
+    failed_at: u64,
+    error: String,
+    request: NotifyRequest,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    attachments: Vec<AttachmentSummary>,
+}
+
+#[derive(Serialize)]
+pub struct DeadLettersResponse {
+    ok: bool,
+    message: String,
+    dead_letters: Vec<DeadLetterView>,
+}
+
+/// Background jobs that failed for good (`DEAD_LETTER`), one JSON file each
+/// under `SPOOL_DIR/deadletter`, kept until an operator retries or discards
+/// them. Unlike the failure log they keep attachments and survive restarts.
+pub struct DeadLetters {
+    dir: PathBuf,
+}
+
+impl DeadLetters {
+    pub fn new(spool_dir: PathBuf) -> Result<Self> {
+        let dir = spool_dir.join("deadletter");
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("failed to create dead-letter dir {}", dir.display()))?;
+        Ok(Self { dir })
+    }
+
+    /// Dead letters in the store, across every key.
+    pub fn count(&self) -> usize {
+        spool::count_json_files(&self.dir)
+    }
+
+    /// Stores the job, which still has its spool copy with attachments
+    /// inlined.
+    pub fn bury(&self, job: SpooledJob, error: String) {
+        let id = job.id.clone();
+        let letter = DeadLetter {
+            failed_at: spool::to_unix(SystemTime::now()),
+            error,
+            job,
+        };
+        match self.write(&letter) {
+            Ok(()) => info!(job_id = %id, "job moved to dead-letter store"),
+            Err(err) => {
+                warn!(job_id = %id, error = %format!("{err:#}"), "failed to store dead letter")
+            }
+        }
+    }
+
+    fn write(&self, letter: &DeadLetter) -> Result<()> {
+        let raw = serde_json::to_vec(letter).context("failed to serialize dead letter")?;
+        let path = self.path(&letter.job.id);
+        let partial = path.with_extension("json.partial");
+        fs::write(&partial, raw)
+            .with_context(|| format!("failed to write {}", partial.display()))?;
+        fs::rename(&partial, &path).with_context(|| format!("failed to write {}", path.display()))
+    }
+
+    /// The caller's dead letters, oldest first. Unreadable files are skipped.
+    fn list(&self, key: &str) -> Result<Vec<DeadLetter>> {
+        let entries = fs::read_dir(&self.dir)
+            .with_context(|| format!("failed to read dead-letter dir {}", self.dir.display()))?;
+        let mut letters = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            match read(&path) {
+                Ok(letter) if letter.job.belongs_to(key) => letters.push(letter),
+                Ok(_) => {}
+                Err(err) => {
+                    warn!(path = %path.display(), error = %format!("{err:#}"), "skipping unreadable dead letter")
+                }
+            }
+        }
+        letters.sort_by_key(|letter| letter.failed_at);
+        Ok(letters)
+    }
+
+    fn get(&self, key: &str, id: &str) -> Option<DeadLetter> {
+        // Ids are generated hex; anything else cannot name a file here.
+        if id.is_empty() || !id.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return None;
+        }
+        read(&self.path(id))
+            .ok()
+            .filter(|letter| letter.job.belongs_to(key))
+    }
+
+    fn remove(&self, id: &str) -> Result<()> {
+        let path = self.path(id);
+        match fs::remove_file(&path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => {
+                Err(err).with_context(|| format!("failed to remove {}", path.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{id}.json"))
+    }
+}
+
+fn read(path: &std::path::Path) -> Result<DeadLetter> {
+    let raw = fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    Ok(serde_json::from_slice(&raw)?)
+}
+
+impl From<DeadLetter> for DeadLetterView {
+    fn from(mut letter: DeadLetter) -> Self {
+        let attachments = letter
+            .job
+            .request
+            .attachments
+            .drain(..)
+            .map(AttachmentSummary::from)
+            .collect();
+        Self {
+            id: letter.job.id,
+            failed_at: letter.failed_at,
+            error: letter.error,
+            request: letter.job.request,
+            attachments,
+        }
+    }
+}
+
+fn store(state: &AppState) -> Result<&DeadLetters, ApiError> {
+    state.dead_letters.as_ref().ok_or_else(|| {
+        error_response(StatusCode::NOT_FOUND, "dead-letter store is disabled").into()
+    })
+}
+
+/// `GET /admin/deadletter`: the caller's dead letters, oldest first.
+pub async fn list_dead_letters(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<DeadLettersResponse>, ApiError> {
+    let Some(caller) = authenticate(&state, &headers).await else {
+        return Err(error_response(StatusCode::UNAUTHORIZED, "invalid api key").into());
+    };
+    let letters = store(&state)?.list(caller.key).map_err(|err| {
+        warn!(error = %format!("{err:#}"), "failed to list dead letters");
+        error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "failed to read dead-letter store",
+        )
+    })?;
+
+    Ok(Json(DeadLettersResponse {
+        ok: true,
+        message: "ok".to_string(),
+        dead_letters: letters.into_iter().map(DeadLetterView::from).collect(),
+    }))
+}
+
+/// `POST /admin/deadletter/{id}/retry`: sends the job again as the key that
+/// queued it. It leaves the store once sent; a repeated failure updates its
+/// error instead.
+pub async fn retry(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> (StatusCode, Json<ApiResponse>) {
+    let Some(caller) = authenticate(&state, &headers).await else {
+        return error_response(StatusCode::UNAUTHORIZED, "invalid api key");
+    };
+    let store = match store(&state) {
+        Ok(store) => store,
+        Err(resp) => return resp.into_inner(),
+    };
+    let Some(mut letter) = store.get(caller.key, &id) else {
+        return error_response(StatusCode::NOT_FOUND, "dead letter not found");
+    };
+
+    info!(job_id = %id, "retrying dead letter");
+    let job_headers = header_map(&letter.job.headers);
+    let (status, body) = dispatch(&state, &caller, &job_headers, letter.job.request.clone()).await;
+    if status.is_success() {
+        if let Err(err) = store.remove(&id) {
+            warn!(job_id = %id, error = %format!("{err:#}"), "failed to remove retried dead letter");
+        }
+    } else {
+        letter.failed_at = spool::to_unix(SystemTime::now());
+        letter.error = body.message.clone();
+        if let Err(err) = store.write(&letter) {
+            warn!(job_id = %id, error = %format!("{err:#}"), "failed to update dead letter");
+        }
+    }
+    (status, body)
+}
+
+/// `DELETE /admin/deadletter/{id}`: discards a dead letter unsent.
+pub async fn discard(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> (StatusCode, Json<ApiResponse>) {
+    let Some(caller) = authenticate(&state, &headers).await else {
+        return error_response(StatusCode::UNAUTHORIZED, "invalid api key");
+    };
+    let store = match store(&state) {
+        Ok(store) => store,
+        Err(resp) => return resp.into_inner(),
+    };
+    if store.get(caller.key, &id).is_none() {
+        return error_response(StatusCode::NOT_FOUND, "dead letter not found");
+    }
+    if let Err(err) = store.remove(&id) {
+        warn!(job_id = %id, error = %format!("{err:#}"), "failed to discard dead letter");
+        return error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "failed to discard dead letter",
+        );
+    }
+
+    info!(job_id = %id, "dead letter discarded");
+    (StatusCode::OK, Json(ApiResponse::ok("discarded")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use axum::http::Method;
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::{
+        queue::spawn_workers,
+        test_support::{self, call, TempDir},
+    };
+
+    /// A state whose background sends fail once with a 550, then succeed.
+    async fn failing_once(smtp: &test_support::MockSmtp) -> (TempDir, Arc<AppState>) {
+        smtp.reply("MAIL", "550 5.7.1 sender rejected");
+        let dir = TempDir::new("deadletter");
+        let state = smtp
+            .state(&[
+                ("SPOOL_DIR", dir.path().to_str().expect("utf-8 path")),
+                ("DEAD_LETTER", "true"),
+            ])
+            .await;
+        spawn_workers(&state, 1);
+        (dir, state)
+    }
+
+    /// Queues a send and waits for it to land in the dead-letter store.
+    async fn buried(app: &axum::Router) -> Value {
+        let (status, body) = call(
+            app,
+            Method::POST,
+            "/notify/async",
+            Some(json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b"})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::ACCEPTED, "{body}");
+        let job_id = body["job_id"].clone();
+
+        let deadline = Instant::now() + Duration::from_secs(3);
+        loop {
+            let (status, body) = call(app, Method::GET, "/admin/deadletter", None).await;
+            assert_eq!(status, StatusCode::OK, "{body}");
+            if let Some(letter) = body["dead_letters"].get(0) {
+                assert_eq!(letter["id"], job_id);
+                return letter.clone();
+            }
+            assert!(Instant::now() < deadline, "job never reached the store");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    async fn listed(app: &axum::Router) -> usize {
+        let (_, body) = call(app, Method::GET, "/admin/deadletter", None).await;
+        body["dead_letters"].as_array().expect("a list").len()
+    }
+
+    #[tokio::test]
+    async fn a_failed_job_lands_in_the_store_and_can_be_retried() {
+        let smtp = test_support::MockSmtp::start().await;
+        let (_dir, state) = failing_once(&smtp).await;
+        let app = test_support::app(&state);
+
+        let letter = buried(&app).await;
+        assert_eq!(letter["error"], "smtp send failed");
+        assert_eq!(letter["request"]["to"], "ops@example.com");
+        assert!(smtp.messages().is_empty());
+
+        let id = letter["id"].as_str().unwrap();
+        let uri = format!("/admin/deadletter/{id}/retry");
+        let (status, body) = call(&app, Method::POST, &uri, None).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(smtp.messages().len(), 1);
+        assert_eq!(listed(&app).await, 0);
+
+        let (status, _) = call(&app, Method::POST, &uri, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn a_failed_retry_stays_in_the_store() {
+        let smtp = test_support::MockSmtp::start().await;
+        let (_dir, state) = failing_once(&smtp).await;
+        let app = test_support::app(&state);
+        let letter = buried(&app).await;
+
+        smtp.reply("MAIL", "550 5.7.1 still rejected");
+        let uri = format!("/admin/deadletter/{}/retry", letter["id"].as_str().unwrap());
+        let (status, _) = call(&app, Method::POST, &uri, None).await;
+        assert!(!status.is_success(), "{status}");
+
+        assert_eq!(
+            smtp.commands()
+                .iter()
+                .filter(|c| c.starts_with("MAIL"))
+                .count(),
+            2
+        );
+        let (_, body) = call(&app, Method::GET, "/admin/deadletter", None).await;
+        assert_eq!(body["dead_letters"][0]["id"], letter["id"]);
+        assert_eq!(body["dead_letters"][0]["error"], "smtp send failed");
+    }
+
+    #[tokio::test]
+    async fn a_dead_letter_can_be_discarded() {
+        let smtp = test_support::MockSmtp::start().await;
+        let (_dir, state) = failing_once(&smtp).await;
+        let app = test_support::app(&state);
+        let letter = buried(&app).await;
+
+        let uri = format!("/admin/deadletter/{}", letter["id"].as_str().unwrap());
+        let (status, body) = call(&app, Method::DELETE, &uri, None).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["message"], "discarded");
+        assert_eq!(listed(&app).await, 0);
+        assert!(smtp.messages().is_empty());
+
+        let (status, _) = call(&app, Method::DELETE, &uri, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = call(&app, Method::DELETE, "/admin/deadletter/..%2Fspool", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn the_store_answers_404_when_disabled() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let (status, body) = call(&app, Method::GET, "/admin/deadletter", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["message"], "dead-letter store is disabled");
+    }
+
+    #[test]
+    fn dead_letter_requires_a_spool_dir() {
+        let err = test_support::config(&[("DEAD_LETTER", "true")]).unwrap_err();
+        assert_eq!(err.to_string(), "DEAD_LETTER requires SPOOL_DIR");
+    }
+}
@@ -0,0 +1,206 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::NotifyRequest;
+
+/// Longest accepted `dedupe_window_secs`; bounds how long hashes are kept.
+pub const MAX_WINDOW_SECS: u64 = 86_400;
+
+type ContentHash = [u8; 32];
+
+struct SentMessage {
+    sent: Instant,
+    /// Unix timestamp in seconds, returned to deduplicated callers.
+    timestamp: u64,
+    expires: Instant,
+}
+
+/// Hashes of recently sent messages, for requests that opt in with
+/// `dedupe_window_secs`. Entries expire with the window they were sent
+/// under and are evicted on the next lookup.
+#[derive(Default)]
+pub struct Deduplicator {
+    sent: Mutex<HashMap<ContentHash, SentMessage>>,
+}
+
+impl Deduplicator {
+    /// Hash of the caller and the message's recipients, subject, body and
+    /// attachments.
+    /// Scoped per API key so one caller cannot suppress another's mail.
+    pub fn content_hash(key: &str, req: &NotifyRequest) -> ContentHash {
+        let mut hasher = Sha256::new();
+        for part in [
+            key,
+            req.to.trim(),
+            req.cc.as_deref().unwrap_or_default(),
+            req.bcc.as_deref().unwrap_or_default(),
+            &req.title,
+            &req.body,
+            req.html.as_deref().unwrap_or_default(),
+            req.template.as_deref().unwrap_or_default(),
+            &req.data.to_string(),
+        ] {
+            hasher.update(part.as_bytes());
+            // Separator so moving text between fields changes the hash.
+            hasher.update([0]);
+        }
+        for attachment in &req.attachments {
+            hasher.update(attachment.filename.as_bytes());
+            hasher.update([0]);
+            match &attachment.spooled {
+                Some(file) => hasher.update(file.digest),
+                None => hasher.update(attachment.content.as_bytes()),
+            }
+            hasher.update([0]);
+        }
+        hasher.finalize().into()
+    }
+
+    /// Claims `hash` for a send, so an identical request arriving while it
+    /// is in flight is deduplicated too. Returns the Unix timestamp of an
+    /// identical message sent or claimed within `window` instead. The claim
+    /// is released when dropped unless the send succeeded and `keep` was
+    /// called.
+    pub fn claim(&self, hash: ContentHash, window: Duration) -> Result<Claim<'_>, u64> {
+        let now = Instant::now();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_secs())
+            .unwrap_or_default();
+        let mut sent = self.sent.lock().expect("dedupe lock poisoned");
+        sent.retain(|_, message| message.expires > now);
+        if let Some(message) = sent
+            .get(&hash)
+            .filter(|message| now.duration_since(message.sent) < window)
+        {
+            return Err(message.timestamp);
+        }
+        sent.insert(
+            hash,
+            SentMessage {
+                sent: now,
+                timestamp,
+                expires: now + window,
+            },
+        );
+        Ok(Claim {
+            dedupe: self,
+            hash,
+            sent: now,
+            kept: false,
+        })
+    }
+}
+
+/// A send in flight under `dedupe_window_secs`; see `Deduplicator::claim`.
+pub struct Claim<'a> {
+    dedupe: &'a Deduplicator,
+    hash: ContentHash,
+    sent: Instant,
+    kept: bool,
+}
+
+impl Claim<'_> {
+    /// Keeps the hash for the rest of its window after a successful send.
+    pub fn keep(mut self) {
+        self.kept = true;
+    }
+
+    /// Holds the claim for a send that finishes in the background, where
+    /// the borrow cannot follow; [`DetachedClaim::attach`] takes it back.
+    pub fn detach(mut self) -> DetachedClaim {
+        self.kept = true;
+        DetachedClaim {
+            hash: self.hash,
+            sent: self.sent,
+        }
+    }
+}
+
+/// A [`Claim`] still held while its send is deferred.
+pub struct DetachedClaim {
+    hash: ContentHash,
+    sent: Instant,
+}
+
+impl DetachedClaim {
+    /// The claim again, released on drop or kept as before.
+    pub fn attach(self, dedupe: &Deduplicator) -> Claim<'_> {
+        Claim {
+            dedupe,
+            hash: self.hash,
+            sent: self.sent,
+            kept: false,
+        }
+    }
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        if self.kept {
+            return;
+        }
+        let mut sent = self.dedupe.sent.lock().expect("dedupe lock poisoned");
+        if sent
+            .get(&self.hash)
+            .is_some_and(|message| message.sent == self.sent)
+        {
+            sent.remove(&self.hash);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_millis(50);
+
+    #[test]
+    fn duplicate_within_the_window_is_refused() {
+        let dedupe = Deduplicator::default();
+        dedupe.claim([1; 32], WINDOW).expect("first send").keep();
+
+        let sent_at = dedupe.claim([1; 32], WINDOW).err().expect("a duplicate");
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        assert!(now - sent_at <= 1, "{sent_at} vs {now}");
+        assert!(dedupe.claim([2; 32], WINDOW).is_ok());
+    }
+
+    #[test]
+    fn duplicate_after_the_window_is_sent() {
+        let dedupe = Deduplicator::default();
+        dedupe.claim([1; 32], WINDOW).expect("first send").keep();
+
+        std::thread::sleep(WINDOW + Duration::from_millis(10));
+        assert!(dedupe.claim([1; 32], WINDOW).is_ok());
+    }
+
+    #[test]
+    fn failed_send_releases_its_claim() {
+        let dedupe = Deduplicator::default();
+        let claim = dedupe.claim([1; 32], WINDOW).expect("first send");
+        assert!(dedupe.claim([1; 32], WINDOW).is_err(), "in flight");
+
+        drop(claim);
+        assert!(dedupe.claim([1; 32], WINDOW).is_ok());
+    }
+
+    #[test]
+    fn a_detached_claim_is_held_until_it_is_resolved() {
+        let dedupe = Deduplicator::default();
+        let detached = dedupe.claim([1; 32], WINDOW).expect("first send").detach();
+        assert!(dedupe.claim([1; 32], WINDOW).is_err(), "still deferred");
+
+        drop(detached.attach(&dedupe));
+        assert!(dedupe.claim([1; 32], WINDOW).is_ok());
+    }
+}
@@ -0,0 +1,282 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::Serialize;
+
+use crate::{
+    authenticate, build_smtp_email, error_response, unprocessable_field, ApiError, AppState,
+    JsonBody, NotificationService, NotifyRequest,
+};
+
+/// Subject words that content filters commonly score as spam.
+const SPAMMY_WORDS: &[&str] = &[
+    "free",
+    "winner",
+    "urgent",
+    "guaranteed",
+    "act now",
+    "limited time",
+    "cash",
+    "100%",
+    "risk-free",
+    "click here",
+    "congratulations",
+    "no cost",
+];
+
+#[derive(Serialize)]
+pub struct DeliverabilityResponse {
+    ok: bool,
+    message: String,
+    /// 0 (no known risk) to 100, the sum of the warnings' weights.
+    score: u32,
+    risk: Risk,
+    warnings: Vec<Warning>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Risk {
+    Low,
+    Medium,
+    High,
+}
+
+#[derive(Serialize)]
+struct Warning {
+    code: &'static str,
+    message: String,
+    #[serde(skip)]
+    weight: u32,
+}
+
+impl Warning {
+    fn new(code: &'static str, weight: u32, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            weight,
+        }
+    }
+}
+
+/// `POST /deliverability-check`: builds the message as `/notify` would and
+/// reports what is likely to hurt its inbox placement, without sending.
+/// The heuristics are advisory; a low score is no delivery guarantee.
+pub async fn deliverability_check(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    JsonBody(req): JsonBody<NotifyRequest>,
+) -> Result<Json<DeliverabilityResponse>, ApiError> {
+    let Some(caller) = authenticate(&state, &headers).await else {
+        return Err(error_response(StatusCode::UNAUTHORIZED, "invalid api key").into());
+    };
+    let subject = req.title.clone();
+    let email = match req.service {
+        NotificationService::Smtp => build_smtp_email(state.as_ref(), &caller.policy, req)?.message,
+        NotificationService::Slack => {
+            return Err(unprocessable_field("service", "only email messages are scored").into())
+        }
+    };
+    let formatted = String::from_utf8_lossy(&email.formatted()).to_ascii_lowercase();
+    let header_block = formatted
+        .split_once("\r\n\r\n")
+        .map_or(formatted.as_str(), |(headers, _)| headers);
+
+    let mut warnings = Vec::new();
+    // A message without a Content-Type is text/plain (RFC 2045), which is
+    // how a plain text body is built.
+    let untyped = !format!("\r\n{header_block}").contains("\r\ncontent-type:");
+    if !untyped && !formatted.contains("content-type: text/plain") {
+        warnings.push(Warning::new(
+            "missing_text_part",
+            25,
+            "message has no text/plain alternative; HTML-only mail is a common spam signal",
+        ));
+    }
+    if !header_block.contains("\r\nlist-unsubscribe:") {
+        warnings.push(Warning::new(
+            "missing_list_unsubscribe",
+            20,
+            "no List-Unsubscribe header; bulk senders are expected to offer one",
+        ));
+    }
+    if state.dkim.is_none() {
+        warnings.push(Warning::new(
+            "no_dkim",
+            25,
+            "DKIM signing is not configured, so the message is unsigned",
+        ));
+    }
+    // Whole words only, so "free" does not match "carefree".
+    let words: String = subject
+        .to_lowercase()
+        .chars()
+        .map(|c| match c.is_alphanumeric() || c == '%' || c == '-' {
+            true => c,
+            false => ' ',
+        })
+        .collect();
+    let words = format!(
+        " {} ",
+        words.split_whitespace().collect::<Vec<_>>().join(" ")
+    );
+    let spammy: Vec<&str> = SPAMMY_WORDS
+        .iter()
+        .copied()
+        .filter(|word| words.contains(&format!(" {word} ")))
+        .collect();
+    if !spammy.is_empty() {
+        warnings.push(Warning::new(
+            "spammy_subject",
+            (10 * spammy.len() as u32).min(30),
+            format!("subject contains spam-prone words: {}", spammy.join(", ")),
+        ));
+    }
+    let letters: Vec<char> = subject.chars().filter(|c| c.is_alphabetic()).collect();
+    if letters.len() >= 8 && letters.iter().all(|c| c.is_uppercase()) {
+        warnings.push(Warning::new(
+            "subject_all_caps",
+            15,
+            "subject is written in capitals",
+        ));
+    }
+    if subject.matches('!').count() >= 2 {
+        warnings.push(Warning::new(
+            "subject_exclamations",
+            10,
+            "subject has several exclamation marks",
+        ));
+    }
+
+    let score = warnings
+        .iter()
+        .map(|warning| warning.weight)
+        .sum::<u32>()
+        .min(100);
+    let risk = match score {
+        0..30 => Risk::Low,
+        30..60 => Risk::Medium,
+        _ => Risk::High,
+    };
+    Ok(Json(DeliverabilityResponse {
+        ok: true,
+        message: "ok".to_string(),
+        score,
+        risk,
+        warnings,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Method;
+    use base64::{prelude::BASE64_STANDARD, Engine};
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::test_support::{self, call, TempDir};
+
+    async fn check(app: &axum::Router, message: Value) -> Value {
+        let (status, body) = call(app, Method::POST, "/deliverability-check", Some(message)).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        body
+    }
+
+    fn codes(body: &Value) -> Vec<&str> {
+        body["warnings"]
+            .as_array()
+            .expect("warnings")
+            .iter()
+            .map(|warning| warning["code"].as_str().unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn an_html_only_message_without_unsubscribe_is_flagged() {
+        let app = test_support::app(&test_support::state(&[("AUTO_TEXT_PART", "false")]).await);
+        let body = check(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "monthly report",
+                "html": "<p>numbers are up</p>"}),
+        )
+        .await;
+        assert_eq!(
+            codes(&body),
+            ["missing_text_part", "missing_list_unsubscribe", "no_dkim"]
+        );
+        assert_eq!(body["score"], 70);
+        assert_eq!(body["risk"], "high");
+        assert!(test_support::sent(&app).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_signed_text_message_is_low_risk() {
+        let dir = TempDir::new("deliverability");
+        let key = dir.path().join("dkim.key");
+        std::fs::write(&key, BASE64_STANDARD.encode([7u8; 32])).unwrap();
+        let app = test_support::app(
+            &test_support::state(&[
+                ("DKIM_PRIVATE_KEY_PATH", key.to_str().expect("utf-8 path")),
+                ("DKIM_SELECTOR", "s1"),
+                ("DKIM_ALGORITHM", "ed25519"),
+            ])
+            .await,
+        );
+        let body = check(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "a carefree deploy",
+                "body": "build 1234 is live", "html": "<p>build 1234 is live</p>"}),
+        )
+        .await;
+        assert_eq!(codes(&body), ["missing_list_unsubscribe"]);
+        assert_eq!(body["score"], 20);
+        assert_eq!(body["risk"], "low");
+    }
+
+    #[tokio::test]
+    async fn spammy_subjects_are_flagged() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let body = check(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com",
+                "title": "URGENT: FREE CASH, WINNER!!", "body": "b"}),
+        )
+        .await;
+        assert_eq!(
+            codes(&body),
+            [
+                "missing_list_unsubscribe",
+                "no_dkim",
+                "spammy_subject",
+                "subject_all_caps",
+                "subject_exclamations"
+            ]
+        );
+        assert_eq!(
+            body["warnings"][2]["message"],
+            "subject contains spam-prone words: free, winner, urgent, cash"
+        );
+        // 20 + 25 + 30 (capped) + 15 + 10
+        assert_eq!(body["score"], 100);
+        assert_eq!(body["risk"], "high");
+    }
+
+    #[tokio::test]
+    async fn only_email_is_scored() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let (status, body) = call(
+            &app,
+            Method::POST,
+            "/deliverability-check",
+            Some(json!({"service": "slack", "to": "#ops", "title": "t", "body": "b"})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["message"], "only email messages are scored");
+    }
+}
@@ -0,0 +1,307 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::Serialize;
+use tracing::info;
+
+use crate::{
+    api_keys::Caller, decode_body, field_error, html_text, queue, validate, ApiError, AppState,
+    BodyEncoding, NotifyRequest,
+};
+
+/// Longest `digest_window_secs` accepted.
+const MAX_WINDOW_SECS: u64 = 86_400;
+
+/// Messages one digest holds; the next one sends it early.
+const MAX_MESSAGES: usize = 100;
+
+/// Sections of a digest's body.
+const SEPARATOR: &str = "\n\n---\n\n";
+
+#[derive(Serialize)]
+pub struct DigestResponse {
+    ok: bool,
+    message: String,
+    /// The job the combined email is sent as, on `/jobs/{id}`.
+    job_id: String,
+    /// Messages in the digest so far, this one included.
+    messages: usize,
+}
+
+/// `digest: true` messages waiting to be combined, per key and recipient.
+/// The first message opens the window; when it closes the messages go out
+/// as one email. Held in memory only, so a restart loses open digests.
+#[derive(Default)]
+pub struct Digests {
+    pending: Mutex<HashMap<(String, String), Pending>>,
+}
+
+struct Pending {
+    id: String,
+    headers: HeaderMap,
+    messages: Vec<NotifyRequest>,
+}
+
+/// Validates a `digest: true` message and adds it to its recipient's open
+/// digest, opening one if there is none.
+pub fn add(
+    state: &Arc<AppState>,
+    caller: &Caller<'_>,
+    headers: &HeaderMap,
+    req: NotifyRequest,
+) -> Result<(StatusCode, Json<DigestResponse>), ApiError> {
+    let window = req.digest_window_secs.unwrap_or(state.digest_window_secs);
+    if window == 0 || window > MAX_WINDOW_SECS {
+        return Err(field_error(
+            "digest_window_secs",
+            &format!("digest_window_secs must be 1 to {MAX_WINDOW_SECS}"),
+        )
+        .into());
+    }
+    if req.template.is_some() {
+        return Err(field_error("digest", "digest cannot be combined with template").into());
+    }
+    validate(state, caller, &req)?;
+
+    let slot = (caller.key.to_string(), req.to.trim().to_ascii_lowercase());
+    let mut pending = state.digests.pending.lock().expect("digest lock poisoned");
+    // A cancelled digest is dropped; this message opens a new one.
+    if pending
+        .get(&slot)
+        .is_some_and(|digest| queue::is_cancelled(state, &digest.id))
+    {
+        pending.remove(&slot);
+    }
+    if !pending.contains_key(&slot) {
+        queue::check_room(state, 1)?;
+    }
+    let digest = pending.entry(slot.clone()).or_insert_with(|| {
+        let digest = Pending {
+            id: queue::reserve(state, caller.key),
+            headers: queue::propagated_headers(headers),
+            messages: Vec::new(),
+        };
+        let (state, slot, id) = (state.clone(), slot.clone(), digest.id.clone());
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(window)).await;
+            flush(&state, &slot, &id);
+        });
+        digest
+    });
+    digest.messages.push(req);
+    let (id, messages) = (digest.id.clone(), digest.messages.len());
+    drop(pending);
+    if messages >= MAX_MESSAGES {
+        flush(state, &slot, &id);
+    }
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(DigestResponse {
+            ok: true,
+            message: "added to digest".to_string(),
+            job_id: id,
+            messages,
+        }),
+    ))
+}
+
+/// Sends the digest `id` if it is still open.
+fn flush(state: &Arc<AppState>, slot: &(String, String), id: &str) {
+    let digest = {
+        let mut pending = state.digests.pending.lock().expect("digest lock poisoned");
+        match pending.get(slot) {
+            Some(digest) if digest.id == id => pending.remove(slot),
+            _ => None,
+        }
+    };
+    let Some(digest) = digest else {
+        return;
+    };
+    info!(job_id = %digest.id, messages = digest.messages.len(), "sending digest");
+    let req = combine(digest.messages, state.clean_text_links);
+    queue::send_reserved(state, digest.id, slot.0.clone(), digest.headers, req);
+}
+
+/// One text email holding every message in order: the subjects joined, the
+/// bodies one after another under their subjects. A lone message is sent as
+/// it came. Other fields and the
+/// envelope come from the first message; attachments are kept from all.
+fn combine(mut messages: Vec<NotifyRequest>, clean_links: bool) -> NotifyRequest {
+    let count = messages.len();
+    if count == 1 {
+        let mut message = messages.remove(0);
+        message.digest = false;
+        return message;
+    }
+    let mut titles = Vec::with_capacity(count);
+    let mut sections = Vec::with_capacity(count);
+    let mut attachments = Vec::new();
+    let mut ttl_secs = None;
+    let mut first = None;
+    for mut message in messages {
+        // Each was built once when added, so its body decodes.
+        let body = decode_body(&message.body, message.body_encoding).unwrap_or_default();
+        let text = match (body.trim().is_empty(), &message.html) {
+            (true, Some(html)) => html_text::from_html(html, clean_links),
+            _ => body,
+        };
+        sections.push(format!("{}\n\n{}", message.title, text));
+        titles.push(std::mem::take(&mut message.title));
+        attachments.append(&mut message.attachments);
+        ttl_secs = match (ttl_secs, message.ttl_secs) {
+            (Some(shortest), Some(ttl)) => Some(ttl.min(shortest)),
+            (shortest, ttl) => shortest.or(ttl),
+        };
+        first.get_or_insert(message);
+    }
+
+    let mut combined = first.expect("a digest has a message");
+    combined.title = format!("{count} notifications: {}", titles.join("; "));
+    combined.body = sections.join(SEPARATOR);
+    combined.body_encoding = BodyEncoding::default();
+    combined.html = None;
+    combined.attachments = attachments;
+    // The parts changed, so the automatic choice fits them.
+    combined.multipart_type = None;
+    combined.digest = false;
+    combined.dedupe_window_secs = None;
+    // The shortest-lived message bounds the digest's expiry.
+    combined.ttl_secs = ttl_secs;
+    combined
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use axum::http::Method;
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::test_support::{self, notify};
+
+    fn digest_message(to: &str, title: &str, body: &str) -> Value {
+        json!({"service": "smtp", "to": to, "title": title, "body": body,
+            "digest": true, "digest_window_secs": 1})
+    }
+
+    fn request(title: &str, body: &str) -> NotifyRequest {
+        serde_json::from_value(digest_message("ops@example.com", title, body)).unwrap()
+    }
+
+    async fn wait_for_sent(app: &axum::Router, count: usize) -> Vec<Value> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let sent = test_support::sent(app).await;
+            if sent.len() >= count {
+                return sent;
+            }
+            assert!(Instant::now() < deadline, "only {} sent", sent.len());
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    #[test]
+    fn messages_are_combined_in_order() {
+        let combined = combine(
+            vec![
+                request("build started", "build 1234"),
+                request("tests passed", "all green"),
+                request("deployed", "live"),
+            ],
+            false,
+        );
+        assert_eq!(
+            combined.title,
+            "3 notifications: build started; tests passed; deployed"
+        );
+        assert_eq!(
+            combined.body,
+            "build started\n\nbuild 1234\n\n---\n\ntests passed\n\nall green\n\n---\n\ndeployed\n\nlive"
+        );
+        assert!(!combined.digest);
+    }
+
+    #[test]
+    fn a_lone_message_is_sent_as_it_came() {
+        let combined = combine(vec![request("deployed", "live")], false);
+        assert_eq!(combined.title, "deployed");
+        assert_eq!(combined.body, "live");
+        assert!(!combined.digest);
+    }
+
+    #[tokio::test]
+    async fn messages_within_the_window_go_out_as_one_email() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let mut job_ids = Vec::new();
+        for (n, (to, title)) in [
+            ("ops@example.com", "build started"),
+            ("OPS@example.com", "tests passed"),
+            ("ops@example.com", "deployed"),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let (status, body) = notify(&app, digest_message(to, title, "details")).await;
+            assert_eq!(status, StatusCode::ACCEPTED, "{body}");
+            assert_eq!(body["message"], "added to digest");
+            assert_eq!(body["messages"], n + 1);
+            job_ids.push(body["job_id"].clone());
+        }
+        let (status, other) = notify(&app, digest_message("dev@example.com", "other", "b")).await;
+        assert_eq!(status, StatusCode::ACCEPTED, "{other}");
+        assert!(job_ids.iter().all(|id| *id == job_ids[0]), "{job_ids:?}");
+        assert_ne!(other["job_id"], job_ids[0]);
+        assert!(
+            test_support::sent(&app).await.is_empty(),
+            "sent before the window closed"
+        );
+
+        let sent = wait_for_sent(&app, 2).await;
+        let digest = sent
+            .iter()
+            .find(|message| message["to"] == json!(["ops@example.com"]))
+            .expect("the digest was sent");
+        assert_eq!(
+            digest["subject"],
+            "3 notifications: build started; tests passed; deployed"
+        );
+        let uri = format!("/jobs/{}", job_ids[0].as_str().unwrap());
+        let (_, job) = test_support::call(&app, Method::GET, &uri, None).await;
+        assert_eq!(job["message"], "sent", "{job}");
+    }
+
+    #[tokio::test]
+    async fn the_window_is_validated() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        for window in [0, MAX_WINDOW_SECS + 1] {
+            let mut message = digest_message("ops@example.com", "t", "b");
+            message["digest_window_secs"] = json!(window);
+            let (status, body) = notify(&app, message).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{window}");
+            assert_eq!(body["message"], "digest_window_secs must be 1 to 86400");
+        }
+    }
+
+    #[test]
+    fn the_shortest_ttl_bounds_the_digest() {
+        let mut long = request("build started", "build 1234");
+        long.ttl_secs = Some(600);
+        let mut short = request("deployed", "live");
+        short.ttl_secs = Some(120);
+        let untimed = request("tests passed", "all green");
+        let combined = combine(vec![long, untimed, short], false);
+        assert_eq!(combined.ttl_secs, Some(120));
+
+        let combined = combine(vec![request("a", "b"), request("c", "d")], false);
+        assert_eq!(combined.ttl_secs, None);
+    }
+}
@@ -0,0 +1,223 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use arc_swap::ArcSwap;
+use lettre::{
+    message::dkim::{DkimConfig, DkimSigningAlgorithm, DkimSigningKey},
+    Message,
+};
+use tracing::{info, warn};
+
+use crate::AppState;
+
+/// DKIM signer whose key is re-read from `DKIM_PRIVATE_KEY_PATH` when the
+/// file changes, so keys can be rotated without a restart.
+pub struct Dkim {
+    path: PathBuf,
+    selector: String,
+    domain: String,
+    algorithm: DkimSigningAlgorithm,
+    config: ArcSwap<DkimConfig>,
+    /// Modification time of the key file the current config came from.
+    loaded_mtime: Mutex<Option<SystemTime>>,
+}
+
+impl Dkim {
+    pub fn load(
+        path: &Path,
+        selector: String,
+        domain: String,
+        algorithm: DkimSigningAlgorithm,
+    ) -> Result<Self> {
+        let mtime = modified(path);
+        let key = read_key(path, algorithm)?;
+        Ok(Self {
+            config: ArcSwap::from_pointee(DkimConfig::default_config(
+                selector.clone(),
+                domain.clone(),
+                key,
+            )),
+            path: path.to_path_buf(),
+            selector,
+            domain,
+            algorithm,
+            loaded_mtime: Mutex::new(mtime),
+        })
+    }
+
+    pub fn sign(&self, message: &mut Message) {
+        message.sign(&self.config.load());
+    }
+
+    /// Swaps in the key file's new contents if it changed since the last
+    /// load. A key that fails to parse is logged and the current one kept.
+    fn reload_if_changed(&self) {
+        let mtime = modified(&self.path);
+        let mut loaded_mtime = self.loaded_mtime.lock().expect("dkim lock poisoned");
+        if mtime.is_none() || mtime == *loaded_mtime {
+            return;
+        }
+
+        match read_key(&self.path, self.algorithm) {
+            Ok(key) => {
+                self.config.store(Arc::new(DkimConfig::default_config(
+                    self.selector.clone(),
+                    self.domain.clone(),
+                    key,
+                )));
+                info!(path = %self.path.display(), "dkim key reloaded");
+            }
+            Err(err) => {
+                warn!(
+                    path = %self.path.display(),
+                    error = %format!("{err:#}"),
+                    "rejected dkim key reload, keeping previous key"
+                );
+            }
+        }
+        // Either way this version of the file has been handled; a fixed key
+        // bumps the mtime again.
+        *loaded_mtime = mtime;
+    }
+}
+
+/// Polls the key file every `interval` for the life of the process.
+pub fn spawn_reloader(state: &Arc<AppState>, interval: Duration) {
+    if state.dkim.is_none() || interval.is_zero() {
+        return;
+    }
+    let state = state.clone();
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            if let Some(dkim) = &state.dkim {
+                // Waits out an admin reload instead of skipping a tick.
+                let _reloading = state.reloading.lock().await;
+                dkim.reload_if_changed();
+            }
+        }
+    });
+}
+
+fn read_key(path: &Path, algorithm: DkimSigningAlgorithm) -> Result<DkimSigningKey> {
+    let raw = fs::read_to_string(path)
+        .with_context(|| format!("failed to read dkim key {}", path.display()))?;
+    DkimSigningKey::new(raw.trim(), algorithm)
+        .with_context(|| format!("invalid {algorithm} dkim key {}", path.display()))
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{prelude::BASE64_STANDARD, Engine};
+
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn key(seed: u8) -> String {
+        BASE64_STANDARD.encode([seed; 32])
+    }
+
+    /// Replaces the key file and moves its mtime on, as a rotation would.
+    fn write_key(path: &Path, contents: &str, age: u64) {
+        fs::write(path, contents).unwrap();
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000 + age);
+        fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+    }
+
+    fn load(path: &Path) -> Dkim {
+        Dkim::load(
+            path,
+            "s1".to_string(),
+            "example.com".to_string(),
+            DkimSigningAlgorithm::Ed25519,
+        )
+        .unwrap()
+    }
+
+    fn message() -> Message {
+        Message::builder()
+            .from("notify@example.com".parse().unwrap())
+            .to("ops@example.com".parse().unwrap())
+            .subject("deploy")
+            .date(SystemTime::UNIX_EPOCH)
+            .body("build 1234 is live".to_string())
+            .unwrap()
+    }
+
+    fn signature(dkim: &Dkim) -> String {
+        let mut message = message();
+        dkim.sign(&mut message);
+        message
+            .headers()
+            .get_raw("DKIM-Signature")
+            .expect("signed")
+            .to_string()
+    }
+
+    /// The signatures of `dkims` made within one second, as the signature
+    /// carries a timestamp.
+    fn signatures<const N: usize>(dkims: [&Dkim; N]) -> [String; N] {
+        loop {
+            let second = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            let signed = dkims.map(signature);
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            if now == second {
+                return signed;
+            }
+        }
+    }
+
+    #[test]
+    fn rotated_key_signs_subsequent_messages() {
+        let dir = TempDir::new("dkim");
+        let path = dir.path().join("dkim.key");
+        write_key(&path, &key(1), 0);
+        let dkim = load(&path);
+        let old = load(&path);
+
+        write_key(&path, &key(2), 60);
+        dkim.reload_if_changed();
+        let new = load(&path);
+
+        let [rotated, new, old] = signatures([&dkim, &new, &old]);
+        assert_eq!(rotated, new);
+        assert_ne!(rotated, old);
+    }
+
+    #[test]
+    fn bad_key_reload_keeps_the_previous_key() {
+        let dir = TempDir::new("dkim");
+        let path = dir.path().join("dkim.key");
+        write_key(&path, &key(1), 0);
+        let dkim = load(&path);
+        let old = load(&path);
+
+        write_key(&path, "not a key", 60);
+        dkim.reload_if_changed();
+
+        let [kept, old] = signatures([&dkim, &old]);
+        assert_eq!(kept, old);
+    }
+}
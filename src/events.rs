@@ -0,0 +1,201 @@
+use std::{
+    convert::Infallible,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+};
+use futures::stream;
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{authenticate, error_response, AppState};
+
+/// One send outcome as published on `GET /events`.
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditEvent {
+    pub kind: &'static str,
+    pub service: &'static str,
+    pub recipient: String,
+    pub message: String,
+    /// Unix timestamp in seconds.
+    pub timestamp: u64,
+    /// `Message-ID` of the message sent, when it got that far.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<Box<str>>,
+}
+
+impl AuditEvent {
+    pub fn new(
+        kind: &'static str,
+        service: &'static str,
+        recipient: String,
+        message: String,
+    ) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_secs())
+            .unwrap_or_default();
+        Self {
+            kind,
+            service,
+            recipient,
+            message,
+            timestamp,
+            message_id: None,
+        }
+    }
+
+    pub fn with_message_id(mut self, message_id: Option<Box<str>>) -> Self {
+        self.message_id = message_id;
+        self
+    }
+}
+
+/// Fan-out of audit events to live subscribers. Publishing never blocks: a
+/// subscriber that falls behind the buffer loses the oldest events and is
+/// told how many it missed.
+pub struct EventBus {
+    tx: broadcast::Sender<AuditEvent>,
+}
+
+impl EventBus {
+    pub fn new(capacity: usize) -> Self {
+        let (tx, _) = broadcast::channel(capacity);
+        Self { tx }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<AuditEvent> {
+        self.tx.subscribe()
+    }
+
+    pub fn publish(&self, event: AuditEvent) {
+        // An error only means nobody is subscribed right now.
+        let _ = self.tx.send(event);
+    }
+}
+
+/// `GET /events`: Server-Sent Events feed of send outcomes.
+pub async fn events(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    if authenticate(&state, &headers).await.is_none() {
+        return error_response(StatusCode::UNAUTHORIZED, "invalid api key").into_response();
+    }
+
+    let rx = state.events.subscribe();
+    let stream = stream::unfold(rx, |mut rx| async move {
+        let event = match rx.recv().await {
+            Ok(event) => Event::default()
+                .event(event.kind)
+                .json_data(&event)
+                .unwrap_or_else(|_| Event::default().event("error")),
+            Err(RecvError::Lagged(skipped)) => Event::default()
+                .event("lagged")
+                .data(format!("{{\"skipped\":{skipped}}}")),
+            Err(RecvError::Closed) => return None,
+        };
+        Some((Ok::<_, Infallible>(event), rx))
+    });
+
+    Sse::new(stream)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::{
+        body::{Body, BodyDataStream},
+        http::Request,
+        Router,
+    };
+    use futures::StreamExt;
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::test_support::{self, notify};
+
+    async fn subscribe(app: &Router) -> BodyDataStream {
+        let request = Request::get("/events")
+            .header("authorization", format!("Bearer {}", test_support::API_KEY))
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        response.into_body().into_data_stream()
+    }
+
+    /// The next SSE frame, without the trailing blank line.
+    async fn next_frame(stream: &mut BodyDataStream) -> String {
+        let chunk = tokio::time::timeout(Duration::from_secs(5), stream.next())
+            .await
+            .expect("an event in time")
+            .expect("stream open")
+            .expect("readable body");
+        String::from_utf8(chunk.to_vec())
+            .unwrap()
+            .trim_end()
+            .to_string()
+    }
+
+    fn send(to: &str) -> serde_json::Value {
+        json!({"service": "smtp", "to": to, "title": "t", "body": "b"})
+    }
+
+    #[tokio::test]
+    async fn sends_are_streamed_to_subscribers() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let mut stream = subscribe(&app).await;
+
+        let (status, _) = notify(&app, send("ops@example.com")).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let frame = next_frame(&mut stream).await;
+        let (kind, data) = frame.split_once('\n').expect("event and data lines");
+        assert_eq!(kind, "event: sent");
+        let event: serde_json::Value =
+            serde_json::from_str(data.strip_prefix("data: ").unwrap()).unwrap();
+        assert_eq!(event["kind"], "sent");
+        assert_eq!(event["service"], "smtp");
+        assert_eq!(event["recipient"], "ops@example.com");
+        assert!(event["message_id"].is_string(), "{event}");
+    }
+
+    #[tokio::test]
+    async fn slow_subscribers_are_told_what_they_missed() {
+        let state = test_support::state(&[("EVENTS_BUFFER", "1")]).await;
+        let app = test_support::app(&state);
+        let mut stream = subscribe(&app).await;
+
+        for to in ["a@example.com", "b@example.com", "last@example.com"] {
+            assert_eq!(notify(&app, send(to)).await.0, StatusCode::OK);
+        }
+
+        assert_eq!(
+            next_frame(&mut stream).await,
+            "event: lagged\ndata: {\"skipped\":2}"
+        );
+        let frame = next_frame(&mut stream).await;
+        assert!(frame.starts_with("event: sent\n"), "{frame}");
+        assert!(frame.contains("last@example.com"), "{frame}");
+    }
+
+    #[tokio::test]
+    async fn events_need_an_api_key() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let response = app
+            .oneshot(Request::get("/events").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+}
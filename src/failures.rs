@@ -0,0 +1,297 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::Serialize;
+use tracing::info;
+
+use crate::{
+    attachments::AttachmentRequest, authenticate, dispatch, error_response, outcome_label,
+    ApiError, ApiResponse, AppState, NotifyRequest,
+};
+
+/// Attachment kept by name and size only; contents are not retained.
+#[derive(Debug, Clone, Serialize)]
+pub struct AttachmentSummary {
+    filename: String,
+    /// Decoded size in bytes.
+    size: usize,
+}
+
+impl From<AttachmentRequest> for AttachmentSummary {
+    fn from(attachment: AttachmentRequest) -> Self {
+        Self {
+            size: attachment.size(),
+            filename: attachment.filename,
+        }
+    }
+}
+
+/// A failed send with the request that produced it.
+#[derive(Debug, Clone, Serialize)]
+pub struct Failure {
+    id: u64,
+    /// Unix timestamp in seconds.
+    failed_at: u64,
+    error: String,
+    /// The original request, attachments removed.
+    request: NotifyRequest,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    attachments: Vec<AttachmentSummary>,
+    /// Only the submitting key may list or replay the failure.
+    #[serde(skip)]
+    key: String,
+}
+
+#[derive(Serialize)]
+pub struct FailuresResponse {
+    ok: bool,
+    message: String,
+    failures: Vec<Failure>,
+}
+
+/// The most recent failed sends (`FAILURE_LOG_SIZE`), kept so they can be
+/// inspected at `GET /admin/failures` and retried with
+/// `POST /admin/replay/{id}`.
+pub struct FailureLog {
+    capacity: usize,
+    entries: Mutex<(u64, VecDeque<Failure>)>,
+}
+
+impl FailureLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::default(),
+        }
+    }
+
+    /// Stores a failure, evicting the oldest when full, and returns its id.
+    pub fn record(&self, key: &str, mut request: NotifyRequest, error: String) -> u64 {
+        let attachments = request
+            .attachments
+            .drain(..)
+            .map(AttachmentSummary::from)
+            .collect();
+        let failed_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_secs())
+            .unwrap_or_default();
+
+        let mut entries = self.entries.lock().expect("failure log lock poisoned");
+        let (next_id, failures) = &mut *entries;
+        *next_id += 1;
+        if failures.len() == self.capacity {
+            failures.pop_front();
+        }
+        failures.push_back(Failure {
+            id: *next_id,
+            failed_at,
+            error,
+            request,
+            attachments,
+            key: key.to_string(),
+        });
+        *next_id
+    }
+
+    fn list(&self, key: &str) -> Vec<Failure> {
+        let entries = self.entries.lock().expect("failure log lock poisoned");
+        entries
+            .1
+            .iter()
+            .filter(|failure| failure.key == key)
+            .cloned()
+            .collect()
+    }
+
+    fn get(&self, key: &str, id: u64) -> Option<Failure> {
+        let entries = self.entries.lock().expect("failure log lock poisoned");
+        entries
+            .1
+            .iter()
+            .find(|failure| failure.id == id && failure.key == key)
+            .cloned()
+    }
+
+    fn remove(&self, id: u64) {
+        let mut entries = self.entries.lock().expect("failure log lock poisoned");
+        entries.1.retain(|failure| failure.id != id);
+    }
+}
+
+/// `GET /admin/failures`: the caller's recorded failures, oldest first.
+pub async fn list_failures(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<FailuresResponse>, ApiError> {
+    let Some(caller) = authenticate(&state, &headers).await else {
+        return Err(error_response(StatusCode::UNAUTHORIZED, "invalid api key").into());
+    };
+    let Some(failures) = &state.failures else {
+        return Err(error_response(StatusCode::NOT_FOUND, "failure log is disabled").into());
+    };
+
+    Ok(Json(FailuresResponse {
+        ok: true,
+        message: "ok".to_string(),
+        failures: failures.list(caller.key),
+    }))
+}
+
+/// `POST /admin/replay/{id}`: sends a recorded failure again and drops it
+/// from the log once it succeeds.
+pub async fn replay(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> (StatusCode, Json<ApiResponse>) {
+    let Some(caller) = authenticate(&state, &headers).await else {
+        return error_response(StatusCode::UNAUTHORIZED, "invalid api key");
+    };
+    let Some(failures) = &state.failures else {
+        return error_response(StatusCode::NOT_FOUND, "failure log is disabled");
+    };
+    let Some(failure) = failures.get(caller.key, id) else {
+        return error_response(StatusCode::NOT_FOUND, "failure not found");
+    };
+    if !failure.attachments.is_empty() {
+        return error_response(
+            StatusCode::CONFLICT,
+            "attachment contents were not kept, cannot replay",
+        );
+    }
+
+    info!(failure_id = id, "replaying failed notification");
+    let (status, body) = dispatch(&state, &caller, &headers, failure.request).await;
+    // A repeated failure has just been recorded again under a new id.
+    if status.is_success() || outcome_label(status) == "failed" {
+        failures.remove(id);
+    }
+    (status, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Method;
+    use base64::{prelude::BASE64_STANDARD, Engine};
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_support::{self, call, notify, MockSmtp};
+
+    #[tokio::test]
+    async fn failed_send_is_recorded_and_replayed() {
+        let smtp = MockSmtp::start().await;
+        smtp.reply("DATA", "554 5.3.0 transaction failed");
+        let state = smtp.state(&[("FAILURE_LOG_SIZE", "10")]).await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "disk full", "body": "/var is at 99%"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR, "{body}");
+
+        let (status, body) = call(&app, Method::GET, "/admin/failures", None).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let failures = body["failures"].as_array().expect("a list");
+        assert_eq!(failures.len(), 1, "{body}");
+        assert_eq!(failures[0]["error"], "smtp send failed");
+        assert_eq!(failures[0]["request"]["to"], "ops@example.com");
+        assert_eq!(failures[0]["request"]["title"], "disk full");
+        let id = failures[0]["id"].as_u64().expect("an id");
+
+        // The server has recovered: DATA is accepted again.
+        let (status, body) = call(&app, Method::POST, &format!("/admin/replay/{id}"), None).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["message"], "sent");
+        assert_eq!(smtp.messages().len(), 1);
+        assert!(smtp.messages()[0].contains("Subject: disk full"));
+
+        let (_, body) = call(&app, Method::GET, "/admin/failures", None).await;
+        assert_eq!(body["failures"], json!([]));
+        let (status, body) = call(&app, Method::POST, &format!("/admin/replay/{id}"), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["message"], "failure not found");
+    }
+
+    #[tokio::test]
+    async fn attachment_contents_are_not_kept() {
+        let smtp = MockSmtp::start().await;
+        smtp.reply("DATA", "554 5.3.0 transaction failed");
+        let state = smtp.state(&[("FAILURE_LOG_SIZE", "10")]).await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({
+                "service": "smtp",
+                "to": "ops@example.com",
+                "title": "report",
+                "body": "attached",
+                "attachments": [{"filename": "report.csv", "content": BASE64_STANDARD.encode("a,b\n1,2\n")}],
+            }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR, "{body}");
+
+        let (_, body) = call(&app, Method::GET, "/admin/failures", None).await;
+        let failure = &body["failures"][0];
+        assert_eq!(
+            failure["attachments"],
+            json!([{"filename": "report.csv", "size": 8}])
+        );
+        assert_eq!(failure["request"]["attachments"], json!([]));
+        let (status, body) = call(
+            &app,
+            Method::POST,
+            &format!("/admin/replay/{}", failure["id"]),
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(
+            body["message"],
+            "attachment contents were not kept, cannot replay"
+        );
+    }
+
+    #[test]
+    fn log_keeps_the_most_recent_failures_per_key() {
+        let log = FailureLog::new(2);
+        let request = |to: &str| -> NotifyRequest {
+            serde_json::from_value(json!({"service": "smtp", "to": to, "title": "t", "body": "b"}))
+                .unwrap()
+        };
+        let first = log.record("a", request("one@example.com"), "failed".to_string());
+        log.record("a", request("two@example.com"), "failed".to_string());
+        log.record("b", request("three@example.com"), "failed".to_string());
+
+        let kept: Vec<_> = log
+            .list("a")
+            .into_iter()
+            .map(|failure| failure.request.to)
+            .collect();
+        assert_eq!(kept, ["two@example.com"]);
+        assert!(log.get("a", first).is_none(), "evicted");
+        assert_eq!(log.list("b").len(), 1);
+        assert!(log.get("a", 3).is_none(), "another key's failure");
+    }
+
+    #[tokio::test]
+    async fn failure_log_is_disabled_by_default() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let (status, body) = call(&app, Method::GET, "/admin/failures", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["message"], "failure log is disabled");
+    }
+}
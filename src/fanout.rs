@@ -0,0 +1,238 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::{
+    authenticate, dispatch, error_response, field_error, ApiError, AppState, JsonBody,
+    NotificationService, NotifyRequest,
+};
+
+#[derive(Serialize)]
+pub struct MultiResponse {
+    ok: bool,
+    message: String,
+    results: Vec<ChannelResult>,
+}
+
+#[derive(Serialize)]
+struct ChannelResult {
+    channel: String,
+    ok: bool,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    provider_message_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    provider_status: Option<String>,
+}
+
+/// `POST /notify-multi`: sends one notification through several channels at
+/// once. The body holds the `/notify` fields shared by every channel plus
+/// `channels` and optional per-channel `channel_overrides`; a failing channel
+/// does not stop the others.
+pub async fn notify_multi(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    JsonBody(body): JsonBody<Map<String, Value>>,
+) -> Result<Json<MultiResponse>, ApiError> {
+    let Some(caller) = authenticate(&state, &headers).await else {
+        return Err(error_response(StatusCode::UNAUTHORIZED, "invalid api key").into());
+    };
+
+    let mut shared = body;
+    let channels: Vec<String> = match shared.remove("channels") {
+        Some(channels) => serde_json::from_value(channels)
+            .map_err(|_| field_error("channels", "channels must be a list of channel names"))?,
+        None => return Err(field_error("channels", "channels cannot be empty").into()),
+    };
+    if channels.is_empty() {
+        return Err(field_error("channels", "channels cannot be empty").into());
+    }
+    if channels
+        .iter()
+        .enumerate()
+        .any(|(i, channel)| channels[..i].contains(channel))
+    {
+        return Err(field_error("channels", "channels cannot repeat").into());
+    }
+    let mut overrides: Map<String, Value> = match shared.remove("channel_overrides") {
+        Some(overrides) => serde_json::from_value(overrides).map_err(|_| {
+            field_error(
+                "channel_overrides",
+                "channel_overrides must map channel names to objects",
+            )
+        })?,
+        None => Map::new(),
+    };
+    shared.remove("service");
+
+    let (state, caller, headers) = (&state, &caller, &headers);
+    let sends = channels.into_iter().map(|channel| {
+        let overrides = overrides.remove(&channel);
+        let shared = shared.clone();
+        async move {
+            let (ok, message, provider) = match channel_request(&channel, shared, overrides) {
+                Ok(req) => {
+                    let (status, Json(body)) = dispatch(state, caller, headers, req).await;
+                    (status.is_success(), body.message, body.provider)
+                }
+                Err(message) => (false, message, None),
+            };
+            let (provider_message_id, provider_status) = provider
+                .map(|provider| (provider.provider_message_id, provider.provider_status))
+                .unwrap_or_default();
+            ChannelResult {
+                channel,
+                ok,
+                message,
+                provider_message_id,
+                provider_status,
+            }
+        }
+    });
+    let results = futures::future::join_all(sends).await;
+
+    let all_ok = results.iter().all(|result| result.ok);
+    let message = if all_ok {
+        "sent"
+    } else if results.iter().any(|result| result.ok) {
+        "partially failed"
+    } else {
+        "failed"
+    };
+    Ok(Json(MultiResponse {
+        ok: all_ok,
+        message: message.to_string(),
+        results,
+    }))
+}
+
+/// The `/notify` request for one channel: the shared fields with the
+/// channel's overrides applied on top.
+fn channel_request(
+    channel: &str,
+    mut fields: Map<String, Value>,
+    overrides: Option<Value>,
+) -> Result<NotifyRequest, String> {
+    let service: NotificationService = serde_json::from_value(Value::from(channel))
+        .map_err(|_| format!("unsupported channel: {channel}"))?;
+    match overrides {
+        Some(Value::Object(overrides)) => fields.extend(overrides),
+        Some(_) => return Err(format!("overrides for {channel} must be an object")),
+        None => {}
+    }
+    fields.insert("service".to_string(), Value::from(service.name()));
+    serde_json::from_value(Value::Object(fields))
+        .map_err(|err| format!("invalid request body: {err}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Method;
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_support::{self, call, MockSlack};
+
+    #[tokio::test]
+    async fn failing_channel_does_not_stop_the_others() {
+        let slack = MockSlack::start(json!({"ok": false, "error": "channel_not_found"})).await;
+        let state = test_support::state(&slack.vars()).await;
+        let app = test_support::app(&state);
+
+        let (status, body) = call(
+            &app,
+            Method::POST,
+            "/notify-multi",
+            Some(json!({
+                "channels": ["email", "slack"],
+                "to": "ops@example.com",
+                "title": "disk full",
+                "body": "/var is at 99%",
+                "channel_overrides": {"slack": {"to": "#nowhere"}},
+            })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["ok"], false);
+        assert_eq!(body["message"], "partially failed");
+        let results = body["results"].as_array().expect("per-channel results");
+        assert_eq!(results.len(), 2, "{body}");
+        assert_eq!(results[0]["channel"], "email");
+        assert_eq!(results[0]["ok"], true);
+        assert_eq!(results[0]["message"], "sent");
+        assert_eq!(results[1]["channel"], "slack");
+        assert_eq!(results[1]["ok"], false);
+        assert_eq!(results[1]["provider_status"], "channel_not_found");
+
+        assert_eq!(test_support::sent(&app).await.len(), 1);
+        let posts = slack.posts();
+        assert_eq!(posts.len(), 1);
+        assert_eq!(posts[0]["channel"], "#nowhere");
+    }
+
+    #[tokio::test]
+    async fn every_channel_succeeding_is_sent() {
+        let slack = MockSlack::start(json!({"ok": true, "ts": "1700000000.000100"})).await;
+        let state = test_support::state(&slack.vars()).await;
+        let app = test_support::app(&state);
+
+        let (status, body) = call(
+            &app,
+            Method::POST,
+            "/notify-multi",
+            Some(json!({
+                "channels": ["smtp", "slack"],
+                "to": "ops@example.com",
+                "title": "deploy",
+                "body": "build 1234 is live",
+                "channel_overrides": {"slack": {"to": "#deploys"}},
+            })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["ok"], true);
+        assert_eq!(body["message"], "sent");
+        assert_eq!(
+            body["results"][1]["provider_message_id"],
+            "1700000000.000100"
+        );
+    }
+
+    #[tokio::test]
+    async fn channel_list_is_validated() {
+        let state = test_support::state(&[]).await;
+        let app = test_support::app(&state);
+
+        for (channels, message) in [
+            (json!([]), "channels cannot be empty"),
+            (json!("email"), "channels must be a list of channel names"),
+            (json!(["smtp", "smtp"]), "channels cannot repeat"),
+        ] {
+            let (status, body) = call(
+                &app,
+                Method::POST,
+                "/notify-multi",
+                Some(json!({"channels": channels, "to": "ops@example.com", "title": "t", "body": "b"})),
+            )
+            .await;
+            assert!(status.is_client_error(), "{status}: {body}");
+            assert_eq!(body["message"], message);
+        }
+
+        let (status, body) = call(
+            &app,
+            Method::POST,
+            "/notify-multi",
+            Some(json!({"channels": ["smtp", "pager"], "to": "ops@example.com", "title": "t", "body": "b"})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["results"][1]["message"], "unsupported channel: pager");
+    }
+}
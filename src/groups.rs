@@ -0,0 +1,167 @@
+use std::{collections::HashMap, fmt, fs, path::Path};
+
+use anyhow::{Context, Result};
+
+/// Prefix marking a recipient entry as a distribution group reference.
+pub const GROUP_PREFIX: &str = "group:";
+
+/// Named recipient lists loaded from `GROUPS_FILE`.
+///
+/// Members are plain addresses or `group:<name>` references to other groups.
+/// References are checked once at load time, so expansion never sees an
+/// unknown nested group or a cycle.
+#[derive(Debug, Clone, Default)]
+pub struct Groups {
+    groups: HashMap<String, Vec<String>>,
+}
+
+#[derive(Debug)]
+pub enum GroupError {
+    Unknown(String),
+}
+
+impl fmt::Display for GroupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GroupError::Unknown(name) => write!(f, "unknown group: {name}"),
+        }
+    }
+}
+
+impl Groups {
+    pub fn load(path: &Path) -> Result<Self> {
+        let raw = fs::read_to_string(path)
+            .with_context(|| format!("failed to read groups file {}", path.display()))?;
+        let groups: HashMap<String, Vec<String>> = serde_json::from_str(&raw)
+            .with_context(|| format!("invalid groups file {}", path.display()))?;
+
+        let groups = Self { groups };
+        for name in groups.groups.keys() {
+            groups.check(name, &mut Vec::new())?;
+        }
+        Ok(groups)
+    }
+
+    /// Depth-first walk from `name` rejecting unknown references and cycles.
+    fn check(&self, name: &str, path: &mut Vec<String>) -> Result<()> {
+        if path.iter().any(|seen| seen == name) {
+            path.push(name.to_string());
+            anyhow::bail!("group cycle detected: {}", path.join(" -> "));
+        }
+        let members = self.groups.get(name).with_context(|| {
+            format!(
+                "group `{}` references unknown group `{name}`",
+                path.join(" -> ")
+            )
+        })?;
+
+        path.push(name.to_string());
+        for nested in members
+            .iter()
+            .filter_map(|m| m.trim().strip_prefix(GROUP_PREFIX))
+        {
+            self.check(nested.trim(), path)?;
+        }
+        path.pop();
+        Ok(())
+    }
+
+    /// Replaces every `group:<name>` entry with the group's member addresses.
+    pub fn expand(&self, entries: Vec<String>) -> Result<Vec<String>, GroupError> {
+        let mut expanded = Vec::with_capacity(entries.len());
+        for entry in entries {
+            match entry.strip_prefix(GROUP_PREFIX) {
+                Some(name) => self.expand_group(name.trim(), &mut expanded)?,
+                None => expanded.push(entry),
+            }
+        }
+        Ok(expanded)
+    }
+
+    fn expand_group(&self, name: &str, out: &mut Vec<String>) -> Result<(), GroupError> {
+        let members = self
+            .groups
+            .get(name)
+            .ok_or_else(|| GroupError::Unknown(name.to_string()))?;
+
+        for member in members {
+            let member = member.trim();
+            match member.strip_prefix(GROUP_PREFIX) {
+                Some(nested) => self.expand_group(nested.trim(), out)?,
+                None => out.push(member.to_string()),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::TempDir;
+
+    use super::*;
+
+    fn load(json: &str) -> Result<Groups> {
+        let dir = TempDir::new("groups");
+        let path = dir.path().join("groups.json");
+        fs::write(&path, json).unwrap();
+        Groups::load(&path)
+    }
+
+    fn entries(raw: &[&str]) -> Vec<String> {
+        raw.iter().map(|entry| entry.to_string()).collect()
+    }
+
+    #[test]
+    fn nested_groups_expand_in_place() {
+        let groups = load(
+            r#"{
+                "oncall-db": ["a@example.com", "group:dba"],
+                "dba": ["b@example.com", " c@example.com "]
+            }"#,
+        )
+        .unwrap();
+        let expanded = groups
+            .expand(entries(&["ops@example.com", "group:oncall-db"]))
+            .unwrap();
+        assert_eq!(
+            expanded,
+            [
+                "ops@example.com",
+                "a@example.com",
+                "b@example.com",
+                "c@example.com"
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_group_is_an_error() {
+        let groups = load(r#"{"dba": ["b@example.com"]}"#).unwrap();
+        let err = groups.expand(entries(&["group:nope"])).unwrap_err();
+        assert_eq!(err.to_string(), "unknown group: nope");
+    }
+
+    #[test]
+    fn cycles_are_refused_at_load() {
+        let err = load(r#"{"a": ["group:b"], "b": ["x@example.com", "group:a"]}"#).unwrap_err();
+        let message = err.to_string();
+        assert!(message.starts_with("group cycle detected: "), "{message}");
+        assert!(
+            message.contains("a -> b -> a") || message.contains("b -> a -> b"),
+            "{message}"
+        );
+
+        let err = load(r#"{"a": ["group:a"]}"#).unwrap_err();
+        assert_eq!(err.to_string(), "group cycle detected: a -> a");
+    }
+
+    #[test]
+    fn unknown_nested_group_is_refused_at_load() {
+        let err = load(r#"{"a": ["group:missing"]}"#).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "group `a` references unknown group `missing`"
+        );
+    }
+}
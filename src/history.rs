@@ -0,0 +1,511 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, ErrorKind, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+use axum::{
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use lettre::message::Mailbox;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{authenticate, error_response, spool, ApiError, ApiResponse, AppState};
+
+/// A send as written to `HISTORY_FILE`, one JSON object per line.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SendRecord {
+    id: u64,
+    /// Unix timestamp in seconds.
+    at: u64,
+    /// Only the sending tenant may list the row.
+    tenant: String,
+    service: String,
+    /// `to` as sent: an address, a list of them or a channel.
+    to: String,
+    /// `sent` or `failed`.
+    outcome: String,
+    message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    message_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    client_reference: Option<String>,
+}
+
+/// A row as listed, without the tenant.
+#[derive(Serialize)]
+struct SendRow {
+    id: u64,
+    at: u64,
+    service: String,
+    to: String,
+    outcome: String,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_reference: Option<String>,
+}
+
+/// Sends and failures to one recipient, for `group_by=recipient`.
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+struct RecipientCounts {
+    recipient: String,
+    sent: u64,
+    failed: u64,
+}
+
+#[derive(Serialize)]
+pub struct HistoryResponse {
+    ok: bool,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rows: Option<Vec<SendRow>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recipients: Option<Vec<RecipientCounts>>,
+}
+
+#[derive(Deserialize)]
+pub struct HistoryQuery {
+    /// Unix timestamp in seconds of the oldest send to include.
+    since: Option<u64>,
+    /// Unix timestamp in seconds of the newest send to include.
+    until: Option<u64>,
+    group_by: Option<String>,
+    /// Only the sends made with this `client_reference`.
+    client_reference: Option<String>,
+}
+
+/// Every send that went out or failed at the provider, appended to
+/// `HISTORY_FILE` and read back on start so the log survives restarts.
+/// Refused requests are not logged. The newest `HISTORY_MAX_ROWS` rows are
+/// kept; the file is rewritten with just those once it holds twice as many.
+pub struct SendLog {
+    path: PathBuf,
+    max_rows: usize,
+    inner: Mutex<LogState>,
+}
+
+struct LogState {
+    next_id: u64,
+    rows: VecDeque<SendRecord>,
+    /// Lines in the file, kept and dropped alike.
+    lines: usize,
+}
+
+impl SendLog {
+    /// Reads the rows already in `path`, skipping unreadable lines with a
+    /// warning, and rewrites it with the ones kept.
+    pub fn open(path: PathBuf, max_rows: usize) -> Result<Self> {
+        let mut rows = VecDeque::new();
+        match File::open(&path) {
+            Ok(file) => {
+                for (n, line) in BufReader::new(file).lines().enumerate() {
+                    let line =
+                        line.with_context(|| format!("failed to read {}", path.display()))?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    match serde_json::from_str::<SendRecord>(&line) {
+                        Ok(record) => {
+                            if rows.len() == max_rows {
+                                rows.pop_front();
+                            }
+                            rows.push_back(record);
+                        }
+                        Err(err) => {
+                            warn!(path = %path.display(), line = n + 1, error = %err, "skipping unreadable history row")
+                        }
+                    }
+                }
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to read {}", path.display()))
+            }
+        }
+        let log = Self {
+            path,
+            max_rows,
+            inner: Mutex::new(LogState {
+                next_id: rows.back().map_or(0, |record| record.id),
+                lines: rows.len(),
+                rows,
+            }),
+        };
+        log.compact(&log.inner.lock().expect("send log lock poisoned"))?;
+        Ok(log)
+    }
+
+    /// Logs one send and the response it got. A row that cannot be written
+    /// is only kept in memory.
+    pub fn record(
+        &self,
+        tenant: &str,
+        service: &str,
+        to: &str,
+        outcome: &str,
+        response: &ApiResponse,
+    ) {
+        let mut inner = self.inner.lock().expect("send log lock poisoned");
+        inner.next_id += 1;
+        let record = SendRecord {
+            id: inner.next_id,
+            at: spool::to_unix(SystemTime::now()),
+            tenant: tenant.to_string(),
+            service: service.to_string(),
+            to: to.to_string(),
+            outcome: outcome.to_string(),
+            message: response.message.clone(),
+            message_id: response.message_id.as_deref().map(str::to_string),
+            client_reference: response.client_reference.clone(),
+        };
+        if let Err(err) = self.append(&record) {
+            warn!(path = %self.path.display(), error = %format!("{err:#}"), "failed to write history row");
+        }
+        if inner.rows.len() == self.max_rows {
+            inner.rows.pop_front();
+        }
+        inner.rows.push_back(record);
+        inner.lines += 1;
+        if inner.lines >= self.max_rows.saturating_mul(2) {
+            match self.compact(&inner) {
+                Ok(()) => inner.lines = inner.rows.len(),
+                Err(err) => {
+                    warn!(path = %self.path.display(), error = %format!("{err:#}"), "failed to compact history")
+                }
+            }
+        }
+    }
+
+    fn append(&self, record: &SendRecord) -> Result<()> {
+        let mut line = serde_json::to_vec(record).context("failed to serialize history row")?;
+        line.push(b'\n');
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(&line))
+            .with_context(|| format!("failed to write {}", self.path.display()))
+    }
+
+    /// Rewrites the file atomically with the rows kept.
+    fn compact(&self, inner: &LogState) -> Result<()> {
+        let mut raw = Vec::new();
+        for record in &inner.rows {
+            serde_json::to_writer(&mut raw, record).context("failed to serialize history row")?;
+            raw.push(b'\n');
+        }
+        let partial = self.path.with_extension("partial");
+        fs::write(&partial, raw)
+            .with_context(|| format!("failed to write {}", partial.display()))?;
+        fs::rename(&partial, &self.path)
+            .with_context(|| format!("failed to write {}", self.path.display()))
+    }
+
+    /// The tenant's rows in `[since, until]`, oldest first.
+    fn rows(&self, tenant: &str, since: u64, until: u64) -> Vec<SendRecord> {
+        let inner = self.inner.lock().expect("send log lock poisoned");
+        inner
+            .rows
+            .iter()
+            .filter(|record| record.tenant == tenant && (since..=until).contains(&record.at))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Per-recipient counts of `rows`, busiest recipient first. Each address
+/// of a message sent to several is counted on its own.
+fn group_by_recipient(rows: &[SendRecord]) -> Vec<RecipientCounts> {
+    let mut counts: BTreeMap<String, RecipientCounts> = BTreeMap::new();
+    for record in rows {
+        for recipient in record.to.split(',').map(recipient_key) {
+            if recipient.is_empty() {
+                continue;
+            }
+            let entry = counts
+                .entry(recipient.clone())
+                .or_insert_with(|| RecipientCounts {
+                    recipient,
+                    ..RecipientCounts::default()
+                });
+            match record.outcome.as_str() {
+                "sent" => entry.sent += 1,
+                _ => entry.failed += 1,
+            }
+        }
+    }
+    let mut counts: Vec<_> = counts.into_values().collect();
+    // Stable, so equal totals stay in address order.
+    counts.sort_by_key(|counts| std::cmp::Reverse(counts.sent + counts.failed));
+    counts
+}
+
+/// The address of a `Name <address>` entry, lowercased; anything else, such
+/// as a Slack channel, as written.
+fn recipient_key(entry: &str) -> String {
+    let entry = entry.trim();
+    match entry.parse::<Mailbox>() {
+        Ok(mailbox) => mailbox.email.to_string().to_ascii_lowercase(),
+        Err(_) => entry.to_string(),
+    }
+}
+
+/// `GET /history`: the caller's sends, or with `group_by=recipient` their
+/// counts per recipient, optionally within `since`/`until`.
+pub async fn history(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Json<HistoryResponse>, ApiError> {
+    let Some(caller) = authenticate(&state, &headers).await else {
+        return Err(error_response(StatusCode::UNAUTHORIZED, "invalid api key").into());
+    };
+    let Some(log) = &state.history else {
+        return Err(error_response(StatusCode::NOT_FOUND, "send history is disabled").into());
+    };
+    let grouped = match query.group_by.as_deref() {
+        None => false,
+        Some("recipient") => true,
+        Some(other) => {
+            return Err(error_response(
+                StatusCode::BAD_REQUEST,
+                &format!("unsupported group_by: {other}"),
+            )
+            .into())
+        }
+    };
+
+    let mut rows = log.rows(
+        &caller.policy.tenant,
+        query.since.unwrap_or(0),
+        query.until.unwrap_or(u64::MAX),
+    );
+    if let Some(reference) = &query.client_reference {
+        rows.retain(|record| record.client_reference.as_ref() == Some(reference));
+    }
+    let (rows, recipients) = match grouped {
+        true => (None, Some(group_by_recipient(&rows))),
+        false => (Some(rows.into_iter().map(SendRow::from).collect()), None),
+    };
+    Ok(Json(HistoryResponse {
+        ok: true,
+        message: "ok".to_string(),
+        rows,
+        recipients,
+    }))
+}
+
+impl From<SendRecord> for SendRow {
+    fn from(record: SendRecord) -> Self {
+        Self {
+            id: record.id,
+            at: record.at,
+            service: record.service,
+            to: record.to,
+            outcome: record.outcome,
+            message: record.message,
+            message_id: record.message_id,
+            client_reference: record.client_reference,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Method;
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::test_support::{self, call, notify, MockSmtp, TempDir};
+
+    fn history_file(dir: &TempDir) -> String {
+        dir.path()
+            .join("history.jsonl")
+            .to_str()
+            .expect("utf-8 path")
+            .to_string()
+    }
+
+    #[test]
+    fn rows_are_grouped_per_recipient() {
+        let dir = TempDir::new("history");
+        let log = SendLog::open(history_file(&dir).into(), 100).expect("log opens");
+        for (to, outcome) in [
+            ("a@example.com", "sent"),
+            ("Ops <A@Example.com>, b@example.com", "sent"),
+            ("b@example.com", "failed"),
+            ("a@example.com", "failed"),
+            ("#ops", "sent"),
+        ] {
+            log.record("acme", "smtp", to, outcome, &ApiResponse::ok("m"));
+        }
+        log.record(
+            "other",
+            "smtp",
+            "a@example.com",
+            "sent",
+            &ApiResponse::ok("m"),
+        );
+
+        let counts = group_by_recipient(&log.rows("acme", 0, u64::MAX));
+        let counts: Vec<_> = counts
+            .iter()
+            .map(|counts| (counts.recipient.as_str(), counts.sent, counts.failed))
+            .collect();
+        assert_eq!(
+            counts,
+            [
+                ("a@example.com", 2, 1),
+                ("b@example.com", 1, 1),
+                ("#ops", 1, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn rows_survive_a_restart_and_the_file_stays_bounded() {
+        let dir = TempDir::new("history");
+        let path = PathBuf::from(history_file(&dir));
+        let log = SendLog::open(path.clone(), 2).expect("log opens");
+        for n in 1..=5 {
+            log.record(
+                "acme",
+                "smtp",
+                &format!("{n}@example.com"),
+                "sent",
+                &ApiResponse::ok("sent"),
+            );
+        }
+        drop(log);
+        let lines = fs::read_to_string(&path).unwrap().lines().count();
+        assert!(lines < 4, "compacted to the rows kept: {lines}");
+
+        let log = SendLog::open(path, 2).expect("log reopens");
+        let rows: Vec<_> = log
+            .rows("acme", 0, u64::MAX)
+            .into_iter()
+            .map(|record| (record.id, record.to))
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                (4, "4@example.com".to_string()),
+                (5, "5@example.com".to_string())
+            ]
+        );
+        log.record(
+            "acme",
+            "smtp",
+            "6@example.com",
+            "sent",
+            &ApiResponse::ok("sent"),
+        );
+        assert_eq!(log.rows("acme", 0, u64::MAX).last().unwrap().id, 6);
+    }
+
+    async fn history(app: &axum::Router, query: &str) -> (StatusCode, Value) {
+        call(app, Method::GET, &format!("/history{query}"), None).await
+    }
+
+    #[tokio::test]
+    async fn history_lists_and_groups_the_callers_sends() {
+        let dir = TempDir::new("history");
+        let smtp = MockSmtp::start().await;
+        let app = test_support::app(&smtp.state(&[("HISTORY_FILE", &history_file(&dir))]).await);
+        let send = |to: &'static str| {
+            notify(
+                &app,
+                json!({"service": "smtp", "to": to, "title": "t", "body": "b"}),
+            )
+        };
+
+        assert_eq!(send("a@example.com").await.0, StatusCode::OK);
+        assert_eq!(send("a@example.com").await.0, StatusCode::OK);
+        smtp.reply("DATA", "554 5.3.0 transaction failed");
+        assert_eq!(
+            send("b@example.com").await.0,
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        // Refused before sending, so not logged.
+        assert_eq!(send("not an address").await.0, StatusCode::BAD_REQUEST);
+
+        let (status, body) = history(&app, "").await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let rows = body["rows"].as_array().expect("rows");
+        assert_eq!(rows.len(), 3, "{body}");
+        assert_eq!(rows[0]["to"], "a@example.com");
+        assert_eq!(rows[0]["outcome"], "sent");
+        assert!(rows[0]["message_id"].is_string(), "{body}");
+        assert_eq!(rows[2]["outcome"], "failed");
+        assert_eq!(rows[2]["message"], "smtp send failed");
+        assert!(rows[0].get("tenant").is_none(), "{body}");
+
+        let (status, body) = history(&app, "?group_by=recipient").await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(
+            body["recipients"],
+            json!([
+                {"recipient": "a@example.com", "sent": 2, "failed": 0},
+                {"recipient": "b@example.com", "sent": 0, "failed": 1}
+            ])
+        );
+        let (_, body) = history(&app, "?group_by=recipient&until=1").await;
+        assert_eq!(body["recipients"], json!([]));
+
+        let (status, body) = history(&app, "?group_by=sender").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["message"], "unsupported group_by: sender");
+    }
+
+    #[tokio::test]
+    async fn history_is_disabled_by_default() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let (status, body) = history(&app, "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["message"], "send history is disabled");
+    }
+
+    #[tokio::test]
+    async fn client_references_are_echoed_stored_and_queryable() {
+        let dir = TempDir::new("history");
+        let path = history_file(&dir);
+        let app = test_support::app(&test_support::state(&[("HISTORY_FILE", &path)]).await);
+        for reference in [Some("order-42"), Some("order-43"), None] {
+            let (status, body) = notify(
+                &app,
+                json!({"service": "smtp", "to": "a@example.com", "title": "t", "body": "b",
+                    "client_reference": reference}),
+            )
+            .await;
+            assert_eq!(status, StatusCode::OK, "{body}");
+            assert_eq!(
+                body.get("client_reference").and_then(Value::as_str),
+                reference
+            );
+        }
+        let (status, body) = history(&app, "?client_reference=order-42").await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let rows = body["rows"].as_array().expect("rows");
+        assert_eq!(rows.len(), 1, "{body}");
+        assert_eq!(rows[0]["client_reference"], "order-42");
+        assert!(rows[0]["message_id"].is_string(), "{body}");
+
+        // Read back from the file after a restart.
+        let app = test_support::app(&test_support::state(&[("HISTORY_FILE", &path)]).await);
+        let (_, body) = history(&app, "?client_reference=order-43").await;
+        assert_eq!(body["rows"].as_array().map(Vec::len), Some(1), "{body}");
+        let (_, body) = history(&app, "?client_reference=unknown").await;
+        assert_eq!(body["rows"], json!([]));
+        let (_, body) = history(&app, "").await;
+        assert_eq!(body["rows"].as_array().map(Vec::len), Some(3), "{body}");
+    }
+}
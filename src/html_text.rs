@@ -0,0 +1,221 @@
+use html2text::render::{TaggedLine, TextDecorator};
+
+/// Column at which the generated text part wraps.
+const WRAP_WIDTH: usize = 78;
+
+/// Query parameters that only identify a campaign or click; `utm_` is
+/// matched as a prefix.
+const TRACKING_PARAMS: &[&str] = &[
+    "fbclid", "gclid", "dclid", "msclkid", "yclid", "igshid", "mc_cid", "mc_eid", "_hsenc",
+    "_hsmi", "mkt_tok",
+];
+
+/// Renders an HTML body as the plain-text alternative sent alongside it.
+/// Links keep their target inline as `text (url)`; with `clean_links`,
+/// without their tracking parameters.
+pub fn from_html(html: &str, clean_links: bool) -> String {
+    let decorator = InlineLinks {
+        clean_links,
+        ..InlineLinks::default()
+    };
+    html2text::config::with_decorator(decorator)
+        .link_footnotes(false)
+        .no_link_wrapping()
+        .allow_width_overflow()
+        .string_from_read(html.as_bytes(), WRAP_WIDTH)
+        // Only reachable for widths too narrow to lay out, which the overflow
+        // setting rules out; fall back to the markup rather than fail a send.
+        .unwrap_or_else(|_| html.to_string())
+}
+
+/// Plain-text decorator that appends each link's URL after its text instead
+/// of numbering footnotes.
+#[derive(Clone, Default)]
+struct InlineLinks {
+    open_links: Vec<String>,
+    clean_links: bool,
+}
+
+impl TextDecorator for InlineLinks {
+    type Annotation = ();
+
+    fn decorate_link_start(&mut self, url: &str) -> (String, Self::Annotation) {
+        self.open_links.push(url.to_string());
+        (String::new(), ())
+    }
+
+    fn decorate_link_end(&mut self) -> String {
+        match self.open_links.pop() {
+            Some(url) if !url.is_empty() && !url.starts_with('#') => match self.clean_links {
+                true => format!(" ({})", without_tracking_params(&url)),
+                false => format!(" ({url})"),
+            },
+            _ => String::new(),
+        }
+    }
+
+    fn decorate_em_start(&self) -> (String, Self::Annotation) {
+        (String::new(), ())
+    }
+
+    fn decorate_em_end(&self) -> String {
+        String::new()
+    }
+
+    fn decorate_strong_start(&self) -> (String, Self::Annotation) {
+        (String::new(), ())
+    }
+
+    fn decorate_strong_end(&self) -> String {
+        String::new()
+    }
+
+    fn decorate_strikeout_start(&self) -> (String, Self::Annotation) {
+        (String::new(), ())
+    }
+
+    fn decorate_strikeout_end(&self) -> String {
+        String::new()
+    }
+
+    fn decorate_code_start(&self) -> (String, Self::Annotation) {
+        (String::new(), ())
+    }
+
+    fn decorate_code_end(&self) -> String {
+        String::new()
+    }
+
+    fn decorate_preformat_first(&self) -> Self::Annotation {}
+
+    fn decorate_preformat_cont(&self) -> Self::Annotation {}
+
+    fn decorate_image(&mut self, _src: &str, title: &str) -> (String, Self::Annotation) {
+        (title.to_string(), ())
+    }
+
+    fn header_prefix(&self, _level: usize) -> String {
+        String::new()
+    }
+
+    fn quote_prefix(&self) -> String {
+        "> ".to_string()
+    }
+
+    fn unordered_item_prefix(&self) -> String {
+        "- ".to_string()
+    }
+
+    fn ordered_item_prefix(&self, i: i64) -> String {
+        format!("{i}. ")
+    }
+
+    fn make_subblock_decorator(&self) -> Self {
+        Self {
+            clean_links: self.clean_links,
+            ..Self::default()
+        }
+    }
+
+    fn finalise(&mut self, _urls: Vec<String>) -> Vec<TaggedLine<Self::Annotation>> {
+        Vec::new()
+    }
+}
+
+/// `url` with its [`TRACKING_PARAMS`] and `utm_*` query parameters removed,
+/// the rest kept in order; the `?` goes too when nothing is left.
+fn without_tracking_params(url: &str) -> String {
+    let (url, fragment) = match url.split_once('#') {
+        Some((url, fragment)) => (url, Some(fragment)),
+        None => (url, None),
+    };
+    let Some((base, query)) = url.split_once('?') else {
+        return match fragment {
+            Some(fragment) => format!("{url}#{fragment}"),
+            None => url.to_string(),
+        };
+    };
+    let kept: Vec<&str> = query
+        .split('&')
+        .filter(|param| {
+            let key = param.split_once('=').map_or(*param, |(key, _)| key);
+            let key = key.to_ascii_lowercase();
+            !key.is_empty() && !key.starts_with("utm_") && !TRACKING_PARAMS.contains(&key.as_str())
+        })
+        .collect();
+    let mut cleaned = base.to_string();
+    if !kept.is_empty() {
+        cleaned.push('?');
+        cleaned.push_str(&kept.join("&"));
+    }
+    if let Some(fragment) = fragment {
+        cleaned.push('#');
+        cleaned.push_str(fragment);
+    }
+    cleaned
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn links_are_kept_inline_and_tags_dropped() {
+        let text = from_html(
+            r#"<h1>Deploy finished</h1>
+<p>Build <strong>1234</strong> is live. <a href="https://ci.example.com/1234">See the logs</a>.</p>
+<ul><li>api</li><li>web</li></ul>"#,
+            false,
+        );
+        assert!(!text.contains('<'), "{text}");
+        assert!(text.contains("Deploy finished"), "{text}");
+        assert!(text.contains("Build 1234 is live."), "{text}");
+        assert!(
+            text.contains("See the logs (https://ci.example.com/1234)"),
+            "{text}"
+        );
+        assert!(text.contains("api") && text.contains("web"), "{text}");
+    }
+
+    #[test]
+    fn anchors_and_empty_links_add_no_url() {
+        let text = from_html(r##"<a href="#top">Top</a> <a href="">Nowhere</a>"##, false);
+        assert_eq!(text.trim(), "Top Nowhere");
+    }
+
+    #[test]
+    fn tracking_params_are_dropped_from_link_targets() {
+        assert_eq!(
+            without_tracking_params(
+                "https://example.com/a?utm_source=mail&id=7&UTM_Medium=x&fbclid=1#top"
+            ),
+            "https://example.com/a?id=7#top"
+        );
+        assert_eq!(
+            without_tracking_params("https://example.com/a?utm_campaign=spring&gclid=2"),
+            "https://example.com/a"
+        );
+        assert_eq!(
+            without_tracking_params("https://example.com/a?page=2"),
+            "https://example.com/a?page=2"
+        );
+        assert_eq!(
+            without_tracking_params("https://example.com/a#utm_source"),
+            "https://example.com/a#utm_source"
+        );
+    }
+
+    #[test]
+    fn clean_links_only_changes_the_text_when_asked() {
+        let html =
+            r#"<a href="https://example.com/unsubscribe?u=42&utm_source=digest">Unsubscribe</a>"#;
+        assert_eq!(
+            from_html(html, true).trim(),
+            "Unsubscribe (https://example.com/unsubscribe?u=42)"
+        );
+        assert_eq!(
+            from_html(html, false).trim(),
+            "Unsubscribe (https://example.com/unsubscribe?u=42&utm_source=digest)"
+        );
+    }
+}
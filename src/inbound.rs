@@ -0,0 +1,365 @@
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use hmac::{Hmac, KeyInit, Mac};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::Sha256;
+use tracing::{info, warn};
+
+use crate::{dispatch, error_response, tracking::decode_hex, ApiError, ApiResponse, AppState};
+
+/// Oldest Mailgun signature timestamp accepted, against replays.
+const MAILGUN_MAX_AGE_SECS: u64 = 300;
+
+/// Payload shape and signature scheme of `POST /webhooks/inbound`, from
+/// `INBOUND_FORMAT`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InboundFormat {
+    /// Mailgun-style JSON: `sender`, `recipient`, `subject`, `body-plain`,
+    /// `body-html`, signed by its `signature` object.
+    Mailgun,
+    /// `{ from, to, subject, text, html }` signed by an `X-Signature:
+    /// sha256=<hex>` HMAC of the raw body, as `/notify` signing.
+    Generic,
+}
+
+/// Bridges parsed inbound email from a provider into a notification: mail
+/// received at the provider is re-sent to `INBOUND_FORWARD_TO` as the key
+/// `INBOUND_API_KEY`, so its policy and quota apply.
+#[derive(Debug)]
+pub struct Inbound {
+    pub format: InboundFormat,
+    pub signing_key: String,
+    pub forward_to: String,
+    pub api_key: String,
+}
+
+/// An inbound email once its provider's format is stripped away.
+#[derive(Debug)]
+struct InboundEmail {
+    from: String,
+    to: String,
+    subject: String,
+    text: String,
+    html: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct MailgunPayload {
+    signature: MailgunSignature,
+    #[serde(default)]
+    sender: String,
+    #[serde(default)]
+    recipient: String,
+    #[serde(default)]
+    subject: String,
+    #[serde(default, rename = "body-plain")]
+    body_plain: String,
+    #[serde(default, rename = "body-html")]
+    body_html: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct MailgunSignature {
+    timestamp: String,
+    token: String,
+    signature: String,
+}
+
+#[derive(Deserialize)]
+struct GenericPayload {
+    #[serde(default)]
+    from: String,
+    #[serde(default)]
+    to: String,
+    #[serde(default)]
+    subject: String,
+    #[serde(default)]
+    text: String,
+    #[serde(default)]
+    html: Option<String>,
+}
+
+impl InboundFormat {
+    /// Checks the signature and parses the payload. The error is the
+    /// response to give the provider.
+    fn parse(self, key: &str, headers: &HeaderMap, body: &[u8]) -> Result<InboundEmail, ApiError> {
+        match self {
+            InboundFormat::Mailgun => {
+                let payload: MailgunPayload = serde_json::from_slice(body).map_err(|err| {
+                    error_response(
+                        StatusCode::BAD_REQUEST,
+                        &format!("invalid inbound payload: {err}"),
+                    )
+                })?;
+                let signature = &payload.signature;
+                let fresh = signature.timestamp.parse::<u64>().is_ok_and(|at| {
+                    let now = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs();
+                    now.abs_diff(at) <= MAILGUN_MAX_AGE_SECS
+                });
+                let signed = format!("{}{}", signature.timestamp, signature.token);
+                if !fresh || !verify(key, signed.as_bytes(), &signature.signature) {
+                    return Err(invalid_signature().into());
+                }
+                Ok(InboundEmail {
+                    from: payload.sender,
+                    to: payload.recipient,
+                    subject: payload.subject,
+                    text: payload.body_plain,
+                    html: payload.body_html,
+                })
+            }
+            InboundFormat::Generic => {
+                let signature = headers
+                    .get("x-signature")
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.trim().strip_prefix("sha256="))
+                    .unwrap_or_default();
+                if !verify(key, body, signature) {
+                    return Err(invalid_signature().into());
+                }
+                let payload: GenericPayload = serde_json::from_slice(body).map_err(|err| {
+                    error_response(
+                        StatusCode::BAD_REQUEST,
+                        &format!("invalid inbound payload: {err}"),
+                    )
+                })?;
+                Ok(InboundEmail {
+                    from: payload.from,
+                    to: payload.to,
+                    subject: payload.subject,
+                    text: payload.text,
+                    html: payload.html,
+                })
+            }
+        }
+    }
+}
+
+/// Compares `signature`, hex HMAC-SHA256 of `data`, in constant time.
+fn verify(key: &str, data: &[u8], signature: &str) -> bool {
+    let Some(signature) = decode_hex(signature.trim()) else {
+        return false;
+    };
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("hmac accepts any key length");
+    mac.update(data);
+    mac.verify_slice(&signature).is_ok()
+}
+
+fn invalid_signature() -> (StatusCode, Json<ApiResponse>) {
+    error_response(StatusCode::UNAUTHORIZED, "invalid inbound signature")
+}
+
+/// `POST /webhooks/inbound`: verifies a provider's inbound email and
+/// forwards it as a notification. The provider sees the send's outcome, so
+/// a failed forward is retried by its own webhook retries.
+pub async fn inbound(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> (StatusCode, Json<ApiResponse>) {
+    let Some(inbound) = &state.inbound else {
+        return error_response(StatusCode::NOT_FOUND, "inbound webhook is not configured");
+    };
+    let email = match inbound.format.parse(&inbound.signing_key, &headers, &body) {
+        Ok(email) => email,
+        Err(resp) => {
+            let resp = resp.into_inner();
+            warn!(status = %resp.0, "inbound webhook rejected");
+            return resp;
+        }
+    };
+    let Some(caller) = state.api_keys.lookup(&inbound.api_key).await else {
+        return error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "INBOUND_API_KEY is not a known api key",
+        );
+    };
+
+    info!(from = %email.from, to = %email.to, "forwarding inbound email");
+    let subject = match email.subject.trim() {
+        "" => "(no subject)",
+        subject => subject,
+    };
+    let request = json!({
+        "service": "smtp",
+        "title": subject,
+        "to": inbound.forward_to,
+        "body": format!("From: {}\nTo: {}\n\n{}", email.from, email.to, email.text),
+        "html": email.html,
+    });
+    let request = match serde_json::from_value(request) {
+        Ok(request) => request,
+        Err(err) => {
+            return error_response(
+                StatusCode::BAD_REQUEST,
+                &format!("invalid inbound payload: {err}"),
+            )
+        }
+    };
+    dispatch(&state, &caller, &HeaderMap::new(), request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        http::{header::CONTENT_TYPE, Method, Request},
+    };
+    use serde_json::Value;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::test_support::{self, call};
+
+    const KEY: &str = "inbound-secret";
+
+    fn hex_hmac(data: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(KEY.as_bytes()).unwrap();
+        mac.update(data);
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+
+    async fn app(format: &str) -> axum::Router {
+        test_support::app(
+            &test_support::state(&[
+                ("INBOUND_FORMAT", format),
+                ("INBOUND_SIGNING_KEY", KEY),
+                ("INBOUND_FORWARD_TO", "support@example.com"),
+                ("INBOUND_API_KEY", test_support::API_KEY),
+            ])
+            .await,
+        )
+    }
+
+    fn mailgun(timestamp: u64) -> Value {
+        let timestamp = timestamp.to_string();
+        let token = "0123456789abcdef";
+        json!({
+            "signature": {
+                "timestamp": timestamp,
+                "token": token,
+                "signature": hex_hmac(format!("{timestamp}{token}").as_bytes()),
+            },
+            "sender": "customer@example.net",
+            "recipient": "help@example.com",
+            "subject": "printer on fire",
+            "body-plain": "it is still on fire",
+        })
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    async fn post_generic(app: &axum::Router, body: &str, signature: &str) -> (StatusCode, Value) {
+        let request = Request::post("/webhooks/inbound")
+            .header(CONTENT_TYPE, "application/json")
+            .header("x-signature", signature)
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (
+            status,
+            serde_json::from_slice(&bytes).unwrap_or(Value::Null),
+        )
+    }
+
+    #[tokio::test]
+    async fn a_signed_mailgun_payload_is_forwarded() {
+        let app = app("mailgun").await;
+        let (status, body) = call(
+            &app,
+            Method::POST,
+            "/webhooks/inbound",
+            Some(mailgun(now())),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        let sent = test_support::sent(&app).await;
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0]["to"], json!(["support@example.com"]));
+        assert_eq!(sent[0]["subject"], "printer on fire");
+        let raw = sent[0]["raw"].as_str().unwrap();
+        assert!(raw.contains("From: customer@example.net"), "{raw}");
+        assert!(raw.contains("To: help@example.com"), "{raw}");
+        assert!(raw.contains("it is still on fire"), "{raw}");
+    }
+
+    #[tokio::test]
+    async fn bad_or_stale_mailgun_signatures_are_refused() {
+        let app = app("mailgun").await;
+        let mut forged = mailgun(now());
+        forged["signature"]["signature"] = json!(hex_hmac(b"something else"));
+        let stale = mailgun(now() - MAILGUN_MAX_AGE_SECS - 60);
+
+        for payload in [forged, stale] {
+            let (status, body) = call(&app, Method::POST, "/webhooks/inbound", Some(payload)).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+            assert_eq!(body["message"], "invalid inbound signature");
+        }
+        assert!(test_support::sent(&app).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_generic_payload_is_checked_against_its_body_signature() {
+        let app = app("generic").await;
+        let body = json!({"from": "customer@example.net", "to": "help@example.com",
+            "subject": "", "text": "hello", "html": "<p>hello</p>"})
+        .to_string();
+
+        let (status, response) = post_generic(&app, &body, "sha256=00").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED, "{response}");
+
+        let signature = format!("sha256={}", hex_hmac(body.as_bytes()));
+        let (status, response) = post_generic(&app, &body, &signature).await;
+        assert_eq!(status, StatusCode::OK, "{response}");
+        let sent = test_support::sent(&app).await;
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0]["subject"], "(no subject)");
+        assert!(sent[0]["raw"].as_str().unwrap().contains("<p>hello</p>"));
+    }
+
+    #[tokio::test]
+    async fn the_webhook_answers_404_unless_configured() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let (status, body) = call(
+            &app,
+            Method::POST,
+            "/webhooks/inbound",
+            Some(mailgun(now())),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["message"], "inbound webhook is not configured");
+    }
+
+    #[test]
+    fn unknown_inbound_formats_are_refused() {
+        let err = test_support::config(&[("INBOUND_FORMAT", "postmark")]).unwrap_err();
+        assert_eq!(err.to_string(), "unsupported INBOUND_FORMAT: postmark");
+    }
+}
@@ -0,0 +1,153 @@
+use std::{sync::Mutex, time::SystemTime};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, NaiveDate, Utc};
+
+/// Daily recipient caps for a new sending IP (`IP_WARMUP_SCHEDULE`), counted
+/// from `IP_WARMUP_START`. Day 1 is the start date in UTC; once the schedule
+/// runs out sending is uncapped.
+#[derive(Debug)]
+pub struct IpWarmup {
+    start: NaiveDate,
+    caps: Vec<u64>,
+    /// Recipients sent on the day stored with them.
+    sent: Mutex<(NaiveDate, u64)>,
+}
+
+impl IpWarmup {
+    /// `schedule` is a comma-separated list of caps, one per day.
+    pub fn new(schedule: &str, start: &str) -> Result<Self> {
+        let caps = schedule
+            .split(',')
+            .map(|cap| {
+                cap.trim()
+                    .parse()
+                    .with_context(|| format!("invalid IP_WARMUP_SCHEDULE cap: {cap}"))
+            })
+            .collect::<Result<Vec<u64>>>()?;
+        let start = NaiveDate::parse_from_str(start.trim(), "%Y-%m-%d")
+            .with_context(|| format!("IP_WARMUP_START must be a YYYY-MM-DD date: {start}"))?;
+        Ok(Self {
+            start,
+            caps,
+            sent: Mutex::new((start, 0)),
+        })
+    }
+
+    /// The cap in force on `now`'s UTC day; `None` past the schedule. Days
+    /// before the start get day 1's cap.
+    pub fn cap(&self, now: SystemTime) -> Option<u64> {
+        let day = (today(now) - self.start).num_days().max(0);
+        self.caps.get(day as usize).copied()
+    }
+
+    /// Counts `recipients` against today's cap, all or nothing. Returns
+    /// `false` when they would go over it.
+    pub fn try_consume(&self, recipients: u64, now: SystemTime) -> bool {
+        let Some(cap) = self.cap(now) else {
+            return true;
+        };
+        let today = today(now);
+        let mut sent = self.sent.lock().expect("warmup lock poisoned");
+        if sent.0 != today {
+            *sent = (today, 0);
+        }
+        if sent.1 + recipients > cap {
+            return false;
+        }
+        sent.1 += recipients;
+        true
+    }
+}
+
+fn today(now: SystemTime) -> NaiveDate {
+    DateTime::<Utc>::from(now).date_naive()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_support;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    /// Noon UTC on 2024-01-01 plus `days`.
+    fn day(days: u32) -> SystemTime {
+        let start = DateTime::parse_from_rfc3339("2024-01-01T12:00:00Z").unwrap();
+        SystemTime::from(start) + DAY * days
+    }
+
+    #[test]
+    fn the_cap_rises_with_each_day_of_the_schedule() {
+        let warmup = IpWarmup::new("50, 100,200", "2024-01-01").unwrap();
+        assert_eq!(warmup.cap(day(0) - DAY), Some(50));
+        assert_eq!(warmup.cap(day(0)), Some(50));
+        assert_eq!(warmup.cap(day(1)), Some(100));
+        assert_eq!(warmup.cap(day(2)), Some(200));
+        assert_eq!(warmup.cap(day(3)), None);
+    }
+
+    #[test]
+    fn sends_are_counted_against_the_day_they_happen_on() {
+        let warmup = IpWarmup::new("2,3", "2024-01-01").unwrap();
+        assert!(warmup.try_consume(2, day(0)));
+        assert!(!warmup.try_consume(1, day(0)));
+
+        // A batch that would overshoot is refused whole.
+        assert!(!warmup.try_consume(4, day(1)));
+        assert!(warmup.try_consume(3, day(1)));
+        assert!(!warmup.try_consume(1, day(1)));
+
+        assert!(warmup.try_consume(1_000, day(2)));
+    }
+
+    #[test]
+    fn the_schedule_and_start_are_validated() {
+        assert!(IpWarmup::new("50,lots", "2024-01-01").is_err());
+        assert!(IpWarmup::new("50", "01/01/2024").is_err());
+    }
+
+    #[tokio::test]
+    async fn notify_answers_429_over_the_cap() {
+        let start = Utc::now().format("%Y-%m-%d").to_string();
+        let app = test_support::app(
+            &test_support::state(&[("IP_WARMUP_SCHEDULE", "2"), ("IP_WARMUP_START", &start)]).await,
+        );
+        let body = json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b"});
+
+        for _ in 0..2 {
+            let (status, response) = test_support::notify(&app, body.clone()).await;
+            assert_eq!(status, StatusCode::OK, "{response}");
+        }
+        let (status, response) = test_support::notify(&app, body).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response["message"], "ip warm-up daily cap reached");
+        assert_eq!(test_support::sent(&app).await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn the_archive_copy_does_not_count_against_the_cap() {
+        let start = Utc::now().format("%Y-%m-%d").to_string();
+        let app = test_support::app(
+            &test_support::state(&[
+                ("IP_WARMUP_SCHEDULE", "2"),
+                ("IP_WARMUP_START", &start),
+                ("ARCHIVE_BCC", "archive@example.com"),
+            ])
+            .await,
+        );
+        let body = json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b"});
+
+        for _ in 0..2 {
+            let (status, response) = test_support::notify(&app, body.clone()).await;
+            assert_eq!(status, StatusCode::OK, "{response}");
+        }
+        let (status, _) = test_support::notify(&app, body).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    }
+}
@@ -1,247 +1,8101 @@
-use std::{env, str::FromStr, sync::Arc};
+mod api_keys;
+mod api_version;
+mod attachments;
+mod auto_pause;
+mod batch;
+mod config_check;
+mod deadletter;
+mod dedupe;
+mod deliverability;
+mod digest;
+mod dkim;
+mod events;
+mod failures;
+mod fanout;
+mod groups;
+mod history;
+mod html_text;
+mod inbound;
+mod ip_warmup;
+mod metrics;
+mod outbox;
+mod pacer;
+mod pgp;
+mod queue;
+mod receipts;
+mod recipient_filter;
+mod retry;
+mod schema;
+mod send_window;
+mod signing;
+mod slack;
+mod smtp_debug;
+mod spool;
+mod stream_batch;
+mod subject;
+mod telemetry;
+mod templates;
+#[cfg(test)]
+mod test_support;
+mod timing;
+mod tracking;
+mod transport;
+mod upload;
+mod wrapper;
+mod write_timeout;
+
+use std::{
+    collections::HashSet,
+    env,
+    net::IpAddr,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
 
 use anyhow::{Context, Result};
 use axum::{
-    extract::State,
-    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
-    response::IntoResponse,
-    routing::{get, post},
+    extract::{rejection::JsonRejection, DefaultBodyLimit, FromRequest, Query, Request, State},
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE},
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
     Json, Router,
 };
+use base64::{prelude::BASE64_STANDARD, Engine};
 use lettre::{
-    message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
-    AsyncTransport, Message, Tokio1Executor,
+    address::{Address, Envelope},
+    message::{
+        dkim::DkimSigningAlgorithm,
+        header::{self, ContentTransferEncoding, ContentType},
+        Body, Mailbox, Mailboxes, MultiPart, MultiPartBuilder, SinglePart,
+    },
+    transport::smtp::{
+        authentication::{Credentials, Mechanism, DEFAULT_MECHANISMS},
+        client::{Tls, TlsParameters, TlsVersion},
+        PoolConfig,
+    },
+    AsyncSmtpTransport, Message, Tokio1Executor,
+};
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
+use tracing::{error, field, info, info_span, warn, Instrument, Span};
+
+use crate::{
+    api_keys::{ApiKeys, AuthConfig, Caller, IntrospectionConfig, KeyPolicy},
+    attachments::AttachmentRequest,
+    auto_pause::AutoPause,
+    batch::{BatchConnectionMode, BatchOverflow},
+    deadletter::DeadLetters,
+    dedupe::{Claim, Deduplicator, DetachedClaim},
+    digest::Digests,
+    dkim::Dkim,
+    events::{AuditEvent, EventBus},
+    failures::FailureLog,
+    groups::Groups,
+    history::SendLog,
+    inbound::{Inbound, InboundFormat},
+    ip_warmup::IpWarmup,
+    metrics::{Metrics, MetricsBackend, Tags},
+    outbox::Outbox,
+    pacer::Pacer,
+    pgp::{MissingKey, PgpKeys},
+    queue::JobQueue,
+    receipts::{ReceiptSigner, ReceiptsConfig},
+    recipient_filter::RecipientFilter,
+    retry::{GreylistPolicy, RetryPolicies, RetryPolicy},
+    schema::RequestSchema,
+    send_window::SendWindow,
+    slack::{Slack, SlackConfig},
+    smtp_debug::DialogLog,
+    spool::Spool,
+    templates::{RenderLimits, TemplateError, Templates},
+    timing::{Phase, Timings},
+    tracking::Tracking,
+    transport::{
+        ApiFallbackTransport, BoundSmtpTransport, CapturedMessage, ConnectionLimit, Delivery,
+        FailoverBackend, FailoverTransport, MemoryTransport, PoolMember, PoolPolicy, PoolTransport,
+        SentLog, SesTransport, SmtpTransport, Transport, TransportError,
+    },
+    wrapper::{BodyWrapper, WrapperFiles},
+    write_timeout::WriteTimeoutListener,
 };
-use serde::{Deserialize, Serialize};
-use tracing::{error, info};
 
-#[derive(Clone)]
 struct AppState {
-    mailer: AsyncSmtpTransport<Tokio1Executor>,
-    from: Mailbox,
-    api_key: String,
+    transport: Box<dyn Transport>,
+    /// Channels requests may use; the others are refused with 422.
+    enabled_channels: Vec<NotificationService>,
+    greylist: Option<GreylistPolicy>,
+    /// One or more From addresses; never empty.
+    from: Mailboxes,
+    /// Domains per-key From and `sender` addresses may use, lowercase and
+    /// including the `SMTP_FROM` domains; empty allows any.
+    from_allowed_domains: Vec<String>,
+    /// `REDIRECT_ALL_TO`: every message goes only to this address instead.
+    redirect_all_to: Option<Address>,
+    /// `ARCHIVE_BCC`: envelope-only copy of every message for compliance.
+    archive_bcc: Option<Address>,
+    api_keys: ApiKeys,
+    max_recipients: usize,
+    /// `DEDUPE_RECIPIENTS`: list an address listed twice only once.
+    dedupe_recipients: bool,
+    /// Recipients per SMTP transaction; larger sends are split.
+    max_rcpt_per_transaction: Option<usize>,
+    /// Reports [`RecipientCounts`] for single-recipient sends too.
+    recipient_counts_always: bool,
+    retry: RetryPolicies,
+    groups: Groups,
+    recipient_filter: RecipientFilter,
+    batch_concurrency: usize,
+    batch_connection_mode: BatchConnectionMode,
+    max_batch_size: Option<usize>,
+    batch_overflow: BatchOverflow,
+    pacer: Option<Pacer>,
+    ip_warmup: Option<IpWarmup>,
+    auto_pause: Option<AutoPause>,
+    inbound: Option<Inbound>,
+    /// The `slack` service, when `SLACK_BOT_TOKEN` is set.
+    slack: Option<Slack>,
+    /// Sends slower than this, retries included, are logged as warnings.
+    slow_send_warn: Option<Duration>,
+    /// `SENDER_NAME`: the name notifications go out under when neither the
+    /// request nor the channel names one.
+    sender_name: Option<String>,
+    templates: Option<Templates>,
+    /// Picks the template locale from `accept_language` when `locale` is
+    /// absent.
+    detect_locale: bool,
+    events: EventBus,
+    limits: FieldLimits,
+    /// Fallbacks for empty fields; `None` in strict mode.
+    defaults: Option<FieldDefaults>,
+    auto_text_part: bool,
+    /// Drops tracking query parameters from links in the generated text part.
+    clean_text_links: bool,
+    /// Rewrite bare CR and LF line endings in the text body as CRLF.
+    normalize_crlf: bool,
+    /// End the text body with exactly one CRLF.
+    trailing_crlf: bool,
+    /// Detect the type of attachments sent without `content_type`.
+    sniff_attachments: bool,
+    /// Combined size cap for files uploaded to `/notify/upload`.
+    max_upload_bytes: usize,
+    body_wrapper: Option<BodyWrapper>,
+    metrics: Metrics,
+    undisclosed_to: UndisclosedTo,
+    queue: JobQueue,
+    tracking: Option<Tracking>,
+    /// Produces multipart boundaries; fixed via `MULTIPART_BOUNDARY` so raw
+    /// MIME output can be snapshot-tested.
+    boundary: BoundaryFn,
+    dkim: Option<Dkim>,
+    pgp: Option<PgpKeys>,
+    started: Instant,
+    dedupe: Deduplicator,
+    digests: Digests,
+    /// `DIGEST_WINDOW_SECS`: window for digests without `digest_window_secs`.
+    digest_window_secs: u64,
+    outbox: Option<Outbox>,
+    /// Checks recipients with RCPT before each send (`VERIFY_RECIPIENTS`).
+    recipient_probe: Option<BoundSmtpTransport>,
+    /// Background jobs persisted across restarts when `SPOOL_DIR` is set.
+    spool: Option<Spool>,
+    request_schema: Option<RequestSchema>,
+    /// Reported by verbose `/healthz`; `None` for non-SMTP backends.
+    smtp_host: Option<String>,
+    /// Captured messages when `BACKEND=memory`.
+    sent_log: Option<SentLog>,
+    /// Recent failed sends kept for replay, when `FAILURE_LOG_SIZE` is set.
+    failures: Option<FailureLog>,
+    /// Failed background jobs kept for retry, when `DEAD_LETTER` is set.
+    dead_letters: Option<DeadLetters>,
+    /// Every send, for `GET /history`, when `HISTORY_FILE` is set.
+    history: Option<SendLog>,
+    /// Cleared while the SMTP pool warms up; `/readyz` reports 503 until set.
+    ready: AtomicBool,
+    sends: Concurrency,
+    /// Held while templates or the DKIM key are swapped, so reloads never
+    /// interleave. Async so the DKIM ticker can wait on it without blocking
+    /// a runtime worker.
+    reloading: tokio::sync::Mutex<()>,
+}
+
+type BoundaryFn = Box<dyn Fn() -> String + Send + Sync>;
+
+/// Visible To header for messages whose recipients are all in Cc/Bcc.
+#[derive(Debug, Clone, Copy)]
+enum UndisclosedTo {
+    /// `To: undisclosed-recipients:;`
+    Group,
+    /// The sender's own address.
+    From,
+}
+
+/// Subject and body used for empty fields when `STRICT_VALIDATION=false`.
+#[derive(Debug, Clone)]
+struct FieldDefaults {
+    subject: String,
+    body: String,
+}
+
+/// Caps on request field lengths, in characters.
+#[derive(Debug, Clone)]
+struct FieldLimits {
+    max_subject_len: usize,
+    max_to_len: usize,
+    subject_policy: SubjectLengthPolicy,
+    subject_sanitize: SubjectSanitize,
+    /// Caps on the built message's header fields and their total size in
+    /// bytes; 0 disables either.
+    max_headers: usize,
+    max_header_bytes: usize,
+    /// Attachments per message, inline ones included; 0 is unlimited.
+    max_attachments: usize,
+}
+
+/// What to do with a subject longer than `MAX_SUBJECT_LEN`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SubjectLengthPolicy {
+    /// Send it as folded encoded-words so no header line breaks RFC 5322.
+    Fold,
+    Reject,
+}
+
+/// What to do with bidi controls and zero-width characters in a subject,
+/// from `SANITIZE_SUBJECT`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SubjectSanitize {
+    Strip,
+    Reject,
+    Off,
 }
 
 #[derive(Debug)]
 struct Config {
     http_bind: String,
-    api_key: String,
-    smtp_host: String,
-    smtp_port: u16,
-    smtp_username: String,
-    smtp_password: String,
-    smtp_from: Mailbox,
-    smtp_tls: bool,
+    /// Deployment name from `ENVIRONMENT`, such as `staging`.
+    environment: Option<String>,
+    api_key: Option<String>,
+    api_keys_file: Option<PathBuf>,
+    auth: AuthConfig,
+    from_allowed_domains: Vec<String>,
+    redirect_all_to: Option<Address>,
+    archive_bcc: Option<Address>,
+    backend: BackendConfig,
+    /// `ENABLED_CHANNELS`; every channel when unset, never empty.
+    enabled_channels: Vec<NotificationService>,
+    /// `SMTP_FROM`, which may list several comma-separated addresses.
+    smtp_from: Mailboxes,
+    max_recipients: usize,
+    dedupe_recipients: bool,
+    digest_window_secs: u64,
+    max_rcpt_per_transaction: Option<usize>,
+    recipient_counts_always: bool,
+    retry: RetryPolicy,
+    retry_policies_file: Option<PathBuf>,
+    greylist: Option<GreylistPolicy>,
+    groups_file: Option<PathBuf>,
+    block_role_addresses: bool,
+    disposable_domains_file: Option<PathBuf>,
+    suppression_file: Option<PathBuf>,
+    batch_concurrency: usize,
+    batch_connection_mode: BatchConnectionMode,
+    max_batch_size: Option<usize>,
+    batch_overflow: BatchOverflow,
+    send_rate_per_sec: Option<f64>,
+    ip_warmup: Option<IpWarmup>,
+    auto_pause: Option<AutoPause>,
+    inbound: Option<Inbound>,
+    receipts: Option<ReceiptsConfig>,
+    slack: Option<SlackConfig>,
+    send_max_wait: Duration,
+    slow_send_warn: Option<Duration>,
+    sender_name: Option<String>,
+    templates_dir: Option<PathBuf>,
+    /// Writes messages here instead of sending them (development only).
+    outbox_dir: Option<PathBuf>,
+    verify_recipients: bool,
+    request_schema_file: Option<PathBuf>,
+    default_locale: String,
+    detect_locale: bool,
+    template_limits: RenderLimits,
+    events_buffer: usize,
+    limits: FieldLimits,
+    defaults: Option<FieldDefaults>,
+    auto_text_part: bool,
+    clean_text_links: bool,
+    normalize_crlf: bool,
+    trailing_crlf: bool,
+    sniff_attachments: bool,
+    max_upload_bytes: usize,
+    body_wrapper: WrapperFiles,
+    undisclosed_to: UndisclosedTo,
+    queue_capacity: usize,
+    max_queued_jobs: Option<usize>,
+    spool_dir: Option<PathBuf>,
+    queue_workers: usize,
+    queue_aging: Duration,
+    job_max_age: Option<Duration>,
+    /// Base URL and signing secret when `TRACKING_ENABLED`.
+    tracking: Option<(String, String)>,
+    multipart_boundary: Option<String>,
+    dkim: Option<DkimSettings>,
+    dkim_reload: Duration,
+    pgp_keys_dir: Option<PathBuf>,
+    pgp_missing_key: MissingKey,
+    bind_retry: Duration,
+    /// How long a shutdown waits for in-flight requests.
+    shutdown_grace: Duration,
+    /// Closes connections whose client reads none of the response for this
+    /// long.
+    write_timeout: Option<Duration>,
+    metric_tag_keys: Vec<String>,
+    /// `METRICS_TENANT_LABEL`, with the most distinct tenants labelled.
+    metrics_tenant_label: Option<usize>,
+    metrics_backend: MetricsBackend,
+    failure_log_size: Option<usize>,
+    /// `SPOOL_DIR`, when `DEAD_LETTER` keeps failed jobs beside the spool.
+    dead_letter_dir: Option<PathBuf>,
+    history_file: Option<PathBuf>,
+    history_max_rows: usize,
+}
+
+/// Signing settings when `DKIM_PRIVATE_KEY_PATH` is set.
+#[derive(Debug)]
+struct DkimSettings {
+    key_path: PathBuf,
+    selector: String,
+    domain: String,
+    algorithm: DkimSigningAlgorithm,
+}
+
+/// Outbound backend selected by `BACKEND`.
+#[derive(Debug)]
+enum BackendConfig {
+    Smtp(Box<SmtpConfig>),
+    Ses {
+        region: Option<String>,
+        /// SMTP server used when the API fails (`SES_SMTP_FALLBACK`).
+        smtp_fallback: Option<Box<SmtpConfig>>,
+    },
+    /// Captures messages in memory; see `/test/sent`.
+    Memory,
+}
+
+#[derive(Debug, Clone)]
+struct SmtpConfig {
+    host: String,
+    port: u16,
+    /// Username and password, or the OAuth2 access token when authenticating
+    /// with `XOAUTH2`; `None` skips AUTH for relays that need none.
+    credentials: Option<Credentials>,
+    auth_mechanisms: Vec<Mechanism>,
+    security: SmtpSecurity,
+    /// Oldest TLS version accepted from the server.
+    min_tls_version: TlsVersion,
+    warmup: bool,
+    warmup_connections: usize,
+    /// TCP connect timeout; lettre's default (60s) when unset.
+    connect_timeout: Option<Duration>,
+    send_timeout: Option<Duration>,
+    /// Source address for outgoing connections; disables pooling.
+    local_bind: Option<IpAddr>,
+    /// `SENDING_POOL`: source addresses with weights, one picked per
+    /// message; disables pooling.
+    sending_pool: Vec<(IpAddr, u32)>,
+    pool_policy: PoolPolicy,
+    /// `SMTP_USE_CHUNKING`: send with `BDAT` where offered; disables pooling.
+    chunking: bool,
+    /// `SMTP_ACCEPT_PARTIAL_RCPT`: send to the recipients the server takes
+    /// when it refuses others; disables pooling.
+    partial_rcpt: bool,
+    /// `SMTP_DISABLE_PIPELINING`: never pipeline commands, even when the
+    /// server offers `PIPELINING`.
+    disable_pipelining: bool,
+    /// Simultaneous sessions allowed to this server.
+    max_connections: Option<usize>,
+    /// `SMTP_DEBUG`: log each send's SMTP dialog.
+    debug: bool,
+    /// `SMTP_FALLBACK_<n>_*` servers tried in order when this one fails.
+    fallbacks: Vec<SmtpConfig>,
+    failover_cooldown: Duration,
+}
+
+/// Connection security from `SMTP_SECURITY`, or `SMTP_TLS` when unset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SmtpSecurity {
+    /// TLS from the first byte, usually port 465.
+    Tls,
+    /// Plain connection upgraded with STARTTLS, usually port 587.
+    StartTls,
+    /// No encryption, usually port 25.
+    None,
+}
+
+impl SmtpSecurity {
+    fn name(self) -> &'static str {
+        match self {
+            SmtpSecurity::Tls => "tls",
+            SmtpSecurity::StartTls => "starttls",
+            SmtpSecurity::None => "none",
+        }
+    }
+
+    fn parse(name: &str, raw: &str) -> Result<Self> {
+        match raw {
+            "tls" => Ok(SmtpSecurity::Tls),
+            "starttls" => Ok(SmtpSecurity::StartTls),
+            "none" => Ok(SmtpSecurity::None),
+            other => anyhow::bail!("unsupported {name}: {other}"),
+        }
+    }
+
+    /// The mode to suggest when `self` does not fit the port's usual one:
+    /// implicit TLS on 465, STARTTLS on 587 and plain on 25.
+    fn suggested_for(self, port: u16) -> Option<Self> {
+        match (port, self) {
+            (587, SmtpSecurity::Tls) => Some(SmtpSecurity::StartTls),
+            (465, SmtpSecurity::StartTls) => Some(SmtpSecurity::Tls),
+            (25, SmtpSecurity::Tls | SmtpSecurity::StartTls) => Some(SmtpSecurity::None),
+            _ => None,
+        }
+    }
 }
 
-#[derive(Debug, Deserialize)]
+/// Field names from the service this one replaced, so its clients can
+/// migrate unchanged: `(legacy, canonical)`.
+const LEGACY_FIELDS: &[(&str, &str)] = &[
+    ("subject", "title"),
+    ("recipient", "to"),
+    ("message", "body"),
+];
+
+// Derived as inherent functions so the trait impls below can map legacy
+// field names first.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(remote = "Self")]
 struct NotifyRequest {
     service: NotificationService,
     title: String,
+    /// May be empty when `cc` or `bcc` has recipients. Also accepted as
+    /// `{ name, address }` objects, see [`deserialize_recipients`].
+    #[serde(default, deserialize_with = "deserialize_recipients")]
     to: String,
+    #[serde(default, deserialize_with = "deserialize_optional_recipients")]
+    cc: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_optional_recipients")]
+    bcc: Option<String>,
+    /// Also delivers the message to its From address, for the sender's own
+    /// records. Envelope only, like `ARCHIVE_BCC`; skipped when From is
+    /// already a recipient or `REDIRECT_ALL_TO` is set.
+    #[serde(default)]
+    copy_sender: bool,
+    #[serde(default)]
     body: String,
+    #[serde(default)]
+    body_encoding: BodyEncoding,
+    /// Forces an RFC 2047 encoding for clients that mishandle the default.
+    #[serde(default)]
+    subject_encoding: SubjectEncoding,
+    /// Pins the text and HTML parts' Content-Transfer-Encoding for gateways
+    /// that mishandle lettre's choice.
+    #[serde(default)]
+    content_transfer_encoding: TransferEncoding,
+    /// Top-level multipart subtype instead of the one picked from the parts;
+    /// the parts must fit it.
+    #[serde(default)]
+    multipart_type: Option<MultipartType>,
+    /// Encrypts the body with PGP/MIME to the recipients' keys in
+    /// `PGP_KEYS_DIR`.
+    #[serde(default)]
+    encrypt: bool,
+    /// HTML body; sent as an alternative to `body`, or on its own.
+    #[serde(default)]
+    html: Option<String>,
+    /// Adds an open-tracking pixel to `html` when tracking is enabled.
+    #[serde(default)]
+    track_opens: bool,
+    /// Routes `html` links through the click redirector when tracking is
+    /// enabled.
+    #[serde(default)]
+    track_clicks: bool,
+    /// Calendar invite sent as a `text/calendar` alternative part.
+    #[serde(default)]
+    calendar: Option<CalendarInvite>,
+    /// Renders the body from `TEMPLATES_DIR` instead of using `body`.
+    #[serde(default)]
+    template: Option<String>,
+    #[serde(default)]
+    data: serde_json::Value,
+    #[serde(default)]
+    locale: Option<String>,
+    /// The recipient's languages in `Accept-Language` form, such as
+    /// `de-AT,de;q=0.9,en;q=0.5`; tried in order when `locale` is absent
+    /// and `DETECT_LOCALE` is on.
+    #[serde(default)]
+    accept_language: Option<String>,
+    /// Attribution tags, counted as metric labels when allowlisted.
+    #[serde(default)]
+    tags: Tags,
+    /// Sends to `SUPPRESSION_FILE` addresses too, e.g. a password reset the
+    /// user asked for; honoured only for keys with `bypass_suppression`.
+    #[serde(default)]
+    bypass_suppression: bool,
+    /// The caller's own id for the send, echoed in the response and kept
+    /// with its `HISTORY_FILE` row.
+    #[serde(default)]
+    client_reference: Option<String>,
+    /// Skips the send when an identical message went out this many seconds
+    /// ago or less.
+    #[serde(default)]
+    dedupe_window_secs: Option<u64>,
+    /// Seconds the whole send may take, validation and retries included;
+    /// the request fails with 504 once they run out.
+    #[serde(default)]
+    total_deadline_secs: Option<u64>,
+    /// `false` omits the Date header for relays that add their own.
+    /// Defaults to `true`.
+    #[serde(default)]
+    set_date: Option<bool>,
+    /// Explicit RFC 2822 Date header, sent as given instead of the current
+    /// time.
+    #[serde(default)]
+    date: Option<String>,
+    /// RFC 2822 time after which the message is no longer relevant, sent as
+    /// `Expiry-Date` (RFC 4021) so clients can auto-archive it.
+    #[serde(default)]
+    expires_at: Option<String>,
+    /// Seconds the message stays useful, such as a one-time code's lifetime.
+    /// Sent as `X-Message-TTL`, and as `Expiry-Date` when `expires_at` is
+    /// absent. A send window or digest that would hold the message longer
+    /// is skipped and it is sent right away.
+    #[serde(default)]
+    ttl_secs: Option<u64>,
+    /// Name the notification goes out under on any channel: the From display
+    /// name for email, the bot's username for slack. Defaults to the
+    /// channel's own (`SMTP_FROM`'s display name, `SLACK_USERNAME`), then
+    /// `SENDER_NAME`.
+    #[serde(default)]
+    sender_name: Option<String>,
+    /// Sender header, naming who actually submitted the message. Required by
+    /// RFC 5322 with several From addresses, where it defaults to the first.
+    #[serde(default)]
+    sender: Option<String>,
+    /// Answers 202 with a job id right away and sends in the background;
+    /// honoured by `/notify` only.
+    #[serde(default)]
+    async_ack: bool,
+    /// Holds the send until the recipient's local send window opens;
+    /// honoured by `/notify` only.
+    #[serde(default)]
+    respect_send_window: Option<SendWindow>,
+    /// Jobs from the same key sharing this are sent one at a time in
+    /// submission order; honoured by `/notify/async` only.
+    #[serde(default)]
+    ordering_key: Option<String>,
+    /// RFC 2919 list identifier such as `alerts.example.com`; adds
+    /// `List-Id` and `List-Post` headers.
+    #[serde(default)]
+    list_id: Option<String>,
+    /// Address replies to the list go to; defaults to the From address.
+    #[serde(default)]
+    list_post: Option<String>,
+    /// Leaves out the `BODY_HEADER` / `BODY_FOOTER` wrapper.
+    #[serde(default)]
+    skip_wrapper: bool,
+    /// Combines this with the recipient's other digest messages arriving
+    /// within `digest_window_secs` into one email; honoured by `/notify`
+    /// only.
+    #[serde(default)]
+    digest: bool,
+    /// Defaults to `DIGEST_WINDOW_SECS`.
+    #[serde(default)]
+    digest_window_secs: Option<u64>,
+    /// Where to send the notification instead once this service has failed
+    /// for good, retries spent.
+    #[serde(default)]
+    fallback_channel: Option<FallbackChannel>,
+    #[serde(default)]
+    attachments: Vec<AttachmentRequest>,
+}
+
+/// `fallback_channel`: another service and its recipient, e.g.
+/// `{ "service": "slack", "to": "#ops" }`. The rest of the request is
+/// reused.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct FallbackChannel {
+    service: NotificationService,
+    to: String,
+}
+
+impl Serialize for NotifyRequest {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        NotifyRequest::serialize(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for NotifyRequest {
+    /// A legacy field is renamed to its canonical one, and dropped when the
+    /// canonical field is also present.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut value = serde_json::Value::deserialize(deserializer)?;
+        if let Some(fields) = value.as_object_mut() {
+            for (legacy, canonical) in LEGACY_FIELDS {
+                if let Some(legacy_value) = fields.remove(*legacy) {
+                    fields.entry(*canonical).or_insert(legacy_value);
+                }
+            }
+        }
+        NotifyRequest::deserialize(value).map_err(D::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CalendarInvite {
+    ics: String,
+    /// iTIP method: `REQUEST` or `CANCEL`.
+    method: String,
+}
+
+/// iTIP methods accepted for `calendar.method`.
+const CALENDAR_METHODS: &[&str] = &["REQUEST", "CANCEL"];
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum BodyEncoding {
+    /// `body` is used as-is.
+    #[default]
+    Raw,
+    /// `body` is base64 of UTF-8 text.
+    Base64,
+}
+
+/// How the Subject header is encoded.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum SubjectEncoding {
+    /// lettre's encoding, or base64 words when the subject must be folded.
+    #[default]
+    Auto,
+    /// Always RFC 2047 base64 (`B`) encoded-words.
+    Base64,
+    /// Always RFC 2047 quoted-printable (`Q`) encoded-words.
+    QuotedPrintable,
+}
+
+/// Content-Transfer-Encoding of the body parts.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum TransferEncoding {
+    /// lettre picks the most compact encoding the body allows.
+    #[default]
+    Auto,
+    #[serde(rename = "7bit")]
+    SevenBit,
+    #[serde(rename = "8bit")]
+    EightBit,
+    QuotedPrintable,
+    Base64,
+}
+
+/// Top-level multipart subtype a caller can pin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum MultipartType {
+    /// The body, then any attachments; forces a wrapper even without them.
+    Mixed,
+    /// The HTML body with the inline `cid` attachments it shows.
+    Related,
+    /// Two or more renderings of the body and nothing else.
+    Alternative,
+}
+
+impl TransferEncoding {
+    fn pinned(self) -> Option<ContentTransferEncoding> {
+        match self {
+            TransferEncoding::Auto => None,
+            TransferEncoding::SevenBit => Some(ContentTransferEncoding::SevenBit),
+            TransferEncoding::EightBit => Some(ContentTransferEncoding::EightBit),
+            TransferEncoding::QuotedPrintable => Some(ContentTransferEncoding::QuotedPrintable),
+            TransferEncoding::Base64 => Some(ContentTransferEncoding::Base64),
+        }
+    }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 enum NotificationService {
-    #[serde(alias = "stmp")]
+    #[serde(alias = "stmp", alias = "email")]
     Smtp,
+    Slack,
 }
 
-#[derive(Serialize)]
+impl NotificationService {
+    fn name(self) -> &'static str {
+        match self {
+            NotificationService::Smtp => "smtp",
+            NotificationService::Slack => "slack",
+        }
+    }
+}
+
+#[derive(Default, Serialize)]
 struct ApiResponse {
     ok: bool,
     message: String,
+    /// Request field that failed validation (v2 only).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    field: Option<&'static str>,
+    /// Unix timestamp of the earlier identical send, for deduplicated
+    /// requests.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    original_sent_at: Option<u64>,
+    /// Per-phase SMTP timings, for `?timing=true` requests.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timings: Option<Box<Timings>>,
+    /// `Message-ID` header of the message as sent, angle brackets included.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message_id: Option<Box<str>>,
+    /// Recipients the server refused while taking the message for the rest.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    rejected: Vec<String>,
+    /// Envelope recipients taken and refused, on a send to several or with
+    /// `RECIPIENT_COUNTS_ALWAYS`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recipients: Option<RecipientCounts>,
+    /// What a non-email provider answered; absent for email.
+    #[serde(flatten)]
+    provider: Option<ChannelResponse>,
+    /// Outcome of `fallback_channel`, when the send failed and it was tried.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fallback: Option<Box<FallbackResult>>,
+    /// The request's `client_reference`, as given.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_reference: Option<String>,
+}
+
+#[derive(Serialize)]
+struct FallbackResult {
+    channel: &'static str,
+    ok: bool,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    provider_message_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    provider_status: Option<String>,
+}
+
+/// Provider details of a non-email send, flattened into its [`ApiResponse`].
+#[derive(Serialize)]
+struct ChannelResponse {
+    channel: &'static str,
+    /// The provider's id for the message, e.g. a Slack `ts`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    provider_message_id: Option<String>,
+    /// The provider's own status or error code, e.g. `channel_not_found`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    provider_status: Option<String>,
+}
+
+impl ApiResponse {
+    fn ok(message: impl Into<String>) -> Self {
+        Self {
+            ok: true,
+            message: message.into(),
+            ..Self::default()
+        }
+    }
+
+    fn error(message: impl Into<String>) -> Self {
+        Self {
+            ok: false,
+            message: message.into(),
+            ..Self::default()
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct RecipientCounts {
+    accepted: usize,
+    rejected: usize,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    init_tracing();
+    let check = parse_bool_env("CONFIG_CHECK").unwrap_or(false);
+    let result = run(check).await;
+    if check {
+        if let Err(err) = &result {
+            config_check::Report::failed(err).print_and_exit();
+        }
+    }
+    result
+}
+
+/// Starts the server; with `check`, stops once the configuration is loaded
+/// and prints a [`config_check::Report`] instead of serving.
+async fn run(check: bool) -> Result<()> {
+    let telemetry = telemetry::init()?;
 
     let cfg = Config::from_env().context("failed to load configuration from environment")?;
-    let mailer = build_mailer(&cfg)?;
+    let (state, cfg) = build_state(cfg).await?;
+    if check {
+        config_check::Report::inspect(
+            &state,
+            &cfg.backend,
+            &cfg.http_bind,
+            cfg.environment.as_deref(),
+        )
+        .await
+        .print_and_exit();
+    }
+    if let Some((mailer, count)) = cfg.warmup {
+        tokio::spawn(warm_up_pool(state.clone(), mailer, count));
+    }
+    if let Some(receipts) = cfg.receipts {
+        receipts::spawn(receipts, &state.events)?;
+    }
+    queue::recover(&state)?;
+    queue::spawn_workers(&state, cfg.queue_workers);
+    dkim::spawn_reloader(&state, cfg.dkim_reload);
+
+    let app = router(&state, cfg.max_upload_bytes);
+
+    let listener = bind_with_retry(&cfg.http_bind, cfg.bind_retry).await?;
+    let listener = WriteTimeoutListener::new(listener, cfg.write_timeout);
+
+    info!(
+        addr = %cfg.http_bind,
+        environment = cfg.environment.as_deref().unwrap_or("unset"),
+        "server started"
+    );
+    let stopping = Arc::new(tokio::sync::Notify::new());
+    let server = axum::serve(listener, app).with_graceful_shutdown({
+        let stopping = stopping.clone();
+        async move {
+            shutdown_signal().await;
+            stopping.notify_one();
+        }
+    });
+    // Event streams never end on their own, so the grace period bounds the
+    // wait for them.
+    let grace = async {
+        stopping.notified().await;
+        tokio::time::sleep(cfg.shutdown_grace).await;
+    };
+    let result = tokio::select! {
+        result = server => result.context("http server exited unexpectedly"),
+        () = grace => {
+            warn!(grace_secs = cfg.shutdown_grace.as_secs(), "requests still in flight after the shutdown grace period, stopping anyway");
+            Ok(())
+        }
+    };
+    if result.is_ok() {
+        log_shutdown_report(&state);
+    }
+
+    telemetry.shutdown();
+    result
+}
+
+/// The parts of [`Config`] that [`run`] still needs once [`build_state`]
+/// has taken the rest.
+struct Serving {
+    backend: BackendConfig,
+    http_bind: String,
+    environment: Option<String>,
+    receipts: Option<ReceiptsConfig>,
+    queue_workers: usize,
+    dkim_reload: Duration,
+    max_upload_bytes: usize,
+    bind_retry: Duration,
+    write_timeout: Option<Duration>,
+    shutdown_grace: Duration,
+    /// The pool to open connections in before reporting ready.
+    warmup: Option<(AsyncSmtpTransport<Tokio1Executor>, usize)>,
+}
+
+/// Builds the transport, stores and loaded files `cfg` names into the shared
+/// state, without starting any background task.
+async fn build_state(cfg: Config) -> Result<(Arc<AppState>, Serving)> {
+    let mut sent_log = None;
+    let mut warmup = None;
+    let transport: Box<dyn Transport> = match &cfg.backend {
+        BackendConfig::Smtp(smtp) => {
+            let (primary, mailer) = build_smtp_transport(smtp)?;
+            if smtp.warmup {
+                warmup = mailer.map(|mailer| (mailer, smtp.warmup_connections));
+            }
+            if smtp.fallbacks.is_empty() {
+                primary
+            } else {
+                let mut backends = vec![FailoverBackend::new(smtp.host.clone(), primary)];
+                for fallback in &smtp.fallbacks {
+                    let (transport, _) = build_smtp_transport(fallback)?;
+                    backends.push(FailoverBackend::new(fallback.host.clone(), transport));
+                }
+                info!(servers = backends.len(), "smtp failover enabled");
+                Box::new(FailoverTransport {
+                    backends,
+                    cooldown: smtp.failover_cooldown,
+                })
+            }
+        }
+        BackendConfig::Ses {
+            region,
+            smtp_fallback,
+        } => {
+            let api = Box::new(SesTransport::from_env(region.clone()).await);
+            match smtp_fallback {
+                Some(smtp) => {
+                    let (smtp_transport, _) = build_smtp_transport(smtp)?;
+                    info!(host = %smtp.host, "smtp fallback for the ses api enabled");
+                    Box::new(ApiFallbackTransport {
+                        api,
+                        smtp: smtp_transport,
+                    })
+                }
+                None => api,
+            }
+        }
+        BackendConfig::Memory => {
+            let transport = MemoryTransport::default();
+            sent_log = Some(transport.sent_log());
+            Box::new(transport)
+        }
+    };
+    let recipient_probe = match &cfg.backend {
+        _ if !cfg.verify_recipients => None,
+        BackendConfig::Smtp(smtp) => {
+            info!(host = %smtp.host, "recipients probed with RCPT before each send");
+            let probe_cfg = SmtpConfig {
+                chunking: false,
+                warmup: false,
+                ..smtp.as_ref().clone()
+            };
+            Some(build_bound_transport(&probe_cfg)?)
+        }
+        BackendConfig::Ses { .. } | BackendConfig::Memory => {
+            anyhow::bail!("VERIFY_RECIPIENTS requires BACKEND=smtp")
+        }
+    };
+    let templates = cfg
+        .templates_dir
+        .as_deref()
+        .map(|dir| Templates::load(dir, &cfg.default_locale, cfg.template_limits))
+        .transpose()?;
+    let dkim = cfg
+        .dkim
+        .map(|dkim| Dkim::load(&dkim.key_path, dkim.selector, dkim.domain, dkim.algorithm))
+        .transpose()?;
+    let outbox = cfg.outbox_dir.clone().map(Outbox::new).transpose()?;
+    if let Some(dir) = &cfg.outbox_dir {
+        warn!(dir = %dir.display(), "OUTBOX_DIR set, messages are saved to disk and not sent");
+    }
+    let body_wrapper = BodyWrapper::load(&cfg.body_wrapper)?;
+    let request_schema = cfg
+        .request_schema_file
+        .as_deref()
+        .map(RequestSchema::load)
+        .transpose()?;
+    let api_keys = ApiKeys::load(
+        &cfg.auth,
+        cfg.api_key.as_deref(),
+        cfg.api_keys_file.as_deref(),
+    )?;
+    if let Some(inbound) = &cfg.inbound {
+        anyhow::ensure!(
+            api_keys.lookup(&inbound.api_key).await.is_some(),
+            "INBOUND_API_KEY is not a known api key"
+        );
+    }
+    let pgp = cfg
+        .pgp_keys_dir
+        .as_deref()
+        .map(|dir| PgpKeys::load(dir, cfg.pgp_missing_key))
+        .transpose()?;
+    let groups = match &cfg.groups_file {
+        Some(path) => Groups::load(path)?,
+        None => Groups::default(),
+    };
+    let recipient_filter = RecipientFilter::new(cfg.block_role_addresses);
+    let recipient_filter = match &cfg.disposable_domains_file {
+        Some(path) => recipient_filter.load_disposable(path)?,
+        None => recipient_filter,
+    };
+    let recipient_filter = match &cfg.suppression_file {
+        Some(path) => recipient_filter.load_suppressed(path)?,
+        None => recipient_filter,
+    };
+
+    let mut from_allowed_domains = cfg.from_allowed_domains;
+    if !from_allowed_domains.is_empty() {
+        from_allowed_domains.extend(
+            cfg.smtp_from
+                .iter()
+                .map(|mailbox| mailbox.email.domain().to_ascii_lowercase()),
+        );
+    }
+
+    if let Some(warmup) = &cfg.ip_warmup {
+        match warmup.cap(SystemTime::now()) {
+            Some(cap) => info!(cap, "ip warm-up schedule active, recipients per day capped"),
+            None => info!("ip warm-up schedule has ended, sending is uncapped"),
+        }
+    }
+    if let Some(address) = &cfg.redirect_all_to {
+        warn!(%address, "REDIRECT_ALL_TO set, all messages go to this address only");
+    }
 
     let state = Arc::new(AppState {
-        mailer,
+        transport,
+        enabled_channels: cfg.enabled_channels,
         from: cfg.smtp_from,
-        api_key: cfg.api_key,
+        from_allowed_domains,
+        redirect_all_to: cfg.redirect_all_to,
+        archive_bcc: cfg.archive_bcc,
+        api_keys,
+        max_recipients: cfg.max_recipients,
+        dedupe_recipients: cfg.dedupe_recipients,
+        max_rcpt_per_transaction: cfg.max_rcpt_per_transaction,
+        recipient_counts_always: cfg.recipient_counts_always,
+        retry: RetryPolicies::load(cfg.retry, cfg.retry_policies_file.as_deref())?,
+        greylist: cfg.greylist,
+        groups,
+        recipient_filter,
+        batch_concurrency: cfg.batch_concurrency,
+        batch_connection_mode: cfg.batch_connection_mode,
+        max_batch_size: cfg.max_batch_size,
+        batch_overflow: cfg.batch_overflow,
+        pacer: cfg
+            .send_rate_per_sec
+            .map(|rate| Pacer::new(rate, cfg.send_max_wait)),
+        ip_warmup: cfg.ip_warmup,
+        auto_pause: cfg.auto_pause,
+        inbound: cfg.inbound,
+        slack: cfg.slack.map(Slack::new).transpose()?,
+        slow_send_warn: cfg.slow_send_warn,
+        sender_name: cfg.sender_name,
+        templates,
+        detect_locale: cfg.detect_locale,
+        events: EventBus::new(cfg.events_buffer),
+        limits: cfg.limits,
+        defaults: cfg.defaults,
+        auto_text_part: cfg.auto_text_part,
+        clean_text_links: cfg.clean_text_links,
+        normalize_crlf: cfg.normalize_crlf,
+        trailing_crlf: cfg.trailing_crlf,
+        sniff_attachments: cfg.sniff_attachments,
+        max_upload_bytes: cfg.max_upload_bytes,
+        body_wrapper,
+        undisclosed_to: cfg.undisclosed_to,
+        metrics: Metrics::new(
+            cfg.metric_tag_keys,
+            cfg.metrics_tenant_label,
+            cfg.metrics_backend,
+        )?,
+        queue: JobQueue::new(
+            cfg.queue_capacity,
+            cfg.max_queued_jobs,
+            cfg.queue_workers,
+            cfg.queue_aging,
+            cfg.job_max_age,
+        ),
+        tracking: cfg
+            .tracking
+            .as_ref()
+            .map(|(base_url, secret)| Tracking::new(base_url, secret)),
+        boundary: match cfg.multipart_boundary {
+            Some(fixed) => Box::new(move || fixed.clone()),
+            None => Box::new(|| format!("{:032x}", rand::random::<u128>())),
+        },
+        dkim,
+        pgp,
+        started: Instant::now(),
+        dedupe: Deduplicator::default(),
+        digests: Digests::default(),
+        digest_window_secs: cfg.digest_window_secs,
+        outbox,
+        recipient_probe,
+        spool: cfg.spool_dir.map(Spool::new).transpose()?,
+        request_schema,
+        smtp_host: match &cfg.backend {
+            BackendConfig::Smtp(smtp) => Some(smtp.host.clone()),
+            BackendConfig::Ses { .. } | BackendConfig::Memory => None,
+        },
+        sent_log,
+        failures: cfg.failure_log_size.map(FailureLog::new),
+        dead_letters: cfg.dead_letter_dir.map(DeadLetters::new).transpose()?,
+        history: cfg
+            .history_file
+            .map(|path| SendLog::open(path, cfg.history_max_rows))
+            .transpose()?,
+        ready: AtomicBool::new(warmup.is_none()),
+        sends: Concurrency::default(),
+        reloading: tokio::sync::Mutex::new(()),
     });
+    let serving = Serving {
+        backend: cfg.backend,
+        http_bind: cfg.http_bind,
+        environment: cfg.environment,
+        receipts: cfg.receipts,
+        queue_workers: cfg.queue_workers,
+        dkim_reload: cfg.dkim_reload,
+        max_upload_bytes: cfg.max_upload_bytes,
+        bind_retry: cfg.bind_retry,
+        write_timeout: cfg.write_timeout,
+        shutdown_grace: cfg.shutdown_grace,
+        warmup,
+    };
+    Ok((state, serving))
+}
 
-    let app = Router::new()
+/// Every route, with the signature check and response shaping on top.
+fn router(state: &Arc<AppState>, max_upload_bytes: usize) -> Router {
+    // Room for the `request` part and multipart framing on top of the files.
+    let upload_body_limit = max_upload_bytes.saturating_add(upload::OVERHEAD_BYTES);
+    let mut app = Router::new()
         .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
         .route("/notify", post(notify))
         .route("/send-notification", post(notify))
-        .with_state(state);
+        .route(
+            "/notify/upload",
+            post(upload::notify_upload).layer(DefaultBodyLimit::max(upload_body_limit)),
+        )
+        .route("/notify/batch", post(batch::notify_batch))
+        .route("/notify/stream", post(stream_batch::notify_stream))
+        .route("/notify-multi", post(fanout::notify_multi))
+        .route("/notify/async", post(queue::enqueue))
+        .route(
+            "/jobs/{id}",
+            get(queue::job_status).delete(queue::cancel_job),
+        )
+        .route("/preview", post(preview))
+        .route(
+            "/deliverability-check",
+            post(deliverability::deliverability_check),
+        )
+        .route("/events", get(events::events))
+        .route("/metrics", get(metrics::metrics))
+        .route("/history", get(history::history))
+        .route("/admin/flush-pool", post(flush_pool))
+        .route("/admin/resume", post(auto_pause::resume))
+        .route("/admin/failures", get(failures::list_failures))
+        .route("/admin/reload-templates", post(templates::reload_templates))
+        .route("/admin/replay/{id}", post(failures::replay))
+        .route("/admin/deadletter", get(deadletter::list_dead_letters))
+        .route("/admin/deadletter/{id}", delete(deadletter::discard))
+        .route("/admin/deadletter/{id}/retry", post(deadletter::retry))
+        .route("/webhooks/inbound", post(inbound::inbound))
+        .route("/open/{id}", get(tracking::open))
+        .route("/click/{id}", get(tracking::click));
+    if state.sent_log.is_some() {
+        app = app.route("/test/sent", get(sent_messages));
+    }
+    app.layer(middleware::from_fn_with_state(
+        state.clone(),
+        signing::verify_signature,
+    ))
+    .layer(middleware::from_fn(api_version::shape_response))
+    .with_state(state.clone())
+}
 
-    let listener = tokio::net::TcpListener::bind(&cfg.http_bind)
-        .await
-        .with_context(|| format!("failed to bind to {}", cfg.http_bind))?;
+/// Resolves on Ctrl-C or SIGTERM, starting a graceful shutdown: no new
+/// connections, in-flight requests finish.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            warn!(error = %err, "failed to listen for ctrl-c");
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(err) => {
+                warn!(error = %err, "failed to listen for SIGTERM");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        () = ctrl_c => {}
+        () = terminate => {}
+    }
+    info!("shutdown signal received, finishing in-flight requests");
+}
 
-    info!(addr = %cfg.http_bind, "server started");
-    axum::serve(listener, app)
-        .await
-        .context("http server exited unexpectedly")
+/// One structured line of lifetime counts after a graceful shutdown, so an
+/// operator can confirm nothing was left behind: jobs still queued survive
+/// only in the spool.
+fn log_shutdown_report(state: &AppState) {
+    let (queued, sending) = state.queue.depth();
+    let spooled = state.spool.as_ref().map(Spool::count);
+    let dead_letters = state.dead_letters.as_ref().map(DeadLetters::count);
+    info!(
+        uptime_secs = state.started.elapsed().as_secs(),
+        sent = state.metrics.total("sent"),
+        failed = state.metrics.total("failed"),
+        peak_concurrent_sends = state.sends.peak.load(Ordering::Relaxed),
+        queued,
+        sending,
+        spooled = spooled.unwrap_or_default(),
+        dead_letters = dead_letters.unwrap_or_default(),
+        "shutdown report"
+    );
+    if spooled.is_none() && queued + sending > 0 {
+        warn!(
+            jobs = queued + sending,
+            "background jobs lost at shutdown, set SPOOL_DIR to keep them"
+        );
+    }
 }
 
-async fn healthz() -> impl IntoResponse {
-    Json(ApiResponse {
-        ok: true,
-        message: "ok".to_string(),
-    })
+/// Sends running now and the most seen at once.
+#[derive(Default)]
+struct Concurrency {
+    current: AtomicUsize,
+    peak: AtomicUsize,
 }
 
-async fn notify(
-    State(state): State<Arc<AppState>>,
-    headers: HeaderMap,
-    Json(req): Json<NotifyRequest>,
-) -> impl IntoResponse {
-    if !is_authorized(&headers, &state.api_key) {
-        return error_response(StatusCode::UNAUTHORIZED, "invalid api key");
+impl Concurrency {
+    /// Counts a send until the guard is dropped.
+    fn enter(&self) -> ConcurrencyGuard<'_> {
+        let now = self.current.fetch_add(1, Ordering::Relaxed) + 1;
+        self.peak.fetch_max(now, Ordering::Relaxed);
+        ConcurrencyGuard(self)
     }
+}
 
-    match req.service {
-        NotificationService::Smtp => send_smtp_email(state.as_ref(), req).await,
+struct ConcurrencyGuard<'a>(&'a Concurrency);
+
+impl Drop for ConcurrencyGuard<'_> {
+    fn drop(&mut self) {
+        self.0.current.fetch_sub(1, Ordering::Relaxed);
     }
 }
 
-async fn send_smtp_email(state: &AppState, req: NotifyRequest) -> (StatusCode, Json<ApiResponse>) {
-    if req.title.trim().is_empty() {
-        return error_response(StatusCode::BAD_REQUEST, "title cannot be empty");
+/// Binds `addr`, retrying with backoff while the port is still held (e.g. by
+/// a terminating predecessor) until `window` has elapsed. Other errors, and
+/// a zero window, fail on the first attempt.
+async fn bind_with_retry(addr: &str, window: Duration) -> Result<tokio::net::TcpListener> {
+    let started = Instant::now();
+    let mut delay = Duration::from_millis(100);
+    let mut attempt = 1;
+
+    loop {
+        match tokio::net::TcpListener::bind(addr).await {
+            Ok(listener) => return Ok(listener),
+            Err(err)
+                if err.kind() == std::io::ErrorKind::AddrInUse
+                    && started.elapsed() + delay <= window =>
+            {
+                warn!(
+                    addr,
+                    attempt,
+                    delay_ms = delay.as_millis() as u64,
+                    error = %err,
+                    "address in use, retrying bind"
+                );
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(Duration::from_secs(2));
+                attempt += 1;
+            }
+            Err(err) => return Err(err).with_context(|| format!("failed to bind to {addr}")),
+        }
     }
-    if req.body.trim().is_empty() {
-        return error_response(StatusCode::BAD_REQUEST, "body cannot be empty");
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct HealthQuery {
+    #[serde(default)]
+    verbose: bool,
+}
+
+/// `GET /healthz?verbose=true` body; the plain probe stays `{ ok, message }`.
+#[derive(Serialize)]
+struct HealthResponse {
+    ok: bool,
+    message: String,
+    uptime_secs: u64,
+    sent: u64,
+    failed: u64,
+    backend: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    smtp_host: Option<String>,
+}
+
+async fn healthz(State(state): State<Arc<AppState>>, Query(query): Query<HealthQuery>) -> Response {
+    if !query.verbose {
+        return Json(ApiResponse::ok("ok")).into_response();
     }
-    if req.to.trim().is_empty() {
-        return error_response(StatusCode::BAD_REQUEST, "to cannot be empty");
+
+    Json(HealthResponse {
+        ok: true,
+        message: "ok".to_string(),
+        uptime_secs: state.started.elapsed().as_secs(),
+        sent: state.metrics.total("sent"),
+        failed: state.metrics.total("failed"),
+        backend: state.transport.name(),
+        smtp_host: state.smtp_host.clone(),
+    })
+    .into_response()
+}
+
+/// Readiness for load balancers: 503 until the SMTP pool has warmed up, so
+/// traffic is not routed to an instance that cannot send yet.
+async fn readyz(State(state): State<Arc<AppState>>) -> (StatusCode, Json<ApiResponse>) {
+    if !state.ready.load(Ordering::Relaxed) {
+        return error_response(StatusCode::SERVICE_UNAVAILABLE, "warming up");
     }
+    (StatusCode::OK, Json(ApiResponse::ok("ready")))
+}
 
-    let to = match Mailbox::from_str(req.to.trim()) {
-        Ok(mailbox) => mailbox,
-        Err(_) => return error_response(StatusCode::BAD_REQUEST, "invalid recipient email"),
+#[derive(Debug, Default, Deserialize)]
+struct NotifyQuery {
+    #[serde(default)]
+    timing: bool,
+}
+
+async fn notify(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<NotifyQuery>,
+    headers: HeaderMap,
+    JsonBody(body): JsonBody<serde_json::Value>,
+) -> Response {
+    let Some(caller) = authenticate(&state, &headers).await else {
+        return error_response(StatusCode::UNAUTHORIZED, "invalid api key").into_response();
+    };
+    let accepted = accept(&state, &caller, &headers, body, Vec::new());
+    let mut response = if query.timing {
+        timing::scope(accepted).await
+    } else {
+        accepted.await
     };
+    if let Some(quota) = state.api_keys.quota_state(&caller) {
+        let headers = response.headers_mut();
+        headers.insert("x-ratelimit-limit", HeaderValue::from(quota.limit));
+        headers.insert("x-ratelimit-remaining", HeaderValue::from(quota.remaining));
+        headers.insert("x-ratelimit-reset", HeaderValue::from(quota.reset_secs));
+    }
+    response
+}
 
-    let email = match Message::builder()
-        .from(state.from.clone())
-        .to(to.clone())
-        .subject(req.title)
-        .body(req.body)
-    {
-        Ok(message) => message,
-        Err(err) => {
-            error!(error = %err, "failed to build message");
-            return error_response(StatusCode::BAD_REQUEST, "invalid email payload");
-        }
+/// Validates a `/notify` body, adds `uploads` to its attachments and sends
+/// it, or queues it when `async_ack` is set or the send window is closed.
+async fn accept(
+    state: &Arc<AppState>,
+    caller: &Caller<'_>,
+    headers: &HeaderMap,
+    body: serde_json::Value,
+    uploads: Vec<AttachmentRequest>,
+) -> Response {
+    let mut req = match parse_notify(state, body) {
+        Ok(req) => req,
+        Err(resp) => return resp.into_response(),
     };
+    req.attachments.extend(uploads);
 
-    match state.mailer.send(email).await {
-        Ok(_) => {
-            info!(service = "smtp", to = %to, "notification sent");
-            (
-                StatusCode::OK,
-                Json(ApiResponse {
-                    ok: true,
-                    message: "sent".to_string(),
-                }),
-            )
+    if let Some(window) = &req.respect_send_window {
+        match window.wait(chrono::Utc::now()) {
+            Ok(None) => {}
+            Ok(Some(wait)) if !outlives_ttl(&req, wait) => {
+                return queue::send_at_window(state, caller, headers, req, wait).into_response()
+            }
+            Ok(Some(_)) => info!("send window opens after the message ttl, sending now"),
+            Err(message) => return field_error("respect_send_window", &message).into_response(),
         }
-        Err(err) => {
-            error!(service = "smtp", to = %to, error = %err, "send failed");
-            error_response(StatusCode::INTERNAL_SERVER_ERROR, "smtp send failed")
+    }
+    let digest_window = req.digest_window_secs.unwrap_or(state.digest_window_secs);
+    if req.digest && outlives_ttl(&req, Duration::from_secs(digest_window)) {
+        info!("digest window is longer than the message ttl, sending now");
+    } else if req.digest {
+        return digest::add(state, caller, headers, req).into_response();
+    }
+    if req.async_ack {
+        return queue::send_detached(state, caller, headers, req).into_response();
+    }
+    dispatch(state, caller, headers, req).await.into_response()
+}
+
+/// Checks a request body against `REQUEST_SCHEMA_FILE`, when one is set, and
+/// deserializes it.
+fn parse_notify(state: &AppState, body: serde_json::Value) -> Result<NotifyRequest, ApiError> {
+    if let Some(schema) = &state.request_schema {
+        if let Err(violation) = schema.validate(&body) {
+            return Err(error_response(
+                StatusCode::BAD_REQUEST,
+                &format!("request does not match schema: {violation}"),
+            )
+            .into());
         }
     }
+    serde_json::from_value(body).map_err(|err| {
+        error_response(
+            StatusCode::BAD_REQUEST,
+            &format!("invalid request body: {err}"),
+        )
+        .into()
+    })
 }
 
-fn is_authorized(headers: &HeaderMap, expected_api_key: &str) -> bool {
-    match extract_api_key(headers) {
-        Some(provided) => provided == expected_api_key,
-        None => false,
+/// Checks that `req` can go out through its service, without sending it,
+/// so queued and digested messages are refused up front.
+fn validate(state: &AppState, caller: &Caller<'_>, req: &NotifyRequest) -> Result<(), ApiError> {
+    check_channel(state, req.service)?;
+    match req.service {
+        NotificationService::Smtp => build_smtp_email(state, &caller.policy, req.clone()).map(drop),
+        NotificationService::Slack => slack::validate(state, req),
     }
 }
 
-fn extract_api_key(headers: &HeaderMap) -> Option<&str> {
-    if let Some(value) = headers.get("x-api-key").and_then(|v| v.to_str().ok()) {
-        return Some(value);
+/// Refuses requests for a channel left out of `ENABLED_CHANNELS`.
+fn check_channel(state: &AppState, service: NotificationService) -> Result<(), ApiError> {
+    if state.enabled_channels.contains(&service) {
+        return Ok(());
     }
+    Err(unprocessable_field(
+        "service",
+        &format!("channel not enabled: {}", service.name()),
+    )
+    .into())
+}
 
-    let auth = headers.get(AUTHORIZATION)?.to_str().ok()?;
-    auth.strip_prefix("Bearer ")
-        .or_else(|| auth.strip_prefix("bearer "))
+/// `from` under the request's `sender_name`, or with `SENDER_NAME` on the
+/// addresses configured without a display name; `None` when neither
+/// changes anything.
+fn rename_from(
+    from: &Mailboxes,
+    requested: Option<&str>,
+    global: Option<&str>,
+) -> Option<Mailboxes> {
+    let requested = requested.map(str::trim).filter(|name| !name.is_empty());
+    if requested.is_none()
+        && (global.is_none() || from.iter().all(|mailbox| mailbox.name.is_some()))
+    {
+        return None;
+    }
+    Some(
+        from.iter()
+            .map(|mailbox| {
+                let name = requested.or(mailbox.name.as_deref()).or(global);
+                Mailbox::new(name.map(str::to_string), mailbox.email.clone())
+            })
+            .collect(),
+    )
+}
+
+/// Whether holding the message for `wait` would keep it past `ttl_secs`.
+fn outlives_ttl(req: &NotifyRequest, wait: Duration) -> bool {
+    req.ttl_secs
+        .is_some_and(|ttl| wait > Duration::from_secs(ttl))
 }
 
-fn build_mailer(cfg: &Config) -> Result<AsyncSmtpTransport<Tokio1Executor>> {
-    let credentials = Credentials::new(cfg.smtp_username.clone(), cfg.smtp_password.clone());
+/// Sends one notification through its service inside a traced span.
+async fn dispatch(
+    state: &Arc<AppState>,
+    caller: &Caller<'_>,
+    headers: &HeaderMap,
+    req: NotifyRequest,
+) -> (StatusCode, Json<ApiResponse>) {
+    let _counted = state.sends.enter();
+    // Counts are `i64`: the OTel layer exports unsigned fields as strings.
+    let span = info_span!(
+        "send_email",
+        recipient = %req.to.trim(),
+        subject_len = req.title.chars().count() as i64,
+        retries = 0i64,
+        outcome = field::Empty,
+    );
+    telemetry::link_parent(&span, headers);
 
-    let mailer = if cfg.smtp_tls {
-        AsyncSmtpTransport::<Tokio1Executor>::relay(&cfg.smtp_host)
-            .context("failed to create TLS SMTP transport")?
-            .port(cfg.smtp_port)
-            .credentials(credentials)
-            .build()
+    let service = req.service.name();
+    let recipient = req.to.trim().to_string();
+    let tags = req.tags.clone();
+    let client_reference = req.client_reference.clone();
+    let replayable = state.failures.as_ref().map(|_| req.clone());
+    let fallback = req.fallback_channel.clone().map(|fallback| NotifyRequest {
+        service: fallback.service,
+        to: fallback.to,
+        fallback_channel: None,
+        ..req.clone()
+    });
+    let deadline = req
+        .total_deadline_secs
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs);
+    let send = async {
+        if let Err(resp) = check_channel(state, req.service) {
+            return resp.into_inner();
+        }
+        match req.service {
+            NotificationService::Smtp => send_smtp_email(state, caller, req).await,
+            NotificationService::Slack => slack::send_slack(state, caller, req).await,
+        }
+    }
+    .instrument(span.clone());
+    let (status, mut body) = match deadline {
+        Some(deadline) => match tokio::time::timeout(deadline, send).await {
+            Ok(resp) => resp,
+            Err(_) => {
+                warn!(service, to = %recipient, "total send deadline exceeded");
+                error_response(StatusCode::GATEWAY_TIMEOUT, "send deadline exceeded")
+            }
+        },
+        None => send.await,
+    };
+    body.timings = timing::current().map(Box::new);
+    body.client_reference = client_reference;
+    let outcome = if body.original_sent_at.is_some() {
+        "deduplicated"
     } else {
-        AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&cfg.smtp_host)
-            .port(cfg.smtp_port)
-            .credentials(credentials)
-            .build()
+        outcome_label(status)
     };
-
-    Ok(mailer)
+    span.record("outcome", outcome);
+    state
+        .metrics
+        .record_send(service, outcome, &caller.policy.tenant, &tags);
+    if let (Some(failures), Some(req), "failed") = (&state.failures, replayable, outcome) {
+        let failure_id = failures.record(caller.key, req, body.message.clone());
+        info!(failure_id, recipient = %recipient, "failed notification recorded for replay");
+    }
+    if let (Some(history), "sent" | "failed") = (&state.history, outcome) {
+        history.record(&caller.policy.tenant, service, &recipient, outcome, &body);
+    }
+    if outcome != "rejected" {
+        state.events.publish(
+            AuditEvent::new(outcome, service, recipient, body.message.clone())
+                .with_message_id(body.message_id.clone()),
+        );
+    }
+    if let (Some(fallback), "failed") = (fallback, outcome) {
+        let channel = fallback.service.name();
+        warn!(service, fallback = channel, fallback_to = %fallback.to.trim(), "send failed, trying the fallback channel");
+        // Counted and published as a send of its own.
+        let (fallback_status, Json(fallback_body)) =
+            Box::pin(dispatch(state, caller, headers, fallback)).await;
+        let (provider_message_id, provider_status) = fallback_body
+            .provider
+            .map(|provider| (provider.provider_message_id, provider.provider_status))
+            .unwrap_or_default();
+        body.fallback = Some(Box::new(FallbackResult {
+            channel,
+            ok: fallback_status.is_success(),
+            message: fallback_body.message,
+            provider_message_id,
+            provider_status,
+        }));
+    }
+    (status, body)
 }
 
-impl Config {
-    fn from_env() -> Result<Self> {
-        let smtp_from_raw = must_env("SMTP_FROM")?;
-        let smtp_from =
-            Mailbox::from_str(&smtp_from_raw).context("SMTP_FROM is not a valid email")?;
-
-        let smtp_port = env::var("SMTP_PORT")
-            .unwrap_or_else(|_| "587".to_string())
-            .parse::<u16>()
-            .context("SMTP_PORT must be a valid u16")?;
+/// Coarse outcome of a send attempt, derived from the response status.
+fn outcome_label(status: StatusCode) -> &'static str {
+    match status {
+        StatusCode::OK => "sent",
+        StatusCode::ACCEPTED => "deferred",
+        StatusCode::TOO_MANY_REQUESTS => "rate_limited",
+        status if status.is_client_error() => "rejected",
+        _ => "failed",
+    }
+}
 
-        Ok(Self {
-            http_bind: env::var("HTTP_BIND").unwrap_or_else(|_| "127.0.0.1:8080".to_string()),
-            api_key: must_env("API_KEY")?,
-            smtp_host: must_env("SMTP_HOST")?,
-            smtp_port,
-            smtp_username: must_env("SMTP_USERNAME")?,
-            smtp_password: must_env("SMTP_PASSWORD")?,
-            smtp_from,
-            smtp_tls: parse_bool_env("SMTP_TLS").unwrap_or(true),
-        })
+/// `GET /test/sent`: messages captured by the memory backend, oldest first.
+async fn sent_messages(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<CapturedMessage>>, ApiError> {
+    if authenticate(&state, &headers).await.is_none() {
+        return Err(error_response(StatusCode::UNAUTHORIZED, "invalid api key").into());
     }
+    let sent_log = state
+        .sent_log
+        .as_ref()
+        .expect("route only registered for the memory backend");
+    let sent = sent_log.lock().expect("sent log lock poisoned");
+    Ok(Json(sent.clone()))
 }
 
-fn must_env(name: &str) -> Result<String> {
-    env::var(name).with_context(|| format!("missing env var: {name}"))
+/// Takes the reload lock for an admin reload endpoint; a reload already
+/// running answers 409 rather than queueing this one behind it.
+fn reload_guard(state: &AppState) -> Result<tokio::sync::MutexGuard<'_, ()>, ApiError> {
+    state
+        .reloading
+        .try_lock()
+        .map_err(|_| error_response(StatusCode::CONFLICT, "reload in progress").into())
 }
 
-fn parse_bool_env(name: &str) -> Option<bool> {
-    let raw = env::var(name).ok()?;
-    match raw.trim().to_ascii_lowercase().as_str() {
-        "1" | "true" | "yes" | "on" => Some(true),
-        "0" | "false" | "no" | "off" => Some(false),
-        _ => None,
+/// `POST /admin/flush-pool`: replaces the SMTP mailer so stale pooled
+/// connections (e.g. after a server restart) are not reused. lettre does not
+/// expose the pool size, so the number of dropped connections is not known.
+async fn flush_pool(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> (StatusCode, Json<ApiResponse>) {
+    if let Err(resp) = authenticate_admin(&state, &headers).await {
+        return resp.into_inner();
     }
-}
 
-fn error_response(status: StatusCode, message: &str) -> (StatusCode, Json<ApiResponse>) {
-    (
-        status,
-        Json(ApiResponse {
-            ok: false,
-            message: message.to_string(),
-        }),
-    )
+    let backend = state.transport.name();
+    let message = match state.transport.flush_pool() {
+        Ok(true) => {
+            info!(backend, "connection pool flushed");
+            "connection pool flushed".to_string()
+        }
+        Ok(false) => format!("{backend} backend has no connection pool"),
+        Err(err) => {
+            error!(backend, error = %format!("{err:#}"), "failed to rebuild transport");
+            return error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "failed to rebuild transport",
+            );
+        }
+    };
+    (StatusCode::OK, Json(ApiResponse::ok(message)))
 }
 
-fn init_tracing() {
-    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
-        .unwrap_or_else(|_| "notification_server=info,axum=info".into());
+async fn preview(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    JsonBody(body): JsonBody<serde_json::Value>,
+) -> Response {
+    let Some(caller) = authenticate(&state, &headers).await else {
+        return error_response(StatusCode::UNAUTHORIZED, "invalid api key").into_response();
+    };
+    let req = match parse_notify(&state, body) {
+        Ok(req) => req,
+        Err(resp) => return resp.into_response(),
+    };
 
-    tracing_subscriber::fmt()
-        .with_env_filter(filter)
-        .with_target(false)
-        .compact()
-        .init();
+    let email = match req.service {
+        NotificationService::Smtp => {
+            build_smtp_email(state.as_ref(), &caller.policy, req).map(|email| email.message)
+        }
+        NotificationService::Slack => Err(ApiError::from(unprocessable_field(
+            "service",
+            "only email messages have a MIME preview",
+        ))),
+    };
+
+    match email {
+        Ok(email) => (
+            [(CONTENT_TYPE, "text/plain; charset=utf-8")],
+            email.formatted(),
+        )
+            .into_response(),
+        Err(resp) => resp.into_response(),
+    }
+}
+
+async fn send_smtp_email(
+    state: &Arc<AppState>,
+    caller: &Caller<'_>,
+    req: NotifyRequest,
+) -> (StatusCode, Json<ApiResponse>) {
+    let to = req.to.trim().to_string();
+    let tags = req.tags.clone();
+    let dedupe = req.dedupe_window_secs.map(|secs| {
+        (
+            Deduplicator::content_hash(caller.key, &req),
+            Duration::from_secs(secs),
+        )
+    });
+    let built = match build_smtp_email(state, &caller.policy, req) {
+        Ok(built) => built,
+        Err(resp) => return resp.into_inner(),
+    };
+    // Hidden copies are neither counted against the warm-up cap nor
+    // reported to the caller.
+    let visible_recipients = built.visible_recipients();
+    let email = &built.message;
+    if !built.bypassed.is_empty() {
+        let bypassed: Vec<String> = built.bypassed.iter().map(ToString::to_string).collect();
+        warn!(service = "smtp", tenant = %caller.policy.tenant, to = %to, tags = ?tags, ?bypassed, "suppression bypassed");
+    }
+
+    let mut claim = match dedupe.map(|(hash, window)| state.dedupe.claim(hash, window)) {
+        None => None,
+        Some(Ok(claim)) => Some(claim),
+        Some(Err(sent_at)) => {
+            info!(service = "smtp", to = %to, tags = ?tags, sent_at, "duplicate notification skipped");
+            return (
+                StatusCode::OK,
+                Json(ApiResponse {
+                    original_sent_at: Some(sent_at),
+                    ..ApiResponse::ok("deduplicated")
+                }),
+            );
+        }
+    };
+
+    if state
+        .auto_pause
+        .as_ref()
+        .is_some_and(|pause| pause.is_paused(Instant::now()))
+    {
+        return error_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "sending paused after sustained failures",
+        );
+    }
+
+    if let (Some(probe), None) = (&state.recipient_probe, &state.outbox) {
+        match probe.probe(email.envelope()).await {
+            Ok(refused) if refused.is_empty() => {}
+            Ok(refused) => {
+                let refused: Vec<String> = refused.iter().map(ToString::to_string).collect();
+                warn!(service = "smtp", to = %to, tags = ?tags, ?refused, "recipients refused by probe");
+                let (status, Json(mut body)) = error_response(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    &format!("recipients refused by probe: {}", refused.join(", ")),
+                );
+                body.rejected = refused;
+                return (status, Json(body));
+            }
+            // Only a definite refusal stops the send; the probe is a hint.
+            Err(err) => {
+                warn!(service = "smtp", to = %to, error = %err, "recipient probe failed, sending anyway")
+            }
+        }
+    }
+
+    // Counted before the warm-up cap so a spent quota costs the IP nothing;
+    // refunded below when the message never goes out.
+    if !state.api_keys.try_consume(caller) {
+        return error_response(StatusCode::TOO_MANY_REQUESTS, "daily quota exceeded");
+    }
+
+    let message_id = email.headers().get_raw("Message-ID").map(Box::from);
+    if let Some(outbox) = &state.outbox {
+        return match outbox.save(email) {
+            Ok(path) => {
+                info!(service = "smtp", to = %to, tags = ?tags, path = %path.display(), "notification saved to outbox");
+                (
+                    StatusCode::OK,
+                    Json(ApiResponse {
+                        message_id,
+                        ..ApiResponse::ok("saved to outbox")
+                    }),
+                )
+            }
+            Err(err) => {
+                error!(service = "smtp", to = %to, error = %format!("{err:#}"), "failed to save to outbox");
+                state.api_keys.refund(caller);
+                error_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "failed to save to outbox",
+                )
+            }
+        };
+    }
+
+    if let Some(warmup) = &state.ip_warmup {
+        if !warmup.try_consume(visible_recipients as u64, SystemTime::now()) {
+            warn!(service = "smtp", to = %to, tags = ?tags, "ip warm-up daily cap reached");
+            state.api_keys.refund(caller);
+            return error_response(
+                StatusCode::TOO_MANY_REQUESTS,
+                "ip warm-up daily cap reached",
+            );
+        }
+    }
+
+    let backend = state.transport.name();
+    // Each batch is its own SMTP transaction, retried on its own; a batch
+    // that fails for good stops the rest, as they would likely fail too.
+    let started = Instant::now();
+    let batches = recipient_batches(email.envelope(), state.max_rcpt_per_transaction);
+    let total = batches.len();
+    let mut deferred = false;
+    let mut rejected = Vec::new();
+    let mut via = None;
+    let mut result = Ok(());
+    for (batch, envelope) in batches.into_iter().enumerate() {
+        match send_with_retry(state, NotificationService::Smtp, &envelope, email).await {
+            Ok(delivery) => {
+                rejected.extend(delivery.rejected.iter().map(ToString::to_string));
+                via = via.or(delivery.via);
+            }
+            Err(SendError::Greylisted(err)) => {
+                warn!(
+                    service = "smtp",
+                    backend,
+                    to = %to,
+                    tags = ?tags,
+                    batch,
+                    error = %err,
+                    "greylisted, deferring send"
+                );
+                // The retry still counts as this send for deduplication.
+                let claim = claim.take().map(Claim::detach);
+                defer_greylisted(state.clone(), email.clone(), envelope, to.clone(), claim);
+                deferred = true;
+            }
+            Err(err) => {
+                if batch > 0 {
+                    warn!(service = "smtp", to = %to, sent_batches = batch, total, "notification partially sent");
+                } else {
+                    // Nothing went out, so the send does not count.
+                    state.api_keys.refund(caller);
+                }
+                result = Err(err);
+                break;
+            }
+        }
+    }
+
+    if let Some(pause) = &state.auto_pause {
+        // A message the server refused says nothing about the provider.
+        match &result {
+            Ok(()) => pause.record(false, Instant::now()),
+            Err(SendError::Transport(_, kind)) if *kind != FailureKind::Permanent => {
+                pause.record(true, Instant::now())
+            }
+            Err(_) => {}
+        }
+    }
+
+    let elapsed = started.elapsed();
+    if state.slow_send_warn.is_some_and(|limit| elapsed > limit) {
+        warn!(
+            service = "smtp",
+            backend,
+            to = %to,
+            elapsed_ms = elapsed.as_millis() as u64,
+            succeeded = result.is_ok(),
+            "slow send"
+        );
+    }
+
+    match result {
+        Ok(()) if deferred => (
+            StatusCode::ACCEPTED,
+            Json(ApiResponse {
+                message_id,
+                ..ApiResponse::ok("deferred for greylisting")
+            }),
+        ),
+        Ok(()) => {
+            if let Some(claim) = claim {
+                claim.keep();
+            }
+            info!(
+                service = "smtp",
+                backend = via.unwrap_or(backend),
+                to = %to,
+                tags = ?tags,
+                message_id = message_id.as_deref().unwrap_or_default(),
+                batches = total,
+                "notification sent"
+            );
+            let recipients = (visible_recipients > 1 || state.recipient_counts_always).then(|| {
+                let rejected = rejected
+                    .iter()
+                    .filter(|address| !built.is_hidden(address))
+                    .count();
+                RecipientCounts {
+                    accepted: visible_recipients - rejected,
+                    rejected,
+                }
+            });
+            let message = if rejected.is_empty() {
+                "sent"
+            } else {
+                warn!(service = "smtp", to = %to, rejected = ?rejected, "recipients refused by the server");
+                "partially sent"
+            };
+            (
+                StatusCode::OK,
+                Json(ApiResponse {
+                    message_id,
+                    rejected,
+                    recipients,
+                    ..ApiResponse::ok(message)
+                }),
+            )
+        }
+        Err(SendError::Paced) => {
+            warn!(service = "smtp", to = %to, tags = ?tags, "global send rate exceeded");
+            error_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "send rate limit reached, retry later",
+            )
+        }
+        Err(SendError::Greylisted(_)) => unreachable!("greylisted batches are deferred"),
+        Err(SendError::Transport(err, kind)) => {
+            error!(
+                service = "smtp",
+                backend,
+                to = %to,
+                tags = ?tags,
+                failure_kind = kind.label(),
+                error = %err,
+                "send failed"
+            );
+            error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &format!("{backend} send failed"),
+            )
+        }
+    }
+}
+
+#[derive(Debug)]
+enum SendError {
+    /// The global pacer could not fit the send within its max wait.
+    Paced,
+    /// The server is greylisting; only returned when deferral is enabled.
+    Greylisted(TransportError),
+    Transport(TransportError, FailureKind),
+}
+
+/// Why a send gave up, logged as `failure_kind` so alerts can tell a
+/// rejected message from a struggling provider.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FailureKind {
+    /// The server refused the message; retrying would not help.
+    Permanent,
+    /// Transient failures outlasted the retry budget.
+    RetriesExhausted,
+    /// The last attempt ran out of time.
+    Timeout,
+}
+
+impl FailureKind {
+    /// Classifies the error that ended a send; `retryable` tells whether it
+    /// was eligible for retries.
+    fn of(err: &TransportError, retryable: bool) -> Self {
+        if matches!(err, TransportError::Timeout(_)) {
+            FailureKind::Timeout
+        } else if retryable {
+            FailureKind::RetriesExhausted
+        } else {
+            FailureKind::Permanent
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            FailureKind::Permanent => "permanent",
+            FailureKind::RetriesExhausted => "retries_exhausted",
+            FailureKind::Timeout => "timeout",
+        }
+    }
+}
+
+/// Sends the message, retrying failures per the channel's retry policy.
+/// Every attempt, retries included, goes through the global pacer.
+async fn send_with_retry(
+    state: &AppState,
+    service: NotificationService,
+    envelope: &Envelope,
+    email: &Message,
+) -> Result<Delivery, SendError> {
+    let policy = state.retry.get(service);
+    let started = Instant::now();
+    let mut attempt = 0;
+
+    loop {
+        if let Some(pacer) = &state.pacer {
+            if !pacer.acquire().await {
+                return Err(SendError::Paced);
+            }
+        }
+
+        let attempt_started = Instant::now();
+        let result = state.transport.send(envelope, email).await;
+        timing::record(Phase::Total, attempt_started.elapsed());
+        let err = match result {
+            Ok(delivery) => return Ok(delivery),
+            Err(err) if state.greylist.is_some() && err.is_greylisting() => {
+                return Err(SendError::Greylisted(err))
+            }
+            Err(err) if !policy.is_retryable(&err) => {
+                let kind = FailureKind::of(&err, false);
+                return Err(SendError::Transport(err, kind));
+            }
+            Err(err) => err,
+        };
+
+        let retry_after = err.retry_after();
+        let Some(delay) =
+            policy.next_delay(attempt, started.elapsed(), retry_after, &mut rand::rng())
+        else {
+            let kind = FailureKind::of(&err, true);
+            return Err(SendError::Transport(err, kind));
+        };
+
+        attempt += 1;
+        Span::current().record("retries", i64::from(attempt));
+        warn!(
+            attempt,
+            delay_ms = delay.as_millis() as u64,
+            retry_after = retry_after.is_some(),
+            error = %err,
+            "transient send failure, retrying"
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Splits the envelope into one per SMTP transaction of at most `max`
+/// recipients; a single envelope when unlimited.
+fn recipient_batches(envelope: &Envelope, max: Option<usize>) -> Vec<Envelope> {
+    let Some(max) = max else {
+        return vec![envelope.clone()];
+    };
+    envelope
+        .to()
+        .chunks(max)
+        .map(|chunk| {
+            Envelope::new(envelope.from().cloned(), chunk.to_vec()).expect("chunks are never empty")
+        })
+        .collect()
+}
+
+/// Retries a greylisted message in the background per `GREYLIST_RETRY_SECS`,
+/// publishing the final outcome on the event stream. A dedupe `claim` is
+/// kept once the retry sends and released when it fails.
+fn defer_greylisted(
+    state: Arc<AppState>,
+    email: Message,
+    envelope: Envelope,
+    to: String,
+    claim: Option<DetachedClaim>,
+) {
+    let Some(policy) = state.greylist.clone() else {
+        return;
+    };
+
+    tokio::spawn(async move {
+        let claim = claim.map(|claim| claim.attach(&state.dedupe));
+        let mut last_error = None;
+        for deferral in 1..=policy.max_deferrals {
+            tokio::time::sleep(policy.delay).await;
+            if let Some(pacer) = &state.pacer {
+                if !pacer.acquire().await {
+                    warn!(to = %to, deferral, "global send rate exceeded, deferring again");
+                    continue;
+                }
+            }
+
+            match state.transport.send(&envelope, &email).await {
+                Ok(_) => {
+                    if let Some(claim) = claim {
+                        claim.keep();
+                    }
+                    info!(service = "smtp", to = %to, deferral, "deferred notification sent");
+                    let message_id = email.headers().get_raw("Message-ID").map(Box::from);
+                    state.events.publish(
+                        AuditEvent::new("sent", "smtp", to, "sent".to_string())
+                            .with_message_id(message_id),
+                    );
+                    return;
+                }
+                Err(err) if err.is_greylisting() || err.is_transient() => {
+                    warn!(to = %to, deferral, error = %err, "deferred send still rejected");
+                    last_error = Some(err);
+                }
+                Err(err) => {
+                    last_error = Some(err);
+                    break;
+                }
+            }
+        }
+
+        let kind = last_error
+            .as_ref()
+            .map_or(FailureKind::RetriesExhausted, |err| {
+                FailureKind::of(err, err.is_greylisting() || err.is_transient())
+            });
+        let error = last_error.map(|err| err.to_string()).unwrap_or_default();
+        error!(
+            service = "smtp",
+            to = %to,
+            failure_kind = kind.label(),
+            error = %error,
+            "deferred send failed"
+        );
+        let message_id = email.headers().get_raw("Message-ID").map(Box::from);
+        state.events.publish(
+            AuditEvent::new(
+                "failed",
+                "smtp",
+                to,
+                format!("{} send failed", state.transport.name()),
+            )
+            .with_message_id(message_id),
+        );
+    });
+}
+
+/// A rendered message and the envelope-only copies it carries for
+/// `ARCHIVE_BCC` and `copy_sender`, which are not the request's recipients.
+struct SmtpEmail {
+    message: Message,
+    hidden: Vec<Address>,
+    /// Suppressed recipients sent to anyway under `bypass_suppression`.
+    bypassed: Vec<Address>,
+}
+
+impl SmtpEmail {
+    /// Envelope recipients the request itself named.
+    fn visible_recipients(&self) -> usize {
+        self.message.envelope().to().len() - self.hidden.len()
+    }
+
+    fn is_hidden(&self, address: &str) -> bool {
+        self.hidden
+            .iter()
+            .any(|hidden| hidden.to_string().eq_ignore_ascii_case(address))
+    }
+}
+
+/// Validates the request and renders it into a ready-to-send `Message`.
+fn build_smtp_email(
+    state: &AppState,
+    policy: &KeyPolicy,
+    mut req: NotifyRequest,
+) -> Result<SmtpEmail, ApiError> {
+    match state.limits.subject_sanitize {
+        SubjectSanitize::Strip => req.title.retain(|ch| !subject::is_dangerous_char(ch)),
+        SubjectSanitize::Reject if req.title.chars().any(subject::is_dangerous_char) => {
+            return Err(field_error(
+                "title",
+                "title contains bidi control or zero-width characters",
+            )
+            .into());
+        }
+        SubjectSanitize::Reject | SubjectSanitize::Off => {}
+    }
+    if req.title.trim().is_empty() {
+        match &state.defaults {
+            Some(defaults) => req.title = defaults.subject.clone(),
+            None => return Err(field_error("title", "title cannot be empty").into()),
+        }
+    }
+    if let Err(msg) = state.metrics.validate_tags(&req.tags) {
+        return Err(field_error("tags", &msg).into());
+    }
+    if req
+        .dedupe_window_secs
+        .is_some_and(|secs| secs == 0 || secs > dedupe::MAX_WINDOW_SECS)
+    {
+        return Err(field_error(
+            "dedupe_window_secs",
+            &format!(
+                "dedupe_window_secs must be 1 to {}",
+                dedupe::MAX_WINDOW_SECS
+            ),
+        )
+        .into());
+    }
+    if req.total_deadline_secs == Some(0) {
+        return Err(field_error(
+            "total_deadline_secs",
+            "total_deadline_secs must be at least 1",
+        )
+        .into());
+    }
+    let limits = &state.limits;
+    let fold_subject = req.title.chars().count() > limits.max_subject_len;
+    if fold_subject && limits.subject_policy == SubjectLengthPolicy::Reject {
+        return Err(field_error(
+            "title",
+            &format!("title exceeds {} characters", limits.max_subject_len),
+        )
+        .into());
+    }
+    if req.to.chars().count() > limits.max_to_len {
+        return Err(field_error(
+            "to",
+            &format!("to exceeds {} characters", limits.max_to_len),
+        )
+        .into());
+    }
+    if limits.max_attachments > 0 && req.attachments.len() > limits.max_attachments {
+        return Err(field_error("attachments", "too many attachments").into());
+    }
+    let body = match req.template.as_deref() {
+        Some(name) => {
+            let locales = match (req.locale, req.accept_language) {
+                (Some(locale), _) => vec![locale],
+                (None, Some(_)) if !state.detect_locale => {
+                    return Err(
+                        field_error("accept_language", "locale detection is not enabled").into(),
+                    );
+                }
+                (None, Some(hint)) => templates::preferred_locales(&hint),
+                (None, None) => Vec::new(),
+            };
+            render_template(state, name, &locales, &req.data)?
+        }
+        None => decode_body(&req.body, req.body_encoding)?,
+    };
+    let html = req.html.filter(|html| !html.trim().is_empty());
+    let text = match (body.trim().is_empty(), &html) {
+        (false, _) => Some(body),
+        (true, Some(html)) if state.auto_text_part => {
+            Some(html_text::from_html(html, state.clean_text_links))
+        }
+        (true, Some(_)) => None,
+        (true, None) => match &state.defaults {
+            Some(defaults) => Some(defaults.body.clone()),
+            None => return Err(field_error("body", "body cannot be empty").into()),
+        },
+    };
+    // Wrapped before tracking so links in the wrapper are tracked too.
+    let (text, html) = match &state.body_wrapper {
+        Some(wrapper) if !req.skip_wrapper => (
+            text.map(|text| wrapper.wrap_text(&text)),
+            html.map(|html| wrapper.wrap_html(&html)),
+        ),
+        _ => (text, html),
+    };
+    let text = if state.normalize_crlf {
+        text.map(|text| normalize_crlf(&text))
+    } else {
+        text
+    };
+    let text = if state.trailing_crlf {
+        text.map(|text| format!("{}\r\n", text.trim_end_matches(['\r', '\n'])))
+    } else {
+        text
+    };
+    let calendar = match req.calendar {
+        Some(calendar) => Some(calendar_part(calendar)?),
+        None => None,
+    };
+    let set_date = req.set_date.unwrap_or(true);
+    let date = match req.date.as_deref().map(str::trim) {
+        Some(_) if !set_date => {
+            return Err(field_error("date", "date cannot be combined with set_date=false").into())
+        }
+        Some(date) => match chrono::DateTime::parse_from_rfc2822(date) {
+            Ok(date) => Some(date.to_rfc2822()),
+            Err(_) => return Err(field_error("date", "date must be an RFC 2822 timestamp").into()),
+        },
+        None => None,
+    };
+    let expires_at = match req.expires_at.as_deref().map(str::trim) {
+        Some(expires_at) => match chrono::DateTime::parse_from_rfc2822(expires_at) {
+            Ok(expires_at) if expires_at > chrono::Utc::now() => Some(expires_at.to_rfc2822()),
+            Ok(_) => {
+                return Err(field_error("expires_at", "expires_at must be in the future").into())
+            }
+            Err(_) => {
+                return Err(
+                    field_error("expires_at", "expires_at must be an RFC 2822 timestamp").into(),
+                )
+            }
+        },
+        None => None,
+    };
+    if req.ttl_secs == Some(0) {
+        return Err(field_error("ttl_secs", "ttl_secs must be at least 1").into());
+    }
+    let expires_at = match (expires_at, req.ttl_secs) {
+        (None, Some(ttl)) => {
+            let expiry = i64::try_from(ttl)
+                .ok()
+                .and_then(chrono::Duration::try_seconds)
+                .and_then(|ttl| chrono::Utc::now().checked_add_signed(ttl));
+            match expiry {
+                Some(expiry) => Some(expiry.to_rfc2822()),
+                None => return Err(field_error("ttl_secs", "ttl_secs is too large").into()),
+            }
+        }
+        (expires_at, _) => expires_at,
+    };
+
+    // Tracking only touches the HTML part; the text part stays clean.
+    let mut tracking_id = None;
+    let html = match (&state.tracking, html) {
+        (Some(tracking), Some(mut html)) if req.track_opens || req.track_clicks => {
+            let id = tracking_id.insert(Tracking::new_message_id());
+            if req.track_clicks {
+                html = tracking.rewrite_links(&html, id);
+            }
+            if req.track_opens {
+                html = tracking.inject_open_pixel(&html, id);
+            }
+            Some(html)
+        }
+        (_, html) => html,
+    };
+
+    let to = parse_recipients(&state.groups, &req.to, "to", "invalid recipient email")?;
+    let cc = parse_recipients(
+        &state.groups,
+        req.cc.as_deref().unwrap_or_default(),
+        "cc",
+        "invalid cc email",
+    )?;
+    let bcc = parse_recipients(
+        &state.groups,
+        req.bcc.as_deref().unwrap_or_default(),
+        "bcc",
+        "invalid bcc email",
+    )?;
+    let (to, cc, bcc) = match state.dedupe_recipients {
+        true => dedupe_recipients(to, cc, bcc),
+        false => (to, cc, bcc),
+    };
+
+    let recipient_count = to.iter().count() + cc.iter().count() + bcc.iter().count();
+    if recipient_count == 0 {
+        return Err(
+            field_error("to", "at least one recipient is required in to, cc or bcc").into(),
+        );
+    }
+    if recipient_count > state.max_recipients {
+        return Err(error_response(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            &format!(
+                "too many recipients ({recipient_count} > {})",
+                state.max_recipients
+            ),
+        )
+        .into());
+    }
+
+    if let Some(domain) = to
+        .iter()
+        .chain(cc.iter())
+        .chain(bcc.iter())
+        .map(|mailbox| mailbox.email.domain())
+        .find(|domain| !policy.allows_domain(domain))
+    {
+        return Err(error_response(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            &format!("recipient domain not permitted: {domain}"),
+        )
+        .into());
+    }
+    if let Some((address, reason)) =
+        to.iter()
+            .chain(cc.iter())
+            .chain(bcc.iter())
+            .find_map(|mailbox| {
+                let reason = state.recipient_filter.rejects(&mailbox.email)?;
+                Some((&mailbox.email, reason))
+            })
+    {
+        return Err(error_response(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            &format!("recipient rejected ({reason}): {address}"),
+        )
+        .into());
+    }
+    let suppressed: Vec<Address> = to
+        .iter()
+        .chain(cc.iter())
+        .chain(bcc.iter())
+        .filter(|mailbox| state.recipient_filter.is_suppressed(&mailbox.email))
+        .map(|mailbox| mailbox.email.clone())
+        .collect();
+    // The flag is ignored for keys not allowed to use it.
+    let bypassed = match suppressed.first() {
+        Some(_) if req.bypass_suppression && policy.bypass_suppression => suppressed,
+        Some(address) => {
+            return Err(error_response(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                &format!("recipient rejected (suppressed): {address}"),
+            )
+            .into())
+        }
+        None => Vec::new(),
+    };
+    // Checked against the real recipients above so staging rejects what
+    // production would.
+    let (to, cc, bcc, original_to) = match &state.redirect_all_to {
+        Some(address) => {
+            let original = to
+                .iter()
+                .chain(cc.iter())
+                .chain(bcc.iter())
+                .map(|mailbox| mailbox.email.to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            let redirected = Mailboxes::from(Mailbox::new(None, address.clone()));
+            (
+                redirected,
+                Mailboxes::new(),
+                Mailboxes::new(),
+                Some(original),
+            )
+        }
+        None => (to, cc, bcc, None),
+    };
+    let encrypt_to = match (&state.pgp, req.encrypt) {
+        (_, false) => None,
+        (None, true) => return Err(field_error("encrypt", "encryption is not configured").into()),
+        (Some(pgp), true) => {
+            let recipients: Vec<Address> = to
+                .iter()
+                .chain(cc.iter())
+                .chain(bcc.iter())
+                .map(|mailbox| mailbox.email.clone())
+                .collect();
+            match pgp.missing(&recipients) {
+                None => Some(recipients),
+                Some(address) if pgp.missing_key == MissingKey::Fail => {
+                    return Err(unprocessable_field(
+                        "encrypt",
+                        &format!("no pgp key for {address}"),
+                    )
+                    .into())
+                }
+                Some(address) => {
+                    warn!(%address, "no pgp key for recipient, sending unencrypted");
+                    None
+                }
+            }
+        }
+    };
+
+    // Borrowed until the From header is built, which needs its own copy.
+    let from = policy.from.as_ref().unwrap_or(&state.from);
+    let named_from = rename_from(
+        from,
+        req.sender_name.as_deref(),
+        state.sender_name.as_deref(),
+    );
+    let from = named_from.as_ref().unwrap_or(from);
+    let primary = from.iter().next().expect("from addresses are never empty");
+    let sender = match req.sender.as_deref().map(str::trim) {
+        Some(sender) => Some(
+            Mailbox::from_str(sender).map_err(|_| field_error("sender", "invalid sender email"))?,
+        ),
+        None if from.iter().nth(1).is_some() => Some(primary.clone()),
+        None => None,
+    };
+    if !state.from_allowed_domains.is_empty()
+        && policy
+            .from
+            .iter()
+            .flat_map(Mailboxes::iter)
+            .chain(sender.iter())
+            .any(|mailbox| {
+                let domain = mailbox.email.domain().to_ascii_lowercase();
+                !state.from_allowed_domains.contains(&domain)
+            })
+    {
+        return Err(forbidden("from domain not permitted").into());
+    }
+    // The sender, when set, is the address that bounces go back to.
+    let originator = sender.as_ref().unwrap_or(primary).email.clone();
+    let list = match req.list_id.as_deref().map(str::trim) {
+        Some(id) if !is_list_id(id) => {
+            return Err(field_error(
+                "list_id",
+                "list_id must be a dotted identifier such as alerts.example.com",
+            )
+            .into())
+        }
+        Some(id) => {
+            let post = match req.list_post.as_deref().map(str::trim) {
+                Some(post) => Address::from_str(post)
+                    .map_err(|_| field_error("list_post", "invalid list_post email"))?,
+                None => primary.email.clone(),
+            };
+            Some((id.to_string(), post))
+        }
+        None if req.list_post.is_some() => {
+            return Err(field_error("list_post", "list_post requires list_id").into())
+        }
+        None => None,
+    };
+
+    let mut builder = Message::builder().mailbox(header::From::from(from.clone()));
+    if let Some(original) = original_to {
+        builder = builder.raw_header(header::HeaderValue::new(
+            header::HeaderName::new_from_ascii_str("X-Original-To"),
+            original,
+        ));
+    }
+    if let Some(sender) = sender {
+        builder = builder.sender(sender);
+    }
+    if let Some(expires_at) = expires_at {
+        builder = builder.raw_header(header::HeaderValue::new(
+            header::HeaderName::new_from_ascii_str("Expiry-Date"),
+            expires_at,
+        ));
+    }
+    if let Some(ttl) = req.ttl_secs {
+        builder = builder.raw_header(header::HeaderValue::new(
+            header::HeaderName::new_from_ascii_str("X-Message-TTL"),
+            ttl.to_string(),
+        ));
+    }
+    if let Some((id, post)) = list {
+        builder = builder
+            .raw_header(header::HeaderValue::new(
+                header::HeaderName::new_from_ascii_str("List-Id"),
+                format!("<{id}>"),
+            ))
+            .raw_header(header::HeaderValue::new(
+                header::HeaderName::new_from_ascii_str("List-Post"),
+                format!("<mailto:{post}>"),
+            ));
+    }
+    // Set here rather than left to the relay so it can be returned to the
+    // caller. Reusing the tracking id lets opens be matched to the send log.
+    let id = tracking_id.unwrap_or_else(|| format!("{:032x}", rand::random::<u128>()));
+    builder = builder.message_id(Some(format!("<{id}@{}>", primary.email.domain())));
+    // Pinned rather than derived from the headers so a placeholder To is
+    // never treated as a recipient and the archive copy appears in none.
+    let mut recipients: Vec<Address> = to
+        .iter()
+        .chain(cc.iter())
+        .chain(bcc.iter())
+        .map(|m| m.email.clone())
+        .collect();
+    let mut hidden = Vec::new();
+    if let Some(archive) = &state.archive_bcc {
+        if !recipients.contains(archive) {
+            recipients.push(archive.clone());
+            hidden.push(archive.clone());
+        }
+    }
+    if req.copy_sender && state.redirect_all_to.is_none() && !recipients.contains(&primary.email) {
+        recipients.push(primary.email.clone());
+        hidden.push(primary.email.clone());
+    }
+    let envelope = Envelope::new(Some(originator), recipients).map_err(|err| {
+        error!(error = %err, "failed to build envelope");
+        error_response(StatusCode::BAD_REQUEST, "invalid email payload")
+    })?;
+    builder = builder.envelope(envelope);
+    if to.iter().next().is_some() {
+        builder = builder.mailbox(header::To::from(to));
+    } else {
+        // Only Cc/Bcc recipients: show a placeholder To.
+        builder = match state.undisclosed_to {
+            UndisclosedTo::Group => builder.raw_header(header::HeaderValue::new(
+                header::HeaderName::new_from_ascii_str("To"),
+                "undisclosed-recipients:;".to_string(),
+            )),
+            UndisclosedTo::From => builder.to(primary.clone()),
+        };
+    }
+    if cc.iter().next().is_some() {
+        builder = builder.mailbox(header::Cc::from(cc));
+    }
+    if bcc.iter().next().is_some() {
+        builder = builder.mailbox(header::Bcc::from(bcc));
+    }
+
+    builder = match (req.subject_encoding, fold_subject) {
+        (SubjectEncoding::Auto, false) => builder.subject(req.title),
+        (SubjectEncoding::Auto | SubjectEncoding::Base64, _) => {
+            builder.raw_header(subject::folded_base64_subject(&req.title))
+        }
+        (SubjectEncoding::QuotedPrintable, _) => {
+            builder.raw_header(subject::folded_q_subject(&req.title))
+        }
+    };
+
+    let (related, attachments): (Vec<_>, Vec<_>) = req
+        .attachments
+        .into_iter()
+        .partition(AttachmentRequest::is_related);
+    let into_parts = |attachments: Vec<AttachmentRequest>| {
+        attachments
+            .into_iter()
+            .map(|attachment| attachment.into_part(state.sniff_attachments))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|message| ApiError::from(field_error("attachments", &message)))
+    };
+    let related = into_parts(related)?;
+    let attachments = into_parts(attachments)?;
+    if let Some(multipart_type) = req.multipart_type {
+        let bodies = [text.is_some(), html.is_some(), calendar.is_some()]
+            .into_iter()
+            .filter(|&present| present)
+            .count();
+        check_multipart_type(
+            multipart_type,
+            bodies,
+            html.is_some(),
+            !related.is_empty(),
+            !attachments.is_empty(),
+            encrypt_to.is_some(),
+        )
+        .map_err(|message| field_error("multipart_type", message))?;
+    }
+
+    let encoding = req.content_transfer_encoding.pinned();
+    let plain = attachments.is_empty() && related.is_empty() && req.multipart_type.is_none();
+    let content = match (text, html, calendar) {
+        (Some(text), None, None) if plain && encoding.is_none() => MessageBody::Text(text),
+        (Some(text), None, None) if plain => {
+            MessageBody::Single(body_part(ContentType::TEXT_PLAIN, text, encoding)?)
+        }
+        (None, Some(html), None) if plain => {
+            MessageBody::Single(body_part(ContentType::TEXT_HTML, html, encoding)?)
+        }
+        (text, html, calendar) => {
+            let text = text
+                .map(|text| body_part(ContentType::TEXT_PLAIN, text, encoding))
+                .transpose()?;
+            let html = html
+                .map(|html| body_part(ContentType::TEXT_HTML, html, encoding))
+                .transpose()?;
+            // Least to most preferred, as multipart/alternative requires.
+            let mut parts = text.into_iter().chain(html).chain(calendar).peekable();
+            let first = parts.next().expect("text or html is always present");
+            let mut body = if parts.peek().is_none() {
+                // A lone body part needs no multipart/alternative wrapper.
+                MessageBody::Single(first)
+            } else {
+                MessageBody::Multi(
+                    parts.fold(
+                        MultiPart::alternative()
+                            .boundary((state.boundary)())
+                            .singlepart(first),
+                        MultiPart::singlepart,
+                    ),
+                )
+            };
+            if !related.is_empty() {
+                // Prefixed so a fixed MULTIPART_BOUNDARY still differs from
+                // the nested parts'.
+                let wrapper =
+                    MultiPart::related().boundary(format!("related-{}", (state.boundary)()));
+                body = MessageBody::Multi(with_attachments(body.nest(wrapper), related));
+            }
+            if attachments.is_empty() && req.multipart_type != Some(MultipartType::Mixed) {
+                body
+            } else {
+                let mixed = match body {
+                    MessageBody::Single(_) => MultiPart::mixed().boundary((state.boundary)()),
+                    _ => MultiPart::mixed().boundary(format!("mixed-{}", (state.boundary)())),
+                };
+                MessageBody::Multi(with_attachments(body.nest(mixed), attachments))
+            }
+        }
+    };
+    let content = match (&state.pgp, encrypt_to) {
+        (Some(pgp), Some(recipients)) => {
+            let entity = match content {
+                MessageBody::Text(text) => SinglePart::plain(text).formatted(),
+                MessageBody::Single(part) => part.formatted(),
+                MessageBody::Multi(multipart) => multipart.formatted(),
+            };
+            let encrypted = pgp
+                .encrypt(&recipients, &entity, (state.boundary)())
+                .map_err(|err| {
+                    error!(error = %format!("{err:#}"), "failed to encrypt message");
+                    field_error("encrypt", &format!("encryption failed: {err}"))
+                })?;
+            MessageBody::Multi(encrypted)
+        }
+        _ => content,
+    };
+    let built = match content {
+        MessageBody::Text(text) => builder.body(text),
+        MessageBody::Single(part) => builder.singlepart(part),
+        MessageBody::Multi(multipart) => builder.multipart(multipart),
+    };
+    let mut email = built.map_err(|err| {
+        error!(error = %err, "failed to build message");
+        error_response(StatusCode::BAD_REQUEST, "invalid email payload")
+    })?;
+    // lettre always stamps a Date on build, so suppressing or overriding it
+    // has to happen afterwards (and before DKIM covers the headers).
+    if !set_date || date.is_some() {
+        email.headers_mut().remove::<header::Date>();
+    }
+    if let Some(date) = date {
+        // Inserted raw to keep the caller's UTC offset; lettre's typed Date
+        // header only renders GMT.
+        email.headers_mut().insert_raw(header::HeaderValue::new(
+            header::HeaderName::new_from_ascii_str("Date"),
+            date,
+        ));
+    }
+    check_header_block(&state.limits, &email)?;
+    if let Some(dkim) = &state.dkim {
+        dkim.sign(&mut email);
+    }
+    Ok(SmtpEmail {
+        message: email,
+        hidden,
+        bypassed,
+    })
+}
+
+/// Enforces `MAX_HEADERS` and `MAX_HEADER_BYTES` on the finished header
+/// block, so a message some servers would refuse outright fails here with a
+/// 400 instead. The DKIM signature is added afterwards and not counted.
+fn check_header_block(limits: &FieldLimits, email: &Message) -> Result<(), ApiError> {
+    if limits.max_headers == 0 && limits.max_header_bytes == 0 {
+        return Ok(());
+    }
+    // Formatted in full since the top-level MIME headers come from the body.
+    let formatted = email.formatted();
+    let formatted = String::from_utf8_lossy(&formatted);
+    let block = formatted
+        .split_once("\r\n\r\n")
+        .map_or(&*formatted, |(headers, _)| headers);
+    // Folded continuation lines start with whitespace.
+    let count = block
+        .split("\r\n")
+        .filter(|line| !line.is_empty() && !line.starts_with([' ', '\t']))
+        .count();
+    if limits.max_headers > 0 && count > limits.max_headers {
+        return Err(error_response(
+            StatusCode::BAD_REQUEST,
+            &format!(
+                "message has {count} header fields, more than {}",
+                limits.max_headers
+            ),
+        )
+        .into());
+    }
+    if limits.max_header_bytes > 0 && block.len() > limits.max_header_bytes {
+        return Err(error_response(
+            StatusCode::BAD_REQUEST,
+            &format!(
+                "message headers are {} bytes, more than {}",
+                block.len(),
+                limits.max_header_bytes
+            ),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// The assembled body, kept apart from the headers until it is known whether
+/// it gets encrypted.
+enum MessageBody {
+    Text(String),
+    Single(SinglePart),
+    Multi(MultiPart),
+}
+
+impl MessageBody {
+    /// Makes the body the first part of `wrapper`.
+    fn nest(self, wrapper: MultiPartBuilder) -> MultiPart {
+        match self {
+            MessageBody::Text(text) => wrapper.singlepart(SinglePart::plain(text)),
+            MessageBody::Single(part) => wrapper.singlepart(part),
+            MessageBody::Multi(multipart) => wrapper.multipart(multipart),
+        }
+    }
+}
+
+/// A text or HTML body part, in `encoding` when pinned.
+fn body_part(
+    content_type: ContentType,
+    content: String,
+    encoding: Option<ContentTransferEncoding>,
+) -> Result<SinglePart, ApiError> {
+    let part = SinglePart::builder().header(content_type);
+    let Some(encoding) = encoding else {
+        return Ok(part.body(content));
+    };
+    let body = Body::new_with_encoding(content, encoding).map_err(|_| {
+        field_error(
+            "content_transfer_encoding",
+            &format!("body cannot be sent as {encoding}"),
+        )
+    })?;
+    Ok(part.body(body))
+}
+
+/// Whether the parts fit under a pinned top-level `multipart_type`:
+/// `bodies` counts the text, HTML and calendar parts, `related` and
+/// `attachments` whether there are inline `cid` and other attachments.
+fn check_multipart_type(
+    multipart_type: MultipartType,
+    bodies: usize,
+    html: bool,
+    related: bool,
+    attachments: bool,
+    encrypted: bool,
+) -> Result<(), &'static str> {
+    if encrypted {
+        return Err("multipart_type cannot be combined with encrypt");
+    }
+    match multipart_type {
+        MultipartType::Mixed => Ok(()),
+        MultipartType::Related if !html || !related => {
+            Err("multipart_type related needs html and an inline attachment with a cid")
+        }
+        MultipartType::Related if attachments => {
+            Err("multipart_type related cannot hold non-inline attachments")
+        }
+        MultipartType::Related => Ok(()),
+        MultipartType::Alternative if bodies < 2 => {
+            Err("multipart_type alternative needs at least two of body, html and calendar")
+        }
+        MultipartType::Alternative if related || attachments => {
+            Err("multipart_type alternative cannot hold attachments")
+        }
+        MultipartType::Alternative => Ok(()),
+    }
+}
+
+/// Appends attachment parts after the body in a multipart/mixed.
+fn with_attachments(mixed: MultiPart, attachments: Vec<SinglePart>) -> MultiPart {
+    attachments.into_iter().fold(mixed, MultiPart::singlepart)
+}
+
+/// RFC 2919 list id: a dot-atom with at least two labels, e.g.
+/// `alerts.example.com`.
+fn is_list_id(id: &str) -> bool {
+    id.len() <= 255
+        && id.contains('.')
+        && id.split('.').all(|label| {
+            !label.is_empty()
+                && label.bytes().all(|byte| {
+                    byte.is_ascii_alphanumeric() || b"!#$%&'*+-/=?^_`{|}~".contains(&byte)
+                })
+        })
+}
+
+/// Validates a calendar invite and renders it as a `text/calendar` part.
+fn calendar_part(calendar: CalendarInvite) -> Result<SinglePart, ApiError> {
+    if calendar.ics.trim().is_empty() {
+        return Err(field_error("calendar", "calendar ics cannot be empty").into());
+    }
+    let method = calendar.method.trim().to_ascii_uppercase();
+    if !CALENDAR_METHODS.contains(&method.as_str()) {
+        return Err(field_error(
+            "calendar",
+            &format!("unsupported calendar method: {}", calendar.method),
+        )
+        .into());
+    }
+
+    let content_type =
+        header::ContentType::parse(&format!("text/calendar; method={method}; charset=utf-8"))
+            .expect("calendar content type is well-formed");
+    Ok(SinglePart::builder()
+        .header(content_type)
+        .body(calendar.ics))
+}
+
+fn render_template(
+    state: &AppState,
+    name: &str,
+    locales: &[String],
+    data: &serde_json::Value,
+) -> Result<String, ApiError> {
+    let rendered = match &state.templates {
+        Some(templates) => templates.render(name, locales, data),
+        None => Err(TemplateError::NotFound(name.to_string())),
+    };
+
+    rendered.map_err(|err| match err {
+        TemplateError::NotFound(_) => {
+            error_response(StatusCode::NOT_FOUND, &err.to_string()).into()
+        }
+        TemplateError::Render(_) => field_error("data", &err.to_string()).into(),
+    })
+}
+
+fn decode_body(body: &str, encoding: BodyEncoding) -> Result<String, ApiError> {
+    match encoding {
+        BodyEncoding::Raw => Ok(body.to_string()),
+        BodyEncoding::Base64 => {
+            let bytes = BASE64_STANDARD
+                .decode(body.trim())
+                .map_err(|_| field_error("body", "body is not valid base64"))?;
+            String::from_utf8(bytes)
+                .map_err(|_| field_error("body", "decoded body is not valid UTF-8").into())
+        }
+    }
+}
+
+/// Rewrites every line ending, bare `\r`, bare `\n` or `\r\n`, as `\r\n`.
+/// Lettre only fixes up bare `\n`, and strict servers refuse a lone `\r`.
+fn normalize_crlf(text: &str) -> String {
+    let mut normalized = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\r' => {
+                chars.next_if_eq(&'\n');
+                normalized.push_str("\r\n");
+            }
+            '\n' => normalized.push_str("\r\n"),
+            c => normalized.push(c),
+        }
+    }
+    normalized
+}
+
+/// Drops repeats of an address, compared case-insensitively, so it gets one
+/// copy. The first occurrence in the most visible header wins: To, then Cc,
+/// then Bcc.
+fn dedupe_recipients(
+    to: Mailboxes,
+    cc: Mailboxes,
+    bcc: Mailboxes,
+) -> (Mailboxes, Mailboxes, Mailboxes) {
+    let mut seen = HashSet::new();
+    let mut keep = |mailboxes: Mailboxes| -> Mailboxes {
+        mailboxes
+            .into_iter()
+            .filter(|mailbox| seen.insert(mailbox.email.to_string().to_ascii_lowercase()))
+            .collect()
+    };
+    let to = keep(to);
+    let cc = keep(cc);
+    let bcc = keep(bcc);
+    (to, cc, bcc)
+}
+
+/// Parses a comma-separated recipient list, expanding `group:<name>` entries.
+fn parse_recipients(
+    groups: &Groups,
+    raw: &str,
+    field: &'static str,
+    invalid_message: &str,
+) -> Result<Mailboxes, ApiError> {
+    let entries = groups
+        .expand(split_recipients(raw))
+        .map_err(|err| error_response(StatusCode::NOT_FOUND, &err.to_string()))?;
+
+    entries
+        .iter()
+        .map(|entry| Mailbox::from_str(entry))
+        .collect::<Result<Mailboxes, _>>()
+        .map_err(|_| field_error(field, invalid_message).into())
+}
+
+/// Splits on commas that are not inside a quoted display name.
+fn split_recipients(raw: &str) -> Vec<String> {
+    let mut entries = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for ch in raw.chars() {
+        match ch {
+            _ if escaped => {
+                escaped = false;
+                current.push(ch);
+            }
+            '\\' if quoted => {
+                escaped = true;
+                current.push(ch);
+            }
+            '"' => {
+                quoted = !quoted;
+                current.push(ch);
+            }
+            ',' if !quoted => entries.push(std::mem::take(&mut current)),
+            _ => current.push(ch),
+        }
+    }
+    entries.push(current);
+
+    entries
+        .into_iter()
+        .map(|entry| entry.trim().to_string())
+        .filter(|entry| !entry.is_empty())
+        .collect()
+}
+
+/// A recipient given as a string or as a `{ name, address }` object, or a
+/// list mixing both. `List` is tried before `One`, as serde would otherwise
+/// read a list of two strings as a name and an address.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RecipientsInput {
+    Raw(String),
+    List(Vec<RecipientsInput>),
+    One(RecipientObject),
+}
+
+#[derive(Deserialize)]
+struct RecipientObject {
+    #[serde(default)]
+    name: Option<String>,
+    address: String,
+}
+
+/// Accepts `to` in string, object or list form and stores it as the string
+/// form. Objects are rendered by lettre, which quotes the name as needed, so
+/// a name like `Doe, "JJ"` needs no escaping from the client.
+fn deserialize_recipients<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<String, D::Error> {
+    let mut entries = Vec::new();
+    flatten_recipients(RecipientsInput::deserialize(deserializer)?, &mut entries)
+        .map_err(serde::de::Error::custom)?;
+    Ok(entries.join(", "))
+}
+
+/// [`deserialize_recipients`] for `cc` and `bcc`, which may be null.
+fn deserialize_optional_recipients<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    let Some(input) = Option::<RecipientsInput>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    let mut entries = Vec::new();
+    flatten_recipients(input, &mut entries).map_err(serde::de::Error::custom)?;
+    Ok(Some(entries.join(", ")))
+}
+
+fn flatten_recipients(input: RecipientsInput, out: &mut Vec<String>) -> Result<(), String> {
+    match input {
+        RecipientsInput::Raw(raw) => out.push(raw),
+        RecipientsInput::One(recipient) => {
+            let address = recipient
+                .address
+                .trim()
+                .parse::<Address>()
+                .map_err(|_| format!("invalid recipient address: {}", recipient.address))?;
+            let name = recipient.name.filter(|name| !name.trim().is_empty());
+            out.push(Mailbox::new(name, address).to_string());
+        }
+        RecipientsInput::List(list) => {
+            for input in list {
+                flatten_recipients(input, out)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn authenticate<'a>(state: &AppState, headers: &'a HeaderMap) -> Option<Caller<'a>> {
+    state.api_keys.lookup(extract_api_key(headers)?).await
+}
+
+/// [`authenticate`] for the admin endpoints that act on the whole server:
+/// 401 for an unknown key, 403 for a key without `admin`.
+async fn authenticate_admin<'a>(
+    state: &AppState,
+    headers: &'a HeaderMap,
+) -> Result<Caller<'a>, ApiError> {
+    match authenticate(state, headers).await {
+        Some(caller) if caller.policy.admin => Ok(caller),
+        Some(_) => Err(forbidden("admin key required").into()),
+        None => Err(error_response(StatusCode::UNAUTHORIZED, "invalid api key").into()),
+    }
+}
+
+fn extract_api_key(headers: &HeaderMap) -> Option<&str> {
+    if let Some(value) = headers.get("x-api-key").and_then(|v| v.to_str().ok()) {
+        return Some(value);
+    }
+
+    let auth = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    auth.strip_prefix("Bearer ")
+        .or_else(|| auth.strip_prefix("bearer "))
+}
+
+fn build_mailer(cfg: &SmtpConfig) -> Result<AsyncSmtpTransport<Tokio1Executor>> {
+    let tls = match cfg.security {
+        SmtpSecurity::Tls => {
+            Tls::Wrapper(tls_parameters(cfg).context("failed to create TLS SMTP transport")?)
+        }
+        SmtpSecurity::StartTls => {
+            Tls::Required(tls_parameters(cfg).context("failed to create STARTTLS SMTP transport")?)
+        }
+        SmtpSecurity::None => Tls::None,
+    };
+    let mut builder = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&cfg.host)
+        .port(cfg.port)
+        .tls(tls);
+    if let Some(credentials) = cfg.credentials.clone() {
+        builder = builder.credentials(credentials);
+    }
+    if let Some(timeout) = cfg.connect_timeout {
+        builder = builder.timeout(Some(timeout));
+    }
+
+    if let Some(max) = cfg.max_connections {
+        // Keep as many idle sessions as may be open at once.
+        builder =
+            builder.pool_config(PoolConfig::new().max_size(u32::try_from(max).unwrap_or(u32::MAX)));
+    }
+
+    // An empty list keeps lettre's default mechanism negotiation.
+    if !cfg.auth_mechanisms.is_empty() {
+        builder = builder.authentication(cfg.auth_mechanisms.clone());
+    }
+
+    // lettre waits for each reply before sending the next command whatever
+    // the server advertises, so it has no pipelining to switch off; the flag
+    // holds without further setup, on the bound transport too.
+    if cfg.disable_pipelining {
+        info!(host = %cfg.host, "smtp pipelining disabled, each command waits for its reply");
+    }
+
+    Ok(builder.build())
+}
+
+/// TLS settings for `cfg.host`; handshakes below `SMTP_MIN_TLS_VERSION`
+/// fail.
+fn tls_parameters(cfg: &SmtpConfig) -> Result<TlsParameters, lettre::transport::smtp::Error> {
+    TlsParameters::builder(cfg.host.clone())
+        .set_min_tls_version(cfg.min_tls_version)
+        .build()
+}
+
+/// The transport for one SMTP server, plus its pool handle when pooled.
+type SmtpBackend = (
+    Box<dyn Transport>,
+    Option<AsyncSmtpTransport<Tokio1Executor>>,
+);
+
+fn build_smtp_transport(cfg: &SmtpConfig) -> Result<SmtpBackend> {
+    if !cfg.sending_pool.is_empty() {
+        let mut members = Vec::with_capacity(cfg.sending_pool.len());
+        for &(addr, weight) in &cfg.sending_pool {
+            let member_cfg = SmtpConfig {
+                local_bind: Some(addr),
+                sending_pool: Vec::new(),
+                ..cfg.clone()
+            };
+            let (transport, _) = build_smtp_transport(&member_cfg)?;
+            members.push(PoolMember {
+                addr,
+                weight,
+                transport,
+            });
+        }
+        info!(host = %cfg.host, addresses = members.len(), policy = ?cfg.pool_policy, "smtp sending pool enabled");
+        return Ok((Box::new(PoolTransport::new(members, cfg.pool_policy)), None));
+    }
+    let (transport, mailer): (Box<dyn Transport>, _) =
+        if cfg.local_bind.is_some() || cfg.chunking || cfg.partial_rcpt {
+            (Box::new(build_bound_transport(cfg)?), None)
+        } else {
+            let mailer_cfg = cfg.clone();
+            let transport = SmtpTransport::new(
+                Box::new(move || build_mailer(&mailer_cfg)),
+                cfg.send_timeout,
+            )?;
+            let mailer = transport.mailer();
+            (Box::new(transport), Some(mailer))
+        };
+    let transport = match cfg.max_connections {
+        Some(max) => Box::new(ConnectionLimit {
+            inner: transport,
+            permits: tokio::sync::Semaphore::new(max),
+        }),
+        None => transport,
+    };
+    let transport = match cfg.debug {
+        true => Box::new(DialogLog {
+            host: cfg.host.clone(),
+            inner: transport,
+        }),
+        false => transport,
+    };
+    Ok((transport, mailer))
+}
+
+fn build_bound_transport(cfg: &SmtpConfig) -> Result<BoundSmtpTransport> {
+    if let Some(local_addr) = cfg.local_bind {
+        // Fail at startup rather than on the first send when the address is
+        // not assigned to this host.
+        std::net::TcpListener::bind((local_addr, 0))
+            .with_context(|| format!("source address {local_addr} is not a local address"))?;
+        info!(%local_addr, "smtp connections bound to local address");
+    }
+    if cfg.chunking {
+        info!("smtp messages sent with BDAT where the server offers CHUNKING");
+    }
+    if cfg.warmup {
+        warn!("SMTP_WARMUP is ignored with SMTP_LOCAL_BIND_ADDR, SENDING_POOL, SMTP_USE_CHUNKING or SMTP_ACCEPT_PARTIAL_RCPT, connections are not pooled");
+    }
+
+    let tls = (cfg.security != SmtpSecurity::None)
+        .then(|| tls_parameters(cfg))
+        .transpose()
+        .context("failed to create TLS SMTP transport")?;
+    let mechanisms = if cfg.auth_mechanisms.is_empty() {
+        DEFAULT_MECHANISMS.to_vec()
+    } else {
+        cfg.auth_mechanisms.clone()
+    };
+
+    Ok(BoundSmtpTransport {
+        host: cfg.host.clone(),
+        port: cfg.port,
+        tls,
+        starttls: cfg.security == SmtpSecurity::StartTls,
+        credentials: cfg.credentials.clone(),
+        mechanisms,
+        local_addr: cfg.local_bind,
+        chunking: cfg.chunking,
+        partial_rcpt: cfg.partial_rcpt,
+        connect_timeout: cfg.connect_timeout,
+        send_timeout: cfg.send_timeout,
+    })
+}
+
+/// Opens `count` pooled connections up front so the first real sends skip
+/// the TCP/TLS handshake, then marks the server ready. Failed connections
+/// are logged; if none succeeds, a single connection is retried with
+/// backoff until one does.
+async fn warm_up_pool(
+    state: Arc<AppState>,
+    mailer: AsyncSmtpTransport<Tokio1Executor>,
+    count: usize,
+) {
+    // Concurrent checks each hold a distinct connection, which the pool
+    // parks once the check completes.
+    let results = futures::future::join_all((0..count).map(|_| mailer.test_connection())).await;
+
+    let mut ready = 0;
+    for result in results {
+        match result {
+            Ok(true) => ready += 1,
+            Ok(false) => warn!("smtp warmup connection did not respond to NOOP"),
+            Err(err) => warn!(error = %err, "smtp warmup connection failed"),
+        }
+    }
+    if ready == count {
+        info!(connections = ready, "smtp connection pool warmed up");
+    } else {
+        warn!(
+            ready,
+            requested = count,
+            "smtp connection pool warmup incomplete"
+        );
+    }
+
+    let mut delay = Duration::from_secs(1);
+    while ready == 0 {
+        tokio::time::sleep(delay).await;
+        match mailer.test_connection().await {
+            Ok(true) => {
+                info!("smtp warmup connection established");
+                ready = 1;
+            }
+            Ok(false) => warn!("smtp warmup connection did not respond to NOOP"),
+            Err(err) => warn!(error = %err, "smtp warmup connection failed"),
+        }
+        delay = (delay * 2).min(Duration::from_secs(30));
+    }
+    state.ready.store(true, Ordering::Relaxed);
+}
+
+impl Config {
+    fn from_env() -> Result<Self> {
+        let smtp_from_raw = must_env("SMTP_FROM")?;
+        let smtp_from = parse_mailbox_list(&smtp_from_raw)
+            .context("SMTP_FROM is not a valid list of email addresses")?;
+        let Some(primary_from) = smtp_from.iter().next() else {
+            anyhow::bail!("SMTP_FROM must contain at least one address");
+        };
+
+        let backend = match env::var("BACKEND").as_deref() {
+            Err(_) | Ok("smtp") => BackendConfig::Smtp(Box::new(SmtpConfig::from_env()?)),
+            Ok("memory") => BackendConfig::Memory,
+            Ok("ses") => BackendConfig::Ses {
+                region: env::var("SES_REGION").ok(),
+                smtp_fallback: if parse_bool_env("SES_SMTP_FALLBACK").unwrap_or(false) {
+                    Some(Box::new(SmtpConfig::from_env()?))
+                } else {
+                    None
+                },
+            },
+            Ok(other) => anyhow::bail!("unsupported BACKEND: {other}"),
+        };
+        let enabled_channels = match env::var("ENABLED_CHANNELS") {
+            Ok(raw) => parse_enabled_channels(&raw)?,
+            Err(_) => vec![NotificationService::Smtp, NotificationService::Slack],
+        };
+        let environment = env::var("ENVIRONMENT")
+            .ok()
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty());
+        if let Some(environment) = &environment {
+            match &backend {
+                BackendConfig::Smtp(smtp)
+                | BackendConfig::Ses {
+                    smtp_fallback: Some(smtp),
+                    ..
+                } => check_smtp_hosts(environment, smtp)?,
+                BackendConfig::Ses { .. } | BackendConfig::Memory => {}
+            }
+        }
+
+        let max_recipients = parse_env("MAX_RECIPIENTS_PER_MESSAGE", 50usize)?;
+        let max_rcpt_per_transaction = parse_env("MAX_RCPT_PER_TRANSACTION", 0usize)?;
+
+        let retry = RetryPolicy {
+            max_retries: parse_env("SMTP_RETRY_MAX", 0u32)?,
+            base: Duration::from_millis(parse_env("SMTP_RETRY_BASE_MS", 200u64)?),
+            max_elapsed: Duration::from_millis(parse_env("SMTP_RETRY_MAX_ELAPSED_MS", 10_000u64)?),
+            retryable_statuses: Vec::new(),
+            retry_after_max: Duration::from_millis(parse_env("RETRY_AFTER_MAX_MS", 60_000u64)?),
+        };
+
+        let greylist = match parse_secs_env("GREYLIST_RETRY_SECS")? {
+            Some(delay) => Some(GreylistPolicy {
+                delay,
+                max_deferrals: parse_env("GREYLIST_MAX_DEFERRALS", 3u32)?,
+            }),
+            None => None,
+        };
+
+        let dkim = match env::var("DKIM_PRIVATE_KEY_PATH") {
+            Ok(path) => Some(DkimSettings {
+                key_path: PathBuf::from(path),
+                selector: must_env("DKIM_SELECTOR")?,
+                domain: env::var("DKIM_DOMAIN")
+                    .unwrap_or_else(|_| primary_from.email.domain().to_string()),
+                algorithm: match env::var("DKIM_ALGORITHM").as_deref() {
+                    Err(_) | Ok("rsa") => DkimSigningAlgorithm::Rsa,
+                    Ok("ed25519") => DkimSigningAlgorithm::Ed25519,
+                    Ok(other) => anyhow::bail!("unsupported DKIM_ALGORITHM: {other}"),
+                },
+            }),
+            Err(_) => None,
+        };
+
+        let send_rate_per_sec = env::var("GLOBAL_SEND_RATE_PER_SEC")
+            .ok()
+            .map(|raw| raw.trim().parse::<f64>())
+            .transpose()
+            .context("GLOBAL_SEND_RATE_PER_SEC must be a number")?;
+        if let Some(rate) = send_rate_per_sec {
+            anyhow::ensure!(
+                rate.is_finite() && rate > 0.0,
+                "GLOBAL_SEND_RATE_PER_SEC must be positive"
+            );
+        }
+
+        let spool_dir = env::var("SPOOL_DIR").ok().map(PathBuf::from);
+        let dead_letter_dir = match parse_bool_env("DEAD_LETTER").unwrap_or(false) {
+            true => Some(
+                spool_dir
+                    .clone()
+                    .context("DEAD_LETTER requires SPOOL_DIR")?,
+            ),
+            false => None,
+        };
+
+        let inbound = match env::var("INBOUND_FORMAT").as_deref() {
+            Err(_) => None,
+            Ok(format) => Some(Inbound {
+                format: match format {
+                    "mailgun" => InboundFormat::Mailgun,
+                    "generic" => InboundFormat::Generic,
+                    other => anyhow::bail!("unsupported INBOUND_FORMAT: {other}"),
+                },
+                signing_key: must_env("INBOUND_SIGNING_KEY")?,
+                forward_to: must_env("INBOUND_FORWARD_TO")?,
+                api_key: must_env("INBOUND_API_KEY")?,
+            }),
+        };
+
+        let slack = env::var("SLACK_BOT_TOKEN").ok().map(|token| SlackConfig {
+            token,
+            api_url: env::var("SLACK_API_URL")
+                .unwrap_or_else(|_| "https://slack.com/api".to_string()),
+            username: env::var("SLACK_USERNAME").ok(),
+        });
+
+        let receipts = match env::var("RECEIPTS_URL") {
+            Err(_) => None,
+            Ok(url) => Some(ReceiptsConfig {
+                url,
+                signer: ReceiptSigner::new(
+                    env::var("WEBHOOK_SIGNATURE_ALGO")
+                        .as_deref()
+                        .unwrap_or("hmac-sha256"),
+                    env::var("RECEIPTS_SECRET").ok(),
+                    env::var("RECEIPTS_SIGNING_KEY_PATH")
+                        .ok()
+                        .map(PathBuf::from)
+                        .as_deref(),
+                )?,
+                queue: parse_env("RECEIPTS_QUEUE", 1000usize)?,
+                concurrency: parse_env("RECEIPTS_CONCURRENCY", 4usize)?,
+                retry: RetryPolicy {
+                    max_retries: parse_env("RECEIPTS_RETRY_MAX", 3u32)?,
+                    base: Duration::from_millis(parse_env("RECEIPTS_RETRY_BASE_MS", 1_000u64)?),
+                    max_elapsed: Duration::from_millis(parse_env(
+                        "RECEIPTS_RETRY_MAX_ELAPSED_MS",
+                        60_000u64,
+                    )?),
+                    retryable_statuses: Vec::new(),
+                    retry_after_max: retry.retry_after_max,
+                },
+            }),
+        };
+
+        let auto_pause = match env::var("AUTO_PAUSE_FAILURE_RATE") {
+            Ok(raw) => {
+                let rate = raw
+                    .trim()
+                    .parse::<f64>()
+                    .context("AUTO_PAUSE_FAILURE_RATE must be a number")?;
+                anyhow::ensure!(
+                    rate > 0.0 && rate <= 1.0,
+                    "AUTO_PAUSE_FAILURE_RATE must be above 0 and at most 1"
+                );
+                Some(AutoPause::new(
+                    Duration::from_secs(parse_env("AUTO_PAUSE_WINDOW_SECS", 60u64)?.max(1)),
+                    rate,
+                    parse_env("AUTO_PAUSE_MIN_SENDS", 20usize)?.max(1),
+                    match parse_env("AUTO_PAUSE_RESUME_SECS", 0u64)? {
+                        0 => None,
+                        secs => Some(Duration::from_secs(secs)),
+                    },
+                ))
+            }
+            Err(_) => None,
+        };
+
+        Ok(Self {
+            http_bind: env::var("HTTP_BIND").unwrap_or_else(|_| "127.0.0.1:8080".to_string()),
+            environment,
+            api_key: env::var("API_KEY").ok(),
+            api_keys_file: env::var("API_KEYS_FILE").ok().map(PathBuf::from),
+            auth: match env::var("AUTH_BACKEND").as_deref().unwrap_or("env") {
+                "env" => AuthConfig::Env,
+                "file" => AuthConfig::File,
+                "http" => AuthConfig::Http(IntrospectionConfig {
+                    url: must_env("AUTH_INTROSPECTION_URL")?,
+                    token: env::var("AUTH_INTROSPECTION_TOKEN").ok(),
+                    cache_ttl: Duration::from_secs(parse_env("AUTH_CACHE_SECS", 60u64)?),
+                }),
+                other => anyhow::bail!("unsupported AUTH_BACKEND: {other}"),
+            },
+            from_allowed_domains: env::var("FROM_ALLOWED_DOMAINS")
+                .map(|raw| {
+                    raw.split(',')
+                        .map(str::trim)
+                        .filter(|domain| !domain.is_empty())
+                        .map(str::to_ascii_lowercase)
+                        .collect()
+                })
+                .unwrap_or_default(),
+            redirect_all_to: env::var("REDIRECT_ALL_TO")
+                .ok()
+                .filter(|raw| !raw.trim().is_empty())
+                .map(|raw| {
+                    Address::from_str(raw.trim())
+                        .with_context(|| format!("invalid REDIRECT_ALL_TO address: {raw}"))
+                })
+                .transpose()?,
+            archive_bcc: env::var("ARCHIVE_BCC")
+                .ok()
+                .filter(|raw| !raw.trim().is_empty())
+                .map(|raw| {
+                    Address::from_str(raw.trim())
+                        .with_context(|| format!("invalid ARCHIVE_BCC address: {raw}"))
+                })
+                .transpose()?,
+            backend,
+            enabled_channels,
+            smtp_from,
+            max_recipients,
+            dedupe_recipients: parse_bool_env("DEDUPE_RECIPIENTS").unwrap_or(true),
+            digest_window_secs: parse_env("DIGEST_WINDOW_SECS", 300u64)?,
+            max_rcpt_per_transaction: (max_rcpt_per_transaction > 0)
+                .then_some(max_rcpt_per_transaction),
+            recipient_counts_always: parse_bool_env("RECIPIENT_COUNTS_ALWAYS").unwrap_or(false),
+            retry,
+            retry_policies_file: env::var("RETRY_POLICIES_FILE").ok().map(PathBuf::from),
+            greylist,
+            groups_file: env::var("GROUPS_FILE").ok().map(PathBuf::from),
+            block_role_addresses: parse_bool_env("BLOCK_ROLE_ADDRESSES").unwrap_or(false),
+            disposable_domains_file: env::var("DISPOSABLE_DOMAINS_FILE").ok().map(PathBuf::from),
+            suppression_file: env::var("SUPPRESSION_FILE").ok().map(PathBuf::from),
+            batch_concurrency: parse_env("BATCH_CONCURRENCY", 4usize)?.max(1),
+            batch_connection_mode: match env::var("BATCH_CONNECTION_MODE").as_deref() {
+                Err(_) | Ok("pooled") => BatchConnectionMode::Pooled,
+                Ok("single") => BatchConnectionMode::Single,
+                Ok(other) => anyhow::bail!("unsupported BATCH_CONNECTION_MODE: {other}"),
+            },
+            max_batch_size: Some(parse_env("MAX_BATCH_SIZE", 0usize)?).filter(|max| *max > 0),
+            batch_overflow: match env::var("BATCH_OVERFLOW").as_deref() {
+                Err(_) | Ok("reject") => BatchOverflow::Reject,
+                Ok("enqueue") => BatchOverflow::Enqueue,
+                Ok(other) => anyhow::bail!("unsupported BATCH_OVERFLOW: {other}"),
+            },
+            send_rate_per_sec,
+            auto_pause,
+            inbound,
+            receipts,
+            slack,
+            ip_warmup: match env::var("IP_WARMUP_SCHEDULE") {
+                Ok(schedule) => Some(IpWarmup::new(&schedule, &must_env("IP_WARMUP_START")?)?),
+                Err(_) => None,
+            },
+            limits: FieldLimits {
+                max_subject_len: parse_env("MAX_SUBJECT_LEN", 998usize)?,
+                max_to_len: parse_env("MAX_TO_LEN", 4096usize)?,
+                subject_policy: match env::var("SUBJECT_LENGTH_POLICY").as_deref() {
+                    Err(_) | Ok("fold") => SubjectLengthPolicy::Fold,
+                    Ok("reject") => SubjectLengthPolicy::Reject,
+                    Ok(other) => anyhow::bail!("unsupported SUBJECT_LENGTH_POLICY: {other}"),
+                },
+                subject_sanitize: match env::var("SANITIZE_SUBJECT").as_deref() {
+                    Err(_) | Ok("strip") => SubjectSanitize::Strip,
+                    Ok("reject") => SubjectSanitize::Reject,
+                    Ok("off") => SubjectSanitize::Off,
+                    Ok(other) => anyhow::bail!("unsupported SANITIZE_SUBJECT: {other}"),
+                },
+                max_headers: parse_env("MAX_HEADERS", 0usize)?,
+                max_header_bytes: parse_env("MAX_HEADER_BYTES", 0usize)?,
+                max_attachments: parse_env("MAX_ATTACHMENTS", 10usize)?,
+            },
+            defaults: (!parse_bool_env("STRICT_VALIDATION").unwrap_or(true)).then(|| {
+                FieldDefaults {
+                    subject: env::var("DEFAULT_SUBJECT")
+                        .unwrap_or_else(|_| "(no subject)".to_string()),
+                    body: env::var("DEFAULT_BODY").unwrap_or_else(|_| "(no content)".to_string()),
+                }
+            }),
+            auto_text_part: parse_bool_env("AUTO_TEXT_PART").unwrap_or(true),
+            clean_text_links: parse_bool_env("CLEAN_TEXT_LINKS").unwrap_or(false),
+            normalize_crlf: parse_bool_env("NORMALIZE_CRLF").unwrap_or(true),
+            trailing_crlf: parse_bool_env("TRAILING_CRLF").unwrap_or(true),
+            sniff_attachments: parse_bool_env("SNIFF_ATTACHMENT_TYPES").unwrap_or(true),
+            max_upload_bytes: parse_env("MAX_UPLOAD_BYTES", 25 * 1024 * 1024usize)?,
+            body_wrapper: WrapperFiles {
+                text_header: env::var("BODY_HEADER").ok().map(PathBuf::from),
+                text_footer: env::var("BODY_FOOTER").ok().map(PathBuf::from),
+                html_header: env::var("BODY_HEADER_HTML").ok().map(PathBuf::from),
+                html_footer: env::var("BODY_FOOTER_HTML").ok().map(PathBuf::from),
+            },
+            undisclosed_to: match env::var("UNDISCLOSED_TO").as_deref() {
+                Err(_) | Ok("undisclosed") => UndisclosedTo::Group,
+                Ok("from") => UndisclosedTo::From,
+                Ok(other) => anyhow::bail!("unsupported UNDISCLOSED_TO: {other}"),
+            },
+            metric_tag_keys: env::var("METRIC_TAG_KEYS")
+                .map(|raw| {
+                    raw.split(',')
+                        .map(str::trim)
+                        .filter(|key| !key.is_empty())
+                        .map(str::to_string)
+                        .collect()
+                })
+                .unwrap_or_default(),
+            metrics_tenant_label: parse_bool_env("METRICS_TENANT_LABEL")
+                .unwrap_or(false)
+                .then(|| parse_env("METRICS_MAX_TENANTS", 100usize))
+                .transpose()?,
+            metrics_backend: match env::var("METRICS_BACKEND").as_deref() {
+                Err(_) | Ok("prometheus") => MetricsBackend::Prometheus,
+                Ok("statsd") => MetricsBackend::Statsd(must_env("STATSD_ADDR")?),
+                Ok("none") => MetricsBackend::None,
+                Ok(other) => anyhow::bail!("unsupported METRICS_BACKEND: {other}"),
+            },
+            failure_log_size: match parse_env("FAILURE_LOG_SIZE", 0usize)? {
+                0 => None,
+                size => Some(size),
+            },
+            history_file: env::var("HISTORY_FILE").ok().map(PathBuf::from),
+            history_max_rows: match parse_env("HISTORY_MAX_ROWS", 100_000usize)? {
+                0 => anyhow::bail!("HISTORY_MAX_ROWS must be at least 1"),
+                max => max,
+            },
+            queue_capacity: parse_env("QUEUE_CAPACITY", 1_000usize)?,
+            max_queued_jobs: match parse_env("MAX_QUEUED_JOBS", 0usize)? {
+                0 => None,
+                max => Some(max),
+            },
+            spool_dir,
+            dead_letter_dir,
+            queue_workers: parse_env("QUEUE_WORKERS", 1usize)?.max(1),
+            queue_aging: Duration::from_secs(parse_env("QUEUE_AGING_SECS", 30u64)?),
+            job_max_age: match parse_env("JOB_MAX_AGE_SECS", 0u64)? {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            tracking: if parse_bool_env("TRACKING_ENABLED").unwrap_or(false) {
+                Some((must_env("TRACKING_BASE_URL")?, must_env("TRACKING_SECRET")?))
+            } else {
+                None
+            },
+            multipart_boundary: env::var("MULTIPART_BOUNDARY").ok(),
+            dkim,
+            dkim_reload: Duration::from_secs(parse_env("DKIM_RELOAD_SECS", 60u64)?),
+            pgp_keys_dir: env::var("PGP_KEYS_DIR").ok().map(PathBuf::from),
+            pgp_missing_key: match env::var("PGP_MISSING_KEY").as_deref() {
+                Err(_) | Ok("fail") => MissingKey::Fail,
+                Ok("plaintext") => MissingKey::Plaintext,
+                Ok(other) => anyhow::bail!("unsupported PGP_MISSING_KEY: {other}"),
+            },
+            bind_retry: Duration::from_secs(parse_env("BIND_RETRY_SECS", 0u64)?),
+            shutdown_grace: Duration::from_secs(parse_env("SHUTDOWN_GRACE_SECS", 30u64)?),
+            write_timeout: match parse_env("HTTP_WRITE_TIMEOUT_SECS", 60u64)? {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            events_buffer: parse_env("EVENTS_BUFFER", 256usize)?.max(1),
+            templates_dir: env::var("TEMPLATES_DIR").ok().map(PathBuf::from),
+            outbox_dir: env::var("OUTBOX_DIR").ok().map(PathBuf::from),
+            verify_recipients: parse_bool_env("VERIFY_RECIPIENTS").unwrap_or(false),
+            request_schema_file: env::var("REQUEST_SCHEMA_FILE").ok().map(PathBuf::from),
+            default_locale: env::var("DEFAULT_LOCALE").unwrap_or_else(|_| "en".to_string()),
+            detect_locale: parse_bool_env("DETECT_LOCALE").unwrap_or(false),
+            template_limits: RenderLimits {
+                max_depth: parse_env("TEMPLATE_MAX_DEPTH", 8usize)?,
+                timeout: match parse_env("TEMPLATE_RENDER_TIMEOUT_MS", 1_000u64)? {
+                    0 => None,
+                    ms => Some(Duration::from_millis(ms)),
+                },
+            },
+            send_max_wait: Duration::from_millis(parse_env("GLOBAL_SEND_MAX_WAIT_MS", 5_000u64)?),
+            sender_name: env::var("SENDER_NAME")
+                .ok()
+                .map(|name| name.trim().to_string())
+                .filter(|name| !name.is_empty()),
+            slow_send_warn: match parse_env("SLOW_SEND_WARN_MS", 0u64)? {
+                0 => None,
+                millis => Some(Duration::from_millis(millis)),
+            },
+        })
+    }
+}
+
+impl SmtpConfig {
+    fn from_env() -> Result<Self> {
+        let auth_mechanisms = match env::var("SMTP_AUTH_MECHANISM") {
+            Ok(raw) => parse_auth_mechanisms(&raw)?,
+            Err(_) => Vec::new(),
+        };
+        let port = parse_env("SMTP_PORT", 587u16)?;
+        let security = match env::var("SMTP_SECURITY") {
+            Err(_) => match parse_bool_env("SMTP_TLS").unwrap_or(true) {
+                true => SmtpSecurity::Tls,
+                false => SmtpSecurity::None,
+            },
+            Ok(raw) => SmtpSecurity::parse("SMTP_SECURITY", &raw)?,
+        };
+        check_port_security("SMTP_", port, security)?;
+
+        let xoauth2 = auth_mechanisms.contains(&Mechanism::Xoauth2);
+        let secret_var = if xoauth2 { "ACCESS_TOKEN" } else { "PASSWORD" };
+        let credentials = smtp_credentials("SMTP_", secret_var)?;
+        anyhow::ensure!(
+            credentials.is_some() || !xoauth2,
+            "XOAUTH2 needs SMTP_USERNAME and SMTP_ACCESS_TOKEN"
+        );
+
+        let primary = Self {
+            host: must_env("SMTP_HOST")?,
+            port,
+            credentials,
+            auth_mechanisms,
+            security,
+            min_tls_version: match env::var("SMTP_MIN_TLS_VERSION").as_deref() {
+                Err(_) | Ok("1.2") => TlsVersion::Tlsv12,
+                Ok("1.3") => TlsVersion::Tlsv13,
+                Ok(other) => anyhow::bail!("unsupported SMTP_MIN_TLS_VERSION: {other}"),
+            },
+            warmup: parse_bool_env("SMTP_WARMUP").unwrap_or(false),
+            warmup_connections: parse_env("SMTP_WARMUP_CONNECTIONS", 1usize)?,
+            connect_timeout: parse_secs_env("SMTP_CONNECT_TIMEOUT_SECS")?,
+            send_timeout: parse_secs_env("SMTP_SEND_TIMEOUT_SECS")?,
+            chunking: parse_bool_env("SMTP_USE_CHUNKING").unwrap_or(false),
+            partial_rcpt: parse_bool_env("SMTP_ACCEPT_PARTIAL_RCPT").unwrap_or(false),
+            disable_pipelining: parse_bool_env("SMTP_DISABLE_PIPELINING").unwrap_or(false),
+            local_bind: env::var("SMTP_LOCAL_BIND_ADDR")
+                .ok()
+                .map(|raw| {
+                    raw.trim().parse().with_context(|| {
+                        format!("SMTP_LOCAL_BIND_ADDR is not an IP address: {raw}")
+                    })
+                })
+                .transpose()?,
+            sending_pool: match env::var("SENDING_POOL") {
+                Ok(raw) => parse_sending_pool(&raw)?,
+                Err(_) => Vec::new(),
+            },
+            pool_policy: match env::var("SENDING_POOL_POLICY").as_deref() {
+                Err(_) | Ok("round_robin") => PoolPolicy::RoundRobin,
+                Ok("weighted") => PoolPolicy::Weighted,
+                Ok(other) => anyhow::bail!("unsupported SENDING_POOL_POLICY: {other}"),
+            },
+            max_connections: match parse_env("SMTP_MAX_CONNECTIONS", 0usize)? {
+                0 => None,
+                max => Some(max),
+            },
+            debug: parse_bool_env("SMTP_DEBUG").unwrap_or(false),
+            fallbacks: Vec::new(),
+            failover_cooldown: Duration::from_secs(parse_env(
+                "SMTP_FAILOVER_COOLDOWN_SECS",
+                30u64,
+            )?),
+        };
+
+        anyhow::ensure!(
+            primary.local_bind.is_none() || primary.sending_pool.is_empty(),
+            "SMTP_LOCAL_BIND_ADDR cannot be combined with SENDING_POOL"
+        );
+
+        // Fallbacks share everything but the server and its credentials,
+        // which default to the primary's.
+        let mut fallbacks = Vec::new();
+        for n in 1.. {
+            let prefix = format!("SMTP_FALLBACK_{n}_");
+            let Ok(host) = env::var(format!("{prefix}HOST")) else {
+                break;
+            };
+            let port = parse_env(&format!("{prefix}PORT"), primary.port)?;
+            let security = match env::var(format!("{prefix}SECURITY")) {
+                Err(_) => primary.security,
+                Ok(raw) => SmtpSecurity::parse(&format!("{prefix}SECURITY"), &raw)?,
+            };
+            check_port_security(&prefix, port, security)?;
+            fallbacks.push(Self {
+                host,
+                port,
+                credentials: match smtp_credentials(&prefix, secret_var)? {
+                    Some(credentials) => Some(credentials),
+                    None => primary.credentials.clone(),
+                },
+                security,
+                warmup: false,
+                ..primary.clone()
+            });
+        }
+        Ok(Self {
+            fallbacks,
+            ..primary
+        })
+    }
+}
+
+/// Parses `ENABLED_CHANNELS`: comma-separated channel names, e.g.
+/// `smtp,slack`. An empty list would leave the server taking no work at
+/// all, so it is refused as the misconfiguration it almost surely is.
+fn parse_enabled_channels(raw: &str) -> Result<Vec<NotificationService>> {
+    let mut channels = Vec::new();
+    for name in raw
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+    {
+        let channel = serde_json::from_value(serde_json::Value::from(name.to_ascii_lowercase()))
+            .map_err(|_| anyhow::anyhow!("unsupported ENABLED_CHANNELS entry: {name}"))?;
+        if !channels.contains(&channel) {
+            channels.push(channel);
+        }
+    }
+    anyhow::ensure!(
+        !channels.is_empty(),
+        "ENABLED_CHANNELS is empty, no notification channel would be enabled; list at least one of smtp, slack"
+    );
+    Ok(channels)
+}
+
+/// Parses `SENDING_POOL`: comma-separated source addresses, each optionally
+/// followed by `=<weight>` (default 1), e.g. `192.0.2.10=3,2001:db8::10`.
+fn parse_sending_pool(raw: &str) -> Result<Vec<(IpAddr, u32)>> {
+    let pool = raw
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (addr, weight) = match entry.split_once('=') {
+                Some((addr, weight)) => (
+                    addr.trim(),
+                    weight
+                        .trim()
+                        .parse::<u32>()
+                        .ok()
+                        .filter(|weight| *weight > 0)
+                        .with_context(|| format!("invalid SENDING_POOL weight: {entry}"))?,
+                ),
+                None => (entry, 1),
+            };
+            let addr = addr
+                .parse()
+                .with_context(|| format!("SENDING_POOL entry is not an IP address: {entry}"))?;
+            Ok((addr, weight))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    anyhow::ensure!(!pool.is_empty(), "SENDING_POOL is empty");
+    Ok(pool)
+}
+
+/// Warns, or fails under `SMTP_SECURITY_STRICT`, when the mode does not fit
+/// the port, however it was configured. STARTTLS on 25 is common between
+/// relays, so it only ever warns. `prefix` names the variables, e.g. `SMTP_`
+/// or `SMTP_FALLBACK_1_`.
+fn check_port_security(prefix: &str, port: u16, security: SmtpSecurity) -> Result<()> {
+    let Some(expected) = security.suggested_for(port) else {
+        return Ok(());
+    };
+    let mismatch = format!(
+        "{prefix}PORT {port} usually expects {prefix}SECURITY={}, but {} is configured",
+        expected.name(),
+        security.name()
+    );
+    let common = (port, security) == (25, SmtpSecurity::StartTls);
+    if !common && parse_bool_env("SMTP_SECURITY_STRICT").unwrap_or(false) {
+        anyhow::bail!(mismatch);
+    }
+    warn!("{mismatch}");
+    Ok(())
+}
+
+/// Refuses to start when an SMTP server, primary or fallback, matches none
+/// of the `SMTP_HOST_PATTERN_<ENVIRONMENT>` patterns, so a staging deploy
+/// cannot be pointed at the production relay. Without that variable any
+/// host is accepted.
+fn check_smtp_hosts(environment: &str, smtp: &SmtpConfig) -> Result<()> {
+    let name: String = environment
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let var = format!("SMTP_HOST_PATTERN_{name}");
+    let Ok(raw) = env::var(&var) else {
+        return Ok(());
+    };
+    let patterns: Vec<&str> = raw
+        .split(',')
+        .map(str::trim)
+        .filter(|pattern| !pattern.is_empty())
+        .collect();
+    anyhow::ensure!(!patterns.is_empty(), "{var} is empty");
+    for host in std::iter::once(smtp)
+        .chain(&smtp.fallbacks)
+        .map(|cfg| &cfg.host)
+    {
+        if !patterns.iter().any(|pattern| wildcard_match(pattern, host)) {
+            anyhow::bail!(
+                "SMTP host {host} does not match {var} ({raw}) for ENVIRONMENT={environment}"
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Case-insensitive match where `*` stands for any run of characters.
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let (pattern, text) = (pattern.to_ascii_lowercase(), text.to_ascii_lowercase());
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts: Vec<&str> = parts.collect();
+    let Some(last) = parts.pop() else {
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+fn parse_auth_mechanisms(raw: &str) -> Result<Vec<Mechanism>> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| match name.to_ascii_uppercase().as_str() {
+            "PLAIN" => Ok(Mechanism::Plain),
+            "LOGIN" => Ok(Mechanism::Login),
+            "XOAUTH2" => Ok(Mechanism::Xoauth2),
+            _ => anyhow::bail!("unsupported SMTP_AUTH_MECHANISM: {name}"),
+        })
+        .collect()
+}
+
+/// Parses a comma-separated list of `addr@x` or `Display Name <addr@x>`
+/// entries. More forgiving than lettre's RFC 5322 parser: display names may
+/// contain commas, dots and other specials without quoting, and quotes inside
+/// a quoted name need not be escaped.
+fn parse_mailbox_list(raw: &str) -> Result<Mailboxes> {
+    let mut mailboxes = Mailboxes::new();
+    for (index, entry) in split_mailbox_list(raw)?.iter().enumerate() {
+        let mailbox =
+            parse_mailbox_entry(entry).with_context(|| format!("entry {} `{entry}`", index + 1))?;
+        mailboxes.push(mailbox);
+    }
+    Ok(mailboxes)
+}
+
+/// Splits on commas outside quotes and `<...>`, rejoining pieces until each
+/// entry ends in an address, so `Acme, Inc <ops@acme.com>` stays one entry.
+fn split_mailbox_list(raw: &str) -> Result<Vec<String>> {
+    let mut pieces = Vec::new();
+    let mut piece = String::new();
+    let (mut quoted, mut escaped, mut angle) = (false, false, false);
+    for ch in raw.chars() {
+        match ch {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' if !angle => quoted = !quoted,
+            '<' if !quoted => angle = true,
+            '>' if !quoted => angle = false,
+            ',' if !quoted && !angle => {
+                pieces.push(std::mem::take(&mut piece));
+                continue;
+            }
+            _ => {}
+        }
+        piece.push(ch);
+    }
+    anyhow::ensure!(!quoted, "unterminated quote in `{raw}`");
+    anyhow::ensure!(!angle, "missing closing `>` in `{raw}`");
+    pieces.push(piece);
+
+    let mut entries = Vec::new();
+    let mut entry = String::new();
+    for piece in pieces {
+        if entry.is_empty() && piece.trim().is_empty() {
+            continue;
+        }
+        if !entry.is_empty() {
+            entry.push(',');
+        }
+        entry.push_str(&piece);
+        let trimmed = entry.trim();
+        if trimmed.ends_with('>') || (!trimmed.contains('<') && trimmed.contains('@')) {
+            entries.push(std::mem::take(&mut entry).trim().to_string());
+        }
+    }
+    if !entry.trim().is_empty() {
+        anyhow::bail!("`{}` has no email address", entry.trim());
+    }
+    anyhow::ensure!(!entries.is_empty(), "no email address given");
+    Ok(entries)
+}
+
+fn parse_mailbox_entry(entry: &str) -> Result<Mailbox> {
+    let Some(open) = entry.rfind('<') else {
+        let address = Address::from_str(entry)
+            .map_err(|err| anyhow::anyhow!("invalid address `{entry}`: {err}"))?;
+        return Ok(Mailbox::new(None, address));
+    };
+    let Some(address) = entry[open + 1..].strip_suffix('>') else {
+        anyhow::bail!("unexpected text after the closing `>`");
+    };
+    let address = address.trim();
+    let address = Address::from_str(address)
+        .map_err(|err| anyhow::anyhow!("invalid address `{address}`: {err}"))?;
+
+    let name = entry[..open].trim();
+    let name = match name
+        .strip_prefix('"')
+        .and_then(|name| name.strip_suffix('"'))
+    {
+        Some(quoted) => quoted.replace("\\\"", "\"").replace("\\\\", "\\"),
+        None => name.to_string(),
+    };
+    Ok(Mailbox::new((!name.is_empty()).then_some(name), address))
+}
+
+/// `<prefix>USERNAME` with `<prefix><secret_var>`, `None` when both are
+/// unset or empty. Setting only one is a mistake rather than a relay that
+/// needs no AUTH.
+fn smtp_credentials(prefix: &str, secret_var: &str) -> Result<Option<Credentials>> {
+    let var = |name: &str| env::var(name).ok().filter(|value| !value.is_empty());
+    let (username_var, secret_var) = (format!("{prefix}USERNAME"), format!("{prefix}{secret_var}"));
+    match (var(&username_var), var(&secret_var)) {
+        (Some(username), Some(secret)) => Ok(Some(Credentials::new(username, secret))),
+        (None, None) => Ok(None),
+        _ => anyhow::bail!("{username_var} and {secret_var} must be set together"),
+    }
+}
+
+fn must_env(name: &str) -> Result<String> {
+    env::var(name).with_context(|| format!("missing env var: {name}"))
+}
+
+fn parse_env<T>(name: &str, default: T) -> Result<T>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match env::var(name) {
+        Ok(raw) => raw
+            .trim()
+            .parse::<T>()
+            .with_context(|| format!("{name} must be a valid {}", std::any::type_name::<T>())),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Optional duration in whole seconds; unset means no explicit limit.
+fn parse_secs_env(name: &str) -> Result<Option<Duration>> {
+    env::var(name)
+        .ok()
+        .map(|raw| {
+            raw.trim()
+                .parse::<u64>()
+                .map(Duration::from_secs)
+                .with_context(|| format!("{name} must be a whole number of seconds"))
+        })
+        .transpose()
+}
+
+fn parse_bool_env(name: &str) -> Option<bool> {
+    let raw = env::var(name).ok()?;
+    match raw.trim().to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(true),
+        "0" | "false" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+/// Error statuses follow one convention: 400 for a request that does not
+/// parse or has a malformed or out-of-range field, 422 for a well-formed
+/// request that is refused for what it asks, such as a filtered recipient.
+fn error_response(status: StatusCode, message: &str) -> (StatusCode, Json<ApiResponse>) {
+    (status, Json(ApiResponse::error(message)))
+}
+
+/// A 400 that names the offending request field.
+fn field_error(field: &'static str, message: &str) -> (StatusCode, Json<ApiResponse>) {
+    let (status, Json(mut body)) = error_response(StatusCode::BAD_REQUEST, message);
+    body.field = Some(field);
+    (status, Json(body))
+}
+
+/// A 422 for a well-formed field that cannot be honoured.
+fn unprocessable_field(field: &'static str, message: &str) -> (StatusCode, Json<ApiResponse>) {
+    let (_, body) = field_error(field, message);
+    (StatusCode::UNPROCESSABLE_ENTITY, body)
+}
+
+/// A 403 for a request the caller is not permitted to make, however it is
+/// formed.
+fn forbidden(message: &str) -> (StatusCode, Json<ApiResponse>) {
+    error_response(StatusCode::FORBIDDEN, message)
+}
+
+/// The error side of fallible handlers and helpers, boxed so their `Ok`
+/// values do not pay for the whole response.
+struct ApiError(Box<(StatusCode, Json<ApiResponse>)>);
+
+impl ApiError {
+    fn into_inner(self) -> (StatusCode, Json<ApiResponse>) {
+        *self.0
+    }
+}
+
+impl From<(StatusCode, Json<ApiResponse>)> for ApiError {
+    fn from(resp: (StatusCode, Json<ApiResponse>)) -> Self {
+        Self(Box::new(resp))
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        self.0.into_response()
+    }
+}
+
+/// [`Json`] whose rejections are answered as an [`ApiResponse`], with 400
+/// for any body that does not deserialize.
+struct JsonBody<T>(T);
+
+impl<S, T> FromRequest<S> for JsonBody<T>
+where
+    Json<T>: FromRequest<S, Rejection = JsonRejection>,
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, Json<ApiResponse>);
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        match Json::<T>::from_request(req, state).await {
+            Ok(Json(value)) => Ok(Self(value)),
+            Err(rejection) => {
+                let status = match &rejection {
+                    JsonRejection::JsonDataError(_) | JsonRejection::JsonSyntaxError(_) => {
+                        StatusCode::BAD_REQUEST
+                    }
+                    other => other.status(),
+                };
+                Err(error_response(
+                    status,
+                    &format!("invalid request body: {}", rejection.body_text()),
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Method;
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_support::{self, call, notify};
+
+    #[tokio::test]
+    async fn memory_backend_captures_sent_messages() {
+        let state = test_support::state(&[]).await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "deploy finished", "body": "build 1234 is live"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        let (status, sent) = call(&app, Method::GET, "/test/sent", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let sent = sent.as_array().expect("a list of messages");
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0]["from"], "notify@example.com");
+        assert_eq!(sent[0]["to"], json!(["ops@example.com"]));
+        assert_eq!(sent[0]["subject"], "deploy finished");
+        let raw = sent[0]["raw"].as_str().expect("raw message");
+        assert!(raw.contains("To: ops@example.com"), "{raw}");
+        assert!(raw.contains("build 1234 is live"), "{raw}");
+    }
+
+    #[tokio::test]
+    async fn failed_validation_captures_nothing() {
+        let state = test_support::state(&[]).await;
+        let app = test_support::app(&state);
+
+        let (status, _) = notify(
+            &app,
+            json!({"service": "smtp", "to": "not an address", "title": "t", "body": "b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (_, sent) = call(&app, Method::GET, "/test/sent", None).await;
+        assert_eq!(sent, json!([]));
+    }
+
+    #[tokio::test]
+    async fn reload_while_another_runs_answers_409() {
+        let dir = test_support::TempDir::new("templates");
+        std::fs::write(dir.path().join("welcome.hbs"), "hi").expect("template written");
+        let state = test_support::state(&[(
+            "TEMPLATES_DIR",
+            dir.path().to_str().expect("utf-8 temp dir"),
+        )])
+        .await;
+        let app = test_support::app(&state);
+
+        let reloading = state.reloading.lock().await;
+        let (status, body) = call(&app, Method::POST, "/admin/reload-templates", None).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["message"], "reload in progress");
+
+        drop(reloading);
+        let (status, body) = call(&app, Method::POST, "/admin/reload-templates", None).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["loaded"], 1);
+    }
+
+    #[tokio::test]
+    async fn flush_pool_does_not_wait_for_reloads() {
+        let state = test_support::state(&[]).await;
+        let app = test_support::app(&state);
+
+        let _reloading = state.reloading.lock().await;
+        let (status, body) = call(&app, Method::POST, "/admin/flush-pool", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["message"], "memory backend has no connection pool");
+    }
+
+    #[tokio::test]
+    async fn alternative_parts_are_text_first_whatever_the_field_order() {
+        let state = test_support::state(&[]).await;
+        let app = test_support::app(&state);
+
+        let request = r#"{"service":"smtp","to":"ops@example.com","title":"t","html":"<p>rich</p>","body":"plain"}"#;
+        let (status, body) = notify(&app, serde_json::from_str(request).expect("valid json")).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        let (_, sent) = call(&app, Method::GET, "/test/sent", None).await;
+        let raw = sent[0]["raw"].as_str().expect("raw message");
+        let alternative = raw
+            .find("multipart/alternative")
+            .expect("an alternative part");
+        let text = raw.find("Content-Type: text/plain").expect("a text part");
+        let html = raw.find("Content-Type: text/html").expect("an html part");
+        assert!(alternative < text && text < html, "{raw}");
+    }
+
+    #[tokio::test]
+    async fn recipients_at_the_cap_are_sent() {
+        let state = test_support::state(&[("MAX_RECIPIENTS_PER_MESSAGE", "3")]).await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "a@example.com", "cc": "b@example.com", "bcc": "hidden@example.com", "title": "t", "body": "b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let sent = test_support::sent(&app).await;
+        assert_eq!(
+            sent[0]["to"],
+            json!(["a@example.com", "b@example.com", "hidden@example.com"])
+        );
+        let raw = sent[0]["raw"].as_str().expect("raw message");
+        assert!(raw.contains("Cc: b@example.com"), "{raw}");
+        assert!(
+            !raw.contains("hidden@example.com"),
+            "bcc stays off the headers: {raw}"
+        );
+    }
+
+    #[tokio::test]
+    async fn recipients_over_the_cap_are_refused() {
+        let state = test_support::state(&[("MAX_RECIPIENTS_PER_MESSAGE", "3")]).await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "a@example.com, d@example.com", "cc": "b@example.com", "bcc": "c@example.com", "title": "t", "body": "b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["message"], "too many recipients (4 > 3)");
+        assert!(test_support::sent(&app).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn preview_renders_the_message_without_sending() {
+        let state = test_support::state(&[]).await;
+        let app = test_support::app(&state);
+
+        let (status, raw) = test_support::call_text(
+            &app,
+            Method::POST,
+            "/preview",
+            Some(json!({"service": "smtp", "to": "ops@example.com", "title": "deploy finished", "body": "plain", "html": "<p>rich</p>"})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{raw}");
+        assert!(
+            raw.contains("From: Notifications <notify@example.com>"),
+            "{raw}"
+        );
+        assert!(raw.contains("To: ops@example.com"), "{raw}");
+        assert!(raw.contains("Subject: deploy finished"), "{raw}");
+        assert!(raw.contains("Content-Type: multipart/alternative"), "{raw}");
+        assert!(raw.contains("Content-Type: text/plain"), "{raw}");
+        assert!(raw.contains("<p>rich</p>"), "{raw}");
+        assert!(test_support::sent(&app).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn preview_validates_like_notify() {
+        let state = test_support::state(&[]).await;
+        let app = test_support::app(&state);
+
+        let (status, body) = call(
+            &app,
+            Method::POST,
+            "/preview",
+            Some(json!({"service": "smtp", "to": "not an address", "title": "t", "body": "b"})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["message"], "invalid recipient email");
+    }
+
+    #[tokio::test]
+    async fn preview_checks_the_request_schema() {
+        let dir = test_support::TempDir::new("schema");
+        let schema = dir.path().join("schema.json");
+        std::fs::write(&schema, r#"{"required": ["tags"]}"#).expect("schema written");
+        let state = test_support::state(&[(
+            "REQUEST_SCHEMA_FILE",
+            schema.to_str().expect("utf-8 temp dir"),
+        )])
+        .await;
+        let app = test_support::app(&state);
+
+        let (status, body) = call(
+            &app,
+            Method::POST,
+            "/preview",
+            Some(json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b"})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            body["message"],
+            "request does not match schema: /: \"tags\" is a required property"
+        );
+    }
+
+    /// The primary SMTP server's config, from `vars` on top of a host.
+    fn smtp_config(vars: &[(&str, &str)]) -> Result<SmtpConfig> {
+        let vars: Vec<_> = [("BACKEND", "smtp"), ("SMTP_HOST", "smtp.example.com")]
+            .into_iter()
+            .chain(vars.iter().copied())
+            .collect();
+        match test_support::config(&vars)?.backend {
+            BackendConfig::Smtp(smtp) => Ok(*smtp),
+            other => panic!("not an smtp backend: {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn auth_mechanism_selects_the_transport_mechanisms() {
+        for (name, mechanism, secret_var) in [
+            ("LOGIN", Mechanism::Login, "SMTP_PASSWORD"),
+            ("plain", Mechanism::Plain, "SMTP_PASSWORD"),
+            ("XOAUTH2", Mechanism::Xoauth2, "SMTP_ACCESS_TOKEN"),
+        ] {
+            let smtp = smtp_config(&[
+                ("SMTP_AUTH_MECHANISM", name),
+                ("SMTP_USERNAME", "relay"),
+                (secret_var, "secret"),
+            ])
+            .expect("config loads");
+            assert_eq!(smtp.auth_mechanisms, vec![mechanism]);
+            assert!(smtp.credentials.is_some());
+            build_mailer(&smtp).expect("transport builds");
+        }
+    }
+
+    #[tokio::test]
+    async fn auth_mechanism_defaults_to_negotiation() {
+        let smtp = smtp_config(&[]).expect("config loads");
+        assert!(smtp.auth_mechanisms.is_empty());
+        build_mailer(&smtp).expect("transport builds");
+    }
+
+    #[test]
+    fn xoauth2_needs_an_access_token() {
+        let err = smtp_config(&[
+            ("SMTP_AUTH_MECHANISM", "XOAUTH2"),
+            ("SMTP_USERNAME", "relay"),
+            ("SMTP_PASSWORD", "secret"),
+        ])
+        .expect_err("a password is not a token");
+        assert_eq!(
+            format!("{err:#}"),
+            "SMTP_USERNAME and SMTP_ACCESS_TOKEN must be set together"
+        );
+        let err = parse_auth_mechanisms("LOGIN, CRAM-MD5").expect_err("unknown mechanism");
+        assert_eq!(err.to_string(), "unsupported SMTP_AUTH_MECHANISM: CRAM-MD5");
+    }
+
+    #[tokio::test]
+    async fn group_recipients_expand_and_unknown_groups_are_404() {
+        let dir = test_support::TempDir::new("groups");
+        let path = dir.path().join("groups.json");
+        std::fs::write(
+            &path,
+            r#"{"oncall-db": ["a@example.com", "group:dba"], "dba": ["b@example.com"]}"#,
+        )
+        .unwrap();
+        let state = test_support::state(&[("GROUPS_FILE", path.to_str().unwrap())]).await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "group:oncall-db", "title": "t", "body": "b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let sent = test_support::sent(&app).await;
+        assert_eq!(sent[0]["to"], json!(["a@example.com", "b@example.com"]));
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "group:nope", "title": "t", "body": "b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["message"], "unknown group: nope");
+    }
+
+    #[tokio::test]
+    async fn api_key_policy_sets_from_and_enforces_quota() {
+        let dir = test_support::TempDir::new("api-keys");
+        let path = dir.path().join("keys.json");
+        std::fs::write(
+            &path,
+            json!({
+                test_support::API_KEY: {
+                    "from": "Billing <billing@tenant.example>",
+                    "daily_quota": 2,
+                    "allowed_domains": ["example.com"]
+                }
+            })
+            .to_string(),
+        )
+        .unwrap();
+        let state =
+            test_support::state(&[("API_KEY", ""), ("API_KEYS_FILE", path.to_str().unwrap())])
+                .await;
+        let app = test_support::app(&state);
+        let send = |to: &'static str| {
+            notify(
+                &app,
+                json!({"service": "smtp", "to": to, "title": "t", "body": "b"}),
+            )
+        };
+
+        let (status, body) = send("ops@example.com").await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let sent = test_support::sent(&app).await;
+        assert_eq!(sent[0]["from"], "billing@tenant.example");
+        let raw = sent[0]["raw"].as_str().unwrap();
+        assert!(
+            raw.contains("From: Billing <billing@tenant.example>"),
+            "{raw}"
+        );
+
+        // A refused send is not counted against the quota.
+        let (status, body) = send("ops@elsewhere.example").await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            body["message"],
+            "recipient domain not permitted: elsewhere.example"
+        );
+
+        let (status, _) = send("ops@example.com").await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, body) = send("ops@example.com").await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(body["message"], "daily quota exceeded");
+        assert_eq!(test_support::sent(&app).await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn base64_body_is_decoded_before_sending() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let encoded = BASE64_STANDARD.encode("deploy <1234> finished\nall green");
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": encoded, "body_encoding": "base64"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let sent = test_support::sent(&app).await;
+        let raw = sent[0]["raw"].as_str().unwrap();
+        assert!(raw.contains("deploy <1234> finished\r\nall green"), "{raw}");
+    }
+
+    #[tokio::test]
+    async fn undecodable_base64_body_is_refused() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        for (body, message) in [
+            ("not base64!", "body is not valid base64"),
+            ("//4=", "decoded body is not valid UTF-8"),
+        ] {
+            let (status, resp) = notify(
+                &app,
+                json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": body, "body_encoding": "base64"}),
+            )
+            .await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert_eq!(resp["message"], message);
+        }
+        assert!(test_support::sent(&app).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn warmup_opens_the_configured_connections() {
+        let smtp = test_support::MockSmtp::start().await;
+        let port = smtp.port();
+        let cfg = test_support::config(&[
+            ("BACKEND", "smtp"),
+            ("SMTP_HOST", "127.0.0.1"),
+            ("SMTP_PORT", &port),
+            ("SMTP_TLS", "false"),
+            ("SMTP_WARMUP", "true"),
+            ("SMTP_WARMUP_CONNECTIONS", "3"),
+        ])
+        .unwrap();
+        let (state, serving) = build_state(cfg).await.unwrap();
+        let (mailer, count) = serving.warmup.expect("warmup configured");
+        assert_eq!(count, 3);
+        assert!(!state.ready.load(Ordering::Relaxed));
+
+        warm_up_pool(state.clone(), mailer, count).await;
+        assert_eq!(smtp.connections(), 3);
+        assert!(state.ready.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn long_subject_is_folded_under_the_fold_policy() {
+        let app = test_support::app(&test_support::state(&[("MAX_SUBJECT_LEN", "40")]).await);
+        let title = "x".repeat(120);
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": title, "body": "b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        let sent = test_support::sent(&app).await;
+        let raw = sent[0]["raw"].as_str().unwrap();
+        let headers = raw.split("\r\n\r\n").next().unwrap();
+        let words: Vec<&str> = headers
+            .split("Subject: ")
+            .nth(1)
+            .unwrap()
+            .split("\r\n")
+            .take_while(|line| !line.contains(": "))
+            .map(str::trim)
+            .collect();
+        assert_eq!(words.len(), 3, "{headers}");
+        // RFC 2047 caps an encoded-word at 75 characters.
+        assert!(words.iter().all(|word| word.len() <= 75), "{headers}");
+        let decoded: String = words
+            .iter()
+            .map(|word| {
+                let word = word.strip_prefix("=?utf-8?b?").unwrap();
+                let word = word.strip_suffix("?=").unwrap();
+                String::from_utf8(BASE64_STANDARD.decode(word).unwrap()).unwrap()
+            })
+            .collect();
+        assert_eq!(decoded, title);
+    }
+
+    #[tokio::test]
+    async fn long_subject_is_refused_under_the_reject_policy() {
+        let app = test_support::app(
+            &test_support::state(&[
+                ("MAX_SUBJECT_LEN", "40"),
+                ("SUBJECT_LENGTH_POLICY", "reject"),
+            ])
+            .await,
+        );
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "x".repeat(41), "body": "b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["message"], "title exceeds 40 characters");
+
+        let (status, _) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "x".repeat(40), "body": "b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn long_to_is_refused() {
+        let app = test_support::app(&test_support::state(&[("MAX_TO_LEN", "20")]).await);
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "someone.long@example.com", "title": "t", "body": "b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["message"], "to exceeds 20 characters");
+    }
+
+    #[tokio::test]
+    async fn html_only_messages_get_a_text_alternative() {
+        let html =
+            r#"<p>Build <b>1234</b> is live: <a href="https://ci.example.com/1234">logs</a></p>"#;
+        let request = json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "", "html": html});
+
+        let app = test_support::app(&test_support::state(&[]).await);
+        let (status, body) = notify(&app, request.clone()).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let sent = test_support::sent(&app).await;
+        let raw = sent[0]["raw"].as_str().unwrap();
+        assert!(raw.contains("multipart/alternative"), "{raw}");
+        assert!(raw.contains("Content-Type: text/plain"), "{raw}");
+        assert!(
+            raw.contains("Build 1234 is live: logs (https://ci.example.com/1234)"),
+            "{raw}"
+        );
+
+        let app = test_support::app(&test_support::state(&[("AUTO_TEXT_PART", "false")]).await);
+        let (status, _) = notify(&app, request).await;
+        assert_eq!(status, StatusCode::OK);
+        let sent = test_support::sent(&app).await;
+        let raw = sent[0]["raw"].as_str().unwrap();
+        assert!(!raw.contains("text/plain"), "{raw}");
+        assert!(raw.contains("Content-Type: text/html"), "{raw}");
+    }
+
+    #[tokio::test]
+    async fn bind_retry_waits_for_the_port_to_be_freed() {
+        let held = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = held.local_addr().unwrap().to_string();
+        let release = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(300));
+            drop(held);
+        });
+
+        let started = Instant::now();
+        let listener = bind_with_retry(&addr, Duration::from_secs(5))
+            .await
+            .expect("bound once the port was freed");
+        assert_eq!(listener.local_addr().unwrap().to_string(), addr);
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        release.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn bind_without_retry_fails_fast() {
+        let held = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = held.local_addr().unwrap().to_string();
+
+        let started = Instant::now();
+        let err = bind_with_retry(&addr, Duration::ZERO)
+            .await
+            .expect_err("the port is taken");
+        assert_eq!(err.to_string(), format!("failed to bind to {addr}"));
+        assert!(started.elapsed() < Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn request_tags_label_the_send_counters() {
+        let app = test_support::app(&test_support::state(&[("METRIC_TAG_KEYS", "team")]).await);
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b", "tags": {"team": "billing"}}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b", "tags": {"user": "42"}}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["message"], "tag key not allowed: user");
+
+        let (status, metrics) = test_support::call_text(&app, Method::GET, "/metrics", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(
+            metrics
+                .contains(r#"notifications_total{outcome="sent",service="smtp",team="billing"} 1"#),
+            "{metrics}"
+        );
+        assert!(!metrics.contains("user"), "{metrics}");
+    }
+
+    #[tokio::test]
+    async fn greylisted_sends_are_deferred_and_retried() {
+        let smtp = test_support::MockSmtp::start().await;
+        smtp.reply("RCPT", "451 4.7.1 Greylisted, please try again later");
+        let state = smtp.state(&[("GREYLIST_RETRY_SECS", "1")]).await;
+        let app = test_support::app(&state);
+        let mut events = state.events.subscribe();
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::ACCEPTED, "{body}");
+        assert_eq!(body["message"], "deferred for greylisting");
+        assert!(smtp.messages().is_empty());
+
+        assert_eq!(events.recv().await.unwrap().kind, "deferred");
+        let event = tokio::time::timeout(Duration::from_secs(10), events.recv())
+            .await
+            .expect("the deferred send finishes")
+            .unwrap();
+        assert_eq!(event.kind, "sent");
+        assert_eq!(event.recipient, "ops@example.com");
+        assert_eq!(smtp.messages().len(), 1);
+        let rcpts = smtp
+            .commands()
+            .iter()
+            .filter(|command| command.starts_with("RCPT TO:<ops@example.com>"))
+            .count();
+        assert_eq!(rcpts, 2);
+    }
+
+    #[tokio::test]
+    async fn strict_validation_refuses_empty_title_and_body() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        for (request, message) in [
+            (
+                json!({"service": "smtp", "to": "ops@example.com", "title": " ", "body": "b"}),
+                "title cannot be empty",
+            ),
+            (
+                json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": ""}),
+                "body cannot be empty",
+            ),
+        ] {
+            let (status, body) = notify(&app, request).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert_eq!(body["message"], message);
+        }
+    }
+
+    #[tokio::test]
+    async fn lenient_validation_fills_in_the_defaults() {
+        let state = test_support::state(&[
+            ("STRICT_VALIDATION", "false"),
+            ("DEFAULT_SUBJECT", "Automated notice"),
+        ])
+        .await;
+        let app = test_support::app(&state);
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "", "body": ""}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let sent = test_support::sent(&app).await;
+        assert_eq!(sent[0]["subject"], "Automated notice");
+        let raw = sent[0]["raw"].as_str().unwrap();
+        assert!(raw.contains("(no content)"), "{raw}");
+
+        // `to` is still checked.
+        let (status, _) = notify(
+            &app,
+            json!({"service": "smtp", "to": "nobody", "title": "", "body": ""}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn bcc_only_sends_show_a_placeholder_to() {
+        let request =
+            json!({"service": "smtp", "bcc": "hidden@example.com", "title": "t", "body": "b"});
+
+        let app = test_support::app(&test_support::state(&[]).await);
+        let (status, body) = notify(&app, request.clone()).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let sent = test_support::sent(&app).await;
+        assert_eq!(sent[0]["to"], json!(["hidden@example.com"]));
+        let raw = sent[0]["raw"].as_str().unwrap();
+        assert!(raw.contains("To: undisclosed-recipients:;"), "{raw}");
+        assert!(!raw.contains("hidden@example.com"), "{raw}");
+
+        let app = test_support::app(&test_support::state(&[("UNDISCLOSED_TO", "from")]).await);
+        let (status, _) = notify(&app, request).await;
+        assert_eq!(status, StatusCode::OK);
+        let sent = test_support::sent(&app).await;
+        let raw = sent[0]["raw"].as_str().unwrap();
+        assert!(
+            raw.contains("To: Notifications <notify@example.com>"),
+            "{raw}"
+        );
+    }
+
+    #[tokio::test]
+    async fn sends_without_any_recipient_are_refused() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "", "cc": " ", "title": "t", "body": "b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            body["message"],
+            "at least one recipient is required in to, cc or bcc"
+        );
+    }
+
+    #[tokio::test]
+    async fn connect_timeout_bounds_an_unanswered_connect() {
+        // A listener whose backlog is full drops further SYNs, so connecting
+        // to it hangs as it does to a host that never answers.
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let listener = socket.listen(1).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _queued: Vec<_> = (0..4)
+            .filter_map(|_| {
+                std::net::TcpStream::connect_timeout(&addr, Duration::from_millis(200)).ok()
+            })
+            .collect();
+
+        let port = addr.port().to_string();
+        let cfg = smtp_config(&[
+            ("SMTP_HOST", "127.0.0.1"),
+            ("SMTP_PORT", &port),
+            ("SMTP_TLS", "false"),
+            ("SMTP_CONNECT_TIMEOUT_SECS", "1"),
+        ])
+        .unwrap();
+        assert_eq!(cfg.connect_timeout, Some(Duration::from_secs(1)));
+        let mailer = build_mailer(&cfg).unwrap();
+
+        let started = Instant::now();
+        let result = tokio::time::timeout(Duration::from_secs(10), mailer.test_connection())
+            .await
+            .expect("gave up on its own");
+        assert!(result.is_err(), "{result:?}");
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(900), "{elapsed:?}");
+        assert!(elapsed < Duration::from_secs(3), "{elapsed:?}");
+    }
+
+    #[tokio::test]
+    async fn fixed_boundary_gives_a_stable_snapshot() {
+        let app =
+            test_support::app(&test_support::state(&[("MULTIPART_BOUNDARY", "snapshot")]).await);
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "deploy", "body": "build 1234 is live", "html": "<p>build 1234 is live</p>"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let sent = test_support::sent(&app).await;
+        let raw = sent[0]["raw"].as_str().unwrap();
+        // Date and Message-ID change with every send; the rest must not.
+        let stable: Vec<&str> = raw
+            .split("\r\n")
+            .filter(|line| !line.starts_with("Date: ") && !line.starts_with("Message-ID: "))
+            .collect();
+        assert_eq!(
+            stable.join("\r\n"),
+            concat!(
+                "From: Notifications <notify@example.com>\r\n",
+                "To: ops@example.com\r\n",
+                "Subject: deploy\r\n",
+                "MIME-Version: 1.0\r\n",
+                "Content-Type: multipart/alternative; boundary=\"snapshot\"\r\n",
+                "\r\n",
+                "--snapshot\r\n",
+                "Content-Type: text/plain; charset=utf-8\r\n",
+                "Content-Transfer-Encoding: 7bit\r\n",
+                "\r\n",
+                "build 1234 is live\r\n",
+                "\r\n",
+                "--snapshot\r\n",
+                "Content-Type: text/html; charset=utf-8\r\n",
+                "Content-Transfer-Encoding: 7bit\r\n",
+                "\r\n",
+                "<p>build 1234 is live</p>\r\n",
+                "--snapshot--\r\n",
+            )
+        );
+    }
+
+    const ICS: &str = "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nMETHOD:REQUEST\r\nBEGIN:VEVENT\r\nUID:standup@example.com\r\nSUMMARY:Standup\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n";
+
+    #[tokio::test]
+    async fn calendar_invites_add_a_text_calendar_part() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "Standup", "body": "See the invite", "calendar": {"ics": ICS, "method": "request"}}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let sent = test_support::sent(&app).await;
+        let raw = sent[0]["raw"].as_str().unwrap();
+        assert!(raw.contains("Content-Type: multipart/alternative"), "{raw}");
+        assert!(
+            raw.contains("Content-Type: text/calendar; method=REQUEST; charset=utf-8"),
+            "{raw}"
+        );
+        assert!(raw.contains("SUMMARY:Standup"), "{raw}");
+        // The calendar is the preferred, last alternative.
+        assert!(raw.find("text/plain").unwrap() < raw.find("text/calendar").unwrap());
+    }
+
+    #[tokio::test]
+    async fn calendar_invites_are_validated() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        for (calendar, message) in [
+            (
+                json!({"ics": " ", "method": "REQUEST"}),
+                "calendar ics cannot be empty",
+            ),
+            (
+                json!({"ics": ICS, "method": "PUBLISH"}),
+                "unsupported calendar method: PUBLISH",
+            ),
+        ] {
+            let (status, body) = notify(
+                &app,
+                json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b", "calendar": calendar}),
+            )
+            .await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert_eq!(body["message"], message);
+        }
+    }
+
+    #[tokio::test]
+    async fn healthz_stays_small_unless_verbose() {
+        let state = test_support::state(&[]).await;
+        let app = test_support::app(&state);
+
+        let (status, body) = call(&app, Method::GET, "/healthz", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({"ok": true, "message": "ok"}));
+    }
+
+    #[tokio::test]
+    async fn verbose_healthz_reports_uptime_and_counters() {
+        let smtp = test_support::MockSmtp::start().await;
+        smtp.reply("RCPT", "550 5.1.1 no such user");
+        let state = smtp.state(&[]).await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "gone@example.com", "title": "deploy", "body": "build 1234"}),
+        )
+        .await;
+        assert!(!status.is_success(), "{body}");
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "deploy", "body": "build 1234"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        let (status, body) = call(&app, Method::GET, "/healthz?verbose=true", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["ok"], true);
+        assert!(body["uptime_secs"].is_u64(), "{body}");
+        assert_eq!(body["sent"], 1);
+        assert_eq!(body["failed"], 1);
+        assert_eq!(body["backend"], "smtp");
+        assert_eq!(body["smtp_host"], "127.0.0.1");
+    }
+
+    #[tokio::test]
+    async fn identical_notification_within_the_window_is_deduplicated() {
+        let state = test_support::state(&[]).await;
+        let app = test_support::app(&state);
+        let request = json!({
+            "service": "smtp",
+            "to": "ops@example.com",
+            "title": "disk full",
+            "body": "/var is at 99%",
+            "dedupe_window_secs": 60,
+        });
+
+        let (status, body) = notify(&app, request.clone()).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["message"], "sent");
+        let (status, body) = notify(&app, request.clone()).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["message"], "deduplicated");
+        assert!(body["original_sent_at"].is_u64(), "{body}");
+
+        let mut changed = request;
+        changed["body"] = json!("/var is at 100%");
+        let (status, body) = notify(&app, changed).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["message"], "sent");
+        assert_eq!(test_support::sent(&app).await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn flush_pool_rebuilds_the_smtp_transport() {
+        let smtp = test_support::MockSmtp::start().await;
+        let state = smtp.state(&[]).await;
+        let app = test_support::app(&state);
+        let request = json!({"service": "smtp", "to": "ops@example.com", "title": "deploy", "body": "build 1234"});
+
+        for _ in 0..2 {
+            let (status, body) = notify(&app, request.clone()).await;
+            assert_eq!(status, StatusCode::OK, "{body}");
+            // lettre parks the connection from a spawned task.
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(smtp.connections(), 1, "the pooled connection is reused");
+
+        let (status, body) = call(&app, Method::POST, "/admin/flush-pool", None).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["message"], "connection pool flushed");
+
+        let (status, body) = notify(&app, request).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(smtp.connections(), 2, "the send dials a fresh connection");
+        assert_eq!(smtp.messages().len(), 3);
+    }
+
+    /// The `Date` header lines of the one message captured by `app`.
+    async fn date_headers(app: &Router) -> Vec<String> {
+        let sent = test_support::sent(app).await;
+        let raw = sent.last().expect("a message")["raw"]
+            .as_str()
+            .expect("raw message");
+        let (headers, _) = raw.split_once("\r\n\r\n").expect("a header block");
+        headers
+            .lines()
+            .filter(|line| line.starts_with("Date:"))
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn date_header_is_set_suppressed_or_given() {
+        let state = test_support::state(&[]).await;
+        let app = test_support::app(&state);
+        let base = json!({"service": "smtp", "to": "ops@example.com", "title": "deploy", "body": "build 1234"});
+
+        let (status, body) = notify(&app, base.clone()).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let dates = date_headers(&app).await;
+        assert_eq!(dates.len(), 1, "{dates:?}");
+
+        let mut suppressed = base.clone();
+        suppressed["set_date"] = json!(false);
+        let (status, body) = notify(&app, suppressed).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(date_headers(&app).await, Vec::<String>::new());
+
+        let mut explicit = base;
+        explicit["date"] = json!("Tue, 1 Jul 2025 10:52:37 +0200");
+        let (status, body) = notify(&app, explicit).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(
+            date_headers(&app).await,
+            ["Date: Tue, 1 Jul 2025 10:52:37 +0200"]
+        );
+    }
+
+    #[tokio::test]
+    async fn explicit_date_is_validated() {
+        let state = test_support::state(&[]).await;
+        let app = test_support::app(&state);
+
+        for (extra, message) in [
+            (
+                json!({"date": "yesterday"}),
+                "date must be an RFC 2822 timestamp",
+            ),
+            (
+                json!({"date": "Tue, 1 Jul 2025 10:52:37 +0200", "set_date": false}),
+                "date cannot be combined with set_date=false",
+            ),
+        ] {
+            let mut request = json!({"service": "smtp", "to": "ops@example.com", "title": "deploy", "body": "build 1234"});
+            request
+                .as_object_mut()
+                .unwrap()
+                .extend(extra.as_object().unwrap().clone());
+            let (status, body) = notify(&app, request).await;
+            assert!(status.is_client_error(), "{status}: {body}");
+            assert_eq!(body["message"], message);
+        }
+        assert!(test_support::sent(&app).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn outbox_saves_the_message_instead_of_sending_it() {
+        let smtp = test_support::MockSmtp::start().await;
+        let dir = test_support::TempDir::new("outbox");
+        let outbox = dir.path().join("outbox");
+        let state = smtp
+            .state(&[("OUTBOX_DIR", outbox.to_str().unwrap())])
+            .await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "deploy finished", "body": "build 1234 is live"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["message"], "saved to outbox");
+
+        let files: Vec<_> = std::fs::read_dir(&outbox)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(files.len(), 1, "{files:?}");
+        assert_eq!(files[0].extension().unwrap(), "eml");
+        let saved = std::fs::read_to_string(&files[0]).unwrap();
+        assert!(saved.contains("To: ops@example.com\r\n"), "{saved}");
+        assert!(saved.contains("Subject: deploy finished\r\n"), "{saved}");
+        assert!(saved.contains("\r\n\r\nbuild 1234 is live"), "{saved}");
+        assert_eq!(smtp.connections(), 0, "nothing went over SMTP");
+    }
+
+    /// The header block of the last message captured by `app`.
+    async fn last_headers(app: &Router) -> String {
+        let sent = test_support::sent(app).await;
+        let raw = sent.last().expect("a message")["raw"]
+            .as_str()
+            .expect("raw message");
+        raw.split_once("\r\n\r\n")
+            .expect("a header block")
+            .0
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn multiple_from_addresses_get_a_sender() {
+        let state = test_support::state(&[(
+            "SMTP_FROM",
+            "Notifications <notify@example.com>, Ops <ops-team@example.com>",
+        )])
+        .await;
+        let app = test_support::app(&state);
+        let request = json!({"service": "smtp", "to": "ops@example.com", "title": "deploy", "body": "build 1234"});
+
+        let (status, body) = notify(&app, request.clone()).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let headers = last_headers(&app).await;
+        assert!(
+            headers.contains(
+                "From: Notifications <notify@example.com>, Ops <ops-team@example.com>\r\n"
+            ),
+            "{headers}"
+        );
+        assert!(
+            headers.contains("Sender: Notifications <notify@example.com>\r\n"),
+            "{headers}"
+        );
+
+        let mut explicit = request;
+        explicit["sender"] = json!("bounces@example.com");
+        let (status, body) = notify(&app, explicit).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let headers = last_headers(&app).await;
+        assert!(
+            headers.contains("Sender: bounces@example.com\r\n"),
+            "{headers}"
+        );
+        let sent = test_support::sent(&app).await;
+        assert_eq!(
+            sent[1]["from"], "bounces@example.com",
+            "bounces go to the sender"
+        );
+    }
+
+    #[tokio::test]
+    async fn single_from_address_has_no_sender() {
+        let state = test_support::state(&[]).await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "deploy", "body": "build 1234"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let headers = last_headers(&app).await;
+        assert!(!headers.contains("Sender:"), "{headers}");
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "deploy", "body": "build 1234", "sender": "not an address"}),
+        )
+        .await;
+        assert!(status.is_client_error(), "{status}: {body}");
+        assert_eq!(body["message"], "invalid sender email");
+    }
+
+    #[tokio::test]
+    async fn notify_checks_the_request_schema() {
+        let dir = test_support::TempDir::new("schema");
+        let schema = dir.path().join("schema.json");
+        std::fs::write(
+            &schema,
+            r#"{"properties": {"tags": {"required": ["team"]}}, "required": ["tags"]}"#,
+        )
+        .expect("schema written");
+        let state = test_support::state(&[
+            (
+                "REQUEST_SCHEMA_FILE",
+                schema.to_str().expect("utf-8 temp dir"),
+            ),
+            ("METRIC_TAG_KEYS", "team,env"),
+        ])
+        .await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b", "tags": {"env": "prod"}}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            body["message"],
+            "request does not match schema: /tags: \"team\" is a required property"
+        );
+        assert!(test_support::sent(&app).await.is_empty());
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b", "tags": {"team": "payments"}}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(test_support::sent(&app).await.len(), 1);
+    }
+
+    #[test]
+    fn local_bind_address_is_parsed_from_the_env() {
+        let smtp = smtp_config(&[("SMTP_LOCAL_BIND_ADDR", " 127.0.0.2 ")]).expect("config loads");
+        assert_eq!(smtp.local_bind, Some(IpAddr::from([127, 0, 0, 2])));
+
+        let err = smtp_config(&[("SMTP_LOCAL_BIND_ADDR", "eth0")]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "SMTP_LOCAL_BIND_ADDR is not an IP address: eth0"
+        );
+    }
+
+    #[tokio::test]
+    async fn bound_transport_requires_a_local_source_address() {
+        let smtp = smtp_config(&[("SMTP_LOCAL_BIND_ADDR", "127.0.0.2")]).expect("config loads");
+        let transport = build_bound_transport(&smtp).expect("loopback is local");
+        assert_eq!(transport.local_addr, Some(IpAddr::from([127, 0, 0, 2])));
+
+        // TEST-NET-1 is never assigned to a host.
+        let smtp = smtp_config(&[("SMTP_LOCAL_BIND_ADDR", "192.0.2.1")]).expect("config loads");
+        let err = build_bound_transport(&smtp)
+            .err()
+            .expect("not a local address");
+        assert_eq!(
+            err.to_string(),
+            "source address 192.0.2.1 is not a local address"
+        );
+    }
+
+    #[tokio::test]
+    async fn sends_leave_from_the_local_bind_address() {
+        let smtp = test_support::MockSmtp::start().await;
+        let state = smtp.state(&[("SMTP_LOCAL_BIND_ADDR", "127.0.0.2")]).await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "deploy", "body": "build 1234"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let peers: Vec<_> = smtp.peers().iter().map(std::net::SocketAddr::ip).collect();
+        assert_eq!(peers, [IpAddr::from([127, 0, 0, 2])]);
+        assert_eq!(smtp.messages().len(), 1);
+    }
+
+    #[test]
+    fn recipient_batches_split_the_envelope() {
+        let to: Vec<Address> = (0..5)
+            .map(|n| format!("user{n}@example.com").parse().unwrap())
+            .collect();
+        let from: Address = "notify@example.com".parse().unwrap();
+        let envelope = Envelope::new(Some(from.clone()), to.clone()).unwrap();
+
+        assert_eq!(recipient_batches(&envelope, None), vec![envelope.clone()]);
+        let batches = recipient_batches(&envelope, Some(2));
+        let sizes: Vec<_> = batches.iter().map(|batch| batch.to().len()).collect();
+        assert_eq!(sizes, [2, 2, 1]);
+        assert!(batches.iter().all(|batch| batch.from() == Some(&from)));
+        let rejoined: Vec<_> = batches
+            .iter()
+            .flat_map(|batch| batch.to().to_vec())
+            .collect();
+        assert_eq!(rejoined, to);
+    }
+
+    #[tokio::test]
+    async fn large_bcc_lists_are_split_into_transactions() {
+        let smtp = test_support::MockSmtp::start().await;
+        let state = smtp
+            .state(&[
+                ("MAX_RCPT_PER_TRANSACTION", "100"),
+                ("MAX_RECIPIENTS_PER_MESSAGE", "500"),
+            ])
+            .await;
+        let app = test_support::app(&state);
+        let bcc: Vec<_> = (0..250).map(|n| format!("user{n}@example.com")).collect();
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "bcc": bcc.join(","), "title": "deploy", "body": "build 1234"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        let mut per_transaction = Vec::new();
+        for command in smtp.commands() {
+            if command.starts_with("MAIL FROM:") {
+                per_transaction.push(0);
+            } else if command.starts_with("RCPT TO:") {
+                *per_transaction.last_mut().expect("RCPT after MAIL") += 1;
+            }
+        }
+        assert_eq!(per_transaction, [100, 100, 51]);
+        let messages = smtp.messages();
+        assert_eq!(messages.len(), 3);
+        assert!(messages.iter().all(|message| message == &messages[0]));
+        assert!(!messages[0].contains("Bcc:"), "{}", messages[0]);
+    }
+
+    #[tokio::test]
+    async fn slow_sends_are_logged() {
+        let capture = test_support::Capture::default();
+        let _subscriber = tracing::subscriber::set_default(capture.subscriber());
+        let smtp = test_support::MockSmtp::start().await;
+        let state = smtp.state(&[("SLOW_SEND_WARN_MS", "100")]).await;
+        let app = test_support::app(&state);
+        let request = json!({"service": "smtp", "to": "ops@example.com", "title": "deploy", "body": "build 1234"});
+
+        let (status, body) = notify(&app, request.clone()).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let slow = |capture: &test_support::Capture| {
+            capture
+                .lines()
+                .into_iter()
+                .filter(|line| line.contains("slow send"))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(slow(&capture), Vec::<String>::new());
+
+        smtp.delay("DATA", Duration::from_millis(150));
+        let (status, body) = notify(&app, request).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let slow = slow(&capture);
+        assert_eq!(slow.len(), 1, "{slow:?}");
+        assert!(slow[0].contains("WARN"), "{}", slow[0]);
+        assert!(slow[0].contains("to=ops@example.com"), "{}", slow[0]);
+        assert!(slow[0].contains("succeeded=true"), "{}", slow[0]);
+        let elapsed_ms: u64 = slow[0]
+            .split("elapsed_ms=")
+            .nth(1)
+            .and_then(|rest| rest.split(' ').next())
+            .and_then(|millis| millis.parse().ok())
+            .expect("elapsed_ms logged");
+        assert!(elapsed_ms >= 150, "{elapsed_ms}");
+    }
+
+    #[tokio::test]
+    async fn subject_encodings_render_valid_encoded_words() {
+        let state = test_support::state(&[]).await;
+        let app = test_support::app(&state);
+        let title = "🚀 デプロイ完了 服务器已上线";
+
+        for (encoding, marker) in [
+            (None, "=?utf-8?"),
+            (Some("auto"), "=?utf-8?"),
+            (Some("base64"), "=?utf-8?b?"),
+            (Some("quoted-printable"), "=?utf-8?q?"),
+        ] {
+            let mut request =
+                json!({"service": "smtp", "to": "ops@example.com", "title": title, "body": "b"});
+            if let Some(encoding) = encoding {
+                request["subject_encoding"] = json!(encoding);
+            }
+            let (status, body) = notify(&app, request).await;
+            assert_eq!(status, StatusCode::OK, "{body}");
+
+            let sent = test_support::sent(&app).await;
+            let raw = sent.last().unwrap()["raw"].as_str().unwrap();
+            let subject = test_support::header_value(raw, "Subject").expect("a subject");
+            assert!(subject.starts_with(marker), "{encoding:?}: {subject}");
+            assert_eq!(test_support::decode_words(&subject), title, "{encoding:?}");
+        }
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": title, "body": "b", "subject_encoding": "utf-7"}),
+        )
+        .await;
+        assert!(status.is_client_error(), "{status}: {body}");
+    }
+
+    #[test]
+    fn list_ids_are_dotted_atoms() {
+        for id in ["alerts.example.com", "ops-team.lists.example.org", "a.b"] {
+            assert!(is_list_id(id), "{id}");
+        }
+        for id in [
+            "alerts",
+            "alerts..example.com",
+            ".example.com",
+            "alerts example.com",
+            "<alerts.example.com>",
+        ] {
+            assert!(!is_list_id(id), "{id}");
+        }
+        assert!(!is_list_id(&format!("{}.com", "a".repeat(252))));
+    }
+
+    #[tokio::test]
+    async fn list_id_sets_the_list_headers() {
+        let state = test_support::state(&[]).await;
+        let app = test_support::app(&state);
+        let base = json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b", "list_id": "alerts.example.com"});
+
+        let (status, body) = notify(&app, base.clone()).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let sent = test_support::sent(&app).await;
+        let raw = sent[0]["raw"].as_str().unwrap();
+        assert_eq!(
+            test_support::header_value(raw, "List-Id").as_deref(),
+            Some("<alerts.example.com>")
+        );
+        assert_eq!(
+            test_support::header_value(raw, "List-Post").as_deref(),
+            Some("<mailto:notify@example.com>")
+        );
+
+        let mut post = base.clone();
+        post["list_post"] = json!("alerts@example.com");
+        let (status, body) = notify(&app, post).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let sent = test_support::sent(&app).await;
+        let raw = sent[1]["raw"].as_str().unwrap();
+        assert_eq!(
+            test_support::header_value(raw, "List-Post").as_deref(),
+            Some("<mailto:alerts@example.com>")
+        );
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let sent = test_support::sent(&app).await;
+        let raw = sent[2]["raw"].as_str().unwrap();
+        assert_eq!(test_support::header_value(raw, "List-Id"), None);
+    }
+
+    #[tokio::test]
+    async fn list_fields_are_validated() {
+        let state = test_support::state(&[]).await;
+        let app = test_support::app(&state);
+
+        for (extra, message) in [
+            (
+                json!({"list_id": "alerts"}),
+                "list_id must be a dotted identifier such as alerts.example.com",
+            ),
+            (
+                json!({"list_post": "alerts@example.com"}),
+                "list_post requires list_id",
+            ),
+            (
+                json!({"list_id": "alerts.example.com", "list_post": "nope"}),
+                "invalid list_post email",
+            ),
+        ] {
+            let mut request =
+                json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b"});
+            request
+                .as_object_mut()
+                .unwrap()
+                .extend(extra.as_object().unwrap().clone());
+            let (status, body) = notify(&app, request).await;
+            assert!(status.is_client_error(), "{status}: {body}");
+            assert_eq!(body["message"], message);
+        }
+    }
+
+    #[tokio::test]
+    async fn body_wrapper_applies_unless_skipped() {
+        let dir = test_support::TempDir::new("wrapper");
+        let file = |name: &str, contents: &str| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, contents).expect("wrapper written");
+            path.to_str().expect("utf-8 temp dir").to_string()
+        };
+        let (header, footer) = (file("h.txt", "ACME Corp"), file("f.txt", "Legal text."));
+        let (html_header, html_footer) = (
+            file("h.html", "<p>ACME</p>"),
+            file("f.html", "<p>Legal</p>"),
+        );
+        let state = test_support::state(&[
+            ("BODY_HEADER", &header),
+            ("BODY_FOOTER", &footer),
+            ("BODY_HEADER_HTML", &html_header),
+            ("BODY_FOOTER_HTML", &html_footer),
+        ])
+        .await;
+        let app = test_support::app(&state);
+        let request = json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "plain", "html": "<b>rich</b>"});
+
+        let (status, body) = notify(&app, request.clone()).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let sent = test_support::sent(&app).await;
+        let raw = sent[0]["raw"].as_str().unwrap();
+        assert!(raw.contains("ACME Corp\r\nplain\r\nLegal text."), "{raw}");
+        assert!(raw.contains("<p>ACME</p><b>rich</b><p>Legal</p>"), "{raw}");
+
+        let mut skipped = request;
+        skipped["skip_wrapper"] = json!(true);
+        let (status, body) = notify(&app, skipped).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let sent = test_support::sent(&app).await;
+        let raw = sent[1]["raw"].as_str().unwrap();
+        assert!(!raw.contains("ACME") && !raw.contains("Legal"), "{raw}");
+        assert!(
+            raw.contains("plain") && raw.contains("<b>rich</b>"),
+            "{raw}"
+        );
+    }
+
+    #[test]
+    fn failure_kind_classifies_the_terminal_error() {
+        let timeout = TransportError::Timeout(Duration::from_secs(1));
+        assert_eq!(FailureKind::of(&timeout, true), FailureKind::Timeout);
+        assert_eq!(FailureKind::of(&timeout, false), FailureKind::Timeout);
+        let refused = TransportError::Ses(Box::new(
+            aws_sdk_ses::error::SdkError::construction_failure("bad request"),
+        ));
+        assert_eq!(FailureKind::of(&refused, false), FailureKind::Permanent);
+        assert_eq!(
+            FailureKind::of(&refused, true),
+            FailureKind::RetriesExhausted
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_sends_log_their_failure_kind() {
+        async fn failure_kind(smtp: &test_support::MockSmtp, vars: &[(&str, &str)]) -> String {
+            let capture = test_support::Capture::default();
+            let _subscriber = tracing::subscriber::set_default(capture.subscriber());
+            let app = test_support::app(&smtp.state(vars).await);
+            let (status, body) = notify(
+                &app,
+                json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b"}),
+            )
+            .await;
+            assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR, "{body}");
+            let lines = capture.lines();
+            let failed = lines
+                .iter()
+                .find(|line| line.contains("send failed"))
+                .expect("a failure logged");
+            failed
+                .split("failure_kind=")
+                .nth(1)
+                .and_then(|rest| rest.split(' ').next())
+                .expect("failure_kind logged")
+                .trim_matches('"')
+                .to_string()
+        }
+
+        let smtp = test_support::MockSmtp::start().await;
+        smtp.reply("RCPT", "550 5.1.1 no such user");
+        assert_eq!(
+            failure_kind(&smtp, &[("SMTP_RETRY_MAX", "3")]).await,
+            "permanent"
+        );
+        assert_eq!(
+            smtp.commands()
+                .iter()
+                .filter(|c| c.starts_with("MAIL"))
+                .count(),
+            1
+        );
+
+        let smtp = test_support::MockSmtp::start().await;
+        smtp.reply("DATA", "451 4.3.0 try again");
+        smtp.reply("DATA", "451 4.3.0 try again");
+        let retries = [("SMTP_RETRY_MAX", "1"), ("SMTP_RETRY_BASE_MS", "1")];
+        assert_eq!(failure_kind(&smtp, &retries).await, "retries_exhausted");
+        assert_eq!(smtp.commands().iter().filter(|c| *c == "DATA").count(), 2);
+
+        let smtp = test_support::MockSmtp::start().await;
+        smtp.delay("DATA", Duration::from_secs(3));
+        let timeout = [("SMTP_SEND_TIMEOUT_SECS", "1")];
+        assert_eq!(failure_kind(&smtp, &timeout).await, "timeout");
+    }
+
+    #[tokio::test]
+    async fn key_from_must_be_in_an_allowed_domain() {
+        let dir = test_support::TempDir::new("api-keys");
+        let path = dir.path().join("keys.json");
+        std::fs::write(
+            &path,
+            json!({test_support::API_KEY: {"from": "Billing <billing@tenant.example>"}})
+                .to_string(),
+        )
+        .unwrap();
+        let request =
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b"});
+
+        for (allowed, expected) in [
+            ("Tenant.Example, other.example", StatusCode::OK),
+            ("other.example", StatusCode::FORBIDDEN),
+        ] {
+            let state = test_support::state(&[
+                ("API_KEY", ""),
+                ("API_KEYS_FILE", path.to_str().unwrap()),
+                ("FROM_ALLOWED_DOMAINS", allowed),
+            ])
+            .await;
+            let app = test_support::app(&state);
+            let (status, body) = notify(&app, request.clone()).await;
+            assert_eq!(status, expected, "{allowed}: {body}");
+            if expected != StatusCode::OK {
+                assert_eq!(body["message"], "from domain not permitted");
+                assert!(test_support::sent(&app).await.is_empty());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn sender_override_must_be_in_an_allowed_domain() {
+        let state = test_support::state(&[("FROM_ALLOWED_DOMAINS", "tenant.example")]).await;
+        let app = test_support::app(&state);
+
+        for (sender, expected) in [
+            // The configured From's domain is always allowed.
+            ("bounces@example.com", StatusCode::OK),
+            ("bounces@TENANT.example", StatusCode::OK),
+            ("ceo@evil.example", StatusCode::FORBIDDEN),
+        ] {
+            let (status, body) = notify(
+                &app,
+                json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b", "sender": sender}),
+            )
+            .await;
+            assert_eq!(status, expected, "{sender}: {body}");
+        }
+        assert_eq!(test_support::sent(&app).await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn readyz_is_503_until_the_pool_is_warm() {
+        let smtp = test_support::MockSmtp::start().await;
+        // The first warmup connection fails, so readiness waits for a retry.
+        smtp.reply("EHLO", "554 5.3.2 not accepting connections yet");
+        let port = smtp.port();
+        let cfg = test_support::config(&[
+            ("BACKEND", "smtp"),
+            ("SMTP_HOST", "127.0.0.1"),
+            ("SMTP_PORT", &port),
+            ("SMTP_TLS", "false"),
+            ("SMTP_WARMUP", "true"),
+        ])
+        .unwrap();
+        let (state, serving) = build_state(cfg).await.unwrap();
+        let app = test_support::app(&state);
+        let (mailer, count) = serving.warmup.expect("warmup configured");
+
+        let (status, body) = call(&app, Method::GET, "/readyz", None).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["message"], "warming up");
+
+        let warmup = tokio::spawn(warm_up_pool(state.clone(), mailer, count));
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let (status, _) = call(&app, Method::GET, "/readyz", None).await;
+        assert_eq!(
+            status,
+            StatusCode::SERVICE_UNAVAILABLE,
+            "first connection failed"
+        );
+
+        tokio::time::timeout(Duration::from_secs(3), warmup)
+            .await
+            .expect("warmup retries within a second")
+            .unwrap();
+        let (status, body) = call(&app, Method::GET, "/readyz", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["message"], "ready");
+        assert_eq!(smtp.connections(), 2);
+    }
+
+    #[tokio::test]
+    async fn readyz_is_ready_without_warmup() {
+        let state = test_support::state(&[]).await;
+        let app = test_support::app(&state);
+
+        let (status, body) = call(&app, Method::GET, "/readyz", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["message"], "ready");
+    }
+
+    #[test]
+    fn security_is_suggested_for_the_other_modes_port() {
+        assert_eq!(
+            SmtpSecurity::Tls.suggested_for(587),
+            Some(SmtpSecurity::StartTls)
+        );
+        assert_eq!(
+            SmtpSecurity::StartTls.suggested_for(465),
+            Some(SmtpSecurity::Tls)
+        );
+        for security in [SmtpSecurity::Tls, SmtpSecurity::StartTls] {
+            assert_eq!(security.suggested_for(25), Some(SmtpSecurity::None));
+        }
+        for (port, security) in [
+            (465, SmtpSecurity::Tls),
+            (587, SmtpSecurity::StartTls),
+            (25, SmtpSecurity::None),
+            (2525, SmtpSecurity::Tls),
+        ] {
+            assert_eq!(security.suggested_for(port), None, "{port} {security:?}");
+        }
+    }
+
+    #[test]
+    fn port_security_mismatch_warns() {
+        let capture = test_support::Capture::default();
+        let _subscriber = tracing::subscriber::set_default(capture.subscriber());
+
+        let smtp = smtp_config(&[("SMTP_PORT", "465"), ("SMTP_SECURITY", "starttls")])
+            .expect("a mismatch only warns");
+        assert_eq!(smtp.security, SmtpSecurity::StartTls);
+        smtp_config(&[("SMTP_PORT", "587"), ("SMTP_SECURITY", "starttls")]).unwrap();
+        smtp_config(&[("SMTP_PORT", "25"), ("SMTP_SECURITY", "tls")]).unwrap();
+        // Left to `SMTP_TLS`, implicit TLS on 587 is checked all the same.
+        smtp_config(&[("SMTP_PORT", "587")]).unwrap();
+        smtp_config(&[("SMTP_PORT", "25"), ("SMTP_TLS", "false")]).unwrap();
+
+        let warnings: Vec<_> = capture
+            .lines()
+            .into_iter()
+            .filter(|line| line.contains("usually expects"))
+            .collect();
+        assert_eq!(warnings.len(), 3, "{warnings:?}");
+        assert!(
+            warnings.iter().all(|line| line.contains("WARN")),
+            "{warnings:?}"
+        );
+        for (warning, expected) in warnings.iter().zip([
+            "SMTP_PORT 465 usually expects SMTP_SECURITY=tls, but starttls is configured",
+            "SMTP_PORT 25 usually expects SMTP_SECURITY=none, but tls is configured",
+            "SMTP_PORT 587 usually expects SMTP_SECURITY=starttls, but tls is configured",
+        ]) {
+            assert!(warning.contains(expected), "{warning}");
+        }
+    }
+
+    #[test]
+    fn port_security_mismatch_fails_when_strict() {
+        let err = smtp_config(&[
+            ("SMTP_PORT", "587"),
+            ("SMTP_SECURITY", "tls"),
+            ("SMTP_SECURITY_STRICT", "true"),
+        ])
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "SMTP_PORT 587 usually expects SMTP_SECURITY=starttls, but tls is configured"
+        );
+
+        let err = smtp_config(&[
+            ("SMTP_SECURITY", "starttls"),
+            ("SMTP_FALLBACK_1_HOST", "backup.example.com"),
+            ("SMTP_FALLBACK_1_PORT", "465"),
+            ("SMTP_SECURITY_STRICT", "true"),
+        ])
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "SMTP_FALLBACK_1_PORT 465 usually expects SMTP_FALLBACK_1_SECURITY=tls, but starttls is configured"
+        );
+
+        let err = smtp_config(&[
+            ("SMTP_PORT", "25"),
+            ("SMTP_SECURITY", "tls"),
+            ("SMTP_SECURITY_STRICT", "true"),
+        ])
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "SMTP_PORT 25 usually expects SMTP_SECURITY=none, but tls is configured"
+        );
+        let err = smtp_config(&[("SMTP_PORT", "587"), ("SMTP_SECURITY_STRICT", "true")])
+            .expect_err("the SMTP_TLS default is checked too");
+        assert_eq!(
+            err.to_string(),
+            "SMTP_PORT 587 usually expects SMTP_SECURITY=starttls, but tls is configured"
+        );
+
+        smtp_config(&[
+            ("SMTP_PORT", "465"),
+            ("SMTP_SECURITY", "tls"),
+            ("SMTP_SECURITY_STRICT", "true"),
+        ])
+        .expect("a matching port loads");
+        smtp_config(&[
+            ("SMTP_PORT", "25"),
+            ("SMTP_SECURITY", "starttls"),
+            ("SMTP_SECURITY_STRICT", "true"),
+        ])
+        .expect("STARTTLS on 25 only warns");
+    }
+
+    #[test]
+    fn from_display_names_may_hold_special_characters() {
+        let parsed = |raw: &str| -> Vec<(Option<String>, String)> {
+            parse_mailbox_list(raw)
+                .unwrap_or_else(|err| panic!("{raw}: {err:#}"))
+                .into_iter()
+                .map(|mailbox| (mailbox.name, mailbox.email.to_string()))
+                .collect()
+        };
+        let one = |name: &str, email: &str| vec![(Some(name.to_string()), email.to_string())];
+
+        assert_eq!(
+            parsed(r#""Acme, Inc." <notify@acme.example>"#),
+            one("Acme, Inc.", "notify@acme.example")
+        );
+        assert_eq!(
+            parsed("Acme, Inc. <notify@acme.example>"),
+            one("Acme, Inc.", "notify@acme.example")
+        );
+        assert_eq!(
+            parsed("Ops (on-call) <ops@example.com>"),
+            one("Ops (on-call)", "ops@example.com")
+        );
+        assert_eq!(
+            parsed(r#""O\"Brien \\ Co" <ob@example.com>"#),
+            one(r#"O"Brien \ Co"#, "ob@example.com")
+        );
+        assert_eq!(
+            parsed("Acme, Inc. <notify@acme.example>, alerts@acme.example"),
+            [
+                (
+                    Some("Acme, Inc.".to_string()),
+                    "notify@acme.example".to_string()
+                ),
+                (None, "alerts@acme.example".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn from_parse_errors_point_at_the_problem() {
+        for (raw, message) in [
+            (
+                r#""Acme <notify@acme.example>"#,
+                r#"unterminated quote in `"Acme <notify@acme.example>`"#,
+            ),
+            (
+                "Acme <notify@acme.example",
+                "missing closing `>` in `Acme <notify@acme.example`",
+            ),
+            ("Acme Inc", "`Acme Inc` has no email address"),
+            (
+                "Acme <notify@acme.example> x",
+                "`Acme <notify@acme.example> x` has no email address",
+            ),
+        ] {
+            let err = parse_mailbox_list(raw).unwrap_err();
+            assert_eq!(format!("{err:#}"), message, "{raw}");
+        }
+
+        let err = parse_mailbox_list("ops@example.com, Ops <not an address>").unwrap_err();
+        let err = format!("{err:#}");
+        assert!(
+            err.starts_with("entry 2 `Ops <not an address>`: invalid address `not an address`"),
+            "{err}"
+        );
+
+        let err = test_support::config(&[("SMTP_FROM", "Acme, Inc.")]).unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "SMTP_FROM is not a valid list of email addresses: `Acme, Inc.` has no email address"
+        );
+    }
+
+    #[tokio::test]
+    async fn from_display_name_with_a_comma_is_encoded_on_the_wire() {
+        let state = test_support::state(&[("SMTP_FROM", "Acme, Inc. <notify@acme.example>")]).await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let sent = test_support::sent(&app).await;
+        let raw = sent[0]["raw"].as_str().unwrap();
+        let from = test_support::header_value(raw, "From").expect("a From header");
+        let (name, address) = from.split_once(" <").expect("a display name");
+        assert_eq!(test_support::decode_words(name), "Acme, Inc.");
+        assert_eq!(address, "notify@acme.example>");
+    }
+
+    #[tokio::test]
+    async fn email_and_slack_retry_under_their_own_policies() {
+        let dir = test_support::TempDir::new("retry");
+        let policies = dir.path().join("retry.json");
+        std::fs::write(
+            &policies,
+            r#"{"smtp": {"max_retries": 0}, "slack": {"max_retries": 2, "base_ms": 1}}"#,
+        )
+        .unwrap();
+        let smtp = test_support::MockSmtp::start().await;
+        smtp.reply("DATA", "451 4.3.0 try again");
+        let slack =
+            test_support::MockSlack::start(json!({"ok": false, "error": "ratelimited"})).await;
+        let mut vars = vec![
+            ("RETRY_POLICIES_FILE", policies.to_str().unwrap()),
+            ("SMTP_RETRY_MAX", "5"),
+            ("SMTP_RETRY_BASE_MS", "1"),
+        ];
+        vars.extend(slack.vars());
+        let app = test_support::app(&smtp.state(&vars).await);
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR, "{body}");
+        assert_eq!(smtp.commands().iter().filter(|c| *c == "DATA").count(), 1);
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "slack", "to": "#ops", "title": "t", "body": "b"}),
+        )
+        .await;
+        assert!(!status.is_success(), "{body}");
+        assert_eq!(slack.posts().len(), 3, "the first try and two retries");
+    }
+
+    #[tokio::test]
+    async fn failing_primary_fails_over_to_the_secondary() {
+        let primary = test_support::MockSmtp::start().await;
+        let secondary = test_support::MockSmtp::start().await;
+        primary.reply("MAIL", "421 4.3.2 service shutting down");
+        let port = secondary.port();
+        let state = primary
+            .state(&[
+                ("SMTP_FALLBACK_1_HOST", "127.0.0.1"),
+                ("SMTP_FALLBACK_1_PORT", &port),
+            ])
+            .await;
+        let app = test_support::app(&state);
+        let request =
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b"});
+
+        let (status, body) = notify(&app, request.clone()).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert!(primary.messages().is_empty());
+        assert_eq!(secondary.messages().len(), 1);
+
+        // The primary cools down, so the next send skips it.
+        let tried = primary.commands().len();
+        let (status, body) = notify(&app, request).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(primary.commands().len(), tried);
+        assert_eq!(secondary.messages().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn permanent_rejection_does_not_fail_over() {
+        let primary = test_support::MockSmtp::start().await;
+        let secondary = test_support::MockSmtp::start().await;
+        primary.reply("RCPT", "550 5.1.1 no such user");
+        let port = secondary.port();
+        let state = primary
+            .state(&[
+                ("SMTP_FALLBACK_1_HOST", "127.0.0.1"),
+                ("SMTP_FALLBACK_1_PORT", &port),
+            ])
+            .await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "gone@example.com", "title": "t", "body": "b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR, "{body}");
+        assert_eq!(secondary.connections(), 0);
+    }
+
+    #[tokio::test]
+    async fn max_connections_wraps_the_transport_in_a_limit() {
+        let smtp = smtp_config(&[("SMTP_MAX_CONNECTIONS", "2")]).expect("config loads");
+        assert_eq!(smtp.max_connections, Some(2));
+        assert_eq!(smtp_config(&[]).unwrap().max_connections, None);
+
+        let mock = test_support::MockSmtp::start().await;
+        let state = mock.state(&[("SMTP_MAX_CONNECTIONS", "2")]).await;
+        let app = test_support::app(&state);
+        let sends = (0..4).map(|n| {
+            notify(
+                &app,
+                json!({"service": "smtp", "to": format!("user{n}@example.com"), "title": "t", "body": "b"}),
+            )
+        });
+        for (status, body) in futures::future::join_all(sends).await {
+            assert_eq!(status, StatusCode::OK, "{body}");
+        }
+        assert_eq!(mock.messages().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn redirect_all_to_keeps_the_original_recipients_in_a_header() {
+        let state = test_support::state(&[("REDIRECT_ALL_TO", "qa@example.com")]).await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "cc": "lead@example.com", "bcc": "audit@example.com", "title": "t", "body": "b", "copy_sender": true}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let sent = test_support::sent(&app).await;
+        assert_eq!(sent[0]["to"], json!(["qa@example.com"]));
+        let raw = sent[0]["raw"].as_str().expect("raw message");
+        assert_eq!(
+            test_support::header_value(raw, "X-Original-To").as_deref(),
+            Some("ops@example.com, lead@example.com, audit@example.com")
+        );
+        assert_eq!(
+            test_support::header_value(raw, "To").as_deref(),
+            Some("qa@example.com")
+        );
+        assert_eq!(test_support::header_value(raw, "Cc"), None);
+    }
+
+    #[tokio::test]
+    async fn without_redirect_all_to_there_is_no_original_to_header() {
+        let state = test_support::state(&[]).await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let raw = last_headers(&app).await;
+        assert_eq!(test_support::header_value(&raw, "X-Original-To"), None);
+    }
+
+    #[test]
+    fn an_invalid_redirect_all_to_is_refused() {
+        let err = test_support::config(&[("REDIRECT_ALL_TO", "not an address")]).unwrap_err();
+        assert!(
+            err.to_string().contains("invalid REDIRECT_ALL_TO address"),
+            "{err:#}"
+        );
+    }
+
+    #[tokio::test]
+    async fn content_transfer_encoding_pins_the_body_encoding() {
+        let state = test_support::state(&[]).await;
+        let app = test_support::app(&state);
+
+        for (encoding, header) in [
+            ("auto", "7bit"),
+            ("7bit", "7bit"),
+            ("8bit", "8bit"),
+            ("quoted-printable", "quoted-printable"),
+            ("base64", "base64"),
+        ] {
+            let (status, body) = notify(
+                &app,
+                json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "build 1234 is live", "content_transfer_encoding": encoding}),
+            )
+            .await;
+            assert_eq!(status, StatusCode::OK, "{encoding}: {body}");
+            let headers = last_headers(&app).await;
+            assert_eq!(
+                test_support::header_value(&headers, "Content-Transfer-Encoding").as_deref(),
+                Some(header),
+                "{encoding}: {headers}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn content_transfer_encoding_applies_to_every_body_part() {
+        let state = test_support::state(&[]).await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "plain", "html": "<p>rich</p>", "content_transfer_encoding": "base64"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let sent = test_support::sent(&app).await;
+        let raw = sent[0]["raw"].as_str().expect("raw message");
+        assert_eq!(
+            raw.matches("Content-Transfer-Encoding: base64").count(),
+            2,
+            "{raw}"
+        );
+    }
+
+    #[tokio::test]
+    async fn a_body_that_does_not_fit_the_pinned_encoding_is_refused() {
+        let state = test_support::state(&[]).await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "déploiement terminé", "content_transfer_encoding": "7bit"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{body}");
+        assert_eq!(body["message"], "body cannot be sent as 7bit");
+        assert!(test_support::sent(&app).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn encrypt_sends_a_multipart_encrypted_message() {
+        let keys = test_support::pgp_keys(&["ops@example.com"]);
+        let dir = keys.path().to_str().expect("utf-8 path");
+        let state = test_support::state(&[("PGP_KEYS_DIR", dir)]).await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "build 1234 is live", "encrypt": true}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let sent = test_support::sent(&app).await;
+        let raw = sent[0]["raw"].as_str().expect("raw message");
+        let content_type = test_support::header_value(raw, "Content-Type").expect("a content type");
+        assert!(
+            content_type.starts_with("multipart/encrypted;"),
+            "{content_type}"
+        );
+        assert!(raw.contains("-----BEGIN PGP MESSAGE-----"), "{raw}");
+        assert!(!raw.contains("build 1234 is live"), "{raw}");
+    }
+
+    #[tokio::test]
+    async fn encrypt_to_a_recipient_without_a_key_fails_or_sends_plaintext() {
+        let keys = test_support::pgp_keys(&["ops@example.com"]);
+        let dir = keys.path().to_str().expect("utf-8 path");
+        let request = json!({"service": "smtp", "to": "ops@example.com, nobody@example.com", "title": "t", "body": "build 1234 is live", "encrypt": true});
+
+        let state = test_support::state(&[("PGP_KEYS_DIR", dir)]).await;
+        let app = test_support::app(&state);
+        let (status, body) = notify(&app, request.clone()).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{body}");
+        assert_eq!(body["message"], "no pgp key for nobody@example.com");
+        assert!(test_support::sent(&app).await.is_empty());
+
+        let state =
+            test_support::state(&[("PGP_KEYS_DIR", dir), ("PGP_MISSING_KEY", "plaintext")]).await;
+        let app = test_support::app(&state);
+        let (status, body) = notify(&app, request).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let sent = test_support::sent(&app).await;
+        let raw = sent[0]["raw"].as_str().expect("raw message");
+        assert!(raw.contains("build 1234 is live"), "{raw}");
+    }
+
+    #[tokio::test]
+    async fn encrypt_without_pgp_keys_is_refused() {
+        let state = test_support::state(&[]).await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b", "encrypt": true}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{body}");
+        assert_eq!(body["message"], "encryption is not configured");
+    }
+
+    #[tokio::test]
+    async fn inline_cid_attachments_sit_beside_the_html_in_multipart_related() {
+        let state = test_support::state(&[]).await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "plain",
+                "html": "<p><img src=\"cid:logo\"></p>",
+                "attachments": [
+                    {"filename": "logo.png", "content": "iVBORw0KGgo=", "content_type": "image/png", "disposition": "inline", "cid": "logo"},
+                    {"filename": "report.csv", "content": "YSxiCg==", "content_type": "text/csv"}
+                ]}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let sent = test_support::sent(&app).await;
+        let raw = sent[0]["raw"].as_str().expect("raw message");
+        let content_type = test_support::header_value(raw, "Content-Type").expect("a content type");
+        assert!(
+            content_type.starts_with("multipart/mixed"),
+            "{content_type}"
+        );
+        let related = raw.find("multipart/related").expect("a related part");
+        let logo = raw.find("Content-ID: <logo>").expect("the inline part");
+        let report = raw
+            .find("Content-Disposition: attachment; filename=\"report.csv\"")
+            .expect("the download");
+        assert!(related < logo && logo < report, "{raw}");
+    }
+
+    #[tokio::test]
+    async fn a_cid_on_a_download_is_refused() {
+        let state = test_support::state(&[]).await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b",
+                "attachments": [{"filename": "logo.png", "content": "iVBORw0KGgo=", "cid": "logo"}]}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{body}");
+        assert_eq!(
+            body["message"],
+            "attachment logo.png has a cid but is not inline"
+        );
+    }
+
+    #[tokio::test]
+    async fn smtp_min_tls_version_sets_the_tls_parameters() {
+        let smtp = smtp_config(&[]).expect("config loads");
+        assert!(matches!(smtp.min_tls_version, TlsVersion::Tlsv12));
+        tls_parameters(&smtp).expect("tls 1.2 parameters build");
+
+        let smtp = smtp_config(&[("SMTP_MIN_TLS_VERSION", "1.2")]).expect("config loads");
+        assert!(matches!(smtp.min_tls_version, TlsVersion::Tlsv12));
+
+        let smtp = smtp_config(&[("SMTP_MIN_TLS_VERSION", "1.3")]).expect("config loads");
+        assert!(matches!(smtp.min_tls_version, TlsVersion::Tlsv13));
+        tls_parameters(&smtp).expect("tls 1.3 parameters build");
+        build_smtp_transport(&smtp).expect("transport builds");
+    }
+
+    #[test]
+    fn smtp_min_tls_version_below_1_2_is_refused() {
+        for version in ["1.0", "1.1", "tls1.3"] {
+            let err = smtp_config(&[("SMTP_MIN_TLS_VERSION", version)]).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("unsupported SMTP_MIN_TLS_VERSION: {version}")
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn per_request_from_changes_leave_the_configured_from_alone() {
+        let state = test_support::state(&[]).await;
+        let app = test_support::app(&state);
+        let configured = state.from.clone();
+
+        for (request, from) in [
+            (
+                json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b", "sender_name": "Deploy Bot"}),
+                r#""Deploy Bot" <notify@example.com>"#,
+            ),
+            (
+                json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b", "sender": "bounces@example.com"}),
+                "Notifications <notify@example.com>",
+            ),
+            (
+                json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b"}),
+                "Notifications <notify@example.com>",
+            ),
+        ] {
+            let (status, body) = notify(&app, request).await;
+            assert_eq!(status, StatusCode::OK, "{body}");
+            let headers = last_headers(&app).await;
+            assert_eq!(
+                test_support::header_value(&headers, "From").as_deref(),
+                Some(from)
+            );
+        }
+        let sent = test_support::sent(&app).await;
+        let originators: Vec<_> = sent.iter().map(|message| &message["from"]).collect();
+        assert_eq!(
+            originators,
+            [
+                "notify@example.com",
+                "bounces@example.com",
+                "notify@example.com"
+            ]
+        );
+        assert_eq!(state.from, configured);
+    }
+
+    #[tokio::test]
+    async fn sanitize_subject_strips_or_rejects_a_right_to_left_override() {
+        let request = json!({"service": "smtp", "to": "ops@example.com", "title": "invoice \u{202e}fdp.exe\u{200b}", "body": "b"});
+
+        for (policy, subject) in [
+            ("", "invoice fdp.exe"),
+            ("strip", "invoice fdp.exe"),
+            ("off", "invoice \u{202e}fdp.exe\u{200b}"),
+        ] {
+            let state = test_support::state(&[("SANITIZE_SUBJECT", policy)]).await;
+            let app = test_support::app(&state);
+            let (status, body) = notify(&app, request.clone()).await;
+            assert_eq!(status, StatusCode::OK, "{policy}: {body}");
+            let sent = test_support::sent(&app).await;
+            assert_eq!(sent[0]["subject"], subject, "{policy}");
+        }
+
+        let state = test_support::state(&[("SANITIZE_SUBJECT", "reject")]).await;
+        let app = test_support::app(&state);
+        let (status, body) = notify(&app, request).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{body}");
+        assert_eq!(
+            body["message"],
+            "title contains bidi control or zero-width characters"
+        );
+        assert!(test_support::sent(&app).await.is_empty());
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "invoice 🚀", "body": "b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+    }
+
+    #[test]
+    fn unknown_sanitize_subject_policies_are_refused() {
+        let err = test_support::config(&[("SANITIZE_SUBJECT", "escape")]).unwrap_err();
+        assert_eq!(err.to_string(), "unsupported SANITIZE_SUBJECT: escape");
+    }
+
+    #[tokio::test]
+    async fn legacy_field_names_map_to_the_current_ones() {
+        let state = test_support::state(&[]).await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "recipient": "ops@example.com", "subject": "deploy", "message": "build 1234 is live"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let sent = test_support::sent(&app).await;
+        assert_eq!(sent[0]["to"], json!(["ops@example.com"]));
+        assert_eq!(sent[0]["subject"], "deploy");
+        assert!(
+            sent[0]["raw"]
+                .as_str()
+                .unwrap()
+                .contains("build 1234 is live"),
+            "{}",
+            sent[0]["raw"]
+        );
+    }
+
+    #[tokio::test]
+    async fn canonical_fields_win_over_their_legacy_aliases() {
+        let state = test_support::state(&[]).await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "recipient": "legacy@example.com",
+                "title": "current", "subject": "legacy", "body": "b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let sent = test_support::sent(&app).await;
+        assert_eq!(sent[0]["to"], json!(["ops@example.com"]));
+        assert_eq!(sent[0]["subject"], "current");
+    }
+
+    #[test]
+    fn legacy_requests_serialize_under_the_current_names() {
+        let req: NotifyRequest = serde_json::from_value(
+            json!({"service": "smtp", "recipient": "ops@example.com", "subject": "deploy", "message": "b"}),
+        )
+        .expect("valid request");
+        let value = serde_json::to_value(&req).unwrap();
+        assert_eq!(value["to"], "ops@example.com");
+        assert_eq!(value["title"], "deploy");
+        assert_eq!(value["body"], "b");
+        for legacy in ["recipient", "subject", "message"] {
+            assert!(value.get(legacy).is_none(), "{legacy}: {value}");
+        }
+        let again: NotifyRequest = serde_json::from_value(value).expect("round trips");
+        assert_eq!(again.title, "deploy");
+    }
+
+    #[tokio::test]
+    async fn legacy_field_names_are_accepted_by_the_queue_and_batch_endpoints() {
+        let state = test_support::state(&[]).await;
+        let app = test_support::app(&state);
+        let legacy = json!({"service": "smtp", "recipient": "ops@example.com", "subject": "deploy", "message": "b"});
+
+        let (status, body) =
+            test_support::call(&app, Method::POST, "/notify/async", Some(legacy.clone())).await;
+        assert_eq!(status, StatusCode::ACCEPTED, "{body}");
+        let (status, body) = test_support::call(
+            &app,
+            Method::POST,
+            "/notify/batch",
+            Some(json!({"messages": [legacy]})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["message"], "sent");
+        let sent = test_support::sent(&app).await;
+        assert_eq!(sent[0]["subject"], "deploy");
+    }
+
+    #[tokio::test]
+    async fn expires_at_sets_an_expiry_date_header() {
+        let state = test_support::state(&[]).await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b", "expires_at": " 1 Jan 2099 12:00:00 +0200 "}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let headers = last_headers(&app).await;
+        let expiry = test_support::header_value(&headers, "Expiry-Date").expect("an expiry date");
+        let expected = chrono::DateTime::parse_from_rfc2822("1 Jan 2099 12:00:00 +0200").unwrap();
+        assert_eq!(expiry, expected.to_rfc2822());
+        assert!(
+            expiry.ends_with("12:00:00 +0200"),
+            "keeps the offset: {expiry}"
+        );
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let headers = last_headers(&app).await;
+        assert_eq!(test_support::header_value(&headers, "Expiry-Date"), None);
+    }
+
+    #[tokio::test]
+    async fn expires_at_in_the_past_or_malformed_is_refused() {
+        let state = test_support::state(&[]).await;
+        let app = test_support::app(&state);
+
+        for (expires_at, message) in [
+            (
+                "1 Jan 2020 12:00:00 +0000",
+                "expires_at must be in the future",
+            ),
+            (
+                "2099-01-01T12:00:00Z",
+                "expires_at must be an RFC 2822 timestamp",
+            ),
+            ("tomorrow", "expires_at must be an RFC 2822 timestamp"),
+        ] {
+            let (status, body) = notify(
+                &app,
+                json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b", "expires_at": expires_at}),
+            )
+            .await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{expires_at}: {body}");
+            assert_eq!(body["message"], message, "{expires_at}");
+        }
+        assert!(test_support::sent(&app).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn archive_bcc_gets_a_copy_of_every_send_without_a_header() {
+        let dir = test_support::TempDir::new("templates");
+        std::fs::write(dir.path().join("welcome.hbs"), "Welcome {{name}}").unwrap();
+        let state = test_support::state(&[
+            ("ARCHIVE_BCC", "archive@example.com"),
+            ("TEMPLATES_DIR", dir.path().to_str().expect("utf-8 path")),
+        ])
+        .await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "bcc": "audit@example.com", "title": "t", "body": "b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "template": "welcome", "data": {"name": "Ada"}}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let (status, body) = test_support::call(
+            &app,
+            Method::POST,
+            "/notify/batch",
+            Some(json!({"messages": [{"service": "smtp", "to": "lead@example.com", "title": "t", "body": "b"}]})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        let sent = test_support::sent(&app).await;
+        assert_eq!(sent.len(), 3);
+        assert_eq!(
+            sent[0]["to"],
+            json!([
+                "ops@example.com",
+                "audit@example.com",
+                "archive@example.com"
+            ])
+        );
+        assert_eq!(
+            sent[1]["to"],
+            json!(["ops@example.com", "archive@example.com"])
+        );
+        assert_eq!(
+            sent[2]["to"],
+            json!(["lead@example.com", "archive@example.com"])
+        );
+        for message in &sent {
+            let raw = message["raw"].as_str().expect("raw message");
+            assert!(!raw.contains("archive@example.com"), "{raw}");
+        }
+    }
+
+    #[tokio::test]
+    async fn archive_bcc_cannot_be_doubled_by_the_client() {
+        let state = test_support::state(&[("ARCHIVE_BCC", "archive@example.com")]).await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "bcc": "archive@example.com", "title": "t", "body": "b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let sent = test_support::sent(&app).await;
+        assert_eq!(
+            sent[0]["to"],
+            json!(["ops@example.com", "archive@example.com"])
+        );
+    }
+
+    #[test]
+    fn an_invalid_archive_bcc_is_refused() {
+        let err = test_support::config(&[("ARCHIVE_BCC", "archive")]).unwrap_err();
+        assert!(
+            err.to_string().contains("invalid ARCHIVE_BCC address"),
+            "{err:#}"
+        );
+    }
+
+    #[tokio::test]
+    async fn a_failed_email_falls_back_to_slack_and_reports_both() {
+        let smtp = test_support::MockSmtp::start().await;
+        smtp.reply("MAIL", "550 5.7.1 sender rejected");
+        let slack =
+            test_support::MockSlack::start(json!({"ok": true, "ts": "1700000000.000200"})).await;
+        let state = smtp.state(&slack.vars()).await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "deploy", "body": "build 1234 is live",
+                "fallback_channel": {"service": "slack", "to": "#ops"}}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR, "{body}");
+        assert_eq!(body["ok"], false);
+        assert_eq!(body["message"], "smtp send failed");
+        assert_eq!(
+            body["fallback"],
+            json!({
+                "channel": "slack",
+                "ok": true,
+                "message": "sent",
+                "provider_message_id": "1700000000.000200",
+                "provider_status": "ok",
+            })
+        );
+        let posts = slack.posts();
+        assert_eq!(posts.len(), 1);
+        assert_eq!(posts[0]["channel"], "#ops");
+        assert!(
+            posts[0]["text"]
+                .as_str()
+                .unwrap()
+                .contains("build 1234 is live"),
+            "{}",
+            posts[0]
+        );
+    }
+
+    #[tokio::test]
+    async fn the_fallback_channel_is_only_tried_after_a_failure() {
+        let slack = test_support::MockSlack::start(json!({"ok": true, "ts": "1"})).await;
+        let state = test_support::state(&slack.vars()).await;
+        let app = test_support::app(&state);
+        let fallback = json!({"service": "slack", "to": "#ops"});
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b", "fallback_channel": fallback}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert!(body.get("fallback").is_none(), "{body}");
+
+        // A rejected request would fail the same way on any channel.
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "not an address", "title": "t", "body": "b", "fallback_channel": fallback}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{body}");
+        assert!(body.get("fallback").is_none(), "{body}");
+        assert!(slack.posts().is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_failed_fallback_is_reported_as_failed() {
+        let smtp = test_support::MockSmtp::start().await;
+        smtp.reply("MAIL", "550 5.7.1 sender rejected");
+        let slack =
+            test_support::MockSlack::start(json!({"ok": false, "error": "channel_not_found"}))
+                .await;
+        let state = smtp.state(&slack.vars()).await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b",
+                "fallback_channel": {"service": "slack", "to": "#gone"}}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR, "{body}");
+        assert_eq!(body["fallback"]["ok"], false);
+        assert_eq!(body["fallback"]["message"], "slack send failed");
+        assert_eq!(body["fallback"]["provider_status"], "channel_not_found");
+    }
+
+    /// The header fields of a sent message and the block's size in bytes.
+    fn header_block(raw: &str) -> (usize, usize) {
+        let block = raw
+            .split_once("\r\n\r\n")
+            .map_or(raw, |(headers, _)| headers);
+        let fields = block
+            .split("\r\n")
+            .filter(|line| !line.starts_with([' ', '\t']))
+            .count();
+        (fields, block.len())
+    }
+
+    fn cc_body(cc: usize) -> serde_json::Value {
+        let cc: Vec<_> = (0..cc).map(|n| format!("user{n}@example.com")).collect();
+        json!({"service": "smtp", "to": "ops@example.com", "cc": cc.join(", "), "title": "t", "body": "b"})
+    }
+
+    /// Field count and header bytes of the message `body` makes, uncapped.
+    async fn uncapped_header_block(body: serde_json::Value) -> (usize, usize) {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let (status, response) = notify(&app, body).await;
+        assert_eq!(status, StatusCode::OK, "{response}");
+        let sent = test_support::sent(&app).await;
+        header_block(sent[0]["raw"].as_str().expect("raw message"))
+    }
+
+    #[tokio::test]
+    async fn max_headers_caps_the_header_field_count() {
+        let (fields, _) = uncapped_header_block(cc_body(1)).await;
+
+        let max = fields.to_string();
+        let app = test_support::app(&test_support::state(&[("MAX_HEADERS", &max)]).await);
+        let (status, body) = notify(&app, cc_body(1)).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        let max = (fields - 1).to_string();
+        let app = test_support::app(&test_support::state(&[("MAX_HEADERS", &max)]).await);
+        let (status, body) = notify(&app, cc_body(1)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{body}");
+        assert_eq!(
+            body["message"],
+            format!("message has {fields} header fields, more than {max}")
+        );
+        assert!(test_support::sent(&app).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn max_header_bytes_caps_the_header_block_size() {
+        let (_, small) = uncapped_header_block(cc_body(1)).await;
+        let (_, large) = uncapped_header_block(cc_body(40)).await;
+        assert!(large > small);
+
+        let max = small.to_string();
+        let app = test_support::app(&test_support::state(&[("MAX_HEADER_BYTES", &max)]).await);
+        let (status, body) = notify(&app, cc_body(1)).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        // Folding the long Cc adds continuation lines, not fields.
+        let (status, body) = notify(&app, cc_body(40)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{body}");
+        assert_eq!(
+            body["message"],
+            format!("message headers are {large} bytes, more than {small}")
+        );
+        assert_eq!(test_support::sent(&app).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn recipients_can_be_name_and_address_objects() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "title": "t", "body": "b",
+                "to": {"name": "Doe, \"JJ\" <ops>", "address": "jj@example.com"},
+                "cc": [{"name": "Ann", "address": "ann@example.com"}, "bob@example.com", {"address": "carol@example.com"}]}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        let sent = test_support::sent(&app).await;
+        assert_eq!(
+            sent[0]["to"],
+            json!([
+                "jj@example.com",
+                "ann@example.com",
+                "bob@example.com",
+                "carol@example.com"
+            ])
+        );
+        let raw = sent[0]["raw"].as_str().unwrap();
+        // lettre encodes the awkward name rather than quoting it.
+        let to = test_support::header_value(raw, "To").unwrap();
+        let (name, address) = to.rsplit_once(' ').unwrap();
+        assert_eq!(test_support::decode_words(name), r#"Doe, "JJ" <ops>"#);
+        assert_eq!(address, "<jj@example.com>");
+        assert_eq!(
+            test_support::header_value(raw, "Cc").as_deref(),
+            Some("Ann <ann@example.com>, bob@example.com, carol@example.com")
+        );
+    }
+
+    #[tokio::test]
+    async fn recipient_objects_need_a_valid_address() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "title": "t", "body": "b",
+                "to": {"name": "Ops", "address": "not an address"}}),
+        )
+        .await;
+        assert!(status.is_client_error(), "{status}: {body}");
+        assert!(test_support::sent(&app).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn the_message_id_is_returned_and_matches_the_sent_header() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            let (status, body) = notify(
+                &app,
+                json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b"}),
+            )
+            .await;
+            assert_eq!(status, StatusCode::OK, "{body}");
+            ids.push(
+                body["message_id"]
+                    .as_str()
+                    .expect("a message_id")
+                    .to_string(),
+            );
+        }
+
+        for id in &ids {
+            let local = id
+                .strip_prefix('<')
+                .and_then(|id| id.strip_suffix("@example.com>"))
+                .unwrap_or_else(|| panic!("malformed Message-ID {id}"));
+            assert_eq!(local.len(), 32, "{id}");
+            assert!(local.bytes().all(|byte| byte.is_ascii_hexdigit()), "{id}");
+        }
+        assert_ne!(ids[0], ids[1]);
+        let sent = test_support::sent(&app).await;
+        for (message, id) in sent.iter().zip(&ids) {
+            let raw = message["raw"].as_str().unwrap();
+            assert_eq!(
+                test_support::header_value(raw, "Message-ID").as_ref(),
+                Some(id)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_sends_return_no_message_id() {
+        let smtp = test_support::MockSmtp::start().await;
+        smtp.reply("MAIL", "550 5.7.1 sender rejected");
+        let app = test_support::app(&smtp.state(&[]).await);
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR, "{body}");
+        assert!(body.get("message_id").is_none(), "{body}");
+    }
+
+    /// An SMTP server offering PIPELINING that flags any command sent while
+    /// the previous one is still unanswered. Returns its port.
+    async fn pipelining_server(pipelined: Arc<AtomicBool>) -> u16 {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let pipelined = pipelined.clone();
+                tokio::spawn(async move {
+                    let (read, mut write) = stream.into_split();
+                    let mut reader = BufReader::new(read);
+                    write.write_all(b"220 legacy ESMTP\r\n").await?;
+                    let mut line = String::new();
+                    while reader.read_line(&mut line).await? > 0 {
+                        let verb = line.get(..4).unwrap_or_default().to_ascii_uppercase();
+                        // Long enough for a pipelining client to send more.
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                        let waiting = !reader.buffer().is_empty()
+                            || reader.get_ref().try_read(&mut [0; 1]).is_ok_and(|n| n > 0);
+                        if waiting && verb != "DATA" {
+                            pipelined.store(true, Ordering::SeqCst);
+                        }
+                        let reply: &[u8] = match verb.as_str() {
+                            "EHLO" => b"250-legacy\r\n250-PIPELINING\r\n250 8BITMIME\r\n",
+                            "DATA" => {
+                                write.write_all(b"354 go ahead\r\n").await?;
+                                let mut body = String::new();
+                                while body != ".\r\n" {
+                                    body.clear();
+                                    reader.read_line(&mut body).await?;
+                                }
+                                b"250 queued\r\n"
+                            }
+                            "QUIT" => b"221 bye\r\n",
+                            _ => b"250 ok\r\n",
+                        };
+                        write.write_all(reply).await?;
+                        line.clear();
+                    }
+                    std::io::Result::Ok(())
+                });
+            }
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn disable_pipelining_is_read_into_the_smtp_config() {
+        assert!(!smtp_config(&[]).unwrap().disable_pipelining);
+        let cfg = smtp_config(&[("SMTP_DISABLE_PIPELINING", "true")]).unwrap();
+        assert!(cfg.disable_pipelining);
+        assert!(build_mailer(&cfg).is_ok());
+    }
+
+    #[tokio::test]
+    async fn commands_are_not_pipelined_even_when_offered() {
+        for extra in [
+            None,
+            Some(("SMTP_LOCAL_BIND_ADDR", "127.0.0.1")),
+            Some(("SMTP_DISABLE_PIPELINING", "true")),
+        ] {
+            let pipelined = Arc::new(AtomicBool::new(false));
+            let port = pipelining_server(pipelined.clone()).await.to_string();
+            let mut vars = vec![
+                ("BACKEND", "smtp"),
+                ("SMTP_HOST", "127.0.0.1"),
+                ("SMTP_PORT", port.as_str()),
+                ("SMTP_TLS", "false"),
+            ];
+            vars.extend(extra);
+            let app = test_support::app(&test_support::state(&vars).await);
+
+            let (status, body) = notify(
+                &app,
+                json!({"service": "smtp", "to": "a@example.com, b@example.com", "title": "t", "body": "b"}),
+            )
+            .await;
+            assert_eq!(status, StatusCode::OK, "{extra:?}: {body}");
+            assert!(!pipelined.load(Ordering::SeqCst), "{extra:?} pipelined");
+        }
+    }
+
+    #[test]
+    fn dedupe_recipients_keeps_the_most_visible_first_copy() {
+        let parse = |raw: &str| raw.parse::<Mailboxes>().unwrap();
+        let (to, cc, bcc) = dedupe_recipients(
+            parse("Ops <ops@example.com>, dev@example.com, OPS@example.com"),
+            parse("Dev Team <DEV@example.com>, qa@example.com"),
+            parse("qa@example.com, ops@example.com, audit@example.com"),
+        );
+        assert_eq!(to.to_string(), "Ops <ops@example.com>, dev@example.com");
+        assert_eq!(cc.to_string(), "qa@example.com");
+        assert_eq!(bcc.to_string(), "audit@example.com");
+    }
+
+    #[tokio::test]
+    async fn duplicated_recipients_get_one_copy() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "title": "t", "body": "b",
+                "to": "ops@example.com, Ops@Example.com", "cc": "ops@example.com, qa@example.com",
+                "bcc": "QA@example.com"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        let sent = test_support::sent(&app).await;
+        assert_eq!(sent[0]["to"], json!(["ops@example.com", "qa@example.com"]));
+        let raw = sent[0]["raw"].as_str().unwrap();
+        assert_eq!(
+            test_support::header_value(raw, "To").as_deref(),
+            Some("ops@example.com")
+        );
+        assert_eq!(
+            test_support::header_value(raw, "Cc").as_deref(),
+            Some("qa@example.com")
+        );
+    }
+
+    #[tokio::test]
+    async fn dedupe_recipients_can_be_turned_off() {
+        let app = test_support::app(&test_support::state(&[("DEDUPE_RECIPIENTS", "false")]).await);
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "title": "t", "body": "b",
+                "to": "ops@example.com", "cc": "ops@example.com"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let raw = test_support::sent(&app).await[0]["raw"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        assert_eq!(
+            test_support::header_value(&raw, "Cc").as_deref(),
+            Some("ops@example.com")
+        );
+    }
+
+    #[tokio::test]
+    async fn a_list_of_two_addresses_is_two_recipients() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "title": "t", "body": "b",
+                "to": ["a@example.com", "b@example.com"]}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        let sent = test_support::sent(&app).await;
+        assert_eq!(sent[0]["to"], json!(["a@example.com", "b@example.com"]));
+        let raw = sent[0]["raw"].as_str().unwrap();
+        assert_eq!(
+            test_support::header_value(raw, "To").as_deref(),
+            Some("a@example.com, b@example.com")
+        );
+    }
+
+    #[tokio::test]
+    async fn partially_refused_recipients_are_listed_as_rejected() {
+        let smtp = test_support::MockSmtp::start().await;
+        smtp.reply("RCPT", "250 2.1.5 ok");
+        smtp.reply("RCPT", "550 5.1.1 no such user");
+        let state = smtp.state(&[("SMTP_ACCEPT_PARTIAL_RCPT", "true")]).await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({
+                "service": "smtp",
+                "to": ["a@example.com", "b@example.com", "c@example.com"],
+                "title": "deploy",
+                "body": "build 1234",
+            }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["message"], "partially sent");
+        assert_eq!(body["rejected"], json!(["b@example.com"]));
+        assert_eq!(body["recipients"], json!({"accepted": 2, "rejected": 1}));
+        assert_eq!(smtp.messages().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn send_fails_when_every_recipient_is_refused() {
+        let smtp = test_support::MockSmtp::start().await;
+        smtp.reply("RCPT", "550 5.1.1 no such user");
+        smtp.reply("RCPT", "550 5.1.1 no such user");
+        let state = smtp.state(&[("SMTP_ACCEPT_PARTIAL_RCPT", "true")]).await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({
+                "service": "smtp",
+                "to": ["a@example.com", "b@example.com"],
+                "title": "deploy",
+                "body": "build 1234",
+            }),
+        )
+        .await;
+        assert!(!status.is_success(), "{body}");
+        assert_eq!(body["ok"], false);
+        assert!(smtp.messages().is_empty());
+    }
+
+    #[tokio::test]
+    async fn fully_accepted_send_has_no_rejected_list() {
+        let smtp = test_support::MockSmtp::start().await;
+        let state = smtp.state(&[("SMTP_ACCEPT_PARTIAL_RCPT", "true")]).await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({
+                "service": "smtp",
+                "to": ["a@example.com", "b@example.com"],
+                "title": "deploy",
+                "body": "build 1234",
+            }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["message"], "sent");
+        assert!(body.get("rejected").is_none(), "{body}");
+        assert_eq!(body["recipients"], json!({"accepted": 2, "rejected": 0}));
+    }
+
+    #[test]
+    fn normalize_crlf_converts_bare_lf_and_cr() {
+        assert_eq!(normalize_crlf("a\nb\rc\r\nd"), "a\r\nb\r\nc\r\nd");
+        assert_eq!(normalize_crlf("\r\r\n\n"), "\r\n\r\n\r\n");
+        assert_eq!(normalize_crlf("no breaks"), "no breaks");
+    }
+
+    #[tokio::test]
+    async fn mixed_line_endings_are_sent_as_crlf() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t",
+                "body": "one\ntwo\rthree\r\nfour"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        let sent = test_support::sent(&app).await;
+        let raw = sent[0]["raw"].as_str().unwrap();
+        assert!(
+            raw.ends_with("\r\n\r\none\r\ntwo\r\nthree\r\nfour\r\n"),
+            "{raw:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn line_endings_are_left_alone_when_normalization_is_off() {
+        let app = test_support::app(&test_support::state(&[("NORMALIZE_CRLF", "false")]).await);
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t",
+                "body": "one\rtwo"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        let sent = test_support::sent(&app).await;
+        let raw = sent[0]["raw"].as_str().unwrap();
+        assert!(!raw.contains("one\r\ntwo"), "{raw:?}");
+    }
+
+    #[tokio::test]
+    async fn total_deadline_cuts_retries_short_with_504() {
+        let smtp = test_support::MockSmtp::start().await;
+        for _ in 0..5 {
+            smtp.reply("DATA", "451 4.3.0 try again");
+        }
+        smtp.delay("DATA", Duration::from_millis(400));
+        let state = smtp
+            .state(&[("SMTP_RETRY_MAX", "5"), ("SMTP_RETRY_BASE_MS", "1")])
+            .await;
+        let app = test_support::app(&state);
+
+        let started = std::time::Instant::now();
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b",
+                "total_deadline_secs": 1}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::GATEWAY_TIMEOUT, "{body}");
+        assert_eq!(body["message"], "send deadline exceeded");
+        assert!(started.elapsed() < Duration::from_millis(1500));
+        assert!(smtp.messages().is_empty());
+    }
+
+    #[tokio::test]
+    async fn send_within_the_total_deadline_succeeds() {
+        let smtp = test_support::MockSmtp::start().await;
+        smtp.reply("DATA", "451 4.3.0 try again");
+        let state = smtp
+            .state(&[("SMTP_RETRY_MAX", "2"), ("SMTP_RETRY_BASE_MS", "1")])
+            .await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b",
+                "total_deadline_secs": 5}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(smtp.messages().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn zero_total_deadline_is_refused() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b",
+                "total_deadline_secs": 0}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{body}");
+        assert_eq!(body["message"], "total_deadline_secs must be at least 1");
+        assert!(test_support::sent(&app).await.is_empty());
+    }
+
+    #[test]
+    fn smtp_host_matching_the_environment_pattern_starts() {
+        smtp_config(&[
+            ("ENVIRONMENT", "staging"),
+            (
+                "SMTP_HOST_PATTERN_STAGING",
+                "*.staging.example.com, smtp.example.com",
+            ),
+        ])
+        .unwrap();
+        // No pattern for the environment accepts any host.
+        smtp_config(&[("ENVIRONMENT", "staging")]).unwrap();
+    }
+
+    #[test]
+    fn smtp_host_outside_the_environment_pattern_refuses_to_start() {
+        let err = smtp_config(&[
+            ("ENVIRONMENT", "staging"),
+            ("SMTP_HOST_PATTERN_STAGING", "*.staging.example.com"),
+        ])
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "SMTP host smtp.example.com does not match SMTP_HOST_PATTERN_STAGING \
+             (*.staging.example.com) for ENVIRONMENT=staging"
+        );
+
+        let err = smtp_config(&[
+            ("ENVIRONMENT", "us-east"),
+            ("SMTP_HOST_PATTERN_US_EAST", "smtp.example.com"),
+            ("SMTP_FALLBACK_1_HOST", "relay.prod.example.com"),
+        ])
+        .unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("SMTP host relay.prod.example.com does not match"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn wildcard_match_is_case_insensitive_and_anchored() {
+        assert!(wildcard_match(
+            "*.staging.example.com",
+            "SMTP.Staging.example.com"
+        ));
+        assert!(wildcard_match(
+            "smtp-*-*.example.com",
+            "smtp-a-b.example.com"
+        ));
+        assert!(wildcard_match("*", "anything"));
+        assert!(!wildcard_match("*.staging.example.com", "smtp.example.com"));
+        assert!(!wildcard_match("smtp.example.com", "smtp.example.com.evil"));
+        assert!(!wildcard_match("a*a", "a"));
+    }
+
+    #[tokio::test]
+    async fn sending_pool_rotates_the_source_address() {
+        let smtp = test_support::MockSmtp::start().await;
+        let state = smtp
+            .state(&[
+                ("SENDING_POOL", "127.0.0.2, 127.0.0.3"),
+                ("SENDING_POOL_POLICY", "round_robin"),
+            ])
+            .await;
+        let app = test_support::app(&state);
+
+        for _ in 0..4 {
+            let (status, body) = notify(
+                &app,
+                json!({"service": "smtp", "to": "ops@example.com", "title": "deploy", "body": "build 1234"}),
+            )
+            .await;
+            assert_eq!(status, StatusCode::OK, "{body}");
+        }
+        let peers: Vec<_> = smtp.peers().iter().map(std::net::SocketAddr::ip).collect();
+        let (two, three) = (IpAddr::from([127, 0, 0, 2]), IpAddr::from([127, 0, 0, 3]));
+        assert_eq!(peers, [two, three, two, three]);
+        assert_eq!(smtp.messages().len(), 4);
+    }
+
+    #[test]
+    fn sending_pool_weights_default_to_one() {
+        let pool = parse_sending_pool("192.0.2.10=3, 2001:db8::10").unwrap();
+        assert_eq!(
+            pool,
+            [
+                (IpAddr::from([192, 0, 2, 10]), 3),
+                ("2001:db8::10".parse().unwrap(), 1)
+            ]
+        );
+        let err = parse_sending_pool("192.0.2.10=0").unwrap_err();
+        assert_eq!(err.to_string(), "invalid SENDING_POOL weight: 192.0.2.10=0");
+        let err = parse_sending_pool("relay.example.com").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "SENDING_POOL entry is not an IP address: relay.example.com"
+        );
+    }
+
+    async fn sent_raw(vars: &[(&str, &str)], text: &str) -> String {
+        let app = test_support::app(&test_support::state(vars).await);
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": text}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let sent = test_support::sent(&app).await;
+        sent[0]["raw"].as_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn text_body_ends_with_exactly_one_crlf() {
+        let raw = sent_raw(&[], "no newline").await;
+        assert!(raw.ends_with("\r\n\r\nno newline\r\n"), "{raw:?}");
+
+        let raw = sent_raw(&[], "several\n\n\r\n").await;
+        assert!(raw.ends_with("\r\n\r\nseveral\r\n"), "{raw:?}");
+    }
+
+    #[tokio::test]
+    async fn trailing_crlf_can_be_turned_off() {
+        let raw = sent_raw(&[("TRAILING_CRLF", "false")], "no newline").await;
+        assert!(raw.ends_with("\r\n\r\nno newline"), "{raw:?}");
+    }
+
+    #[tokio::test]
+    async fn ttl_is_sent_as_x_message_ttl_and_expiry_date() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "code", "body": "123456",
+                "ttl_secs": 300}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        let sent = test_support::sent(&app).await;
+        let raw = sent[0]["raw"].as_str().unwrap();
+        assert_eq!(
+            test_support::header_value(raw, "X-Message-TTL").as_deref(),
+            Some("300")
+        );
+        let expiry = test_support::header_value(raw, "Expiry-Date").expect("an expiry");
+        let expiry = chrono::DateTime::parse_from_rfc2822(&expiry).unwrap();
+        let left = expiry
+            .signed_duration_since(chrono::Utc::now())
+            .num_seconds();
+        assert!((295..=300).contains(&left), "{left}s left");
+    }
+
+    #[tokio::test]
+    async fn short_ttl_skips_the_digest() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "code", "body": "123456",
+                "digest": true, "digest_window_secs": 600, "ttl_secs": 60}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["message"], "sent");
+        assert_eq!(test_support::sent(&app).await.len(), 1);
+
+        // A ttl outlasting the window leaves the message to the digest.
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "code", "body": "123456",
+                "digest": true, "digest_window_secs": 60, "ttl_secs": 600}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::ACCEPTED, "{body}");
+        assert_eq!(body["message"], "added to digest");
+        assert_eq!(test_support::sent(&app).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn short_ttl_skips_a_closed_send_window() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let hour = chrono::Timelike::hour(&chrono::Utc::now());
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "code", "body": "123456",
+                "ttl_secs": 60,
+                "respect_send_window": {"timezone": "UTC", "start_hour": (hour + 2) % 24, "end_hour": (hour + 3) % 24}}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["message"], "sent");
+        assert_eq!(test_support::sent(&app).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn zero_ttl_is_refused() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b",
+                "ttl_secs": 0}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{body}");
+        assert_eq!(body["message"], "ttl_secs must be at least 1");
+    }
+
+    #[tokio::test]
+    async fn copy_sender_delivers_a_copy_to_the_from_address() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b",
+                "copy_sender": true}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        let sent = test_support::sent(&app).await;
+        assert_eq!(
+            sent[0]["to"],
+            json!(["ops@example.com", "notify@example.com"])
+        );
+        let raw = sent[0]["raw"].as_str().unwrap();
+        assert_eq!(
+            test_support::header_value(raw, "To").as_deref(),
+            Some("ops@example.com")
+        );
+        assert_eq!(test_support::header_value(raw, "Bcc"), None);
+    }
+
+    #[tokio::test]
+    async fn copy_sender_does_not_duplicate_a_sender_already_addressed() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "cc": "notify@example.com",
+                "title": "t", "body": "b", "copy_sender": true}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        let sent = test_support::sent(&app).await;
+        assert_eq!(
+            sent[0]["to"],
+            json!(["ops@example.com", "notify@example.com"])
+        );
+    }
+
+    #[tokio::test]
+    async fn without_copy_sender_the_sender_gets_no_copy() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(
+            test_support::sent(&app).await[0]["to"],
+            json!(["ops@example.com"])
+        );
+    }
+
+    async fn post_raw(app: &axum::Router, body: &'static str) -> (StatusCode, serde_json::Value) {
+        use tower::ServiceExt;
+
+        let request = axum::http::Request::post("/notify")
+            .header("authorization", format!("Bearer {}", test_support::API_KEY))
+            .header(CONTENT_TYPE, "application/json")
+            .body(axum::body::Body::from(body))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap())
+    }
+
+    #[tokio::test]
+    async fn bodies_that_do_not_parse_are_400() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let (status, body) = post_raw(&app, r#"{"service": "smtp", "to": "#).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{body}");
+        assert!(
+            body["message"]
+                .as_str()
+                .unwrap()
+                .starts_with("invalid request body: "),
+            "{body}"
+        );
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": 5, "body": "b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{body}");
+    }
+
+    #[tokio::test]
+    async fn malformed_address_is_400_and_a_refused_recipient_is_422() {
+        let dir = test_support::TempDir::new("api-keys");
+        let path = dir.path().join("keys.json");
+        std::fs::write(
+            &path,
+            json!({test_support::API_KEY: {"allowed_domains": ["example.com"]}}).to_string(),
+        )
+        .unwrap();
+        let state = test_support::state(&[
+            ("API_KEY", ""),
+            ("API_KEYS_FILE", path.to_str().unwrap()),
+            ("MAX_RECIPIENTS_PER_MESSAGE", "2"),
+        ])
+        .await;
+        let app = test_support::app(&state);
+        let send = |to: &'static str| {
+            notify(
+                &app,
+                json!({"service": "smtp", "to": to, "title": "t", "body": "b"}),
+            )
+        };
+
+        let (status, body) = send("not an address").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{body}");
+        assert_eq!(body["message"], "invalid recipient email");
+
+        let (status, body) = send("ops@elsewhere.example").await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{body}");
+        assert_eq!(
+            body["message"],
+            "recipient domain not permitted: elsewhere.example"
+        );
+
+        let (status, body) = send("a@example.com, b@example.com, c@example.com").await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{body}");
+        assert_eq!(body["message"], "too many recipients (3 > 2)");
+        assert!(test_support::sent(&app).await.is_empty());
+    }
+
+    fn verbs(smtp: &test_support::MockSmtp) -> Vec<String> {
+        smtp.commands()
+            .iter()
+            .map(|command| command.split(' ').next().unwrap_or_default().to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn a_recipient_refused_by_the_probe_is_422_and_nothing_is_sent() {
+        let smtp = test_support::MockSmtp::start().await;
+        smtp.reply("RCPT", "250 2.1.5 ok");
+        smtp.reply("RCPT", "550 5.1.1 no such user");
+        let state = smtp.state(&[("VERIFY_RECIPIENTS", "true")]).await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com, gone@example.com",
+                "title": "t", "body": "b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{body}");
+        assert_eq!(
+            body["message"],
+            "recipients refused by probe: gone@example.com"
+        );
+        assert_eq!(body["rejected"], json!(["gone@example.com"]));
+        assert_eq!(
+            verbs(&smtp),
+            ["EHLO", "MAIL", "RCPT", "RCPT", "RSET", "QUIT"]
+        );
+        assert!(smtp.messages().is_empty());
+    }
+
+    #[tokio::test]
+    async fn probed_recipients_that_are_accepted_are_sent_to() {
+        let smtp = test_support::MockSmtp::start().await;
+        let state = smtp.state(&[("VERIFY_RECIPIENTS", "true")]).await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let verbs = verbs(&smtp);
+        assert_eq!(verbs[..5], ["EHLO", "MAIL", "RCPT", "RSET", "QUIT"]);
+        assert!(verbs[5..].contains(&"DATA".to_string()), "{verbs:?}");
+        assert_eq!(smtp.messages().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn a_temporary_probe_failure_sends_anyway() {
+        let smtp = test_support::MockSmtp::start().await;
+        smtp.reply("RCPT", "451 4.7.1 try again later");
+        let state = smtp.state(&[("VERIFY_RECIPIENTS", "true")]).await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(smtp.messages().len(), 1);
+    }
+
+    async fn sent_from(vars: &[(&str, &str)], sender_name: Option<&str>) -> String {
+        let app = test_support::app(&test_support::state(vars).await);
+        let mut request =
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b"});
+        if let Some(name) = sender_name {
+            request["sender_name"] = json!(name);
+        }
+        let (status, body) = notify(&app, request).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let sent = test_support::sent(&app).await;
+        test_support::header_value(sent[0]["raw"].as_str().unwrap(), "From").unwrap()
+    }
+
+    #[tokio::test]
+    async fn email_goes_out_under_the_request_channel_then_global_name() {
+        let global = ("SENDER_NAME", "Acme");
+        assert_eq!(
+            sent_from(&[global], Some("Deploy Bot")).await,
+            r#""Deploy Bot" <notify@example.com>"#
+        );
+        // SMTP_FROM's own display name is the email channel's default.
+        assert_eq!(
+            sent_from(&[global], None).await,
+            "Notifications <notify@example.com>"
+        );
+        let bare = ("SMTP_FROM", "notify@example.com");
+        assert_eq!(
+            sent_from(&[bare, global], None).await,
+            "Acme <notify@example.com>"
+        );
+        assert_eq!(sent_from(&[bare], None).await, "notify@example.com");
+    }
+
+    #[test]
+    fn rename_from_changes_nothing_without_a_name_to_apply() {
+        let named: Mailboxes = "Ops <ops@example.com>".parse().unwrap();
+        assert!(rename_from(&named, None, Some("Acme")).is_none());
+        assert!(rename_from(&named, Some("  "), None).is_none());
+
+        let mixed: Mailboxes = "Ops <ops@example.com>, alerts@example.com".parse().unwrap();
+        let renamed = rename_from(&mixed, None, Some("Acme")).unwrap();
+        assert_eq!(
+            renamed.to_string(),
+            "Ops <ops@example.com>, Acme <alerts@example.com>"
+        );
+    }
+
+    fn shutdown_lines(state: &AppState) -> Vec<String> {
+        let capture = test_support::Capture::default();
+        tracing::subscriber::with_default(capture.subscriber(), || log_shutdown_report(state));
+        capture.lines()
+    }
+
+    #[tokio::test]
+    async fn the_shutdown_report_reflects_the_lifetime_counts() {
+        let smtp = test_support::MockSmtp::start().await;
+        smtp.reply("RCPT", "550 5.1.1 no such user");
+        let state = smtp.state(&[]).await;
+        let app = test_support::app(&state);
+        for to in ["gone@example.com", "ops@example.com", "qa@example.com"] {
+            notify(
+                &app,
+                json!({"service": "smtp", "to": to, "title": "t", "body": "b"}),
+            )
+            .await;
+        }
+        // No workers, so the job is still queued at shutdown.
+        let (status, body) = call(
+            &app,
+            Method::POST,
+            "/notify/async",
+            Some(json!({"service": "smtp", "to": "later@example.com", "title": "t", "body": "b"})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::ACCEPTED, "{body}");
+
+        let lines = shutdown_lines(&state);
+        let report = lines
+            .iter()
+            .find(|line| line.contains("shutdown report"))
+            .expect("a shutdown report");
+        for field in [
+            "sent=2",
+            "failed=1",
+            "peak_concurrent_sends=1",
+            "queued=1",
+            "sending=0",
+            "spooled=0",
+            "dead_letters=0",
+        ] {
+            assert!(report.contains(field), "{field} in {report}");
+        }
+        let lost = lines
+            .iter()
+            .find(|line| line.contains("background jobs lost at shutdown"))
+            .expect("a lost jobs warning");
+        assert!(lost.contains("WARN") && lost.contains("jobs=1"), "{lost}");
+    }
+
+    #[tokio::test]
+    async fn spooled_jobs_are_reported_as_kept() {
+        let dir = test_support::TempDir::new("shutdown-spool");
+        let state =
+            test_support::state(&[("SPOOL_DIR", dir.path().to_str().expect("utf-8 path"))]).await;
+        let app = test_support::app(&state);
+        let (status, body) = call(
+            &app,
+            Method::POST,
+            "/notify/async",
+            Some(json!({"service": "smtp", "to": "later@example.com", "title": "t", "body": "b"})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::ACCEPTED, "{body}");
+
+        let lines = shutdown_lines(&state);
+        assert!(
+            lines
+                .iter()
+                .any(|line| line.contains("shutdown report") && line.contains("spooled=1")),
+            "{lines:?}"
+        );
+        assert!(
+            !lines.iter().any(|line| line.contains("jobs lost")),
+            "{lines:?}"
+        );
+    }
+
+    #[test]
+    fn concurrency_keeps_the_peak_after_sends_finish() {
+        let sends = Concurrency::default();
+        let first = sends.enter();
+        let second = sends.enter();
+        drop(first);
+        let third = sends.enter();
+        assert_eq!(sends.current.load(Ordering::Relaxed), 2);
+        drop((second, third));
+        assert_eq!(sends.current.load(Ordering::Relaxed), 0);
+        assert_eq!(sends.peak.load(Ordering::Relaxed), 2);
+    }
+
+    async fn multipart_type_of(request: serde_json::Value) -> (StatusCode, String) {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let (status, body) = notify(&app, request).await;
+        if status != StatusCode::OK {
+            return (
+                status,
+                body["message"].as_str().unwrap_or_default().to_string(),
+            );
+        }
+        let sent = test_support::sent(&app).await;
+        let raw = sent[0]["raw"].as_str().expect("raw message");
+        let content_type = test_support::header_value(raw, "Content-Type").expect("a content type");
+        (status, content_type)
+    }
+
+    #[tokio::test]
+    async fn multipart_type_mixed_wraps_a_lone_body() {
+        let (status, content_type) = multipart_type_of(
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "plain",
+                "multipart_type": "mixed"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{content_type}");
+        assert!(
+            content_type.starts_with("multipart/mixed"),
+            "{content_type}"
+        );
+    }
+
+    #[tokio::test]
+    async fn multipart_type_related_tops_the_html_and_its_inline_parts() {
+        let (status, content_type) = multipart_type_of(
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "plain",
+                "html": "<p><img src=\"cid:logo\"></p>", "multipart_type": "related",
+                "attachments": [{"filename": "logo.png", "content": "iVBORw0KGgo=",
+                    "content_type": "image/png", "disposition": "inline", "cid": "logo"}]}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{content_type}");
+        assert!(
+            content_type.starts_with("multipart/related"),
+            "{content_type}"
+        );
+    }
+
+    #[tokio::test]
+    async fn multipart_type_alternative_tops_two_renderings() {
+        let (status, content_type) = multipart_type_of(
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "plain",
+                "html": "<p>rich</p>", "multipart_type": "alternative"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{content_type}");
+        assert!(
+            content_type.starts_with("multipart/alternative"),
+            "{content_type}"
+        );
+    }
+
+    #[tokio::test]
+    async fn a_multipart_type_the_parts_do_not_fit_is_refused() {
+        let (status, message) = multipart_type_of(
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "plain",
+                "multipart_type": "alternative"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            message,
+            "multipart_type alternative needs at least two of body, html and calendar"
+        );
+
+        let (status, message) = multipart_type_of(
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "plain",
+                "html": "<p>rich</p>", "multipart_type": "alternative",
+                "attachments": [{"filename": "report.csv", "content": "YSxiCg=="}]}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            message,
+            "multipart_type alternative cannot hold attachments"
+        );
+    }
+
+    #[test]
+    fn check_multipart_type_matches_the_parts() {
+        assert_eq!(
+            check_multipart_type(MultipartType::Related, 2, true, false, false, false),
+            Err("multipart_type related needs html and an inline attachment with a cid")
+        );
+        assert_eq!(
+            check_multipart_type(MultipartType::Related, 2, true, true, true, false),
+            Err("multipart_type related cannot hold non-inline attachments")
+        );
+        assert_eq!(
+            check_multipart_type(MultipartType::Mixed, 1, false, false, false, true),
+            Err("multipart_type cannot be combined with encrypt")
+        );
+        assert_eq!(
+            check_multipart_type(MultipartType::Mixed, 1, false, false, false, false),
+            Ok(())
+        );
+    }
+
+    #[tokio::test]
+    async fn a_relay_without_credentials_builds_a_transport_without_auth() {
+        let smtp = smtp_config(&[]).expect("config loads");
+        assert!(smtp.credentials.is_none());
+        build_mailer(&smtp).expect("transport builds");
+
+        let smtp = smtp_config(&[("SMTP_USERNAME", ""), ("SMTP_PASSWORD", "")])
+            .expect("empty vars count as unset");
+        assert!(smtp.credentials.is_none());
+
+        let smtp = smtp_config(&[("SMTP_USERNAME", "relay"), ("SMTP_PASSWORD", "secret")])
+            .expect("config loads");
+        assert!(smtp.credentials.is_some());
+        build_mailer(&smtp).expect("transport builds");
+    }
+
+    #[test]
+    fn half_set_smtp_credentials_are_refused() {
+        let err = smtp_config(&[("SMTP_USERNAME", "relay")]).expect_err("no password");
+        assert_eq!(
+            format!("{err:#}"),
+            "SMTP_USERNAME and SMTP_PASSWORD must be set together"
+        );
+        let err = smtp_config(&[("SMTP_PASSWORD", "secret")]).expect_err("no username");
+        assert_eq!(
+            format!("{err:#}"),
+            "SMTP_USERNAME and SMTP_PASSWORD must be set together"
+        );
+    }
+
+    #[tokio::test]
+    async fn auth_is_only_attempted_with_credentials() {
+        let smtp = test_support::MockSmtp::start().await;
+        smtp.offer("AUTH PLAIN");
+        let app = test_support::app(&smtp.state(&[]).await);
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert!(
+            !verbs(&smtp).contains(&"AUTH".to_string()),
+            "{:?}",
+            smtp.commands()
+        );
+
+        let smtp = test_support::MockSmtp::start().await;
+        smtp.offer("AUTH PLAIN");
+        smtp.reply("AUTH", "235 2.7.0 authenticated");
+        let app = test_support::app(
+            &smtp
+                .state(&[("SMTP_USERNAME", "relay"), ("SMTP_PASSWORD", "secret")])
+                .await,
+        );
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert!(
+            verbs(&smtp).contains(&"AUTH".to_string()),
+            "{:?}",
+            smtp.commands()
+        );
+    }
+
+    #[tokio::test]
+    async fn recipient_counts_cover_single_sends_only_when_always_is_set() {
+        let request =
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b"});
+        let app = test_support::app(&test_support::state(&[]).await);
+        let (status, body) = notify(&app, request.clone()).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert!(body.get("recipients").is_none(), "{body}");
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "cc": "lead@example.com", "title": "t", "body": "b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["recipients"], json!({"accepted": 2, "rejected": 0}));
+
+        let app =
+            test_support::app(&test_support::state(&[("RECIPIENT_COUNTS_ALWAYS", "true")]).await);
+        let (status, body) = notify(&app, request).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["recipients"], json!({"accepted": 1, "rejected": 0}));
+    }
+
+    #[tokio::test]
+    async fn clean_text_links_strips_tracking_from_the_text_part_but_not_the_html() {
+        let html = r#"<p><a href="https://example.com/unsubscribe?u=42&amp;utm_source=digest">Unsubscribe</a></p>"#;
+        let app = test_support::app(&test_support::state(&[("CLEAN_TEXT_LINKS", "true")]).await);
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "", "html": html}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let sent = test_support::sent(&app).await;
+        let raw = sent[0]["raw"].as_str().unwrap();
+        let html_part = raw.find("Content-Type: text/html").expect("an html part");
+        let (text, html) = raw.split_at(html_part);
+        assert!(
+            text.contains("Unsubscribe (https://example.com/unsubscribe?u=42)"),
+            "{raw}"
+        );
+        assert!(!text.contains("utm_source"), "{raw}");
+        // Quoted-printable, so `=` is `=3D`.
+        assert!(html.contains("utm_source=3D"), "{raw}");
+    }
+
+    #[tokio::test]
+    async fn a_greylisted_send_keeps_its_dedupe_claim_until_the_retry_finishes() {
+        let smtp = test_support::MockSmtp::start().await;
+        smtp.reply("RCPT", "451 4.7.1 Greylisted, please try again later");
+        let state = smtp.state(&[("GREYLIST_RETRY_SECS", "1")]).await;
+        let app = test_support::app(&state);
+        let mut events = state.events.subscribe();
+        let request = json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b",
+            "dedupe_window_secs": 60});
+
+        let (status, body) = notify(&app, request.clone()).await;
+        assert_eq!(status, StatusCode::ACCEPTED, "{body}");
+        // A client retry while the deferred send waits is not sent again.
+        let (status, body) = notify(&app, request.clone()).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["message"], "deduplicated");
+
+        let finished = async {
+            loop {
+                let kind = events.recv().await.unwrap().kind;
+                if kind != "deferred" && kind != "deduplicated" {
+                    return kind;
+                }
+            }
+        };
+        let kind = tokio::time::timeout(Duration::from_secs(10), finished)
+            .await
+            .expect("the deferred send finishes");
+        assert_eq!(kind, "sent");
+        let (status, body) = notify(&app, request).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["message"], "deduplicated");
+        assert_eq!(smtp.messages().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn server_wide_admin_endpoints_need_an_admin_key() {
+        let dir = test_support::TempDir::new("keys");
+        let path = dir.path().join("keys.json");
+        let templates = test_support::TempDir::new("templates");
+        let keys = |admin: bool| {
+            std::fs::write(
+                &path,
+                json!({test_support::API_KEY: {"tenant": "acme", "admin": admin}}).to_string(),
+            )
+            .unwrap();
+        };
+        let vars = [
+            ("API_KEY", ""),
+            ("API_KEYS_FILE", path.to_str().unwrap()),
+            ("TEMPLATES_DIR", templates.path().to_str().unwrap()),
+            ("AUTO_PAUSE_FAILURE_RATE", "0.5"),
+        ];
+
+        keys(false);
+        let app = test_support::app(&test_support::state(&vars).await);
+        for uri in [
+            "/admin/flush-pool",
+            "/admin/resume",
+            "/admin/reload-templates",
+        ] {
+            let (status, body) = call(&app, Method::POST, uri, None).await;
+            assert_eq!(status, StatusCode::FORBIDDEN, "{uri}: {body}");
+            assert_eq!(body["message"], "admin key required", "{uri}");
+        }
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        keys(true);
+        let app = test_support::app(&test_support::state(&vars).await);
+        for uri in [
+            "/admin/flush-pool",
+            "/admin/resume",
+            "/admin/reload-templates",
+        ] {
+            let (status, body) = call(&app, Method::POST, uri, None).await;
+            assert_eq!(status, StatusCode::OK, "{uri}: {body}");
+        }
+    }
+
+    #[tokio::test]
+    async fn recipient_counts_leave_out_the_archive_and_sender_copies() {
+        let app = test_support::app(
+            &test_support::state(&[
+                ("ARCHIVE_BCC", "archive@example.com"),
+                ("RECIPIENT_COUNTS_ALWAYS", "true"),
+            ])
+            .await,
+        );
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b", "copy_sender": true}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["recipients"], json!({"accepted": 1, "rejected": 0}));
+        assert_eq!(
+            test_support::sent(&app).await[0]["to"]
+                .as_array()
+                .unwrap()
+                .len(),
+            3
+        );
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "cc": "lead@example.com", "bcc": "audit@example.com", "title": "t", "body": "b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["recipients"], json!({"accepted": 3, "rejected": 0}));
+    }
+
+    #[test]
+    fn startup_fails_when_no_channel_is_enabled() {
+        for raw in [" ", " , "] {
+            let err = test_support::config(&[("ENABLED_CHANNELS", raw)]).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "ENABLED_CHANNELS is empty, no notification channel would be enabled; list at least one of smtp, slack"
+            );
+        }
+        let err = test_support::config(&[("ENABLED_CHANNELS", "smtp,pager")]).unwrap_err();
+        assert_eq!(err.to_string(), "unsupported ENABLED_CHANNELS entry: pager");
+        let cfg = test_support::config(&[("ENABLED_CHANNELS", "Email, smtp")]).unwrap();
+        assert_eq!(cfg.enabled_channels, [NotificationService::Smtp]);
+    }
+
+    #[tokio::test]
+    async fn channels_left_out_of_enabled_channels_are_refused() {
+        let app = test_support::app(&test_support::state(&[("ENABLED_CHANNELS", "smtp")]).await);
+        let (status, body) = notify(
+            &app,
+            json!({"service": "slack", "to": "#ops", "title": "t", "body": "b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{body}");
+        assert_eq!(body["message"], "channel not enabled: slack");
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+    }
 }
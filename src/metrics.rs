@@ -0,0 +1,429 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Write as _,
+    net::{ToSocketAddrs, UdpSocket},
+    sync::Arc,
+    sync::Mutex,
+};
+
+use anyhow::{Context, Result};
+use axum::{
+    extract::State,
+    http::{header::CONTENT_TYPE, StatusCode},
+    response::{IntoResponse, Response},
+};
+
+use crate::{error_response, AppState};
+
+/// Longest accepted tag value; keeps label values bounded alongside the key
+/// allowlist.
+const MAX_TAG_VALUE_LEN: usize = 64;
+
+/// `tenant` label of the sends of tenants past `METRICS_MAX_TENANTS`.
+const OTHER_TENANT: &str = "other";
+
+/// Labels every series carries; tags may not reuse them.
+const RESERVED_LABELS: &[&str] = &["service", "outcome", "tenant"];
+
+/// Per-message tags attached to a send, e.g. `{ "team": "billing" }`.
+pub type Tags = BTreeMap<String, String>;
+
+/// Where send counters are published, from `METRICS_BACKEND`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetricsBackend {
+    /// Scraped from `/metrics`.
+    Prometheus,
+    /// Pushed to `STATSD_ADDR` over UDP, one DogStatsD packet per send.
+    Statsd(String),
+    /// Not published; `/metrics` answers 404.
+    None,
+}
+
+/// Send counters exposed at `/metrics` in the Prometheus text format, or
+/// pushed to StatsD with the same name and labels as tags.
+///
+/// Tags become label dimensions, but only for keys in `METRIC_TAG_KEYS`, so
+/// callers cannot grow the number of series without a config change. With
+/// `METRICS_TENANT_LABEL` each series is also split by the caller's tenant.
+/// An introspection backend can name any number of tenants, so only the
+/// first `METRICS_MAX_TENANTS` seen get their own label value; the rest are
+/// counted together as `other`.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    tag_keys: Vec<String>,
+    /// Most tenants labelled by name; `None` without the tenant label.
+    max_tenants: Option<usize>,
+    tenants: Mutex<HashSet<String>>,
+    /// Whether `/metrics` serves the counters.
+    scrape: bool,
+    statsd: Option<UdpSocket>,
+    /// Also kept with StatsD, as `/healthz` reports the totals.
+    sends: Mutex<BTreeMap<Vec<(String, String)>, u64>>,
+}
+
+impl Metrics {
+    pub fn new(
+        tag_keys: Vec<String>,
+        max_tenants: Option<usize>,
+        backend: MetricsBackend,
+    ) -> Result<Self> {
+        for key in &tag_keys {
+            anyhow::ensure!(
+                is_label_name(key),
+                "METRIC_TAG_KEYS contains an invalid label name: {key}"
+            );
+            anyhow::ensure!(
+                !RESERVED_LABELS.contains(&key.as_str()),
+                "METRIC_TAG_KEYS cannot use reserved label: {key}"
+            );
+        }
+        let statsd = match &backend {
+            MetricsBackend::Statsd(addr) => Some(statsd_socket(addr)?),
+            MetricsBackend::Prometheus | MetricsBackend::None => None,
+        };
+        Ok(Self {
+            tag_keys,
+            max_tenants,
+            tenants: Mutex::default(),
+            scrape: backend == MetricsBackend::Prometheus,
+            statsd,
+            sends: Mutex::default(),
+        })
+    }
+
+    /// Checks request tags against the allowlist and value limits.
+    pub fn validate_tags(&self, tags: &Tags) -> Result<(), String> {
+        for (key, value) in tags {
+            if !self.tag_keys.contains(key) {
+                return Err(format!("tag key not allowed: {key}"));
+            }
+            if value.is_empty() || value.chars().count() > MAX_TAG_VALUE_LEN {
+                return Err(format!(
+                    "tag `{key}` must be 1 to {MAX_TAG_VALUE_LEN} characters"
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts one send. Tags that fail validation are dropped, so rejected
+    /// requests are still counted without adding series.
+    pub fn record_send(&self, service: &str, outcome: &str, tenant: &str, tags: &Tags) {
+        let mut labels = vec![
+            ("outcome".to_string(), outcome.to_string()),
+            ("service".to_string(), service.to_string()),
+        ];
+        if let Some(max_tenants) = self.max_tenants {
+            let mut tenants = self.tenants.lock().expect("metrics lock poisoned");
+            let tenant = if tenants.contains(tenant) {
+                tenant
+            } else if tenants.len() < max_tenants {
+                tenants.insert(tenant.to_string());
+                tenant
+            } else {
+                OTHER_TENANT
+            };
+            labels.push(("tenant".to_string(), tenant.to_string()));
+        }
+        labels.extend(
+            tags.iter()
+                .filter(|(key, value)| {
+                    self.tag_keys.contains(key)
+                        && !value.is_empty()
+                        && value.chars().count() <= MAX_TAG_VALUE_LEN
+                })
+                .map(|(key, value)| (key.clone(), value.clone())),
+        );
+        labels.sort();
+        if let Some(socket) = &self.statsd {
+            // Fire and forget: a lost packet is StatsD's usual trade-off, and
+            // a full socket buffer must not hold up the send.
+            let _ = socket.send(statsd_packet(&labels).as_bytes());
+        }
+
+        let mut sends = self.sends.lock().expect("metrics lock poisoned");
+        *sends.entry(labels).or_default() += 1;
+    }
+
+    /// Sends counted with `outcome`, summed across services and tags.
+    pub fn total(&self, outcome: &str) -> u64 {
+        let sends = self.sends.lock().expect("metrics lock poisoned");
+        sends
+            .iter()
+            .filter(|(labels, _)| {
+                labels
+                    .iter()
+                    .any(|(key, value)| key == "outcome" && value == outcome)
+            })
+            .map(|(_, count)| count)
+            .sum()
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::from(
+            "# HELP notifications_total Notifications handled, by outcome and tags.\n\
+             # TYPE notifications_total counter\n",
+        );
+        let sends = self.sends.lock().expect("metrics lock poisoned");
+        for (labels, count) in sends.iter() {
+            let labels = labels
+                .iter()
+                .map(|(key, value)| format!("{key}=\"{}\"", escape_label_value(value)))
+                .collect::<Vec<_>>()
+                .join(",");
+            let _ = writeln!(out, "notifications_total{{{labels}}} {count}");
+        }
+        out
+    }
+}
+
+pub async fn metrics(State(state): State<Arc<AppState>>) -> Response {
+    if !state.metrics.scrape {
+        return error_response(StatusCode::NOT_FOUND, "prometheus metrics are disabled")
+            .into_response();
+    }
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")],
+        state.metrics.render(),
+    )
+        .into_response()
+}
+
+/// A non-blocking UDP socket connected to the StatsD agent at `addr`.
+fn statsd_socket(addr: &str) -> Result<UdpSocket> {
+    let target = addr
+        .to_socket_addrs()
+        .with_context(|| format!("failed to resolve STATSD_ADDR {addr}"))?
+        .next()
+        .with_context(|| format!("STATSD_ADDR {addr} resolves to no address"))?;
+    let local = if target.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = UdpSocket::bind(local).context("failed to open statsd socket")?;
+    socket
+        .connect(target)
+        .with_context(|| format!("failed to connect statsd socket to {target}"))?;
+    socket
+        .set_nonblocking(true)
+        .context("failed to configure statsd socket")?;
+    Ok(socket)
+}
+
+/// One increment of `notifications_total`, labels as DogStatsD tags.
+fn statsd_packet(labels: &[(String, String)]) -> String {
+    let tags = labels
+        .iter()
+        .map(|(key, value)| format!("{key}:{}", escape_tag_value(value)))
+        .collect::<Vec<_>>()
+        .join(",");
+    format!("notifications_total:1|c|#{tags}")
+}
+
+fn is_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|ch| ch.is_ascii_alphanumeric() || ch == '_')
+        && !name.starts_with("__")
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Replaces the characters that delimit DogStatsD tags.
+fn escape_tag_value(value: &str) -> String {
+    value
+        .chars()
+        .map(|ch| match ch {
+            ',' | '|' | '#' | '\n' => '_',
+            ch => ch,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metrics(max_tenants: Option<usize>) -> Metrics {
+        Metrics::new(Vec::new(), max_tenants, MetricsBackend::Prometheus).expect("valid metrics")
+    }
+
+    #[test]
+    fn tenants_past_the_cap_are_counted_as_other() {
+        let metrics = metrics(Some(2));
+        for tenant in ["a", "b", "c", "d", "a"] {
+            metrics.record_send("smtp", "sent", tenant, &Tags::new());
+        }
+        let rendered = metrics.render();
+        assert!(
+            rendered.contains(r#"notifications_total{outcome="sent",service="smtp",tenant="a"} 2"#),
+            "{rendered}"
+        );
+        assert!(rendered.contains(r#"tenant="b"} 1"#), "{rendered}");
+        assert!(rendered.contains(r#"tenant="other"} 2"#), "{rendered}");
+        assert!(!rendered.contains(r#"tenant="c""#), "{rendered}");
+        assert_eq!(
+            metrics.tenants.lock().expect("metrics lock poisoned").len(),
+            2
+        );
+    }
+
+    #[test]
+    fn tenant_label_is_off_by_default() {
+        let metrics = metrics(None);
+        metrics.record_send("smtp", "sent", "a", &Tags::new());
+        let rendered = metrics.render();
+        assert!(
+            rendered.contains(r#"notifications_total{outcome="sent",service="smtp"} 1"#),
+            "{rendered}"
+        );
+    }
+
+    fn tags(pairs: &[(&str, &str)]) -> Tags {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn allowed_tags_become_labels() {
+        let metrics = Metrics::new(
+            vec!["team".to_string(), "env".to_string()],
+            None,
+            MetricsBackend::Prometheus,
+        )
+        .expect("valid metrics");
+        let billing = tags(&[("team", "billing"), ("env", "prod")]);
+        assert_eq!(metrics.validate_tags(&billing), Ok(()));
+        metrics.record_send("smtp", "sent", "a", &billing);
+        metrics.record_send("smtp", "sent", "a", &billing);
+        metrics.record_send("smtp", "sent", "a", &tags(&[("team", "ops")]));
+        let rendered = metrics.render();
+        assert!(
+            rendered.contains(
+                r#"notifications_total{env="prod",outcome="sent",service="smtp",team="billing"} 2"#
+            ),
+            "{rendered}"
+        );
+        assert!(
+            rendered.contains(r#"notifications_total{outcome="sent",service="smtp",team="ops"} 1"#),
+            "{rendered}"
+        );
+    }
+
+    #[test]
+    fn disallowed_tags_are_refused_and_never_labelled() {
+        let metrics = Metrics::new(vec!["team".to_string()], None, MetricsBackend::Prometheus)
+            .expect("valid metrics");
+        let user = tags(&[("user_id", "42")]);
+        assert_eq!(
+            metrics.validate_tags(&user),
+            Err("tag key not allowed: user_id".to_string())
+        );
+        assert_eq!(
+            metrics.validate_tags(&tags(&[("team", &"x".repeat(65))])),
+            Err("tag `team` must be 1 to 64 characters".to_string())
+        );
+        metrics.record_send("smtp", "rejected", "a", &user);
+        assert!(!metrics.render().contains("user_id"));
+    }
+
+    #[test]
+    fn tag_keys_cannot_reuse_reserved_labels() {
+        let err = Metrics::new(
+            vec!["outcome".to_string()],
+            None,
+            MetricsBackend::Prometheus,
+        )
+        .expect_err("outcome is reserved");
+        assert_eq!(
+            err.to_string(),
+            "METRIC_TAG_KEYS cannot use reserved label: outcome"
+        );
+        let err = Metrics::new(
+            vec!["bad-key".to_string()],
+            None,
+            MetricsBackend::Prometheus,
+        )
+        .expect_err("not a label name");
+        assert_eq!(
+            err.to_string(),
+            "METRIC_TAG_KEYS contains an invalid label name: bad-key"
+        );
+    }
+
+    /// A StatsD agent on a local port, and its address for `STATSD_ADDR`.
+    fn statsd_agent() -> (UdpSocket, String) {
+        let agent = UdpSocket::bind("127.0.0.1:0").expect("udp socket binds");
+        agent
+            .set_read_timeout(Some(std::time::Duration::from_secs(2)))
+            .unwrap();
+        let addr = agent.local_addr().unwrap().to_string();
+        (agent, addr)
+    }
+
+    fn packet(agent: &UdpSocket) -> String {
+        let mut buf = [0; 1024];
+        let len = agent.recv(&mut buf).expect("a statsd packet");
+        String::from_utf8_lossy(&buf[..len]).into_owned()
+    }
+
+    #[test]
+    fn sends_are_pushed_to_statsd_with_labels_as_tags() {
+        let (agent, addr) = statsd_agent();
+        let metrics = Metrics::new(vec!["team".to_string()], None, MetricsBackend::Statsd(addr))
+            .expect("valid metrics");
+        metrics.record_send("smtp", "sent", "a", &tags(&[("team", "bill|ing,#1")]));
+        assert_eq!(
+            packet(&agent),
+            "notifications_total:1|c|#outcome:sent,service:smtp,team:bill_ing__1"
+        );
+        // Totals are still kept for /healthz.
+        assert_eq!(metrics.total("sent"), 1);
+    }
+
+    #[tokio::test]
+    async fn a_notification_emits_the_send_counter_and_disables_scraping() {
+        use axum::http::Method;
+        use serde_json::json;
+
+        use crate::test_support;
+
+        let (agent, addr) = statsd_agent();
+        let state =
+            test_support::state(&[("METRICS_BACKEND", "statsd"), ("STATSD_ADDR", &addr)]).await;
+        let app = test_support::app(&state);
+        let (status, body) = test_support::notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(
+            packet(&agent),
+            "notifications_total:1|c|#outcome:sent,service:smtp"
+        );
+
+        let (status, body) = test_support::call(&app, Method::GET, "/metrics", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["message"], "prometheus metrics are disabled");
+    }
+
+    #[test]
+    fn the_none_backend_publishes_nothing() {
+        let metrics = Metrics::new(Vec::new(), None, MetricsBackend::None).expect("valid metrics");
+        metrics.record_send("smtp", "sent", "a", &Tags::new());
+        assert!(!metrics.scrape);
+        assert!(metrics.statsd.is_none());
+        assert_eq!(metrics.total("sent"), 1);
+    }
+}
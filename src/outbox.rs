@@ -0,0 +1,38 @@
+use std::{
+    fs,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use lettre::Message;
+
+/// Development mode enabled by `OUTBOX_DIR`: messages are written to disk as
+/// `.eml` files, byte for byte what SMTP would have carried, instead of
+/// being sent.
+pub struct Outbox {
+    dir: PathBuf,
+}
+
+impl Outbox {
+    pub fn new(dir: PathBuf) -> Result<Self> {
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("failed to create outbox dir {}", dir.display()))?;
+        Ok(Self { dir })
+    }
+
+    /// Writes the message and returns the file it landed in. Names sort by
+    /// save time.
+    pub fn save(&self, email: &Message) -> Result<PathBuf> {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_millis())
+            .unwrap_or_default();
+        let path = self
+            .dir
+            .join(format!("{millis}-{:08x}.eml", rand::random::<u32>()));
+        fs::write(&path, email.formatted())
+            .with_context(|| format!("failed to write {}", path.display()))?;
+        Ok(path)
+    }
+}
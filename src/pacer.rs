@@ -0,0 +1,71 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Global leaky-bucket pacer that spaces outbound sends to the provider's
+/// maximum rate. Callers reserve the next free slot and sleep until it; a
+/// caller whose slot is further away than `max_wait` is turned away instead.
+#[derive(Debug)]
+pub struct Pacer {
+    interval: Duration,
+    max_wait: Duration,
+    next_slot: Mutex<Instant>,
+}
+
+impl Pacer {
+    pub fn new(rate_per_sec: f64, max_wait: Duration) -> Self {
+        Self {
+            interval: Duration::from_secs_f64(1.0 / rate_per_sec),
+            max_wait,
+            next_slot: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Waits for a send slot. Returns `false` without reserving one when the
+    /// queue of waiting senders is already longer than `max_wait`.
+    pub async fn acquire(&self) -> bool {
+        let wait = {
+            let mut next_slot = self.next_slot.lock().expect("pacer lock poisoned");
+            let now = Instant::now();
+            let slot = (*next_slot).max(now);
+            let wait = slot - now;
+            if wait > self.max_wait {
+                return false;
+            }
+            *next_slot = slot + self.interval;
+            wait
+        };
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future::join_all;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn burst_is_spaced_to_the_rate() {
+        let pacer = Pacer::new(50.0, Duration::from_secs(5));
+        let started = Instant::now();
+        let acquired = join_all((0..10).map(|_| pacer.acquire())).await;
+        let elapsed = started.elapsed();
+        assert!(acquired.iter().all(|ok| *ok));
+        // Ten sends at 50/s: the last goes out nine intervals after the first.
+        assert!(elapsed >= Duration::from_millis(175), "{elapsed:?}");
+        assert!(elapsed < Duration::from_millis(600), "{elapsed:?}");
+    }
+
+    #[tokio::test]
+    async fn senders_past_max_wait_are_turned_away() {
+        let pacer = Pacer::new(10.0, Duration::from_millis(150));
+        let acquired = join_all((0..4).map(|_| pacer.acquire())).await;
+        assert_eq!(acquired, [true, true, false, false]);
+    }
+}
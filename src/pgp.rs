@@ -0,0 +1,191 @@
+use std::{collections::HashMap, fs, io::Write, path::Path};
+
+use anyhow::{Context, Result};
+use lettre::{
+    message::{header::ContentType, MultiPart, SinglePart},
+    Address,
+};
+use sequoia_openpgp::{
+    cert::CertParser,
+    parse::Parse,
+    policy::StandardPolicy,
+    serialize::stream::{Armorer, Encryptor, LiteralWriter, Message},
+    Cert,
+};
+
+/// What to do when `encrypt` is requested but a recipient has no key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissingKey {
+    /// Reject the request.
+    Fail,
+    /// Send the message unencrypted to everyone.
+    Plaintext,
+}
+
+/// Recipient public keys from `PGP_KEYS_DIR`, indexed by the email
+/// addresses in their user IDs.
+pub struct PgpKeys {
+    certs: HashMap<String, Cert>,
+    pub missing_key: MissingKey,
+}
+
+impl PgpKeys {
+    /// Reads every file in `dir` as one or more certificates, armored or
+    /// binary.
+    pub fn load(dir: &Path, missing_key: MissingKey) -> Result<Self> {
+        let mut certs = HashMap::new();
+        let entries = fs::read_dir(dir)
+            .with_context(|| format!("failed to read pgp keys dir {}", dir.display()))?;
+        for entry in entries {
+            let path = entry?.path();
+            if !path.is_file() {
+                continue;
+            }
+            let parser = CertParser::from_file(&path)
+                .map_err(|err| anyhow::anyhow!("invalid pgp key {}: {err}", path.display()))?;
+            for cert in parser {
+                let cert = cert
+                    .map_err(|err| anyhow::anyhow!("invalid pgp key {}: {err}", path.display()))?;
+                for userid in cert.userids() {
+                    if let Ok(Some(email)) = userid.userid().email_normalized() {
+                        certs.insert(email, cert.clone());
+                    }
+                }
+            }
+        }
+        Ok(Self { certs, missing_key })
+    }
+
+    /// The first recipient without a key, if any.
+    pub fn missing<'a>(&self, recipients: &'a [Address]) -> Option<&'a Address> {
+        recipients
+            .iter()
+            .find(|address| !self.certs.contains_key(&address.to_string().to_lowercase()))
+    }
+
+    /// Encrypts a MIME entity to every recipient as an RFC 3156
+    /// multipart/encrypted body. All recipients must have a key.
+    pub fn encrypt(
+        &self,
+        recipients: &[Address],
+        entity: &[u8],
+        boundary: String,
+    ) -> Result<MultiPart> {
+        let policy = StandardPolicy::new();
+        let mut keys = Vec::new();
+        for address in recipients {
+            let cert = self
+                .certs
+                .get(&address.to_string().to_lowercase())
+                .with_context(|| format!("no pgp key for {address}"))?;
+            let before = keys.len();
+            keys.extend(
+                cert.keys()
+                    .with_policy(&policy, None)
+                    .supported()
+                    .alive()
+                    .revoked(false)
+                    .for_transport_encryption(),
+            );
+            if keys.len() == before {
+                anyhow::bail!("pgp key for {address} has no usable encryption subkey");
+            }
+        }
+
+        let mut armored = Vec::new();
+        let message = Armorer::new(Message::new(&mut armored)).build()?;
+        let message = Encryptor::for_recipients(message, keys).build()?;
+        let mut message = LiteralWriter::new(message).build()?;
+        message.write_all(entity)?;
+        message.finalize()?;
+
+        let control = SinglePart::builder()
+            .header(ContentType::parse("application/pgp-encrypted").expect("valid mime type"))
+            .body(String::from("Version: 1\r\n"));
+        let encrypted = SinglePart::builder()
+            .header(
+                ContentType::parse("application/octet-stream; name=\"encrypted.asc\"")
+                    .expect("valid mime type"),
+            )
+            .body(armored);
+        Ok(
+            MultiPart::encrypted("application/pgp-encrypted".to_string())
+                .boundary(boundary)
+                .singlepart(control)
+                .singlepart(encrypted),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support;
+
+    use super::*;
+
+    fn address(raw: &str) -> Address {
+        raw.parse().expect("valid address")
+    }
+
+    #[test]
+    fn keys_are_indexed_by_their_user_id_emails() {
+        let dir = test_support::pgp_keys(&["ops@example.com", "Lead@Example.com"]);
+        let keys = PgpKeys::load(dir.path(), MissingKey::Fail).expect("keys load");
+
+        assert_eq!(
+            keys.missing(&[address("ops@example.com"), address("lead@example.com")]),
+            None
+        );
+        let recipients = [address("ops@example.com"), address("nobody@example.com")];
+        assert_eq!(keys.missing(&recipients), Some(&recipients[1]));
+    }
+
+    #[test]
+    fn a_file_that_is_not_a_key_fails_to_load() {
+        let dir = test_support::TempDir::new("pgp-bad-key");
+        fs::write(dir.path().join("junk.asc"), "not a key").unwrap();
+        let err = PgpKeys::load(dir.path(), MissingKey::Fail)
+            .err()
+            .map(|err| err.to_string())
+            .expect("load fails");
+        assert!(err.starts_with("invalid pgp key"), "{err}");
+    }
+
+    #[test]
+    fn encrypting_gives_an_rfc_3156_multipart() {
+        let dir = test_support::pgp_keys(&["ops@example.com"]);
+        let keys = PgpKeys::load(dir.path(), MissingKey::Fail).expect("keys load");
+
+        let multipart = keys
+            .encrypt(
+                &[address("ops@example.com")],
+                b"Content-Type: text/plain\r\n\r\nbuild 1234 is live",
+                "boundary-1".to_string(),
+            )
+            .expect("encryption succeeds");
+        let formatted = String::from_utf8(multipart.formatted()).expect("ascii armored");
+        assert!(formatted.contains("multipart/encrypted"), "{formatted}");
+        assert!(
+            formatted.contains("protocol=\"application/pgp-encrypted\""),
+            "{formatted}"
+        );
+        assert!(formatted.contains("Version: 1"), "{formatted}");
+        assert!(
+            formatted.contains("-----BEGIN PGP MESSAGE-----"),
+            "{formatted}"
+        );
+        assert!(!formatted.contains("build 1234 is live"), "{formatted}");
+    }
+
+    #[test]
+    fn encrypting_to_a_recipient_without_a_key_fails() {
+        let dir = test_support::pgp_keys(&["ops@example.com"]);
+        let keys = PgpKeys::load(dir.path(), MissingKey::Fail).expect("keys load");
+
+        let err = keys
+            .encrypt(&[address("nobody@example.com")], b"body", "b".to_string())
+            .err()
+            .map(|err| err.to_string());
+        assert_eq!(err.as_deref(), Some("no pgp key for nobody@example.com"));
+    }
+}
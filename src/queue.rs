@@ -0,0 +1,1738 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BinaryHeap, HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, HeaderName, HeaderValue, StatusCode},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+use crate::{
+    api_keys::{self, Caller},
+    authenticate, dispatch, error_response, field_error,
+    spool::{self, JobKind, SpooledJob},
+    validate, ApiError, ApiResponse, AppState, JsonBody, NotifyRequest,
+};
+
+/// Finished jobs kept for `GET /jobs/{id}` before the oldest are forgotten.
+const FINISHED_HISTORY: usize = 1_000;
+
+/// How often a chunked batch checks for queue room and finished jobs.
+const BATCH_CHUNK_POLL: Duration = Duration::from_millis(200);
+
+/// Recent queued sends averaged for `eta_secs`.
+const SEND_TIME_SAMPLES: usize = 20;
+
+/// Headers carried from the enqueue request to the worker so the send
+/// continues the caller's trace. Credentials are deliberately not kept.
+const PROPAGATED_HEADERS: &[&str] = &["traceparent", "tracestate"];
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+impl Priority {
+    /// How many aging steps ahead of a `low` job this priority starts.
+    fn head_start(self) -> u32 {
+        match self {
+            Priority::High => 2,
+            Priority::Normal => 1,
+            Priority::Low => 0,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EnqueueRequest {
+    #[serde(flatten)]
+    message: NotifyRequest,
+    #[serde(default)]
+    priority: Priority,
+}
+
+#[derive(Serialize)]
+pub struct EnqueueResponse {
+    ok: bool,
+    message: String,
+    job_id: String,
+    #[serde(flatten)]
+    estimate: Option<Estimate>,
+}
+
+/// Where a waiting job stands in the queue: 1 is next to be sent.
+/// `eta_secs` is a rough guess from recent send times, absent until a queued
+/// send has finished.
+#[derive(Debug, Clone, Copy, Serialize)]
+struct Estimate {
+    queue_position: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    eta_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum JobStatus {
+    Queued,
+    Sending,
+    Sent,
+    Failed,
+    /// Waited in the queue past `JOB_MAX_AGE_SECS` and was dropped unsent.
+    Expired,
+    /// Withdrawn with `DELETE /jobs/{id}` before it was sent.
+    Cancelled,
+}
+
+impl JobStatus {
+    fn label(self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Sending => "sending",
+            JobStatus::Sent => "sent",
+            JobStatus::Failed => "failed",
+            JobStatus::Expired => "expired",
+            JobStatus::Cancelled => "cancelled",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct JobView {
+    id: String,
+    status: JobStatus,
+    priority: Priority,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(flatten)]
+    estimate: Option<Estimate>,
+}
+
+#[derive(Serialize)]
+pub struct JobResponse {
+    ok: bool,
+    message: String,
+    job: JobView,
+}
+
+struct JobRecord {
+    key: String,
+    view: JobView,
+    /// Heap ordering of a job still waiting in the queue.
+    order: Option<(Duration, u64)>,
+}
+
+/// A waiting job. Ordering is by virtual enqueue time: each priority level
+/// counts as having waited one `aging` step longer, so a `low` job overtakes
+/// `high` work enqueued more than two steps after it and never starves.
+/// Jobs sharing an ordering key enter the heap one at a time, so among them
+/// submission order wins over priority.
+struct QueuedJob {
+    /// Virtual enqueue time, measured from the queue's creation.
+    ready_at: Duration,
+    seq: u64,
+    /// Wall clock, so an age carried over from a spooled job still counts.
+    enqueued_at: SystemTime,
+    id: String,
+    request: NotifyRequest,
+    headers: HeaderMap,
+}
+
+impl PartialEq for QueuedJob {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for QueuedJob {}
+
+impl PartialOrd for QueuedJob {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for QueuedJob {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.ready_at, self.seq).cmp(&(other.ready_at, other.seq))
+    }
+}
+
+#[derive(Default)]
+struct QueueInner {
+    heap: BinaryHeap<Reverse<QueuedJob>>,
+    /// Per ordering key (with the API key that owns it), the jobs waiting
+    /// behind the one in the heap or being sent. A key is present while any
+    /// of its jobs is.
+    held: HashMap<(String, String), VecDeque<QueuedJob>>,
+    held_jobs: usize,
+    jobs: HashMap<String, JobRecord>,
+    /// Records in `jobs` still `queued`, for `MAX_QUEUED_JOBS`.
+    waiting: usize,
+    finished: VecDeque<String>,
+    next_seq: u64,
+    send_times: VecDeque<Duration>,
+}
+
+impl QueueInner {
+    /// Adds or replaces the record of job `id`.
+    fn track(&mut self, id: &str, record: JobRecord) {
+        if record.view.status == JobStatus::Queued {
+            self.waiting += 1;
+        }
+        if let Some(old) = self.jobs.insert(id.to_string(), record) {
+            if old.view.status == JobStatus::Queued {
+                self.waiting -= 1;
+            }
+        }
+    }
+
+    fn set_status(&mut self, id: &str, status: JobStatus) -> Option<&mut JobRecord> {
+        let record = self.jobs.get_mut(id)?;
+        match (
+            record.view.status == JobStatus::Queued,
+            status == JobStatus::Queued,
+        ) {
+            (true, false) => self.waiting -= 1,
+            (false, true) => self.waiting += 1,
+            _ => {}
+        }
+        record.view.status = status;
+        Some(record)
+    }
+
+    fn finish(&mut self, id: &str, status: JobStatus, error: Option<String>) {
+        if let Some(record) = self.set_status(id, status) {
+            record.view.error = error;
+            record.order = None;
+        }
+        self.finished.push_back(id.to_string());
+        while self.finished.len() > FINISHED_HISTORY {
+            if let Some(oldest) = self.finished.pop_front() {
+                if let Some(old) = self.jobs.remove(&oldest) {
+                    if old.view.status == JobStatus::Queued {
+                        self.waiting -= 1;
+                    }
+                }
+            }
+        }
+    }
+
+    fn is_cancelled(&self, id: &str) -> bool {
+        self.jobs
+            .get(id)
+            .is_some_and(|record| record.view.status == JobStatus::Cancelled)
+    }
+}
+
+/// Why `DELETE /jobs/{id}` could not cancel a job.
+enum CancelError {
+    NotFound,
+    /// The job is already past `queued`.
+    Started(JobStatus),
+}
+
+/// In-memory priority queue behind `POST /notify/async`, drained by
+/// `QUEUE_WORKERS` background workers.
+pub struct JobQueue {
+    capacity: usize,
+    /// `MAX_QUEUED_JOBS`: jobs accepted but not yet sending, across the
+    /// queue, send windows, digests and chunked batches.
+    max_waiting: Option<usize>,
+    workers: usize,
+    aging: Duration,
+    max_age: Option<Duration>,
+    created: Instant,
+    inner: Mutex<QueueInner>,
+    ready: Notify,
+}
+
+impl JobQueue {
+    pub fn new(
+        capacity: usize,
+        max_waiting: Option<usize>,
+        workers: usize,
+        aging: Duration,
+        max_age: Option<Duration>,
+    ) -> Self {
+        Self {
+            capacity,
+            max_waiting,
+            workers,
+            aging,
+            max_age,
+            created: Instant::now(),
+            inner: Mutex::default(),
+            ready: Notify::new(),
+        }
+    }
+
+    /// Queues a job under `id`; `false` when the queue is full.
+    fn push(
+        &self,
+        id: &str,
+        key: &str,
+        request: NotifyRequest,
+        priority: Priority,
+        headers: HeaderMap,
+        enqueued_at: SystemTime,
+    ) -> bool {
+        // Offset every job by the largest head start so the subtraction
+        // cannot go below zero.
+        let ready_at = self.created.elapsed()
+            + self.aging.saturating_mul(Priority::High.head_start())
+            - self.aging.saturating_mul(priority.head_start());
+
+        {
+            let mut inner = self.inner.lock().expect("queue lock poisoned");
+            // A reserved id cancelled before it got here stays cancelled.
+            if inner.is_cancelled(id) {
+                return true;
+            }
+            if inner.heap.len() + inner.held_jobs >= self.capacity {
+                return false;
+            }
+            let seq = inner.next_seq;
+            inner.next_seq += 1;
+            inner.track(
+                id,
+                JobRecord {
+                    key: key.to_string(),
+                    view: JobView {
+                        id: id.to_string(),
+                        status: JobStatus::Queued,
+                        priority,
+                        error: None,
+                        estimate: None,
+                    },
+                    order: Some((ready_at, seq)),
+                },
+            );
+            let ordering = request
+                .ordering_key
+                .clone()
+                .map(|ordering_key| (key.to_string(), ordering_key));
+            let job = QueuedJob {
+                ready_at,
+                seq,
+                enqueued_at,
+                id: id.to_string(),
+                request,
+                headers,
+            };
+            match ordering {
+                Some(ordering) if inner.held.contains_key(&ordering) => {
+                    inner.held.entry(ordering).or_default().push_back(job);
+                    inner.held_jobs += 1;
+                    return true;
+                }
+                Some(ordering) => {
+                    inner.held.insert(ordering, VecDeque::new());
+                    inner.heap.push(Reverse(job));
+                }
+                None => inner.heap.push(Reverse(job)),
+            }
+        }
+        self.ready.notify_one();
+        true
+    }
+
+    /// Lets the next job behind a finished one with the same ordering key
+    /// into the heap. Called once the job is done with, however it ended.
+    fn release(&self, key: &str, ordering_key: Option<String>) {
+        let Some(ordering_key) = ordering_key else {
+            return;
+        };
+        {
+            let mut inner = self.inner.lock().expect("queue lock poisoned");
+            let ordering = (key.to_string(), ordering_key);
+            let next = inner.held.get_mut(&ordering).and_then(VecDeque::pop_front);
+            match next {
+                Some(job) => {
+                    inner.held_jobs -= 1;
+                    inner.heap.push(Reverse(job));
+                }
+                None => {
+                    inner.held.remove(&ordering);
+                    return;
+                }
+            }
+        }
+        self.ready.notify_one();
+    }
+
+    /// Registers a job that skips the queue and runs on its own task;
+    /// `false` when its reserved id was cancelled meanwhile.
+    fn track_detached(&self, id: &str, key: &str, status: JobStatus) -> bool {
+        let mut inner = self.inner.lock().expect("queue lock poisoned");
+        if inner.is_cancelled(id) {
+            return false;
+        }
+        inner.track(
+            id,
+            JobRecord {
+                key: key.to_string(),
+                view: JobView {
+                    id: id.to_string(),
+                    status,
+                    priority: Priority::default(),
+                    error: None,
+                    estimate: None,
+                },
+                order: None,
+            },
+        );
+        true
+    }
+
+    /// Waits for the most urgent job and marks it as sending.
+    async fn pop(&self) -> (QueuedJob, String) {
+        loop {
+            // Register interest before checking so a push in between is not
+            // missed.
+            let notified = self.ready.notified();
+            {
+                let mut inner = self.inner.lock().expect("queue lock poisoned");
+                if let Some(Reverse(job)) = inner.heap.pop() {
+                    let record = inner
+                        .set_status(&job.id, JobStatus::Sending)
+                        .expect("queued job has a record");
+                    record.order = None;
+                    let key = record.key.clone();
+                    return (job, key);
+                }
+            }
+            notified.await;
+        }
+    }
+
+    /// Marks a waiting detached job as sending; `false` when it was
+    /// cancelled while it waited.
+    fn start(&self, id: &str) -> bool {
+        let mut inner = self.inner.lock().expect("queue lock poisoned");
+        if inner.is_cancelled(id) {
+            return false;
+        }
+        inner.set_status(id, JobStatus::Sending);
+        true
+    }
+
+    fn finish(&self, id: &str, status: JobStatus, error: Option<String>) {
+        let mut inner = self.inner.lock().expect("queue lock poisoned");
+        inner.finish(id, status, error);
+    }
+
+    /// Withdraws a job of `key` that has not started sending. A job waiting
+    /// in the heap is dropped from it, letting the next job with its
+    /// ordering key in; a detached one is skipped when its wait ends.
+    fn cancel(&self, key: &str, id: &str) -> Result<(), CancelError> {
+        let released = {
+            let mut inner = self.inner.lock().expect("queue lock poisoned");
+            let record = inner
+                .jobs
+                .get(id)
+                .filter(|record| record.key == key)
+                .ok_or(CancelError::NotFound)?;
+            if record.view.status != JobStatus::Queued {
+                return Err(CancelError::Started(record.view.status));
+            }
+
+            let mut removed = None;
+            inner.heap.retain(|Reverse(job)| {
+                let keep = job.id != id;
+                if !keep {
+                    removed = job.request.ordering_key.clone();
+                }
+                keep
+            });
+            let mut held_removed = 0;
+            for held in inner.held.values_mut() {
+                let before = held.len();
+                held.retain(|job| job.id != id);
+                held_removed += before - held.len();
+            }
+            inner.held_jobs -= held_removed;
+            inner.finish(id, JobStatus::Cancelled, None);
+            removed
+        };
+        // The cancelled job held its ordering key like a running one.
+        self.release(key, released);
+        Ok(())
+    }
+
+    /// Jobs waiting to send and jobs sending now.
+    pub fn depth(&self) -> (usize, usize) {
+        let inner = self.inner.lock().expect("queue lock poisoned");
+        let sending = inner
+            .jobs
+            .values()
+            .filter(|record| record.view.status == JobStatus::Sending)
+            .count();
+        (inner.waiting, sending)
+    }
+
+    /// Whether `count` more waiting jobs fit under `MAX_QUEUED_JOBS`.
+    fn has_room(&self, count: usize) -> bool {
+        let Some(max) = self.max_waiting else {
+            return true;
+        };
+        let inner = self.inner.lock().expect("queue lock poisoned");
+        inner.waiting + count <= max
+    }
+
+    /// Whether the job has finished, or is no longer known at all.
+    fn is_done(&self, id: &str) -> bool {
+        let inner = self.inner.lock().expect("queue lock poisoned");
+        inner.jobs.get(id).is_none_or(|record| {
+            matches!(
+                record.view.status,
+                JobStatus::Sent | JobStatus::Failed | JobStatus::Expired | JobStatus::Cancelled
+            )
+        })
+    }
+
+    fn view(&self, key: &str, id: &str) -> Option<JobView> {
+        let inner = self.inner.lock().expect("queue lock poisoned");
+        let record = inner.jobs.get(id).filter(|record| record.key == key)?;
+        Some(JobView {
+            estimate: self.estimate(&inner, record),
+            ..record.view.clone()
+        })
+    }
+
+    /// Counts the jobs ahead of `record`, so the cost is linear in the queue
+    /// depth.
+    fn estimate(&self, inner: &QueueInner, record: &JobRecord) -> Option<Estimate> {
+        let order = record.order?;
+        let ahead = inner
+            .heap
+            .iter()
+            .filter(|Reverse(job)| (job.ready_at, job.seq) < order)
+            .count();
+        let eta_secs = (!inner.send_times.is_empty()).then(|| {
+            let average = inner.send_times.iter().sum::<Duration>() / inner.send_times.len() as u32;
+            let rounds = (ahead / self.workers + 1) as u32;
+            average.saturating_mul(rounds).as_secs()
+        });
+        Some(Estimate {
+            queue_position: ahead + 1,
+            eta_secs,
+        })
+    }
+
+    fn estimate_for(&self, id: &str) -> Option<Estimate> {
+        let inner = self.inner.lock().expect("queue lock poisoned");
+        inner
+            .jobs
+            .get(id)
+            .and_then(|record| self.estimate(&inner, record))
+    }
+
+    /// Notes how long a send taken off the queue ran.
+    fn record_send_time(&self, elapsed: Duration) {
+        let mut inner = self.inner.lock().expect("queue lock poisoned");
+        if inner.send_times.len() == SEND_TIME_SAMPLES {
+            inner.send_times.pop_front();
+        }
+        inner.send_times.push_back(elapsed);
+    }
+}
+
+/// Starts `count` workers draining the queue for the life of the process.
+pub fn spawn_workers(state: &Arc<AppState>, count: usize) {
+    for _ in 0..count {
+        let state = state.clone();
+        tokio::spawn(async move {
+            loop {
+                let (job, key) = state.queue.pop().await;
+                let ordering_key = job.request.ordering_key.clone();
+                run_job(&state, job, &key).await;
+                state.queue.release(&key, ordering_key);
+            }
+        });
+    }
+}
+
+/// Sends one job taken off the queue, unless it expired or its key is gone.
+async fn run_job(state: &Arc<AppState>, job: QueuedJob, key: &str) {
+    if let Some(max_age) = state.queue.max_age {
+        let age = job.enqueued_at.elapsed().unwrap_or_default();
+        if age > max_age {
+            expire_job(state, &job, key, age).await;
+            return;
+        }
+    }
+    let Some(caller) = state.api_keys.lookup(key).await else {
+        fail_invalid_key(state, &job.id);
+        return;
+    };
+
+    if let Some(spool) = &state.spool {
+        spool.mark_sending(&job.id);
+    }
+    let started = Instant::now();
+    let (status, Json(body)) = dispatch(state, &caller, &job.headers, job.request).await;
+    state.queue.record_send_time(started.elapsed());
+    finish_job(state, &job.id, status, body);
+}
+
+fn finish_job(state: &AppState, id: &str, status: StatusCode, body: ApiResponse) {
+    if !status.is_success() {
+        bury(state, id, &body.message);
+    }
+    if let Some(spool) = &state.spool {
+        spool.remove(id);
+    }
+    if status.is_success() {
+        info!(job_id = %id, "background notification sent");
+        state.queue.finish(id, JobStatus::Sent, None);
+    } else {
+        warn!(job_id = %id, error = %body.message, "background notification failed");
+        state
+            .queue
+            .finish(id, JobStatus::Failed, Some(body.message));
+    }
+}
+
+async fn expire_job(state: &AppState, job: &QueuedJob, key: &str, age: Duration) {
+    bury(
+        state,
+        &job.id,
+        &format!("expired after {}s in the queue", age.as_secs()),
+    );
+    if let Some(spool) = &state.spool {
+        spool.remove(&job.id);
+    }
+    warn!(job_id = %job.id, age_secs = age.as_secs(), "queued notification expired unsent");
+    // A key removed since the job was queued no longer has a tenant.
+    let tenant = state
+        .api_keys
+        .lookup(key)
+        .await
+        .map(|caller| caller.policy.tenant.clone())
+        .unwrap_or_default();
+    state.metrics.record_send(
+        job.request.service.name(),
+        JobStatus::Expired.label(),
+        &tenant,
+        &job.request.tags,
+    );
+    state.queue.finish(
+        &job.id,
+        JobStatus::Expired,
+        Some(format!("expired after {}s in the queue", age.as_secs())),
+    );
+}
+
+/// Copies a failed job's spool file to the dead-letter store, when enabled.
+fn bury(state: &AppState, id: &str, error: &str) {
+    let (Some(dead_letters), Some(spool)) = (&state.dead_letters, &state.spool) else {
+        return;
+    };
+    match spool.get(id) {
+        Some(job) => dead_letters.bury(job, error.to_string()),
+        None => warn!(job_id = %id, "spooled job missing, not moved to dead-letter store"),
+    }
+}
+
+/// The key that queued the job was removed from `API_KEYS_FILE` since.
+fn fail_invalid_key(state: &AppState, id: &str) {
+    if let Some(spool) = &state.spool {
+        spool.remove(id);
+    }
+    state
+        .queue
+        .finish(id, JobStatus::Failed, Some("invalid api key".to_string()));
+}
+
+/// Refuses new background work once `MAX_QUEUED_JOBS` jobs are waiting.
+pub fn check_room(state: &AppState, count: usize) -> Result<(), ApiError> {
+    if state.queue.has_room(count) {
+        return Ok(());
+    }
+    warn!(count, "too many queued jobs, refusing new work");
+    Err(error_response(
+        StatusCode::TOO_MANY_REQUESTS,
+        "too many queued jobs, retry later",
+    )
+    .into())
+}
+
+fn new_job_id() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+/// Writes the job to `SPOOL_DIR`, when set, before it is acknowledged.
+fn persist(state: &AppState, mut job: SpooledJob) -> Result<(), ApiError> {
+    let Some(spool) = &state.spool else {
+        return Ok(());
+    };
+    spool.save(&mut job).map_err(|err| {
+        warn!(job_id = %job.id, error = %format!("{err:#}"), "failed to spool job");
+        error_response(StatusCode::INTERNAL_SERVER_ERROR, "failed to persist job").into()
+    })
+}
+
+pub fn propagated_headers(headers: &HeaderMap) -> HeaderMap {
+    let mut propagated = HeaderMap::new();
+    for name in PROPAGATED_HEADERS {
+        if let Some(value) = headers.get(*name) {
+            propagated.insert(*name, value.clone());
+        }
+    }
+    propagated
+}
+
+/// `async_ack` on `POST /notify`: validates now, answers 202 with a job id
+/// and sends on a task of its own. Unlike `/notify/async` there is no queue,
+/// so nothing bounds how many such sends run at once.
+pub fn send_detached(
+    state: &Arc<AppState>,
+    caller: &Caller<'_>,
+    headers: &HeaderMap,
+    req: NotifyRequest,
+) -> Result<(StatusCode, Json<EnqueueResponse>), ApiError> {
+    validate(state, caller, &req)?;
+    check_room(state, 1)?;
+    let job_id = spawn_detached(state, caller, headers, req, None)?;
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(EnqueueResponse {
+            ok: true,
+            message: "accepted".to_string(),
+            job_id,
+            estimate: None,
+        }),
+    ))
+}
+
+/// `respect_send_window` outside the window: validates now and sends once
+/// `wait` has passed. Without `SPOOL_DIR` the wait is lost on restart.
+pub fn send_at_window(
+    state: &Arc<AppState>,
+    caller: &Caller<'_>,
+    headers: &HeaderMap,
+    req: NotifyRequest,
+    wait: Duration,
+) -> Result<(StatusCode, Json<EnqueueResponse>), ApiError> {
+    validate(state, caller, &req)?;
+    check_room(state, 1)?;
+    let job_id = spawn_detached(state, caller, headers, req, Some(wait))?;
+    info!(job_id = %job_id, wait_secs = wait.as_secs(), "notification deferred until send window");
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(EnqueueResponse {
+            ok: true,
+            message: "deferred until send window".to_string(),
+            job_id,
+            estimate: None,
+        }),
+    ))
+}
+
+/// Sends `req` on a task of its own, after `wait` when given, and returns
+/// the id its progress is tracked under.
+fn spawn_detached(
+    state: &Arc<AppState>,
+    caller: &Caller<'_>,
+    headers: &HeaderMap,
+    req: NotifyRequest,
+    wait: Option<Duration>,
+) -> Result<String, ApiError> {
+    let id = new_job_id();
+    let headers = propagated_headers(headers);
+    persist(
+        state,
+        SpooledJob {
+            id: id.clone(),
+            key_id: api_keys::key_id(caller.key),
+            legacy_key: None,
+            kind: JobKind::Detached,
+            priority: Priority::default(),
+            request: req.clone(),
+            headers: header_pairs(&headers),
+            not_before: wait.map(|wait| spool::to_unix(SystemTime::now() + wait)),
+            enqueued_at: None,
+        },
+    )?;
+    run_detached(
+        state.clone(),
+        id.clone(),
+        caller.key.to_string(),
+        headers,
+        req,
+        wait,
+    );
+    Ok(id)
+}
+
+/// A job id for a send that is put together later, e.g. a digest, shown as
+/// queued until [`send_reserved`] starts it.
+pub fn reserve(state: &AppState, key: &str) -> String {
+    let id = new_job_id();
+    state.queue.track_detached(&id, key, JobStatus::Queued);
+    id
+}
+
+/// Whether the job `id` was withdrawn with `DELETE /jobs/{id}`.
+pub fn is_cancelled(state: &AppState, id: &str) -> bool {
+    let inner = state.queue.inner.lock().expect("queue lock poisoned");
+    inner.is_cancelled(id)
+}
+
+/// Sends `req` on a task of its own as the job `id` from [`reserve`].
+/// `headers` are already [`propagated_headers`].
+pub fn send_reserved(
+    state: &Arc<AppState>,
+    id: String,
+    key: String,
+    headers: HeaderMap,
+    req: NotifyRequest,
+) {
+    // Spooled like any detached send; there is no caller to tell if that
+    // fails, so the send goes ahead regardless.
+    let _ = persist(
+        state,
+        SpooledJob {
+            id: id.clone(),
+            key_id: api_keys::key_id(&key),
+            legacy_key: None,
+            kind: JobKind::Detached,
+            priority: Priority::default(),
+            request: req.clone(),
+            headers: header_pairs(&headers),
+            not_before: None,
+            enqueued_at: None,
+        },
+    );
+    run_detached(state.clone(), id, key, headers, req, None);
+}
+
+fn run_detached(
+    state: Arc<AppState>,
+    id: String,
+    key: String,
+    headers: HeaderMap,
+    req: NotifyRequest,
+    wait: Option<Duration>,
+) {
+    let status = match wait {
+        Some(_) => JobStatus::Queued,
+        None => JobStatus::Sending,
+    };
+    if !state.queue.track_detached(&id, &key, status) {
+        skip_cancelled(&state, &id);
+        return;
+    }
+    tokio::spawn(async move {
+        if let Some(wait) = wait {
+            tokio::time::sleep(wait).await;
+            if !state.queue.start(&id) {
+                skip_cancelled(&state, &id);
+                return;
+            }
+        }
+        let Some(caller) = state.api_keys.lookup(&key).await else {
+            fail_invalid_key(&state, &id);
+            return;
+        };
+        if let Some(spool) = &state.spool {
+            spool.mark_sending(&id);
+        }
+        let (status, Json(body)) = dispatch(&state, &caller, &headers, req).await;
+        finish_job(&state, &id, status, body);
+    });
+}
+
+fn skip_cancelled(state: &AppState, id: &str) {
+    if let Some(spool) = &state.spool {
+        spool.remove(id);
+    }
+    info!(job_id = %id, "cancelled job skipped");
+}
+
+/// Resumes the jobs left in `SPOOL_DIR` by the previous run. Delivery is at
+/// least once: a job that was mid-send may already have gone out and is
+/// sent again.
+pub fn recover(state: &Arc<AppState>) -> anyhow::Result<()> {
+    let Some(spool) = &state.spool else {
+        return Ok(());
+    };
+    let jobs = spool.load()?;
+    if !jobs.is_empty() {
+        info!(jobs = jobs.len(), "resuming spooled jobs");
+    }
+    for (job, sending) in jobs {
+        if sending {
+            warn!(job_id = %job.id, "job was mid-send at shutdown, sending again");
+        }
+        let key = job
+            .legacy_key
+            .clone()
+            .or_else(|| state.api_keys.key_for_id(&job.key_id));
+        let Some(key) = key else {
+            warn!(job_id = %job.id, "api key of spooled job no longer known, moving it to dead letters");
+            bury(state, &job.id, "api key no longer known");
+            spool.remove(&job.id);
+            continue;
+        };
+        let headers = header_map(&job.headers);
+        match job.kind {
+            JobKind::Queued => {
+                let enqueued_at = job
+                    .enqueued_at
+                    .map_or_else(SystemTime::now, spool::from_unix);
+                if !state.queue.push(
+                    &job.id,
+                    &key,
+                    job.request,
+                    job.priority,
+                    headers,
+                    enqueued_at,
+                ) {
+                    warn!(job_id = %job.id, "queue full, spooled job left for the next start");
+                }
+            }
+            JobKind::Detached => {
+                let wait = job.not_before.map(spool::until_unix);
+                run_detached(state.clone(), job.id, key, headers, job.request, wait);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn header_pairs(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect()
+}
+
+pub fn header_map(pairs: &[(String, String)]) -> HeaderMap {
+    pairs
+        .iter()
+        .filter_map(|(name, value)| {
+            Some((
+                HeaderName::try_from(name.as_str()).ok()?,
+                HeaderValue::try_from(value.as_str()).ok()?,
+            ))
+        })
+        .collect()
+}
+
+/// `POST /notify/async`: validates the message now and sends it from the
+/// queue, highest priority first.
+pub async fn enqueue(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    JsonBody(req): JsonBody<EnqueueRequest>,
+) -> Result<(StatusCode, Json<EnqueueResponse>), ApiError> {
+    let Some(caller) = authenticate(&state, &headers).await else {
+        return Err(error_response(StatusCode::UNAUTHORIZED, "invalid api key").into());
+    };
+    if req
+        .message
+        .ordering_key
+        .as_deref()
+        .is_some_and(|ordering_key| ordering_key.trim().is_empty())
+    {
+        return Err(field_error("ordering_key", "ordering_key cannot be empty").into());
+    }
+    // Reject invalid messages up front rather than failing them later.
+    validate(&state, &caller, &req.message)?;
+    check_room(&state, 1)?;
+
+    let job_id = new_job_id();
+    let headers = propagated_headers(&headers);
+    let enqueued_at = SystemTime::now();
+    persist(
+        &state,
+        SpooledJob {
+            id: job_id.clone(),
+            key_id: api_keys::key_id(caller.key),
+            legacy_key: None,
+            kind: JobKind::Queued,
+            priority: req.priority,
+            request: req.message.clone(),
+            headers: header_pairs(&headers),
+            not_before: None,
+            enqueued_at: Some(spool::to_unix(enqueued_at)),
+        },
+    )?;
+    if !state.queue.push(
+        &job_id,
+        caller.key,
+        req.message,
+        req.priority,
+        headers,
+        enqueued_at,
+    ) {
+        if let Some(spool) = &state.spool {
+            spool.remove(&job_id);
+        }
+        return Err(
+            error_response(StatusCode::SERVICE_UNAVAILABLE, "queue full, retry later").into(),
+        );
+    }
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(EnqueueResponse {
+            ok: true,
+            message: "queued".to_string(),
+            estimate: state.queue.estimate_for(&job_id),
+            job_id,
+        }),
+    ))
+}
+
+/// Feeds an oversized `/notify/batch` into the queue `chunk` messages at a
+/// time, each chunk once the previous one has finished, so one batch never
+/// holds more than `chunk` queue slots. Every message is validated first
+/// and gets its job id up front, showing as `queued` until it is pushed.
+pub fn enqueue_batch(
+    state: &Arc<AppState>,
+    caller: &Caller<'_>,
+    headers: &HeaderMap,
+    messages: Vec<NotifyRequest>,
+    chunk: usize,
+) -> Result<(String, Vec<String>), ApiError> {
+    for (index, message) in messages.iter().enumerate() {
+        if let Err(err) = validate(state, caller, message) {
+            let (status, Json(body)) = err.into_inner();
+            return Err(
+                error_response(status, &format!("message {index}: {}", body.message)).into(),
+            );
+        }
+    }
+
+    check_room(state, messages.len())?;
+
+    let batch_id = new_job_id();
+    let jobs: Vec<(String, NotifyRequest)> = messages
+        .into_iter()
+        .map(|message| (new_job_id(), message))
+        .collect();
+    for (id, _) in &jobs {
+        state
+            .queue
+            .track_detached(id, caller.key, JobStatus::Queued);
+    }
+    let job_ids = jobs.iter().map(|(id, _)| id.clone()).collect();
+    info!(batch_id = %batch_id, jobs = jobs.len(), chunk, "oversized batch accepted for the queue");
+
+    let state = state.clone();
+    let key = caller.key.to_string();
+    let headers = propagated_headers(headers);
+    let task_batch_id = batch_id.clone();
+    tokio::spawn(async move {
+        let batch_id = task_batch_id;
+        let mut jobs = jobs.into_iter().peekable();
+        while jobs.peek().is_some() {
+            let mut pushed = Vec::with_capacity(chunk);
+            for (id, message) in jobs.by_ref().take(chunk) {
+                push_batch_job(&state, &key, &headers, &id, message).await;
+                pushed.push(id);
+            }
+            while !pushed.iter().all(|id| state.queue.is_done(id)) {
+                tokio::time::sleep(BATCH_CHUNK_POLL).await;
+            }
+        }
+        info!(batch_id = %batch_id, "oversized batch fully processed");
+    });
+    Ok((batch_id, job_ids))
+}
+
+/// Queues one message of a chunked batch, waiting for room when the queue
+/// is full.
+async fn push_batch_job(
+    state: &AppState,
+    key: &str,
+    headers: &HeaderMap,
+    id: &str,
+    message: NotifyRequest,
+) {
+    let enqueued_at = SystemTime::now();
+    if let Some(spool) = &state.spool {
+        let mut job = SpooledJob {
+            id: id.to_string(),
+            key_id: api_keys::key_id(key),
+            legacy_key: None,
+            kind: JobKind::Queued,
+            priority: Priority::default(),
+            request: message.clone(),
+            headers: header_pairs(headers),
+            not_before: None,
+            enqueued_at: Some(spool::to_unix(enqueued_at)),
+        };
+        if let Err(err) = spool.save(&mut job) {
+            warn!(job_id = %id, error = %format!("{err:#}"), "failed to spool job");
+        }
+    }
+    while !state.queue.push(
+        id,
+        key,
+        message.clone(),
+        Priority::default(),
+        headers.clone(),
+        enqueued_at,
+    ) {
+        tokio::time::sleep(BATCH_CHUNK_POLL).await;
+    }
+}
+
+/// `DELETE /jobs/{id}`: cancels a job of the same API key that is still
+/// `queued`, whether waiting in the queue, for its send window or in an
+/// open digest. 409 once it is sending or done.
+pub async fn cancel_job(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> (StatusCode, Json<ApiResponse>) {
+    let Some(caller) = authenticate(&state, &headers).await else {
+        return error_response(StatusCode::UNAUTHORIZED, "invalid api key");
+    };
+    match state.queue.cancel(caller.key, &id) {
+        Ok(()) => {}
+        Err(CancelError::NotFound) => {
+            return error_response(StatusCode::NOT_FOUND, "job not found")
+        }
+        Err(CancelError::Started(status)) => {
+            return error_response(
+                StatusCode::CONFLICT,
+                &format!("job is already {}", status.label()),
+            )
+        }
+    }
+    if let Some(spool) = &state.spool {
+        spool.remove(&id);
+    }
+
+    info!(job_id = %id, "job cancelled");
+    (StatusCode::OK, Json(ApiResponse::ok("cancelled")))
+}
+
+/// `GET /jobs/{id}`: status of a job queued with the same API key.
+pub async fn job_status(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<JobResponse>, ApiError> {
+    let Some(caller) = authenticate(&state, &headers).await else {
+        return Err(error_response(StatusCode::UNAUTHORIZED, "invalid api key").into());
+    };
+    let Some(job) = state.queue.view(caller.key, &id) else {
+        return Err(error_response(StatusCode::NOT_FOUND, "job not found").into());
+    };
+
+    Ok(Json(JobResponse {
+        ok: !matches!(job.status, JobStatus::Failed | JobStatus::Expired),
+        message: job.status.label().to_string(),
+        job,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Method;
+
+    use crate::test_support::{self, call, notify};
+    use serde_json::json;
+
+    use super::*;
+
+    fn request(to: &str) -> NotifyRequest {
+        serde_json::from_value(json!({"service": "smtp", "to": to, "title": "t", "body": "b"}))
+            .expect("valid request")
+    }
+
+    fn queue(aging: Duration) -> JobQueue {
+        JobQueue::new(100, None, 1, aging, None)
+    }
+
+    fn push(queue: &JobQueue, id: &str, priority: Priority) {
+        let pushed = queue.push(
+            id,
+            "test-key",
+            request("ops@example.com"),
+            priority,
+            HeaderMap::new(),
+            SystemTime::now(),
+        );
+        assert!(pushed, "queue has room");
+    }
+
+    async fn drain(queue: &JobQueue, count: usize) -> Vec<String> {
+        let mut order = Vec::new();
+        for _ in 0..count {
+            order.push(queue.pop().await.0.id);
+        }
+        order
+    }
+
+    #[tokio::test]
+    async fn higher_priorities_are_sent_first() {
+        let queue = queue(Duration::from_secs(60));
+        push(&queue, "low-1", Priority::Low);
+        push(&queue, "normal-1", Priority::Normal);
+        push(&queue, "high-1", Priority::High);
+        push(&queue, "low-2", Priority::Low);
+        push(&queue, "high-2", Priority::High);
+        assert_eq!(
+            drain(&queue, 5).await,
+            ["high-1", "high-2", "normal-1", "low-1", "low-2"]
+        );
+    }
+
+    #[tokio::test]
+    async fn waiting_low_jobs_overtake_newer_high_ones() {
+        let queue = queue(Duration::from_millis(20));
+        push(&queue, "low", Priority::Low);
+        // Older than two aging steps by the time the high job arrives.
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        push(&queue, "high", Priority::High);
+        push(&queue, "normal", Priority::Normal);
+        assert_eq!(drain(&queue, 3).await, ["low", "high", "normal"]);
+    }
+
+    #[tokio::test]
+    async fn async_ack_answers_before_the_send_completes() {
+        let smtp = test_support::MockSmtp::start().await;
+        smtp.delay("DATA", Duration::from_millis(300));
+        let state = smtp.state(&[]).await;
+        let app = test_support::app(&state);
+
+        let started = Instant::now();
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b", "async_ack": true}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::ACCEPTED, "{body}");
+        assert!(started.elapsed() < Duration::from_millis(300));
+        assert_eq!(body["message"], "accepted");
+        let uri = format!("/jobs/{}", body["job_id"].as_str().expect("a job id"));
+        let (_, job) = call(&app, Method::GET, &uri, None).await;
+        assert_ne!(job["message"], "sent", "{job}");
+        assert!(smtp.messages().is_empty());
+
+        let deadline = Instant::now() + Duration::from_secs(3);
+        loop {
+            let (status, job) = call(&app, Method::GET, &uri, None).await;
+            assert_eq!(status, StatusCode::OK, "{job}");
+            if job["message"] == "sent" {
+                break;
+            }
+            assert!(Instant::now() < deadline, "job never sent: {job}");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(smtp.messages().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn inside_the_send_window_is_sent_now() {
+        let state = test_support::state(&[]).await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b",
+                "respect_send_window": {"timezone": "UTC", "start_hour": 0, "end_hour": 24}}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(test_support::sent(&app).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn outside_the_send_window_is_deferred() {
+        let state = test_support::state(&[]).await;
+        let app = test_support::app(&state);
+        let hour = chrono::Timelike::hour(&chrono::Utc::now());
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b",
+                "respect_send_window": {"timezone": "UTC", "start_hour": (hour + 2) % 24, "end_hour": (hour + 3) % 24}}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::ACCEPTED, "{body}");
+        assert_eq!(body["message"], "deferred until send window");
+        assert!(body["job_id"].is_string(), "{body}");
+        assert!(test_support::sent(&app).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_deferred_send_is_validated_up_front() {
+        let state = test_support::state(&[]).await;
+        let app = test_support::app(&state);
+        let hour = chrono::Timelike::hour(&chrono::Utc::now());
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "not an address", "title": "t", "body": "b",
+                "respect_send_window": {"timezone": "UTC", "start_hour": (hour + 2) % 24, "end_hour": (hour + 3) % 24}}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{body}");
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b",
+                "respect_send_window": {"timezone": "Mars/Olympus", "start_hour": 9, "end_hour": 17}}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{body}");
+        assert_eq!(body["message"], "unknown timezone: Mars/Olympus");
+    }
+
+    async fn wait_for_sent(app: &axum::Router, count: usize) -> Vec<serde_json::Value> {
+        let deadline = Instant::now() + Duration::from_secs(3);
+        loop {
+            let sent = test_support::sent(app).await;
+            if sent.len() >= count {
+                return sent;
+            }
+            assert!(Instant::now() < deadline, "only {} sent", sent.len());
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn spooled_jobs_are_delivered_after_a_restart() {
+        let dir = test_support::TempDir::new("queue-restart");
+        let vars = [("SPOOL_DIR", dir.path().to_str().expect("utf-8 path"))];
+
+        // No workers, so the job is still pending when this run "stops".
+        let before = test_support::state(&vars).await;
+        let (status, body) = call(
+            &test_support::app(&before),
+            Method::POST,
+            "/notify/async",
+            Some(json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b"})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::ACCEPTED, "{body}");
+        assert_eq!(before.spool.as_ref().map(spool::Spool::count), Some(1));
+        drop(before);
+
+        let after = test_support::state(&vars).await;
+        let app = test_support::app(&after);
+        recover(&after).expect("spool loads");
+        spawn_workers(&after, 1);
+        let sent = wait_for_sent(&app, 1).await;
+        assert_eq!(sent[0]["to"], json!(["ops@example.com"]));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(after.spool.as_ref().map(spool::Spool::count), Some(0));
+    }
+
+    #[tokio::test]
+    async fn a_job_that_was_mid_send_is_sent_again() {
+        let dir = test_support::TempDir::new("queue-mid-send");
+        let vars = [("SPOOL_DIR", dir.path().to_str().expect("utf-8 path"))];
+        let hour = chrono::Timelike::hour(&chrono::Utc::now());
+
+        let before = test_support::state(&vars).await;
+        let (status, body) = notify(
+            &test_support::app(&before),
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b",
+                "respect_send_window": {"timezone": "UTC", "start_hour": (hour + 2) % 24, "end_hour": (hour + 3) % 24}}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::ACCEPTED, "{body}");
+        let job_id = body["job_id"].as_str().expect("a job id");
+        let spool = before.spool.as_ref().expect("spool is configured");
+        let mut job = spool.get(job_id).expect("job is spooled");
+        assert_eq!(job.kind, JobKind::Detached);
+        assert!(job.not_before.is_some());
+        // As if the window had opened and the send was cut short.
+        job.not_before = None;
+        spool.save(&mut job).unwrap();
+        spool.mark_sending(job_id);
+        drop(before);
+
+        let after = test_support::state(&vars).await;
+        let app = test_support::app(&after);
+        recover(&after).expect("spool loads");
+        wait_for_sent(&app, 1).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(after.spool.as_ref().map(spool::Spool::count), Some(0));
+    }
+
+    async fn wait_for_job(app: &axum::Router, id: &str) -> serde_json::Value {
+        let uri = format!("/jobs/{id}");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let (status, job) = call(app, Method::GET, &uri, None).await;
+            assert_eq!(status, StatusCode::OK, "{job}");
+            if !matches!(job["message"].as_str(), Some("queued" | "sending")) {
+                return job;
+            }
+            assert!(Instant::now() < deadline, "job never finished: {job}");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn jobs_queued_past_the_max_age_expire_unsent() {
+        let smtp = test_support::MockSmtp::start().await;
+        // The first send holds the only worker past the second job's max age.
+        smtp.delay("DATA", Duration::from_millis(1500));
+        let state = smtp.state(&[("JOB_MAX_AGE_SECS", "1")]).await;
+        let app = test_support::app(&state);
+        spawn_workers(&state, 1);
+
+        let mut ids = Vec::new();
+        for to in ["first@example.com", "second@example.com"] {
+            let (status, body) = call(
+                &app,
+                Method::POST,
+                "/notify/async",
+                Some(json!({"service": "smtp", "to": to, "title": "t", "body": "b"})),
+            )
+            .await;
+            assert_eq!(status, StatusCode::ACCEPTED, "{body}");
+            ids.push(body["job_id"].as_str().expect("a job id").to_string());
+        }
+
+        let first = wait_for_job(&app, &ids[0]).await;
+        assert_eq!(first["message"], "sent", "{first}");
+        let second = wait_for_job(&app, &ids[1]).await;
+        assert_eq!(second["message"], "expired", "{second}");
+        assert_eq!(second["ok"], false);
+        let error = second["job"]["error"].as_str().expect("a reason");
+        assert!(error.starts_with("expired after "), "{error}");
+        assert_eq!(smtp.messages().len(), 1);
+
+        let (_, metrics) = test_support::call_text(&app, Method::GET, "/metrics", None).await;
+        assert!(
+            metrics
+                .lines()
+                .any(|line| line.contains(r#"outcome="expired""#) && line.ends_with(" 1")),
+            "{metrics}"
+        );
+    }
+
+    fn position(queue: &JobQueue, id: &str) -> Option<(usize, Option<u64>)> {
+        queue
+            .estimate_for(id)
+            .map(|estimate| (estimate.queue_position, estimate.eta_secs))
+    }
+
+    #[tokio::test]
+    async fn positions_follow_the_send_order_and_move_up_as_the_queue_drains() {
+        let queue = queue(Duration::from_secs(60));
+        push(&queue, "normal-1", Priority::Normal);
+        push(&queue, "normal-2", Priority::Normal);
+        push(&queue, "high", Priority::High);
+        assert_eq!(position(&queue, "high"), Some((1, None)));
+        assert_eq!(position(&queue, "normal-1"), Some((2, None)));
+        assert_eq!(position(&queue, "normal-2"), Some((3, None)));
+
+        assert_eq!(drain(&queue, 1).await, ["high"]);
+        assert_eq!(position(&queue, "high"), None, "taken off the queue");
+        assert_eq!(position(&queue, "normal-1"), Some((1, None)));
+        assert_eq!(position(&queue, "normal-2"), Some((2, None)));
+    }
+
+    #[tokio::test]
+    async fn eta_is_the_average_send_time_per_round_of_workers() {
+        let queue = JobQueue::new(100, None, 2, Duration::from_secs(60), None);
+        for id in ["a", "b", "c", "d", "e"] {
+            push(&queue, id, Priority::Normal);
+        }
+        queue.record_send_time(Duration::from_secs(1));
+        queue.record_send_time(Duration::from_secs(3));
+
+        // Two workers send two jobs a round, two seconds each on average.
+        assert_eq!(position(&queue, "a"), Some((1, Some(2))));
+        assert_eq!(position(&queue, "b"), Some((2, Some(2))));
+        assert_eq!(position(&queue, "c"), Some((3, Some(4))));
+        assert_eq!(position(&queue, "e"), Some((5, Some(6))));
+    }
+
+    #[tokio::test]
+    async fn enqueued_jobs_are_told_their_position() {
+        let state = test_support::state(&[]).await;
+        let app = test_support::app(&state);
+
+        let mut ids = Vec::new();
+        for n in 1..=3 {
+            let (status, body) = call(
+                &app,
+                Method::POST,
+                "/notify/async",
+                Some(json!({"service": "smtp", "to": format!("user{n}@example.com"), "title": "t", "body": "b"})),
+            )
+            .await;
+            assert_eq!(status, StatusCode::ACCEPTED, "{body}");
+            assert_eq!(body["queue_position"], n, "{body}");
+            assert!(body.get("eta_secs").is_none(), "no sends timed yet: {body}");
+            ids.push(body["job_id"].as_str().expect("a job id").to_string());
+        }
+
+        let (status, job) = call(&app, Method::GET, &format!("/jobs/{}", ids[2]), None).await;
+        assert_eq!(status, StatusCode::OK, "{job}");
+        assert_eq!(job["job"]["queue_position"], 3, "{job}");
+
+        spawn_workers(&state, 1);
+        let finished = wait_for_job(&app, &ids[2]).await;
+        assert_eq!(finished["message"], "sent");
+        assert!(
+            finished["job"].get("queue_position").is_none(),
+            "{finished}"
+        );
+    }
+
+    fn push_ordered(queue: &JobQueue, id: &str, ordering_key: &str, priority: Priority) {
+        let mut request = request("ops@example.com");
+        request.ordering_key = Some(ordering_key.to_string());
+        let pushed = queue.push(
+            id,
+            "test-key",
+            request,
+            priority,
+            HeaderMap::new(),
+            SystemTime::now(),
+        );
+        assert!(pushed, "queue has room");
+    }
+
+    #[tokio::test]
+    async fn jobs_sharing_an_ordering_key_wait_for_the_one_before() {
+        let queue = queue(Duration::from_secs(60));
+        push_ordered(&queue, "started", "deploy-1", Priority::Low);
+        push_ordered(&queue, "completed", "deploy-1", Priority::High);
+        push_ordered(&queue, "other", "deploy-2", Priority::Normal);
+
+        // Priority does not reorder a key's jobs, other keys go ahead.
+        assert_eq!(drain(&queue, 2).await, ["other", "started"]);
+        let next = tokio::time::timeout(Duration::from_millis(50), queue.pop()).await;
+        assert!(
+            next.is_err(),
+            "completed was let in before started finished"
+        );
+
+        queue.release("test-key", Some("deploy-1".to_string()));
+        assert_eq!(drain(&queue, 1).await, ["completed"]);
+        queue.release("test-key", Some("deploy-1".to_string()));
+        queue.release("test-key", Some("deploy-2".to_string()));
+        assert!(queue.inner.lock().unwrap().held.is_empty());
+    }
+
+    #[tokio::test]
+    async fn ordered_sends_do_not_overlap() {
+        let smtp = test_support::MockSmtp::start().await;
+        smtp.delay("DATA", Duration::from_millis(200));
+        let state = smtp.state(&[]).await;
+        let app = test_support::app(&state);
+
+        let mut ids = Vec::new();
+        for (to, priority) in [
+            ("started@example.com", "low"),
+            ("completed@example.com", "high"),
+        ] {
+            let (status, body) = call(
+                &app,
+                Method::POST,
+                "/notify/async",
+                Some(
+                    json!({"service": "smtp", "to": to, "title": "t", "body": "b",
+                    "ordering_key": "deploy-1", "priority": priority}),
+                ),
+            )
+            .await;
+            assert_eq!(status, StatusCode::ACCEPTED, "{body}");
+            ids.push(body["job_id"].as_str().expect("a job id").to_string());
+        }
+        let started = Instant::now();
+        spawn_workers(&state, 2);
+        for id in &ids {
+            assert_eq!(wait_for_job(&app, id).await["message"], "sent");
+        }
+
+        // Two free workers, yet the second send waited out the first.
+        assert!(started.elapsed() >= Duration::from_millis(400));
+        let messages = smtp.messages();
+        assert!(
+            messages[0].contains("To: started@example.com"),
+            "{}",
+            messages[0]
+        );
+        assert!(
+            messages[1].contains("To: completed@example.com"),
+            "{}",
+            messages[1]
+        );
+    }
+
+    #[tokio::test]
+    async fn an_empty_ordering_key_is_refused() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let (status, body) = call(
+            &app,
+            Method::POST,
+            "/notify/async",
+            Some(json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b", "ordering_key": " "})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["message"], "ordering_key cannot be empty");
+    }
+
+    async fn enqueue_async(app: &axum::Router, to: &str) -> String {
+        let (status, body) = call(
+            app,
+            Method::POST,
+            "/notify/async",
+            Some(json!({"service": "smtp", "to": to, "title": "t", "body": "b"})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::ACCEPTED, "{body}");
+        body["job_id"].as_str().expect("a job id").to_string()
+    }
+
+    #[tokio::test]
+    async fn a_cancelled_queued_job_is_never_sent() {
+        let state = test_support::state(&[]).await;
+        let app = test_support::app(&state);
+        // No worker yet, so the job waits in the queue.
+        let cancelled = enqueue_async(&app, "cancelled@example.com").await;
+
+        let uri = format!("/jobs/{cancelled}");
+        let (status, body) = call(&app, Method::DELETE, &uri, None).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["message"], "cancelled");
+
+        spawn_workers(&state, 1);
+        let kept = enqueue_async(&app, "kept@example.com").await;
+        assert_eq!(wait_for_job(&app, &kept).await["message"], "sent");
+        let job = wait_for_job(&app, &cancelled).await;
+        assert_eq!(job["message"], "cancelled", "{job}");
+        let sent = test_support::sent(&app).await;
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0]["to"], json!(["kept@example.com"]));
+    }
+
+    #[tokio::test]
+    async fn a_job_deferred_to_its_send_window_can_be_cancelled() {
+        let state = test_support::state(&[]).await;
+        let app = test_support::app(&state);
+        let hour = chrono::Timelike::hour(&chrono::Utc::now());
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b",
+                "respect_send_window": {"timezone": "UTC", "start_hour": (hour + 2) % 24, "end_hour": (hour + 3) % 24}}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::ACCEPTED, "{body}");
+        let uri = format!("/jobs/{}", body["job_id"].as_str().expect("a job id"));
+
+        let (status, body) = call(&app, Method::DELETE, &uri, None).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let (_, job) = call(&app, Method::GET, &uri, None).await;
+        assert_eq!(job["message"], "cancelled", "{job}");
+        assert!(is_cancelled(&state, uri.trim_start_matches("/jobs/")));
+    }
+
+    #[tokio::test]
+    async fn cancelling_a_sent_job_is_a_conflict() {
+        let state = test_support::state(&[]).await;
+        let app = test_support::app(&state);
+        spawn_workers(&state, 1);
+        let id = enqueue_async(&app, "ops@example.com").await;
+        assert_eq!(wait_for_job(&app, &id).await["message"], "sent");
+
+        let (status, body) = call(&app, Method::DELETE, &format!("/jobs/{id}"), None).await;
+        assert_eq!(status, StatusCode::CONFLICT, "{body}");
+        assert_eq!(body["message"], "job is already sent");
+        assert_eq!(test_support::sent(&app).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn cancelling_an_unknown_job_is_not_found() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let (status, body) = call(&app, Method::DELETE, "/jobs/no-such-job", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "{body}");
+        assert_eq!(body["message"], "job not found");
+    }
+
+    #[tokio::test]
+    async fn enqueues_past_max_queued_jobs_are_refused_until_the_queue_drains() {
+        let state = test_support::state(&[("MAX_QUEUED_JOBS", "2")]).await;
+        let app = test_support::app(&state);
+        let first = enqueue_async(&app, "first@example.com").await;
+        enqueue_async(&app, "second@example.com").await;
+
+        let request =
+            json!({"service": "smtp", "to": "third@example.com", "title": "t", "body": "b"});
+        let (status, body) = call(&app, Method::POST, "/notify/async", Some(request.clone())).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS, "{body}");
+        assert_eq!(body["message"], "too many queued jobs, retry later");
+
+        // A cancelled job no longer counts against the cap.
+        let (status, _) = call(&app, Method::DELETE, &format!("/jobs/{first}"), None).await;
+        assert_eq!(status, StatusCode::OK);
+        let third = enqueue_async(&app, "third@example.com").await;
+        let (status, _) = call(&app, Method::POST, "/notify/async", Some(request.clone())).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+
+        spawn_workers(&state, 1);
+        assert_eq!(wait_for_job(&app, &third).await["message"], "sent");
+        let (status, body) = call(&app, Method::POST, "/notify/async", Some(request)).await;
+        assert_eq!(status, StatusCode::ACCEPTED, "{body}");
+    }
+
+    #[tokio::test]
+    async fn room_is_checked_for_every_job_asked_for() {
+        let state = test_support::state(&[("MAX_QUEUED_JOBS", "2")]).await;
+        assert!(check_room(&state, 2).is_ok());
+        assert!(check_room(&state, 3).is_err());
+        let unlimited = test_support::state(&[]).await;
+        assert!(check_room(&unlimited, 10_000).is_ok());
+    }
+
+    #[tokio::test]
+    async fn a_spooled_job_whose_key_is_gone_is_dead_lettered_on_restart() {
+        let dir = test_support::TempDir::new("queue-key-gone");
+        let spool_dir = dir.path().to_str().expect("utf-8 path");
+
+        let before = test_support::state(&[("SPOOL_DIR", spool_dir)]).await;
+        let (status, body) = call(
+            &test_support::app(&before),
+            Method::POST,
+            "/notify/async",
+            Some(json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b"})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::ACCEPTED, "{body}");
+        drop(before);
+
+        let after = test_support::state(&[
+            ("SPOOL_DIR", spool_dir),
+            ("DEAD_LETTER", "true"),
+            ("API_KEY", "rotated-key"),
+        ])
+        .await;
+        recover(&after).expect("spool loads");
+        assert_eq!(after.spool.as_ref().map(spool::Spool::count), Some(0));
+        assert_eq!(
+            after.dead_letters.as_ref().map(|store| store.count()),
+            Some(1)
+        );
+        assert_eq!(after.queue.depth().0, 0);
+    }
+}
@@ -0,0 +1,581 @@
+use std::{
+    fmt,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use ed25519_dalek::{pkcs8::DecodePrivateKey, Signer as _, SigningKey};
+use hmac::{Hmac, KeyInit, Mac};
+use reqwest::header::{CONTENT_TYPE, RETRY_AFTER};
+use serde::Serialize;
+use sha1::Sha1;
+use sha2::Sha256;
+use tokio::sync::{
+    broadcast::error::RecvError,
+    mpsc::{self, error::TrySendError},
+    Semaphore,
+};
+use tracing::warn;
+
+use crate::{
+    events::{AuditEvent, EventBus},
+    retry::{parse_retry_after, RetryPolicy},
+};
+
+/// Longest a receipt POST may take.
+const RECEIPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Audit event kinds that end a send; opens and clicks are not receipts.
+const SEND_OUTCOMES: &[&str] = &[
+    "sent",
+    "deferred",
+    "deduplicated",
+    "rate_limited",
+    "failed",
+    "expired",
+];
+
+/// `RECEIPTS_URL`: where every send outcome is posted.
+#[derive(Debug, Clone)]
+pub struct ReceiptsConfig {
+    pub url: String,
+    /// Signs each POST over `<X-Timestamp>.<body>`, so a receiver can
+    /// refuse stale replays.
+    pub signer: ReceiptSigner,
+    /// `RECEIPTS_QUEUE`: receipts waiting to be posted; more are dropped.
+    pub queue: usize,
+    /// `RECEIPTS_CONCURRENCY`: receipts posted at once.
+    pub concurrency: usize,
+    /// `RECEIPTS_RETRY_*`: retries of a POST that failed to connect or was
+    /// answered 429 or 5xx.
+    pub retry: RetryPolicy,
+}
+
+/// The normalized delivery event posted to `RECEIPTS_URL`.
+#[derive(Serialize)]
+struct Receipt {
+    /// Unique per receipt, so a receiver can drop a duplicate.
+    id: String,
+    channel: &'static str,
+    status: &'static str,
+    recipient: String,
+    /// Unix timestamp in seconds.
+    timestamp: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message_id: Option<Box<str>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Receipt {
+    fn new(event: AuditEvent) -> Self {
+        let failed = !matches!(event.kind, "sent" | "deferred" | "deduplicated");
+        Self {
+            id: format!("{:032x}", rand::random::<u128>()),
+            channel: channel(event.service),
+            status: event.kind,
+            recipient: event.recipient,
+            timestamp: event.timestamp,
+            message_id: event.message_id,
+            error: failed.then_some(event.message),
+        }
+    }
+}
+
+/// The receipt `channel` for an audit event's service: what kind of
+/// message was delivered rather than which backend delivered it.
+fn channel(service: &'static str) -> &'static str {
+    match service {
+        "smtp" => "email",
+        other => other,
+    }
+}
+
+/// One POST of a receipt: the receipt with a fresh `nonce`, so a receiver
+/// remembering the nonces it saw within its replay window can refuse a
+/// captured request sent again.
+#[derive(Serialize)]
+struct Payload<'a> {
+    #[serde(flatten)]
+    receipt: &'a Receipt,
+    nonce: String,
+}
+
+/// `WEBHOOK_SIGNATURE_ALGO`: how receipts are signed, for receivers that
+/// expect a particular scheme.
+#[derive(Clone)]
+pub enum ReceiptSigner {
+    /// `hmac-sha256`, the default: `X-Signature: sha256=<hex>` keyed with
+    /// `RECEIPTS_SECRET`, the scheme signed requests to this server use.
+    HmacSha256(String),
+    /// `hmac-sha1`, for legacy receivers: `X-Signature: sha1=<hex>`.
+    HmacSha1(String),
+    /// `ed25519`: `X-Signature: ed25519=<base64>` from the PKCS#8 key at
+    /// `RECEIPTS_SIGNING_KEY_PATH`, so receivers only hold the public key.
+    Ed25519(Box<SigningKey>),
+}
+
+impl ReceiptSigner {
+    /// Reads the signer `algo` names; the HMAC schemes take `secret`, and
+    /// `ed25519` the PEM key at `key_path`.
+    pub fn new(algo: &str, secret: Option<String>, key_path: Option<&Path>) -> Result<Self> {
+        let secret = || secret.context("missing env var: RECEIPTS_SECRET");
+        match algo {
+            "hmac-sha256" => Ok(ReceiptSigner::HmacSha256(secret()?)),
+            "hmac-sha1" => Ok(ReceiptSigner::HmacSha1(secret()?)),
+            "ed25519" => {
+                let path = key_path.context("missing env var: RECEIPTS_SIGNING_KEY_PATH")?;
+                let key = SigningKey::read_pkcs8_pem_file(path).map_err(|err| {
+                    anyhow::anyhow!("invalid ed25519 key {}: {err}", path.display())
+                })?;
+                Ok(ReceiptSigner::Ed25519(Box::new(key)))
+            }
+            other => anyhow::bail!("unsupported WEBHOOK_SIGNATURE_ALGO: {other}"),
+        }
+    }
+
+    /// The `X-Signature` value for `message`.
+    fn signature(&self, message: &[u8]) -> String {
+        match self {
+            ReceiptSigner::HmacSha256(secret) => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+                    .expect("hmac accepts any key length");
+                mac.update(message);
+                format!("sha256={}", hex(&mac.finalize().into_bytes()))
+            }
+            ReceiptSigner::HmacSha1(secret) => {
+                let mut mac = Hmac::<Sha1>::new_from_slice(secret.as_bytes())
+                    .expect("hmac accepts any key length");
+                mac.update(message);
+                format!("sha1={}", hex(&mac.finalize().into_bytes()))
+            }
+            ReceiptSigner::Ed25519(key) => {
+                let signature = key.sign(message);
+                format!("ed25519={}", BASE64_STANDARD.encode(signature.to_bytes()))
+            }
+        }
+    }
+
+    /// Signs `<timestamp>.<body>`: binding the timestamp into the signature
+    /// keeps a replayed body from being passed off as fresh.
+    fn sign(&self, timestamp: u64, body: &[u8]) -> String {
+        let mut message = format!("{timestamp}.").into_bytes();
+        message.extend_from_slice(body);
+        self.signature(&message)
+    }
+}
+
+/// Keeps secrets and keys out of the logged config.
+impl fmt::Debug for ReceiptSigner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ReceiptSigner::HmacSha256(_) => "hmac-sha256",
+            ReceiptSigner::HmacSha1(_) => "hmac-sha1",
+            ReceiptSigner::Ed25519(_) => "ed25519",
+        })
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Why one POST of a receipt failed.
+enum PostError {
+    Unreachable(reqwest::Error),
+    Status(reqwest::StatusCode, Option<Duration>),
+}
+
+impl PostError {
+    fn is_retryable(&self) -> bool {
+        match self {
+            PostError::Unreachable(_) => true,
+            PostError::Status(status, _) => {
+                *status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+            }
+        }
+    }
+}
+
+impl fmt::Display for PostError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PostError::Unreachable(err) => write!(f, "{err}"),
+            PostError::Status(status, _) => write!(f, "receiver answered HTTP {status}"),
+        }
+    }
+}
+
+struct Poster {
+    client: reqwest::Client,
+    cfg: ReceiptsConfig,
+}
+
+impl Poster {
+    async fn post_once(&self, receipt: &Receipt) -> Result<(), PostError> {
+        let body = serde_json::to_vec(&Payload {
+            receipt,
+            nonce: format!("{:032x}", rand::random::<u128>()),
+        })
+        .expect("receipt serializes");
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_secs())
+            .unwrap_or_default();
+        let signature = self.cfg.signer.sign(timestamp, &body);
+        let response = self
+            .client
+            .post(&self.cfg.url)
+            .header(CONTENT_TYPE, "application/json")
+            .header("x-timestamp", timestamp)
+            .header("x-signature", signature)
+            .body(body)
+            .send()
+            .await
+            .map_err(PostError::Unreachable)?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| parse_retry_after(value, SystemTime::now()));
+        Err(PostError::Status(status, retry_after))
+    }
+
+    /// Posts `receipt`, retrying under `RECEIPTS_RETRY_*`; a receipt still
+    /// failing after that is logged and dropped.
+    async fn post(&self, receipt: Receipt) {
+        let policy = &self.cfg.retry;
+        let started = Instant::now();
+        let mut attempt = 0;
+        loop {
+            let err = match self.post_once(&receipt).await {
+                Ok(()) => return,
+                Err(err) => err,
+            };
+            let retry_after = match &err {
+                PostError::Status(_, retry_after) => *retry_after,
+                PostError::Unreachable(_) => None,
+            };
+            let delay = err
+                .is_retryable()
+                .then(|| {
+                    policy.next_delay(attempt, started.elapsed(), retry_after, &mut rand::rng())
+                })
+                .flatten();
+            let Some(delay) = delay else {
+                warn!(receipt_id = %receipt.id, status = receipt.status, attempts = attempt + 1, error = %err, "failed to post delivery receipt, dropped");
+                return;
+            };
+            attempt += 1;
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+/// Follows the audit events and posts a signed [`Receipt`] for each send
+/// outcome in the background, up to `RECEIPTS_CONCURRENCY` at once.
+///
+/// Delivery is best effort and receipts only live in memory, so some are
+/// lost: those still failing after `RECEIPTS_RETRY_MAX` retries, those
+/// arriving while `RECEIPTS_QUEUE` is full, those the event bus dropped
+/// because this poster lagged, and any queued or in flight at shutdown.
+/// Each loss is logged.
+pub fn spawn(cfg: ReceiptsConfig, events: &EventBus) -> Result<()> {
+    let client = reqwest::Client::builder()
+        .timeout(RECEIPT_TIMEOUT)
+        .build()
+        .context("failed to create receipts client")?;
+    let (tx, mut queue) = mpsc::channel(cfg.queue.max(1));
+    let slots = Arc::new(Semaphore::new(cfg.concurrency.max(1)));
+    let poster = Arc::new(Poster { client, cfg });
+
+    let mut rx = events.subscribe();
+    tokio::spawn(async move {
+        loop {
+            let event = match rx.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!(skipped, "delivery receipts dropped, raise EVENTS_BUFFER");
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+            if !SEND_OUTCOMES.contains(&event.kind) {
+                continue;
+            }
+            match tx.try_send(Receipt::new(event)) {
+                Ok(()) => {}
+                Err(TrySendError::Full(receipt)) => {
+                    warn!(receipt_id = %receipt.id, status = receipt.status, "delivery receipt queue full, dropped; raise RECEIPTS_QUEUE");
+                }
+                Err(TrySendError::Closed(_)) => return,
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        while let Some(receipt) = queue.recv().await {
+            let slot = slots
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("receipt semaphore is never closed");
+            let poster = poster.clone();
+            tokio::spawn(async move {
+                poster.post(receipt).await;
+                drop(slot);
+            });
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use axum::{extract::State, http::StatusCode, routing::post, Router};
+    use serde_json::Value;
+
+    use super::*;
+
+    /// Each body, and whether its signature checked out.
+    type Received = Arc<Mutex<Vec<(bool, Value)>>>;
+
+    #[derive(Clone)]
+    struct Receiver {
+        received: Received,
+        answers: Arc<Mutex<std::vec::IntoIter<u16>>>,
+        delay: Duration,
+    }
+
+    async fn receive(
+        State(receiver): State<Receiver>,
+        headers: axum::http::HeaderMap,
+        body: String,
+    ) -> StatusCode {
+        tokio::time::sleep(receiver.delay).await;
+        let timestamp: u64 = headers["x-timestamp"].to_str().unwrap().parse().unwrap();
+        // As a receiver would: fresh, and signed over the timestamp too.
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let signed = now.abs_diff(timestamp) <= 60
+            && ReceiptSigner::HmacSha256("secret".to_string()).sign(timestamp, body.as_bytes())
+                == headers["x-signature"].to_str().unwrap();
+        let body = serde_json::from_str(&body).unwrap();
+        receiver.received.lock().unwrap().push((signed, body));
+        let status = receiver.answers.lock().unwrap().next().unwrap_or(204);
+        StatusCode::from_u16(status).unwrap()
+    }
+
+    /// A receiver answering `statuses` in turn and then 204, each after
+    /// `delay`.
+    async fn receiver(statuses: Vec<u16>, delay: Duration) -> (String, Received) {
+        let receiver = Receiver {
+            received: Received::default(),
+            answers: Arc::new(Mutex::new(statuses.into_iter())),
+            delay,
+        };
+        let received = receiver.received.clone();
+        let app = Router::new()
+            .route("/receipts", post(receive))
+            .with_state(receiver);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/receipts", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (url, received)
+    }
+
+    fn config(url: String, queue: usize, concurrency: usize) -> ReceiptsConfig {
+        ReceiptsConfig {
+            url,
+            signer: ReceiptSigner::HmacSha256("secret".to_string()),
+            queue,
+            concurrency,
+            retry: RetryPolicy {
+                max_retries: 2,
+                base: Duration::from_millis(1),
+                max_elapsed: Duration::from_secs(5),
+                retryable_statuses: Vec::new(),
+                retry_after_max: Duration::from_secs(1),
+            },
+        }
+    }
+
+    fn sent(service: &'static str, recipient: &str) -> AuditEvent {
+        AuditEvent::new("sent", service, recipient.to_string(), "sent".to_string())
+    }
+
+    /// Waits up to five seconds for `count` requests.
+    async fn wait_for(received: &Received, count: usize) -> Vec<(bool, Value)> {
+        for _ in 0..500 {
+            if received.lock().unwrap().len() >= count {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        received.lock().unwrap().clone()
+    }
+
+    #[test]
+    fn channel_names_the_kind_of_message() {
+        assert_eq!(channel("smtp"), "email");
+        assert_eq!(channel("slack"), "slack");
+    }
+
+    #[tokio::test]
+    async fn failed_post_is_retried_with_the_same_receipt() {
+        let (url, received) = receiver(vec![503, 500], Duration::ZERO).await;
+        let events = EventBus::new(16);
+        spawn(config(url, 10, 1), &events).expect("receipts start");
+
+        events.publish(sent("slack", "#ops"));
+        events.publish(AuditEvent::new(
+            "opened",
+            "smtp",
+            "a@example.com".to_string(),
+            String::new(),
+        ));
+        let received = wait_for(&received, 3).await;
+        assert_eq!(received.len(), 3);
+        assert!(received.iter().all(|(signed, _)| *signed));
+        let ids: Vec<_> = received.iter().map(|(_, body)| &body["id"]).collect();
+        assert!(ids.iter().all(|id| *id == ids[0]), "{ids:?}");
+        let nonces: std::collections::HashSet<_> = received
+            .iter()
+            .map(|(_, body)| body["nonce"].to_string())
+            .collect();
+        assert_eq!(nonces.len(), 3, "each attempt has its own nonce");
+        assert_eq!(received[0].1["channel"], "slack");
+        assert_eq!(received[0].1["recipient"], "#ops");
+    }
+
+    #[tokio::test]
+    async fn refused_post_is_not_retried() {
+        let (url, received) = receiver(vec![400], Duration::ZERO).await;
+        let events = EventBus::new(16);
+        spawn(config(url, 10, 1), &events).expect("receipts start");
+
+        events.publish(sent("smtp", "a@example.com"));
+        events.publish(sent("smtp", "b@example.com"));
+        let received = wait_for(&received, 2).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0].1["recipient"], "a@example.com");
+        assert_eq!(received[0].1["channel"], "email");
+        assert_eq!(received[1].1["recipient"], "b@example.com");
+    }
+
+    #[tokio::test]
+    async fn receipts_are_posted_concurrently() {
+        let (url, received) = receiver(Vec::new(), Duration::from_millis(300)).await;
+        let events = EventBus::new(16);
+        spawn(config(url, 10, 4), &events).expect("receipts start");
+
+        let started = Instant::now();
+        for n in 0..4 {
+            events.publish(sent("smtp", &format!("{n}@example.com")));
+        }
+        assert_eq!(wait_for(&received, 4).await.len(), 4);
+        assert!(
+            started.elapsed() < Duration::from_millis(900),
+            "{:?}",
+            started.elapsed()
+        );
+    }
+
+    #[tokio::test]
+    async fn receipts_past_a_full_queue_are_dropped() {
+        let (url, received) = receiver(Vec::new(), Duration::from_millis(300)).await;
+        let events = EventBus::new(16);
+        spawn(config(url, 1, 1), &events).expect("receipts start");
+
+        for n in 0..6 {
+            events.publish(sent("smtp", &format!("{n}@example.com")));
+        }
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        let received = received.lock().unwrap().len();
+        assert!((1..6).contains(&received), "{received} posted");
+    }
+
+    fn decode(hex: &str) -> Vec<u8> {
+        crate::tracking::decode_hex(hex).expect("valid hex")
+    }
+
+    #[test]
+    fn signature_covers_the_timestamp() {
+        let signer = ReceiptSigner::HmacSha256("secret".to_string());
+        assert_eq!(
+            signer.sign(1_700_000_000, br#"{"id":"1"}"#),
+            "sha256=086f6aff7bd084c98679825129c5a64dbad88c760016d6d2c0fb123f27951d54"
+        );
+    }
+
+    #[test]
+    fn tampered_timestamp_invalidates_the_signature() {
+        let signer = ReceiptSigner::HmacSha256("secret".to_string());
+        let body = br#"{"id":"1"}"#;
+        let signature = signer.sign(1_700_000_000, body);
+        assert_ne!(signer.sign(1_700_000_001, body), signature);
+        assert_ne!(signer.sign(1_700_000_000, br#"{"id":"2"}"#), signature);
+    }
+
+    // RFC 4231 test case 2.
+    #[test]
+    fn hmac_sha256_matches_known_vector() {
+        let signer = ReceiptSigner::HmacSha256("Jefe".to_string());
+        assert_eq!(
+            signer.signature(b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    // RFC 2202 test case 2.
+    #[test]
+    fn hmac_sha1_matches_known_vector() {
+        let signer = ReceiptSigner::HmacSha1("Jefe".to_string());
+        assert_eq!(
+            signer.signature(b"what do ya want for nothing?"),
+            "sha1=effcdf6ae5eb2fa2d27416d5f184df9c259a7c79"
+        );
+    }
+
+    // RFC 8032 section 7.1, test 2.
+    #[test]
+    fn ed25519_matches_known_vector() {
+        let seed = decode("4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb");
+        let key = SigningKey::from_bytes(&seed.try_into().expect("32-byte seed"));
+        let signer = ReceiptSigner::Ed25519(Box::new(key));
+        let expected = decode(
+            "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da\
+             085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+        );
+        assert_eq!(
+            signer.signature(&[0x72]),
+            format!("ed25519={}", BASE64_STANDARD.encode(expected))
+        );
+    }
+
+    #[test]
+    fn the_signed_message_is_the_timestamp_a_dot_and_the_body() {
+        let signer = ReceiptSigner::HmacSha256("secret".to_string());
+        assert_eq!(
+            signer.sign(1_700_000_000, br#"{"id":"1"}"#),
+            signer.signature(br#"1700000000.{"id":"1"}"#)
+        );
+        // Moving the dot cannot shift digits between timestamp and body.
+        assert_ne!(
+            signer.sign(1_700_000_000, br#"1{"id":"1"}"#),
+            signer.sign(17_000_000_001, br#"{"id":"1"}"#)
+        );
+    }
+}
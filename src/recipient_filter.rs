@@ -0,0 +1,290 @@
+use std::{collections::HashSet, fs, path::Path};
+
+use anyhow::{Context, Result};
+use lettre::Address;
+
+/// Local parts of shared role mailboxes that should not receive mail.
+const ROLE_LOCAL_PARTS: &[&str] = &[
+    "postmaster",
+    "abuse",
+    "noreply",
+    "no-reply",
+    "hostmaster",
+    "mailer-daemon",
+];
+
+/// Recipients refused before sending: disposable-email domains from
+/// `DISPOSABLE_DOMAINS_FILE`, with `BLOCK_ROLE_ADDRESSES` role addresses
+/// such as `postmaster@`, and the addresses in `SUPPRESSION_FILE`.
+#[derive(Debug, Default)]
+pub struct RecipientFilter {
+    disposable: HashSet<String>,
+    block_roles: bool,
+    /// Lowercased addresses that asked not to be mailed or bounced for good.
+    suppressed: HashSet<String>,
+}
+
+impl RecipientFilter {
+    pub fn new(block_roles: bool) -> Self {
+        Self {
+            block_roles,
+            ..Self::default()
+        }
+    }
+
+    /// Reads one domain per line; blank lines and `#` comments are skipped.
+    pub fn load_disposable(mut self, path: &Path) -> Result<Self> {
+        let raw = fs::read_to_string(path)
+            .with_context(|| format!("failed to read disposable domains {}", path.display()))?;
+        self.disposable = raw
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or_default().trim())
+            .filter(|domain| !domain.is_empty())
+            .map(|domain| domain.trim_end_matches('.').to_ascii_lowercase())
+            .collect();
+        Ok(self)
+    }
+
+    /// Reads one address per line; blank lines and `#` comments are skipped.
+    pub fn load_suppressed(mut self, path: &Path) -> Result<Self> {
+        let raw = fs::read_to_string(path)
+            .with_context(|| format!("failed to read suppression list {}", path.display()))?;
+        self.suppressed = raw
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or_default().trim())
+            .filter(|address| !address.is_empty())
+            .map(|address| {
+                address
+                    .parse::<Address>()
+                    .map(|address| address.to_string().to_ascii_lowercase())
+                    .with_context(|| format!("invalid address in {}: {address}", path.display()))
+            })
+            .collect::<Result<_>>()?;
+        Ok(self)
+    }
+
+    /// Whether `address` is on the suppression list, which a permitted
+    /// `bypass_suppression` request may skip.
+    pub fn is_suppressed(&self, address: &Address) -> bool {
+        !self.suppressed.is_empty()
+            && self
+                .suppressed
+                .contains(&address.to_string().to_ascii_lowercase())
+    }
+
+    /// Why `address` is refused, if it is, leaving out suppression. Subdomains
+    /// of a listed domain count as disposable too.
+    pub fn rejects(&self, address: &Address) -> Option<&'static str> {
+        if self.block_roles {
+            let local = address.user().to_ascii_lowercase();
+            if ROLE_LOCAL_PARTS.contains(&local.as_str()) {
+                return Some("role address");
+            }
+        }
+        let domain = address.domain().to_ascii_lowercase();
+        let mut suffix = domain.as_str();
+        loop {
+            if self.disposable.contains(suffix) {
+                return Some("disposable email domain");
+            }
+            match suffix.split_once('.') {
+                Some((_, parent)) => suffix = parent,
+                None => return None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    use crate::test_support::{self, notify, TempDir};
+
+    use super::*;
+
+    fn address(raw: &str) -> Address {
+        raw.parse().expect("valid address")
+    }
+
+    fn disposable(block_roles: bool) -> (TempDir, RecipientFilter) {
+        let dir = TempDir::new("disposable");
+        let path = dir.path().join("domains.txt");
+        fs::write(
+            &path,
+            "# throwaway providers\nMailinator.com.\n\ntempmail.dev # and subdomains\n",
+        )
+        .expect("list written");
+        let filter = RecipientFilter::new(block_roles)
+            .load_disposable(&path)
+            .expect("list loads");
+        (dir, filter)
+    }
+
+    #[test]
+    fn disposable_domains_and_their_subdomains_are_rejected() {
+        let (_dir, filter) = disposable(false);
+        assert_eq!(
+            filter.rejects(&address("someone@mailinator.com")),
+            Some("disposable email domain")
+        );
+        assert_eq!(
+            filter.rejects(&address("someone@eu.TempMail.dev")),
+            Some("disposable email domain")
+        );
+        assert_eq!(filter.rejects(&address("someone@notmailinator.com")), None);
+    }
+
+    #[test]
+    fn role_addresses_are_rejected_only_when_blocked() {
+        let filter = RecipientFilter::new(true);
+        assert_eq!(
+            filter.rejects(&address("PostMaster@example.com")),
+            Some("role address")
+        );
+        assert_eq!(
+            filter.rejects(&address("no-reply@example.com")),
+            Some("role address")
+        );
+        assert_eq!(
+            RecipientFilter::new(false).rejects(&address("abuse@example.com")),
+            None
+        );
+    }
+
+    #[test]
+    fn normal_addresses_pass() {
+        let (_dir, filter) = disposable(true);
+        assert_eq!(filter.rejects(&address("ops@example.com")), None);
+        assert_eq!(
+            filter.rejects(&address("postmaster-team@example.com")),
+            None
+        );
+    }
+
+    #[test]
+    fn a_missing_list_fails_to_load() {
+        let err = RecipientFilter::new(false)
+            .load_disposable(Path::new("/nonexistent/domains.txt"))
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("failed to read disposable domains"),
+            "{err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn rejected_recipients_answer_422_naming_the_reason() {
+        let dir = TempDir::new("disposable");
+        let path = dir.path().join("domains.txt");
+        fs::write(&path, "mailinator.com\n").expect("list written");
+        let state = test_support::state(&[
+            ("BLOCK_ROLE_ADDRESSES", "true"),
+            (
+                "DISPOSABLE_DOMAINS_FILE",
+                path.to_str().expect("utf-8 path"),
+            ),
+        ])
+        .await;
+        let app = test_support::app(&state);
+
+        for (to, message) in [
+            (
+                "someone@mailinator.com",
+                "recipient rejected (disposable email domain): someone@mailinator.com",
+            ),
+            (
+                "postmaster@example.com",
+                "recipient rejected (role address): postmaster@example.com",
+            ),
+        ] {
+            let (status, body) = notify(
+                &app,
+                json!({"service": "smtp", "to": "ops@example.com", "bcc": to, "title": "t", "body": "b"}),
+            )
+            .await;
+            assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{body}");
+            assert_eq!(body["message"], message);
+        }
+        assert!(test_support::sent(&app).await.is_empty());
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+    }
+
+    #[test]
+    fn suppressed_addresses_are_matched_without_regard_to_case() {
+        let dir = TempDir::new("suppression");
+        let path = dir.path().join("suppressed.txt");
+        fs::write(&path, "# hard bounces\nGone@Example.com\n\n").expect("list written");
+        let filter = RecipientFilter::new(false).load_suppressed(&path).unwrap();
+        assert!(filter.is_suppressed(&address("gone@example.com")));
+        assert!(!filter.is_suppressed(&address("ops@example.com")));
+        assert_eq!(filter.rejects(&address("gone@example.com")), None);
+
+        fs::write(&path, "not an address\n").expect("list written");
+        let err = RecipientFilter::new(false)
+            .load_suppressed(&path)
+            .unwrap_err();
+        assert!(err.to_string().contains("invalid address"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn only_permitted_keys_bypass_suppression_and_the_bypass_is_logged() {
+        let capture = test_support::Capture::default();
+        let _subscriber = tracing::subscriber::set_default(capture.subscriber());
+        let dir = TempDir::new("suppression");
+        let list = dir.path().join("suppressed.txt");
+        fs::write(&list, "gone@example.com\n").expect("list written");
+        let request = json!({"service": "smtp", "to": "gone@example.com", "title": "reset",
+            "body": "b", "bypass_suppression": true});
+
+        for (permitted, expected) in [
+            (false, StatusCode::UNPROCESSABLE_ENTITY),
+            (true, StatusCode::OK),
+        ] {
+            let keys = dir.path().join(format!("keys-{permitted}.json"));
+            fs::write(
+                &keys,
+                json!({test_support::API_KEY: {"bypass_suppression": permitted}}).to_string(),
+            )
+            .expect("keys written");
+            let state = test_support::state(&[
+                ("API_KEY", ""),
+                ("API_KEYS_FILE", keys.to_str().expect("utf-8 path")),
+                ("SUPPRESSION_FILE", list.to_str().expect("utf-8 path")),
+            ])
+            .await;
+            let app = test_support::app(&state);
+
+            let (status, body) = notify(&app, request.clone()).await;
+            assert_eq!(status, expected, "{body}");
+            if !permitted {
+                assert_eq!(
+                    body["message"],
+                    "recipient rejected (suppressed): gone@example.com"
+                );
+                assert!(test_support::sent(&app).await.is_empty());
+            }
+            // Without the flag even a permitted key is held back.
+            let mut plain = request.clone();
+            plain["bypass_suppression"] = json!(false);
+            let (status, _) = notify(&app, plain).await;
+            assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        }
+
+        let audit: Vec<_> = capture
+            .lines()
+            .into_iter()
+            .filter(|line| line.contains("suppression bypassed"))
+            .collect();
+        assert_eq!(audit.len(), 1, "{audit:?}");
+        assert!(audit[0].contains("gone@example.com"), "{}", audit[0]);
+    }
+}
@@ -0,0 +1,322 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use rand::{Rng, RngExt};
+use serde::Deserialize;
+
+use crate::{transport::TransportError, NotificationService};
+
+/// Retry schedule for transient SMTP failures.
+///
+/// Delays use "full jitter": each retry waits a uniformly random duration
+/// between zero and the exponential backoff for that attempt, so clients that
+/// failed together do not retry together. The total time spent retrying is
+/// bounded by `max_elapsed` regardless of `max_retries`.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base: Duration,
+    pub max_elapsed: Duration,
+    /// Reply codes worth retrying; empty keeps the default transient/permanent
+    /// classification. Failures without a code (e.g. connection errors) are
+    /// always classified by default.
+    pub retryable_statuses: Vec<u16>,
+    /// Longest `Retry-After` from a rate-limited provider that is honoured
+    /// as given; longer ones are cut to this.
+    pub retry_after_max: Duration,
+}
+
+impl RetryPolicy {
+    /// Whether `err` should be retried under this policy.
+    pub fn is_retryable(&self, err: &TransportError) -> bool {
+        match err.status() {
+            Some(status) if !self.retryable_statuses.is_empty() => {
+                self.retryable_statuses.contains(&status)
+            }
+            _ => err.is_transient(),
+        }
+    }
+
+    /// Upper bound of the backoff window for a zero-based retry attempt.
+    fn ceiling(&self, attempt: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempt).unwrap_or(u32::MAX);
+        self.base.saturating_mul(factor).min(self.max_elapsed)
+    }
+
+    /// Jittered delay for a zero-based retry attempt, in `[0, base * 2^attempt]`.
+    pub fn backoff<R: Rng + ?Sized>(&self, attempt: u32, rng: &mut R) -> Duration {
+        let ceiling_ms = u64::try_from(self.ceiling(attempt).as_millis()).unwrap_or(u64::MAX);
+        Duration::from_millis(rng.random_range(0..=ceiling_ms))
+    }
+
+    /// Returns the delay before the next attempt, or `None` when the retry
+    /// budget (attempt count or elapsed time) is exhausted. A provider's
+    /// `retry_after` replaces the backoff.
+    pub fn next_delay<R: Rng + ?Sized>(
+        &self,
+        attempt: u32,
+        elapsed: Duration,
+        retry_after: Option<Duration>,
+        rng: &mut R,
+    ) -> Option<Duration> {
+        if attempt >= self.max_retries {
+            return None;
+        }
+
+        let delay = match retry_after {
+            Some(after) => after.min(self.retry_after_max),
+            None => self.backoff(attempt, rng),
+        };
+        if elapsed + delay > self.max_elapsed {
+            return None;
+        }
+        Some(delay)
+    }
+}
+
+/// Parses a `Retry-After` value (RFC 9110): delay seconds or an HTTP-date,
+/// the latter measured from `now`. A date already past means no wait.
+pub fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    Some(SystemTime::from(at).duration_since(now).unwrap_or_default())
+}
+
+/// Per-channel retry policies from `RETRY_POLICIES_FILE`, falling back to
+/// the `SMTP_RETRY_*` policy for channels not listed.
+#[derive(Debug, Clone)]
+pub struct RetryPolicies {
+    default: RetryPolicy,
+    channels: HashMap<NotificationService, RetryPolicy>,
+}
+
+/// One channel's entry; omitted fields keep the default policy's values.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RetryPolicyEntry {
+    max_retries: Option<u32>,
+    base_ms: Option<u64>,
+    max_elapsed_ms: Option<u64>,
+    retryable_statuses: Option<Vec<u16>>,
+    retry_after_max_ms: Option<u64>,
+}
+
+impl RetryPolicies {
+    pub fn load(default: RetryPolicy, path: Option<&Path>) -> Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self {
+                default,
+                channels: HashMap::new(),
+            });
+        };
+        let raw = fs::read_to_string(path)
+            .with_context(|| format!("failed to read retry policies file {}", path.display()))?;
+        let entries: HashMap<NotificationService, RetryPolicyEntry> = serde_json::from_str(&raw)
+            .with_context(|| format!("invalid retry policies file {}", path.display()))?;
+
+        let channels = entries
+            .into_iter()
+            .map(|(service, entry)| {
+                let policy = RetryPolicy {
+                    max_retries: entry.max_retries.unwrap_or(default.max_retries),
+                    base: entry.base_ms.map_or(default.base, Duration::from_millis),
+                    max_elapsed: entry
+                        .max_elapsed_ms
+                        .map_or(default.max_elapsed, Duration::from_millis),
+                    retryable_statuses: entry
+                        .retryable_statuses
+                        .unwrap_or_else(|| default.retryable_statuses.clone()),
+                    retry_after_max: entry
+                        .retry_after_max_ms
+                        .map_or(default.retry_after_max, Duration::from_millis),
+                };
+                (service, policy)
+            })
+            .collect();
+        Ok(Self { default, channels })
+    }
+
+    pub fn get(&self, service: NotificationService) -> &RetryPolicy {
+        self.channels.get(&service).unwrap_or(&self.default)
+    }
+}
+
+/// Deferred retry schedule for greylisted messages. Greylisting servers only
+/// accept a retry after a minute-scale delay, so these retries run in the
+/// background at a fixed interval instead of inside the request.
+#[derive(Debug, Clone)]
+pub struct GreylistPolicy {
+    pub delay: Duration,
+    pub max_deferrals: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::TempDir;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 5,
+            base: Duration::from_millis(100),
+            max_elapsed: Duration::from_millis(1_000),
+            retryable_statuses: Vec::new(),
+            retry_after_max: Duration::from_secs(30),
+        }
+    }
+
+    #[test]
+    fn backoff_stays_within_the_jitter_window() {
+        let policy = policy();
+        let mut rng = StdRng::seed_from_u64(7);
+        for attempt in 0..8 {
+            let ceiling = (Duration::from_millis(100) * 2u32.pow(attempt)).min(policy.max_elapsed);
+            let delays: Vec<_> = (0..200)
+                .map(|_| policy.backoff(attempt, &mut rng))
+                .collect();
+            assert!(
+                delays.iter().all(|delay| *delay <= ceiling),
+                "attempt {attempt}"
+            );
+            // Full jitter spreads over the window rather than sitting at its top.
+            assert!(
+                delays.iter().any(|delay| *delay < ceiling / 2),
+                "attempt {attempt}"
+            );
+        }
+    }
+
+    #[test]
+    fn backoff_is_reproducible_with_a_seed() {
+        let policy = policy();
+        let run = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (0..5)
+                .map(|attempt| policy.backoff(attempt, &mut rng))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(run(42), run(42));
+    }
+
+    #[test]
+    fn next_delay_respects_the_elapsed_cap_and_attempts() {
+        let policy = policy();
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..100 {
+            if let Some(delay) = policy.next_delay(3, Duration::from_millis(900), None, &mut rng) {
+                assert!(Duration::from_millis(900) + delay <= policy.max_elapsed);
+            }
+        }
+        assert_eq!(
+            policy.next_delay(0, Duration::from_millis(1_001), None, &mut rng),
+            None
+        );
+        assert_eq!(policy.next_delay(5, Duration::ZERO, None, &mut rng), None);
+    }
+
+    #[test]
+    fn retry_after_replaces_the_backoff() {
+        let policy = RetryPolicy {
+            max_elapsed: Duration::from_secs(120),
+            ..policy()
+        };
+        let mut rng = StdRng::seed_from_u64(1);
+        assert_eq!(
+            policy.next_delay(0, Duration::ZERO, Some(Duration::from_secs(20)), &mut rng),
+            Some(Duration::from_secs(20))
+        );
+        assert_eq!(
+            policy.next_delay(0, Duration::ZERO, Some(Duration::from_secs(90)), &mut rng),
+            Some(Duration::from_secs(30))
+        );
+    }
+
+    fn policies(dir: &TempDir, raw: &str) -> Result<RetryPolicies> {
+        let path = dir.path().join("retry.json");
+        fs::write(&path, raw).unwrap();
+        RetryPolicies::load(policy(), Some(&path))
+    }
+
+    #[test]
+    fn channels_use_their_own_policies() {
+        let dir = TempDir::new("retry");
+        let policies = policies(
+            &dir,
+            r#"{
+                "smtp": {"max_retries": 2, "retryable_statuses": [421, 451]},
+                "slack": {"max_retries": 8, "base_ms": 1000, "retry_after_max_ms": 120000}
+            }"#,
+        )
+        .unwrap();
+
+        let smtp = policies.get(NotificationService::Smtp);
+        assert_eq!(smtp.max_retries, 2);
+        assert_eq!(smtp.retryable_statuses, [421, 451]);
+        assert_eq!(smtp.base, Duration::from_millis(100), "default kept");
+
+        let slack = policies.get(NotificationService::Slack);
+        assert_eq!(slack.max_retries, 8);
+        assert_eq!(slack.base, Duration::from_secs(1));
+        assert_eq!(slack.retry_after_max, Duration::from_secs(120));
+        assert!(slack.retryable_statuses.is_empty(), "default kept");
+        assert_eq!(
+            slack.max_elapsed,
+            Duration::from_millis(1_000),
+            "default kept"
+        );
+    }
+
+    #[test]
+    fn unlisted_channels_use_the_default_policy() {
+        let dir = TempDir::new("retry");
+        let policies = policies(&dir, r#"{"slack": {"max_retries": 8}}"#).unwrap();
+        assert_eq!(policies.get(NotificationService::Smtp).max_retries, 5);
+        assert_eq!(policies.get(NotificationService::Slack).max_retries, 8);
+
+        let policies = RetryPolicies::load(policy(), None).unwrap();
+        assert_eq!(policies.get(NotificationService::Slack).max_retries, 5);
+    }
+
+    #[test]
+    fn malformed_policies_fail_to_load() {
+        let dir = TempDir::new("retry");
+        for raw in [
+            r#"{"slack": {"max_retrys": 8}}"#,
+            r#"{"pager": {"max_retries": 8}}"#,
+            r#"{"slack": {"max_retries": -1}}"#,
+        ] {
+            let err = policies(&dir, raw).unwrap_err();
+            assert!(
+                err.to_string().starts_with("invalid retry policies file"),
+                "{raw}: {err}"
+            );
+        }
+    }
+
+    #[test]
+    fn retry_after_takes_seconds_or_an_http_date() {
+        let now =
+            SystemTime::from(chrono::DateTime::parse_from_rfc3339("2015-10-21T07:28:00Z").unwrap());
+        assert_eq!(parse_retry_after(" 5 ", now), Some(Duration::from_secs(5)));
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:30 GMT", now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:27:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+        assert_eq!(parse_retry_after("-5", now), None);
+    }
+}
@@ -0,0 +1,110 @@
+use std::{fs, path::Path};
+
+use anyhow::{Context, Result};
+use jsonschema::Validator;
+use serde_json::Value;
+
+/// Operator-supplied JSON Schema from `REQUEST_SCHEMA_FILE` that `/notify`
+/// bodies must satisfy before the built-in validation runs, e.g. to make a
+/// `tags.team` entry mandatory.
+pub struct RequestSchema {
+    validator: Validator,
+}
+
+impl RequestSchema {
+    /// Reads and compiles the schema; references to other files or URLs are
+    /// not resolved.
+    pub fn load(path: &Path) -> Result<Self> {
+        let raw = fs::read_to_string(path)
+            .with_context(|| format!("failed to read request schema {}", path.display()))?;
+        let schema: Value = serde_json::from_str(&raw)
+            .with_context(|| format!("invalid request schema {}", path.display()))?;
+        let validator = jsonschema::validator_for(&schema)
+            .map_err(|err| anyhow::anyhow!("invalid request schema {}: {err}", path.display()))?;
+        Ok(Self { validator })
+    }
+
+    /// First violation as `<json pointer>: <reason>`, the pointer being `/`
+    /// for the document root.
+    pub fn validate(&self, body: &Value) -> Result<(), String> {
+        self.validator.validate(body).map_err(|err| {
+            let path = err.instance_path().to_string();
+            let path = if path.is_empty() { "/" } else { &path };
+            format!("{path}: {err}")
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_support::TempDir;
+
+    const TEAM_REQUIRED: &str = r#"{
+        "type": "object",
+        "required": ["tags"],
+        "properties": {
+            "tags": {
+                "type": "object",
+                "required": ["team"],
+                "properties": {"team": {"type": "string"}}
+            }
+        }
+    }"#;
+
+    fn schema(dir: &TempDir, raw: &str) -> Result<RequestSchema> {
+        let path = dir.path().join("schema.json");
+        fs::write(&path, raw).unwrap();
+        RequestSchema::load(&path)
+    }
+
+    #[test]
+    fn missing_team_tag_is_reported_with_its_path() {
+        let dir = TempDir::new("schema");
+        let schema = schema(&dir, TEAM_REQUIRED).unwrap();
+
+        assert_eq!(
+            schema.validate(&json!({"to": "ops@example.com", "tags": {"env": "prod"}})),
+            Err("/tags: \"team\" is a required property".to_string())
+        );
+        assert_eq!(
+            schema.validate(&json!({"to": "ops@example.com", "tags": {"team": 7}})),
+            Err("/tags/team: 7 is not of type \"string\"".to_string())
+        );
+        assert_eq!(
+            schema.validate(&json!({"to": "ops@example.com"})),
+            Err("/: \"tags\" is a required property".to_string())
+        );
+    }
+
+    #[test]
+    fn present_team_tag_is_accepted() {
+        let dir = TempDir::new("schema");
+        let schema = schema(&dir, TEAM_REQUIRED).unwrap();
+
+        assert_eq!(
+            schema.validate(&json!({"to": "ops@example.com", "tags": {"team": "payments"}})),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn invalid_schema_fails_to_load() {
+        let dir = TempDir::new("schema");
+
+        let err = schema(&dir, r#"{"type": "no such type"}"#)
+            .err()
+            .expect("refused");
+        assert!(
+            err.to_string().starts_with("invalid request schema"),
+            "{err}"
+        );
+        let err = schema(&dir, "{").err().expect("refused");
+        assert!(
+            err.to_string().starts_with("invalid request schema"),
+            "{err}"
+        );
+    }
+}
@@ -0,0 +1,155 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Days, NaiveTime, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+/// Local hours, in the recipient's timezone, during which a message may go
+/// out: `start_hour` inclusive to `end_hour` exclusive. A range such as
+/// 22 to 6 wraps past midnight.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SendWindow {
+    /// IANA name such as `Europe/Berlin`.
+    pub timezone: String,
+    pub start_hour: u32,
+    pub end_hour: u32,
+}
+
+impl SendWindow {
+    /// How long until the window next opens, or `None` when `now` is inside
+    /// it.
+    pub fn wait(&self, now: DateTime<Utc>) -> Result<Option<Duration>, String> {
+        let timezone: Tz = self
+            .timezone
+            .parse()
+            .map_err(|_| format!("unknown timezone: {}", self.timezone))?;
+        if self.start_hour > 23 || self.end_hour > 24 || self.start_hour == self.end_hour {
+            return Err(
+                "start_hour must be 0 to 23 and end_hour 0 to 24, and they must differ".to_string(),
+            );
+        }
+
+        let local = now.with_timezone(&timezone);
+        let hour = local.hour();
+        let open = if self.start_hour < self.end_hour {
+            (self.start_hour..self.end_hour).contains(&hour)
+        } else {
+            hour >= self.start_hour || hour < self.end_hour
+        };
+        if open {
+            return Ok(None);
+        }
+
+        let start = NaiveTime::from_hms_opt(self.start_hour, 0, 0).expect("hour is below 24");
+        let mut date = local.date_naive();
+        if hour >= self.start_hour {
+            date = date + Days::new(1);
+        }
+        // A DST gap can skip the opening hour; the window then opens at the
+        // first local time after it.
+        let mut opens = date.and_time(start);
+        let opens_at = loop {
+            if let Some(opens_at) = timezone.from_local_datetime(&opens).earliest() {
+                break opens_at;
+            }
+            opens += chrono::Duration::minutes(30);
+        };
+        Ok(Some(
+            (opens_at.with_timezone(&Utc) - now)
+                .to_std()
+                .unwrap_or_default(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(timezone: &str, start_hour: u32, end_hour: u32) -> SendWindow {
+        SendWindow {
+            timezone: timezone.to_string(),
+            start_hour,
+            end_hour,
+        }
+    }
+
+    fn at(rfc3339: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(rfc3339)
+            .expect("valid timestamp")
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn inside_the_window_sends_now() {
+        let window = window("Europe/Berlin", 9, 17);
+        // 10:30 in Berlin (UTC+2 in summer).
+        assert_eq!(window.wait(at("2026-07-01T08:30:00Z")), Ok(None));
+    }
+
+    #[test]
+    fn before_the_window_waits_until_it_opens_the_same_day() {
+        let window = window("Europe/Berlin", 9, 17);
+        // 07:00 in Berlin.
+        assert_eq!(
+            window.wait(at("2026-07-01T05:00:00Z")),
+            Ok(Some(Duration::from_secs(2 * 3600)))
+        );
+    }
+
+    #[test]
+    fn after_the_window_waits_until_it_opens_the_next_day() {
+        let window = window("Europe/Berlin", 9, 17);
+        // 18:00 in Berlin.
+        assert_eq!(
+            window.wait(at("2026-07-01T16:00:00Z")),
+            Ok(Some(Duration::from_secs(15 * 3600)))
+        );
+    }
+
+    #[test]
+    fn the_end_hour_is_exclusive() {
+        let window = window("UTC", 9, 17);
+        assert_eq!(window.wait(at("2026-07-01T09:00:00Z")), Ok(None));
+        assert_eq!(
+            window.wait(at("2026-07-01T17:00:00Z")),
+            Ok(Some(Duration::from_secs(16 * 3600)))
+        );
+    }
+
+    #[test]
+    fn a_window_can_wrap_past_midnight() {
+        let window = window("UTC", 22, 6);
+        assert_eq!(window.wait(at("2026-07-01T23:00:00Z")), Ok(None));
+        assert_eq!(window.wait(at("2026-07-01T03:00:00Z")), Ok(None));
+        assert_eq!(
+            window.wait(at("2026-07-01T12:00:00Z")),
+            Ok(Some(Duration::from_secs(10 * 3600)))
+        );
+    }
+
+    #[test]
+    fn an_opening_hour_in_a_dst_gap_opens_after_the_gap() {
+        // Berlin skips 02:00 to 03:00 on 2026-03-29; 00:00 local is 23:00 UTC.
+        let window = window("Europe/Berlin", 2, 5);
+        assert_eq!(
+            window.wait(at("2026-03-28T23:00:00Z")),
+            Ok(Some(Duration::from_secs(2 * 3600)))
+        );
+    }
+
+    #[test]
+    fn unknown_timezones_and_bad_hours_are_refused() {
+        let now = at("2026-07-01T12:00:00Z");
+        assert_eq!(
+            window("Mars/Olympus", 9, 17).wait(now),
+            Err("unknown timezone: Mars/Olympus".to_string())
+        );
+        for (start, end) in [(24, 6), (9, 25), (9, 9)] {
+            assert!(
+                window("UTC", start, end).wait(now).is_err(),
+                "{start}..{end}"
+            );
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use std::sync::Arc;
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use hmac::{Hmac, KeyInit, Mac};
+use sha2::Sha256;
+
+use crate::{authenticate, error_response, tracking::decode_hex, upload, AppState};
+
+/// Checks `X-Signature: sha256=<hex HMAC-SHA256 of the raw body>` for keys
+/// with a `signing_secret`, before any handler parses the body. Other
+/// requests pass through; authentication itself is left to the handlers.
+///
+/// Signed bodies are buffered whole, so signed uploads are held in memory.
+pub async fn verify_signature(
+    State(state): State<Arc<AppState>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let secret = match authenticate(&state, req.headers()).await {
+        Some(caller) => caller.policy.signing_secret.clone(),
+        None => None,
+    };
+    let Some(secret) = secret else {
+        return next.run(req).await;
+    };
+    let signature = req
+        .headers()
+        .get("x-signature")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().strip_prefix("sha256="))
+        .and_then(decode_hex);
+    let Some(signature) = signature else {
+        return error_response(StatusCode::UNAUTHORIZED, "missing request signature")
+            .into_response();
+    };
+
+    let (parts, body) = req.into_parts();
+    let limit = state
+        .max_upload_bytes
+        .saturating_add(upload::OVERHEAD_BYTES);
+    let Ok(bytes) = to_bytes(body, limit).await else {
+        return error_response(StatusCode::PAYLOAD_TOO_LARGE, "request body too large")
+            .into_response();
+    };
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts any key length");
+    mac.update(&bytes);
+    // `verify_slice` compares in constant time.
+    if mac.verify_slice(&signature).is_err() {
+        return error_response(StatusCode::UNAUTHORIZED, "invalid request signature")
+            .into_response();
+    }
+    next.run(Request::from_parts(parts, Body::from(bytes)))
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::header::CONTENT_TYPE;
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::test_support::{self, TempDir};
+
+    const SECRET: &str = "shared-secret";
+
+    fn sign(body: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(SECRET.as_bytes()).unwrap();
+        mac.update(body.as_bytes());
+        let hex: String = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        format!("sha256={hex}")
+    }
+
+    /// A state whose `test-key` must sign its requests, and the dir holding
+    /// its key file.
+    async fn signed_state() -> (TempDir, Arc<AppState>) {
+        let dir = TempDir::new("signing");
+        let path = dir.path().join("keys.json");
+        std::fs::write(
+            &path,
+            json!({test_support::API_KEY: {"signing_secret": SECRET}}).to_string(),
+        )
+        .unwrap();
+        let state = test_support::state(&[
+            ("API_KEY", ""),
+            ("API_KEYS_FILE", path.to_str().expect("utf-8 path")),
+        ])
+        .await;
+        (dir, state)
+    }
+
+    async fn post(
+        state: &Arc<AppState>,
+        body: &str,
+        signature: Option<&str>,
+    ) -> (StatusCode, Value) {
+        let mut request = Request::post("/notify")
+            .header("authorization", format!("Bearer {}", test_support::API_KEY))
+            .header(CONTENT_TYPE, "application/json");
+        if let Some(signature) = signature {
+            request = request.header("x-signature", signature);
+        }
+        let response = test_support::app(state)
+            .oneshot(request.body(Body::from(body.to_string())).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (
+            status,
+            serde_json::from_slice(&bytes).unwrap_or(Value::Null),
+        )
+    }
+
+    /// `GET /test/sent`, signed over its empty body.
+    async fn sent(state: &Arc<AppState>) -> Vec<Value> {
+        let request = Request::get("/test/sent")
+            .header("authorization", format!("Bearer {}", test_support::API_KEY))
+            .header("x-signature", sign(""))
+            .body(Body::empty())
+            .unwrap();
+        let response = test_support::app(state).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    fn body(to: &str) -> String {
+        json!({"service": "smtp", "to": to, "title": "t", "body": "b"}).to_string()
+    }
+
+    #[tokio::test]
+    async fn a_valid_signature_is_accepted() {
+        let (_dir, state) = signed_state().await;
+        let body = body("ops@example.com");
+
+        let (status, response) = post(&state, &body, Some(&sign(&body))).await;
+        assert_eq!(status, StatusCode::OK, "{response}");
+        assert_eq!(sent(&state).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn an_invalid_or_missing_signature_is_refused() {
+        let (_dir, state) = signed_state().await;
+        let body = body("ops@example.com");
+
+        for (signature, message) in [
+            (Some("sha256=00ff"), "invalid request signature"),
+            (Some(&*sign("something else")), "invalid request signature"),
+            (Some("sha1=00ff"), "missing request signature"),
+            (Some("sha256=not-hex"), "missing request signature"),
+            (None, "missing request signature"),
+        ] {
+            let (status, response) = post(&state, &body, signature).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED, "{signature:?}");
+            assert_eq!(response["message"], message, "{signature:?}");
+        }
+        assert!(sent(&state).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_tampered_body_is_refused() {
+        let (_dir, state) = signed_state().await;
+        let signature = sign(&body("ops@example.com"));
+
+        let (status, response) =
+            post(&state, &body("attacker@example.com"), Some(&signature)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED, "{response}");
+        assert_eq!(response["message"], "invalid request signature");
+        assert!(sent(&state).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn keys_without_a_secret_need_no_signature() {
+        let state = test_support::state(&[]).await;
+        let (status, response) = post(&state, &body("ops@example.com"), None).await;
+        assert_eq!(status, StatusCode::OK, "{response}");
+    }
+}
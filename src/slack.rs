@@ -0,0 +1,438 @@
+use std::{
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use axum::{http::StatusCode, Json};
+use reqwest::header::RETRY_AFTER;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use crate::{
+    api_keys::Caller,
+    decode_body, error_response, field_error,
+    retry::{parse_retry_after, RetryPolicy},
+    unprocessable_field, ApiError, ApiResponse, AppState, ChannelResponse, NotificationService,
+    NotifyRequest,
+};
+
+/// Longest one `chat.postMessage` call may take.
+const SLACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// `SLACK_BOT_TOKEN`: enables the `slack` service, which posts to the
+/// channel named in `to` through the Web API.
+#[derive(Debug, Clone)]
+pub struct SlackConfig {
+    pub token: String,
+    /// `SLACK_API_URL`, the Web API base; `https://slack.com/api` unless a
+    /// proxy sits in between.
+    pub api_url: String,
+    /// `SLACK_USERNAME`: the bot name messages are posted under, when the
+    /// request has no `sender_name`. Needs the `chat:write.customize` scope.
+    pub username: Option<String>,
+}
+
+pub struct Slack {
+    client: reqwest::Client,
+    cfg: SlackConfig,
+}
+
+#[derive(Serialize)]
+struct PostMessage<'a> {
+    channel: &'a str,
+    text: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    username: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+struct PostMessageResponse {
+    ok: bool,
+    /// Timestamp Slack assigned the message, its id within the channel.
+    ts: Option<String>,
+    error: Option<String>,
+}
+
+#[derive(Debug)]
+enum SlackError {
+    /// No answer: the connection failed or timed out.
+    Unreachable(reqwest::Error),
+    /// A non-2xx answer, with the `Retry-After` Slack sends on 429.
+    Status(u16, Option<Duration>),
+    /// `ok: false`, with Slack's error code such as `channel_not_found`.
+    Api(String),
+}
+
+impl SlackError {
+    /// Whether `policy` retries this failure. Listed `retryable_statuses`
+    /// replace the default of 429 and 5xx, as for SMTP reply codes.
+    fn is_retryable(&self, policy: &RetryPolicy) -> bool {
+        match self {
+            SlackError::Unreachable(_) => true,
+            SlackError::Status(status, _) if !policy.retryable_statuses.is_empty() => {
+                policy.retryable_statuses.contains(status)
+            }
+            SlackError::Status(status, _) => *status == 429 || *status >= 500,
+            SlackError::Api(code) => matches!(
+                code.as_str(),
+                "ratelimited" | "internal_error" | "service_unavailable"
+            ),
+        }
+    }
+
+    /// Slack's error code or HTTP status, for the client.
+    fn provider_status(&self) -> Option<String> {
+        match self {
+            SlackError::Unreachable(_) => None,
+            SlackError::Status(status, _) => Some(status.to_string()),
+            SlackError::Api(code) => Some(code.clone()),
+        }
+    }
+
+    fn retry_after(&self) -> Option<Duration> {
+        match self {
+            SlackError::Status(_, retry_after) => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for SlackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SlackError::Unreachable(err) => write!(f, "slack unreachable: {err}"),
+            SlackError::Status(status, _) => write!(f, "slack answered HTTP {status}"),
+            SlackError::Api(code) => write!(f, "slack refused the message: {code}"),
+        }
+    }
+}
+
+impl Slack {
+    pub fn new(cfg: SlackConfig) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(SLACK_TIMEOUT)
+            .build()
+            .context("failed to create slack client")?;
+        Ok(Self { client, cfg })
+    }
+
+    /// Posts `text` to `channel`, returning the message's `ts`.
+    async fn post(
+        &self,
+        channel: &str,
+        text: &str,
+        username: Option<&str>,
+    ) -> Result<String, SlackError> {
+        let url = format!(
+            "{}/chat.postMessage",
+            self.cfg.api_url.trim_end_matches('/')
+        );
+        let response = self
+            .client
+            .post(url)
+            .bearer_auth(&self.cfg.token)
+            .json(&PostMessage {
+                channel,
+                text,
+                username,
+            })
+            .send()
+            .await
+            .map_err(SlackError::Unreachable)?;
+        let status = response.status();
+        if !status.is_success() {
+            let retry_after = response
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| parse_retry_after(value, SystemTime::now()));
+            return Err(SlackError::Status(status.as_u16(), retry_after));
+        }
+        let answer: PostMessageResponse = response.json().await.map_err(SlackError::Unreachable)?;
+        match answer {
+            PostMessageResponse {
+                ok: true,
+                ts: Some(ts),
+                ..
+            } => Ok(ts),
+            PostMessageResponse { error, .. } => Err(SlackError::Api(
+                error.unwrap_or_else(|| "unknown_error".to_string()),
+            )),
+        }
+    }
+}
+
+/// The message text: the title in bold over the body, either of which may
+/// be empty.
+fn message_text(req: &NotifyRequest) -> Result<String, ApiError> {
+    let body = decode_body(&req.body, req.body_encoding)?;
+    let (title, body) = (req.title.trim(), body.trim());
+    let text = match (title.is_empty(), body.is_empty()) {
+        (true, true) => {
+            return Err(field_error("body", "title and body cannot both be empty").into())
+        }
+        (true, false) => body.to_string(),
+        (false, true) => format!("*{title}*"),
+        (false, false) => format!("*{title}*\n{body}"),
+    };
+    Ok(text)
+}
+
+/// Checks a `slack` request up front, as queued and digested email is
+/// checked by building it.
+pub fn validate(state: &AppState, req: &NotifyRequest) -> Result<(), ApiError> {
+    if state.slack.is_none() {
+        return Err(unprocessable_field("service", "slack is not configured").into());
+    }
+    if req.to.trim().is_empty() {
+        return Err(field_error("to", "to must name a slack channel").into());
+    }
+    message_text(req).map(drop)
+}
+
+/// Sends `req` to the Slack channel in `to`, retried under the `slack`
+/// entry of `RETRY_POLICIES_FILE` and paced with email.
+pub async fn send_slack(
+    state: &Arc<AppState>,
+    caller: &Caller<'_>,
+    req: NotifyRequest,
+) -> (StatusCode, Json<ApiResponse>) {
+    if let Err(resp) = validate(state, &req) {
+        return resp.into_inner();
+    }
+    let slack = state.slack.as_ref().expect("validated");
+    let channel = req.to.trim();
+    let text = match message_text(&req) {
+        Ok(text) => text,
+        Err(resp) => return resp.into_inner(),
+    };
+    let username = req
+        .sender_name
+        .as_deref()
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .or(slack.cfg.username.as_deref())
+        .or(state.sender_name.as_deref());
+    if !state.api_keys.try_consume(caller) {
+        return error_response(StatusCode::TOO_MANY_REQUESTS, "daily quota exceeded");
+    }
+
+    let policy = state.retry.get(NotificationService::Slack);
+    let started = Instant::now();
+    let mut attempt = 0;
+    let result = loop {
+        if let Some(pacer) = &state.pacer {
+            if !pacer.acquire().await {
+                state.api_keys.refund(caller);
+                warn!(service = "slack", channel, "global send rate exceeded");
+                return error_response(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "send rate limit reached, retry later",
+                );
+            }
+        }
+        let err = match slack.post(channel, &text, username).await {
+            Ok(ts) => break Ok(ts),
+            Err(err) if !err.is_retryable(policy) => break Err(err),
+            Err(err) => err,
+        };
+        let Some(delay) = policy.next_delay(
+            attempt,
+            started.elapsed(),
+            err.retry_after(),
+            &mut rand::rng(),
+        ) else {
+            break Err(err);
+        };
+        attempt += 1;
+        warn!(service = "slack", channel, attempt, delay_ms = delay.as_millis() as u64, error = %err, "slack send failed, retrying");
+        tokio::time::sleep(delay).await;
+    };
+
+    match result {
+        Ok(ts) => {
+            info!(service = "slack", channel, tags = ?req.tags, ts, "notification sent");
+            let body = ApiResponse {
+                provider: Some(ChannelResponse {
+                    channel: "slack",
+                    provider_message_id: Some(ts),
+                    provider_status: Some("ok".to_string()),
+                }),
+                ..ApiResponse::ok("sent")
+            };
+            (StatusCode::OK, Json(body))
+        }
+        Err(err) => {
+            state.api_keys.refund(caller);
+            error!(service = "slack", channel, tags = ?req.tags, error = %err, "send failed");
+            let (status, Json(mut body)) =
+                error_response(StatusCode::INTERNAL_SERVER_ERROR, "slack send failed");
+            body.provider = Some(ChannelResponse {
+                channel: "slack",
+                provider_message_id: None,
+                provider_status: err.provider_status(),
+            });
+            (status, Json(body))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::json;
+    use std::time::{Duration, Instant};
+
+    use crate::test_support::{self, notify, MockSlack};
+
+    #[tokio::test]
+    async fn a_slack_send_returns_the_message_timestamp() {
+        let slack = MockSlack::start(json!({"ok": true, "ts": "1700000000.000100"})).await;
+        let state = test_support::state(&slack.vars()).await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "slack", "to": "#ops", "title": "deploy", "body": "build 1234 is live"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(
+            body,
+            json!({
+                "ok": true,
+                "message": "sent",
+                "channel": "slack",
+                "provider_message_id": "1700000000.000100",
+                "provider_status": "ok",
+            })
+        );
+        assert_eq!(slack.posts()[0]["channel"], "#ops");
+    }
+
+    #[tokio::test]
+    async fn a_failed_slack_send_returns_the_provider_error() {
+        let slack = MockSlack::start(json!({"ok": false, "error": "channel_not_found"})).await;
+        let state = test_support::state(&slack.vars()).await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "slack", "to": "#gone", "title": "t", "body": "b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR, "{body}");
+        assert_eq!(body["message"], "slack send failed");
+        assert_eq!(body["channel"], "slack");
+        assert_eq!(body["provider_status"], "channel_not_found");
+        assert!(body.get("provider_message_id").is_none(), "{body}");
+    }
+
+    #[tokio::test]
+    async fn email_responses_carry_no_provider_details() {
+        let state = test_support::state(&[]).await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        for field in ["channel", "provider_message_id", "provider_status"] {
+            assert!(body.get(field).is_none(), "{field}: {body}");
+        }
+    }
+
+    /// A state retrying Slack once, after a backoff far longer than any
+    /// `Retry-After` used here.
+    async fn retrying_state(
+        slack: &MockSlack,
+    ) -> (test_support::TempDir, std::sync::Arc<crate::AppState>) {
+        let dir = test_support::TempDir::new("slack-retry");
+        let policies = dir.path().join("retry.json");
+        std::fs::write(
+            &policies,
+            json!({"slack": {"max_retries": 1, "base_ms": 60_000, "max_elapsed_ms": 120_000}})
+                .to_string(),
+        )
+        .unwrap();
+        let mut vars = slack.vars().to_vec();
+        vars.push((
+            "RETRY_POLICIES_FILE",
+            policies.to_str().expect("utf-8 path"),
+        ));
+        (dir, test_support::state(&vars).await)
+    }
+
+    async fn timed_send(app: &axum::Router) -> Duration {
+        let started = Instant::now();
+        let (status, body) = notify(
+            app,
+            json!({"service": "slack", "to": "#ops", "title": "t", "body": "b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        started.elapsed()
+    }
+
+    #[tokio::test]
+    async fn a_429_is_retried_after_its_retry_after_seconds() {
+        let slack = MockSlack::start(json!({"ok": true, "ts": "1.0"})).await;
+        slack.rate_limit("1");
+        let (_dir, state) = retrying_state(&slack).await;
+
+        let elapsed = timed_send(&test_support::app(&state)).await;
+        assert!(elapsed >= Duration::from_secs(1), "{elapsed:?}");
+        assert!(elapsed < Duration::from_secs(5), "{elapsed:?}");
+        assert_eq!(slack.posts().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn a_429_is_retried_at_its_retry_after_date() {
+        let slack = MockSlack::start(json!({"ok": true, "ts": "1.0"})).await;
+        // Whole seconds only, so the date is two to three seconds away.
+        let at = chrono::Utc::now() + chrono::Duration::seconds(3);
+        slack.rate_limit(&at.format("%a, %d %b %Y %H:%M:%S GMT").to_string());
+        let (_dir, state) = retrying_state(&slack).await;
+
+        let elapsed = timed_send(&test_support::app(&state)).await;
+        assert!(elapsed >= Duration::from_secs(1), "{elapsed:?}");
+        assert!(elapsed < Duration::from_secs(5), "{elapsed:?}");
+        assert_eq!(slack.posts().len(), 2);
+    }
+
+    async fn posted_username(
+        vars: &[(&str, &str)],
+        request: serde_json::Value,
+    ) -> serde_json::Value {
+        let slack = MockSlack::start(json!({"ok": true, "ts": "1700000000.000100"})).await;
+        let vars: Vec<_> = slack
+            .vars()
+            .into_iter()
+            .chain(vars.iter().copied())
+            .collect();
+        let app = test_support::app(&test_support::state(&vars).await);
+        let (status, body) = notify(&app, request).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        slack.posts()[0]["username"].clone()
+    }
+
+    #[tokio::test]
+    async fn slack_posts_under_the_request_channel_then_global_name() {
+        let plain = json!({"service": "slack", "to": "#ops", "title": "t", "body": "b"});
+        let mut named = plain.clone();
+        named["sender_name"] = json!("Deploy Bot");
+        let both = [("SLACK_USERNAME", "Ops Bot"), ("SENDER_NAME", "Acme")];
+
+        assert_eq!(posted_username(&both, named).await, "Deploy Bot");
+        assert_eq!(posted_username(&both, plain.clone()).await, "Ops Bot");
+        assert_eq!(
+            posted_username(&[("SENDER_NAME", "Acme")], plain.clone()).await,
+            "Acme"
+        );
+        assert!(posted_username(&[], plain).await.is_null());
+    }
+}
@@ -0,0 +1,234 @@
+use std::{cell::RefCell, fmt};
+
+use futures::future::BoxFuture;
+use lettre::{address::Envelope, Message};
+use tracing::{
+    debug,
+    field::{Field, Visit},
+    Event, Metadata, Subscriber,
+};
+use tracing_subscriber::{layer::Context, Layer};
+
+use crate::transport::{Delivery, Transport, TransportError};
+
+tokio::task_local! {
+    /// Dialog lines of the send running on this task.
+    static DIALOG: RefCell<Vec<String>>;
+}
+
+/// lettre's wire-level events, which carry AUTH credentials and message
+/// contents verbatim; only [`DialogLayer`] may see them.
+pub fn is_smtp_wire(meta: &Metadata<'_>) -> bool {
+    let target = meta.target();
+    target.starts_with("lettre::transport::smtp::client")
+        || target.starts_with("lettre::transport::smtp::commands")
+}
+
+/// Turns lettre's wire events into redacted dialog lines for the send on
+/// the current task. Events outside a [`DialogLog`] send, such as pool
+/// upkeep, are dropped.
+pub struct DialogLayer;
+
+impl<S: Subscriber> Layer<S> for DialogLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let mut message = MessageVisitor(String::new());
+        event.record(&mut message);
+        let _ = DIALOG.try_with(|dialog| {
+            let mut dialog = dialog.borrow_mut();
+            if let Some(line) = redact(&message.0, dialog.last().map(String::as_str)) {
+                dialog.push(line);
+            }
+        });
+    }
+}
+
+struct MessageVisitor(String);
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{value:?}");
+        }
+    }
+}
+
+/// Client commands are kept up to their verb, except AUTH which keeps only
+/// the mechanism; anything else the client writes is credentials or the
+/// message itself and is reduced to its size.
+fn redact(event: &str, previous: Option<&str>) -> Option<String> {
+    if let Some(written) = event.strip_prefix("Wrote: ") {
+        if written == "<CRLF>.<CRLF>" {
+            return None;
+        }
+        let written = written.trim_end_matches("<CRLF>");
+        let verb = written
+            .split(' ')
+            .next()
+            .unwrap_or_default()
+            .to_ascii_uppercase();
+        return Some(match verb.as_str() {
+            "EHLO" | "HELO" | "MAIL" | "RCPT" | "DATA" | "RSET" | "QUIT" | "NOOP" | "STARTTLS" => {
+                format!("C: {written}")
+            }
+            "AUTH" => match written.split(' ').nth(1) {
+                Some(mechanism) => format!("C: AUTH {mechanism} [redacted]"),
+                None => "C: AUTH [redacted]".to_string(),
+            },
+            _ if previous == Some("C: DATA")
+                || previous.is_some_and(|line| line.starts_with("S: 354")) =>
+            {
+                format!("C: [message, {} bytes]", written.len())
+            }
+            _ => "C: [redacted]".to_string(),
+        });
+    }
+    if let Some(response) = event.strip_prefix("<< ") {
+        // lettre logs a multiline reply again with each line it reads.
+        let line = response.trim_end_matches("<CRLF>").rsplit("<CRLF>").next();
+        return Some(format!("S: {}", line.unwrap_or_default()));
+    }
+    (event == "connection encrypted").then(|| "* connection encrypted".to_string())
+}
+
+/// `SMTP_DEBUG`: logs each send's SMTP dialog, credentials redacted, at
+/// debug level once the send finishes. Connections reused from the pool
+/// show no greeting or AUTH.
+pub struct DialogLog {
+    pub host: String,
+    pub inner: Box<dyn Transport>,
+}
+
+impl Transport for DialogLog {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn send<'a>(
+        &'a self,
+        envelope: &'a Envelope,
+        email: &'a Message,
+    ) -> BoxFuture<'a, Result<Delivery, TransportError>> {
+        Box::pin(DIALOG.scope(RefCell::new(Vec::new()), async move {
+            let result = self.inner.send(envelope, email).await;
+            let dialog = DIALOG.with(|dialog| dialog.take());
+            debug!(host = %self.host, ok = result.is_ok(), dialog = ?dialog, "smtp dialog");
+            result
+        }))
+    }
+
+    fn flush_pool(&self) -> anyhow::Result<bool> {
+        self.inner.flush_pool()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use base64::{prelude::BASE64_STANDARD, Engine};
+    use serde_json::json;
+    use tracing_subscriber::{filter::filter_fn, layer::SubscriberExt};
+
+    use crate::test_support::{self, notify, Capture};
+
+    use super::*;
+
+    #[test]
+    fn commands_keep_their_verb_and_arguments() {
+        assert_eq!(
+            redact("Wrote: MAIL FROM:<notify@example.com><CRLF>", None).as_deref(),
+            Some("C: MAIL FROM:<notify@example.com>")
+        );
+        assert_eq!(
+            redact("Wrote: rcpt TO:<ops@example.com><CRLF>", None).as_deref(),
+            Some("C: rcpt TO:<ops@example.com>")
+        );
+        assert_eq!(
+            redact("<< 250-mock<CRLF>250 SIZE 1000<CRLF>", None).as_deref(),
+            Some("S: 250 SIZE 1000")
+        );
+        assert_eq!(
+            redact("connection encrypted", None).as_deref(),
+            Some("* connection encrypted")
+        );
+        assert_eq!(redact("something else", None), None);
+    }
+
+    #[test]
+    fn credentials_and_message_contents_are_redacted() {
+        assert_eq!(
+            redact("Wrote: AUTH PLAIN AHJlbGF5AHNlY3JldA==<CRLF>", None).as_deref(),
+            Some("C: AUTH PLAIN [redacted]")
+        );
+        assert_eq!(
+            redact("Wrote: AUTH<CRLF>", None).as_deref(),
+            Some("C: AUTH [redacted]")
+        );
+        // A LOGIN username or password answering a 334 challenge.
+        assert_eq!(
+            redact("Wrote: cmVsYXk=<CRLF>", Some("S: 334 VXNlcm5hbWU6")).as_deref(),
+            Some("C: [redacted]")
+        );
+        assert_eq!(
+            redact("Wrote: Subject: deploy<CRLF>", Some("S: 354 go ahead")).as_deref(),
+            Some("C: [message, 15 bytes]")
+        );
+        assert_eq!(redact("Wrote: <CRLF>.<CRLF>", None), None);
+    }
+
+    #[tokio::test]
+    async fn the_dialog_of_a_send_is_logged_without_credentials() {
+        let capture = Capture::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .with_ansi(false)
+                    .with_writer(capture.writer())
+                    .with_filter(filter_fn(|meta| !is_smtp_wire(meta))),
+            )
+            .with(DialogLayer.with_filter(filter_fn(is_smtp_wire)));
+        let _subscriber = tracing::subscriber::set_default(subscriber);
+        let smtp = test_support::MockSmtp::start().await;
+        smtp.reply("EHLO", "250-mock\r\n250 AUTH PLAIN LOGIN");
+        let state = smtp
+            .state(&[
+                ("SMTP_DEBUG", "true"),
+                ("SMTP_USERNAME", "relay"),
+                ("SMTP_PASSWORD", "hunter2-secret"),
+            ])
+            .await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert!(
+            smtp.commands()
+                .iter()
+                .any(|command| command.starts_with("AUTH")),
+            "the mock was authenticated against: {:?}",
+            smtp.commands()
+        );
+
+        let lines = capture.lines();
+        let dialog = lines
+            .iter()
+            .find(|line| line.contains("smtp dialog"))
+            .unwrap_or_else(|| panic!("no dialog logged: {lines:#?}"));
+        for expected in [
+            "S: 220 mock ESMTP",
+            "C: AUTH PLAIN [redacted]",
+            "C: MAIL FROM:<notify@example.com>",
+            "C: RCPT TO:<ops@example.com>",
+            "S: 250 2.0.0 queued",
+        ] {
+            assert!(dialog.contains(expected), "{expected} missing: {dialog}");
+        }
+        let encoded = BASE64_STANDARD.encode("\0relay\0hunter2-secret");
+        let output = lines.join("\n");
+        assert!(!output.contains("hunter2-secret"), "{output}");
+        assert!(!output.contains(&encoded), "{output}");
+    }
+}
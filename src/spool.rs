@@ -0,0 +1,315 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{api_keys, queue::Priority, NotifyRequest};
+
+/// How a spooled job is resumed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobKind {
+    /// `POST /notify/async`: back onto the queue.
+    Queued,
+    /// `async_ack` or a closed send window: on a task of its own.
+    Detached,
+}
+
+/// A job as written to `SPOOL_DIR`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SpooledJob {
+    pub id: String,
+    /// [`api_keys::key_id`] of the caller's key; the raw key is never
+    /// written, so resuming looks the caller up again by this id.
+    #[serde(default)]
+    pub key_id: String,
+    /// The raw key as files written before `key_id` existed stored it.
+    /// Only read, and dropped from the file when it is loaded.
+    #[serde(default, rename = "key", skip_serializing)]
+    pub legacy_key: Option<String>,
+    pub kind: JobKind,
+    #[serde(default)]
+    pub priority: Priority,
+    pub request: NotifyRequest,
+    /// Trace headers carried to the send.
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+    /// Unix timestamp in seconds before which the job must not be sent.
+    #[serde(default)]
+    pub not_before: Option<u64>,
+    /// Unix timestamp in seconds of when a queued job was accepted.
+    #[serde(default)]
+    pub enqueued_at: Option<u64>,
+}
+
+impl SpooledJob {
+    /// Whether the job was accepted for `key`.
+    pub fn belongs_to(&self, key: &str) -> bool {
+        match &self.legacy_key {
+            Some(legacy) => legacy == key,
+            None => self.key_id == api_keys::key_id(key),
+        }
+    }
+}
+
+/// Durable copies of accepted background jobs, one JSON file each, so jobs
+/// survive a restart. A job's file is written before it is acknowledged and
+/// removed once the send finishes either way; a `.sending` marker beside it
+/// flags jobs that were mid-send when the process stopped.
+pub struct Spool {
+    dir: PathBuf,
+}
+
+impl Spool {
+    pub fn new(dir: PathBuf) -> Result<Self> {
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("failed to create spool dir {}", dir.display()))?;
+        Ok(Self { dir })
+    }
+
+    /// Writes the job atomically. Uploaded attachments are inlined as base64
+    /// since their temp files do not outlive the process.
+    pub fn save(&self, job: &mut SpooledJob) -> Result<()> {
+        for attachment in &mut job.request.attachments {
+            if let Some(file) = &attachment.spooled {
+                let bytes = fs::read(file.path())
+                    .with_context(|| format!("failed to read upload {}", attachment.filename))?;
+                attachment.content = BASE64_STANDARD.encode(bytes);
+            }
+        }
+        let raw = serde_json::to_vec(job).context("failed to serialize job")?;
+        let path = self.job_path(&job.id);
+        let partial = path.with_extension("json.partial");
+        fs::write(&partial, raw)
+            .with_context(|| format!("failed to write {}", partial.display()))?;
+        fs::rename(&partial, &path).with_context(|| format!("failed to write {}", path.display()))
+    }
+
+    /// The job's stored copy, if it is still on disk.
+    pub fn get(&self, id: &str) -> Option<SpooledJob> {
+        let raw = fs::read(self.job_path(id)).ok()?;
+        serde_json::from_slice(&raw).ok()
+    }
+
+    pub fn mark_sending(&self, id: &str) {
+        let marker = self.marker_path(id);
+        if let Err(err) = fs::write(&marker, b"") {
+            warn!(job_id = %id, error = %err, "failed to write spool marker");
+        }
+    }
+
+    pub fn remove(&self, id: &str) {
+        for path in [self.job_path(id), self.marker_path(id)] {
+            match fs::remove_file(&path) {
+                Ok(()) => {}
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => {
+                    warn!(path = %path.display(), error = %err, "failed to remove spooled job")
+                }
+            }
+        }
+    }
+
+    /// Jobs on disk, to be recovered on the next start.
+    pub fn count(&self) -> usize {
+        count_json_files(&self.dir)
+    }
+
+    /// Every pending job, oldest first, with whether it was mid-send.
+    /// Unreadable files are skipped with a warning and left in place. Files
+    /// that still hold a raw key are rewritten with its id; the key stays on
+    /// the returned job for this run.
+    pub fn load(&self) -> Result<Vec<(SpooledJob, bool)>> {
+        let mut files = Vec::new();
+        let entries = fs::read_dir(&self.dir)
+            .with_context(|| format!("failed to read spool dir {}", self.dir.display()))?;
+        for entry in entries {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                let modified = entry.metadata()?.modified().unwrap_or(UNIX_EPOCH);
+                files.push((modified, path));
+            }
+        }
+        files.sort();
+
+        let mut jobs = Vec::new();
+        for (_, path) in files {
+            let job = fs::read(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|raw| Ok(serde_json::from_slice::<SpooledJob>(&raw)?));
+            match job {
+                Ok(mut job) => {
+                    if let Some(legacy) = job.legacy_key.take() {
+                        job.key_id = api_keys::key_id(&legacy);
+                        if let Err(err) = self.save(&mut job) {
+                            warn!(path = %path.display(), error = %format!("{err:#}"), "failed to rewrite spooled job without its key");
+                        }
+                        job.legacy_key = Some(legacy);
+                    }
+                    let sending = self.marker_path(&job.id).exists();
+                    jobs.push((job, sending));
+                }
+                Err(err) => {
+                    warn!(path = %path.display(), error = %err, "skipping unreadable spooled job")
+                }
+            }
+        }
+        Ok(jobs)
+    }
+
+    fn job_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{id}.json"))
+    }
+
+    fn marker_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{id}.sending"))
+    }
+}
+
+/// Whole seconds since the Unix epoch.
+pub fn to_unix(at: SystemTime) -> u64 {
+    at.duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs())
+        .unwrap_or_default()
+}
+
+pub fn from_unix(secs: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+/// Time left until the Unix timestamp `at`, zero once it has passed.
+pub fn until_unix(at: u64) -> Duration {
+    from_unix(at)
+        .duration_since(SystemTime::now())
+        .unwrap_or_default()
+}
+
+/// `*.json` files in `dir`; 0 when it cannot be read.
+pub fn count_json_files(dir: &Path) -> usize {
+    fs::read_dir(dir).map_or(0, |entries| {
+        entries
+            .filter_map(Result::ok)
+            .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "json"))
+            .count()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::test_support::TempDir;
+
+    use super::*;
+
+    fn job(id: &str) -> SpooledJob {
+        SpooledJob {
+            id: id.to_string(),
+            key_id: api_keys::key_id("test-key"),
+            legacy_key: None,
+            kind: JobKind::Queued,
+            priority: Priority::High,
+            request: serde_json::from_value(
+                json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b"}),
+            )
+            .expect("valid request"),
+            headers: vec![("traceparent".to_string(), "00-abc-def-01".to_string())],
+            not_before: Some(1_700_000_000),
+            enqueued_at: None,
+        }
+    }
+
+    #[test]
+    fn saved_jobs_load_back_oldest_first() {
+        let dir = TempDir::new("spool-load");
+        let spool = Spool::new(dir.path().join("spool")).expect("spool dir is created");
+
+        spool.save(&mut job("first")).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        spool.save(&mut job("second")).unwrap();
+        assert_eq!(spool.count(), 2);
+
+        let jobs = spool.load().unwrap();
+        let ids: Vec<_> = jobs.iter().map(|(job, _)| job.id.as_str()).collect();
+        assert_eq!(ids, ["first", "second"]);
+        let (job, sending) = &jobs[0];
+        assert!(!sending);
+        assert_eq!(job.priority, Priority::High);
+        assert_eq!(job.not_before, Some(1_700_000_000));
+        assert_eq!(job.headers[0].0, "traceparent");
+        assert!(spool.get("second").unwrap().belongs_to("test-key"));
+    }
+
+    #[test]
+    fn the_raw_key_is_never_written_and_old_files_lose_it_on_load() {
+        let dir = TempDir::new("spool-key");
+        let spool = Spool::new(dir.path().to_path_buf()).unwrap();
+
+        spool.save(&mut job("new")).unwrap();
+        let raw = fs::read_to_string(dir.path().join("new.json")).unwrap();
+        assert!(!raw.contains("test-key"), "{raw}");
+
+        let mut legacy: serde_json::Value = serde_json::from_str(&raw).unwrap();
+        legacy.as_object_mut().unwrap().remove("key_id");
+        legacy["key"] = json!("test-key");
+        legacy["id"] = json!("old");
+        fs::write(dir.path().join("old.json"), legacy.to_string()).unwrap();
+
+        let jobs = spool.load().unwrap();
+        let old = &jobs.iter().find(|(job, _)| job.id == "old").unwrap().0;
+        assert_eq!(old.legacy_key.as_deref(), Some("test-key"));
+        assert_eq!(old.key_id, api_keys::key_id("test-key"));
+        let raw = fs::read_to_string(dir.path().join("old.json")).unwrap();
+        assert!(!raw.contains("test-key"), "{raw}");
+        let reread = spool.get("old").unwrap();
+        assert!(reread.belongs_to("test-key"));
+        assert!(!reread.belongs_to("other-key"));
+    }
+
+    #[test]
+    fn mid_send_jobs_are_flagged_and_removed_with_their_marker() {
+        let dir = TempDir::new("spool-marker");
+        let spool = Spool::new(dir.path().to_path_buf()).unwrap();
+
+        spool.save(&mut job("job-1")).unwrap();
+        spool.mark_sending("job-1");
+        assert!(spool.load().unwrap()[0].1, "marked mid-send");
+
+        spool.remove("job-1");
+        assert_eq!(spool.count(), 0);
+        assert!(spool.get("job-1").is_none());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+        // Removing twice is not an error.
+        spool.remove("job-1");
+    }
+
+    #[test]
+    fn unreadable_jobs_are_skipped_and_left_in_place() {
+        let dir = TempDir::new("spool-unreadable");
+        let spool = Spool::new(dir.path().to_path_buf()).unwrap();
+
+        fs::write(dir.path().join("broken.json"), "{not json").unwrap();
+        spool.save(&mut job("job-1")).unwrap();
+
+        let jobs = spool.load().unwrap();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].0.id, "job-1");
+        assert!(dir.path().join("broken.json").exists());
+    }
+
+    #[test]
+    fn unix_timestamps_round_trip() {
+        let at = from_unix(1_700_000_000);
+        assert_eq!(to_unix(at), 1_700_000_000);
+        assert_eq!(until_unix(1), Duration::ZERO);
+        assert!(until_unix(to_unix(SystemTime::now()) + 60) > Duration::from_secs(50));
+    }
+}
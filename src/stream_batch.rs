@@ -0,0 +1,395 @@
+use std::{
+    convert::Infallible,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use axum::{
+    body::Body,
+    extract::State,
+    http::{header::CONTENT_TYPE, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures::{future, stream, Stream, StreamExt};
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tracing::info;
+
+use crate::{
+    api_keys::Caller, authenticate, dispatch, error_response, field_error, flatten_recipients,
+    ApiError, AppState, NotifyRequest, RecipientsInput,
+};
+
+/// Longest line accepted, the message line included; a longer one ends the
+/// stream, as nothing past it can be read as lines.
+const MAX_LINE_BYTES: usize = 1 << 20;
+
+/// One recipient's outcome, written as soon as its send finishes.
+#[derive(Serialize)]
+struct LineResult {
+    /// 1-based line of the request body.
+    line: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    to: Option<String>,
+    ok: bool,
+    message: String,
+}
+
+/// The last line of the response.
+#[derive(Serialize)]
+struct Summary {
+    done: bool,
+    ok: bool,
+    sent: usize,
+    failed: usize,
+    /// Why the stream ended early, when it did.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
+
+/// `POST /notify/stream`: one message to a recipient list too long to send
+/// as one request body. The NDJSON body starts with the message, as for
+/// `/notify` but without `to`, `cc` or `bcc`; every following line is one
+/// recipient in any form `to` accepts, and gets its own copy. Lines are
+/// sent as they arrive, `BATCH_CONCURRENCY` at a time, and each result is
+/// streamed back as an NDJSON line, so neither list is held in memory.
+pub async fn notify_stream(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, ApiError> {
+    let Some(caller) = authenticate(&state, &headers).await else {
+        return Err(error_response(StatusCode::UNAUTHORIZED, "invalid api key").into());
+    };
+    let key = caller.key.to_string();
+    let policy = caller.policy.clone();
+
+    let mut lines =
+        Box::pin(lines(body).enumerate().filter(|(_, line)| {
+            future::ready(!matches!(line, Ok(line) if line.trim().is_empty()))
+        }));
+    let message = match lines.next().await {
+        Some((_, Ok(line))) => serde_json::from_str::<NotifyRequest>(&line).map_err(|err| {
+            error_response(
+                StatusCode::BAD_REQUEST,
+                &format!("invalid message line: {err}"),
+            )
+        })?,
+        Some((_, Err(message))) => {
+            return Err(error_response(StatusCode::BAD_REQUEST, &message).into())
+        }
+        None => return Err(error_response(StatusCode::BAD_REQUEST, "body is empty").into()),
+    };
+    if !message.to.trim().is_empty() {
+        return Err(field_error("to", "recipients go on the lines after the message").into());
+    }
+    if message.cc.is_some() || message.bcc.is_some() {
+        return Err(field_error("cc", "cc and bcc cannot be combined with a streamed send").into());
+    }
+
+    // Room for a few results ahead of a slow reader, which then holds up
+    // the sends: backpressure instead of buffering.
+    let (tx, mut rx) = mpsc::channel(state.batch_concurrency.max(1) * 2);
+    tokio::spawn(async move {
+        let caller = Caller { key: &key, policy };
+        send_lines(&state, &caller, &headers, message, lines, tx).await;
+    });
+    let results = stream::poll_fn(move |cx| rx.poll_recv(cx)).map(Ok::<_, Infallible>);
+
+    Ok((
+        [(CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(results),
+    )
+        .into_response())
+}
+
+async fn send_lines(
+    state: &Arc<AppState>,
+    caller: &Caller<'_>,
+    headers: &HeaderMap,
+    message: NotifyRequest,
+    lines: impl Stream<Item = (usize, Result<String, String>)>,
+    tx: mpsc::Sender<String>,
+) {
+    // Set once the client stops reading; lines still arriving are then
+    // drained unsent, while sends already started run to completion.
+    let gone = AtomicBool::new(false);
+    let (message, gone) = (&message, &gone);
+    let mut ended = None;
+    let mut results = Box::pin(
+        lines
+            .map(|(index, line)| async move {
+                let line_number = index + 1;
+                let line = match line {
+                    Ok(line) => line,
+                    // Nothing past a broken line can be read.
+                    Err(reason) => return Err(reason),
+                };
+                if gone.load(Ordering::Acquire) {
+                    return Ok(None);
+                }
+                let to = match parse_recipient(&line) {
+                    Ok(to) => to,
+                    Err(reason) => {
+                        return Ok(Some(LineResult {
+                            line: line_number,
+                            to: None,
+                            ok: false,
+                            message: reason,
+                        }))
+                    }
+                };
+                let mut req = message.clone();
+                req.to = to.clone();
+                let (status, Json(body)) = dispatch(state, caller, headers, req).await;
+                Ok(Some(LineResult {
+                    line: line_number,
+                    to: Some(to),
+                    ok: status.is_success(),
+                    message: body.message,
+                }))
+            })
+            .buffer_unordered(state.batch_concurrency),
+    );
+
+    let (mut sent, mut failed) = (0, 0);
+    while let Some(result) = results.next().await {
+        let result = match result {
+            Ok(Some(result)) => result,
+            Ok(None) => continue,
+            Err(reason) => {
+                ended.get_or_insert(reason);
+                continue;
+            }
+        };
+        if result.ok {
+            sent += 1;
+        } else {
+            failed += 1;
+        }
+        let line = serde_json::to_string(&result).expect("line result serializes") + "\n";
+        if tx.send(line).await.is_err() {
+            gone.store(true, Ordering::Release);
+        }
+    }
+
+    info!(sent, failed, "streamed send finished");
+    let summary = Summary {
+        done: true,
+        ok: failed == 0 && ended.is_none(),
+        sent,
+        failed,
+        message: ended,
+    };
+    let line = serde_json::to_string(&summary).expect("summary serializes") + "\n";
+    let _ = tx.send(line).await;
+}
+
+/// A recipient line, in any form `to` takes.
+fn parse_recipient(line: &str) -> Result<String, String> {
+    let input: RecipientsInput =
+        serde_json::from_str(line).map_err(|_| "invalid recipient line".to_string())?;
+    let mut entries = Vec::new();
+    flatten_recipients(input, &mut entries)?;
+    Ok(entries.join(", "))
+}
+
+/// The body's lines as its chunks arrive. A line over [`MAX_LINE_BYTES`],
+/// invalid UTF-8 or a failed read is the last item.
+fn lines(body: Body) -> impl Stream<Item = Result<String, String>> + Send {
+    let data = body.into_data_stream();
+    stream::unfold(
+        (data, Vec::new(), false),
+        |(mut data, mut buf, done)| async move {
+            if done {
+                return None;
+            }
+            loop {
+                let end = buf.iter().position(|&byte| byte == b'\n');
+                if end.unwrap_or(buf.len()) > MAX_LINE_BYTES {
+                    let reason = format!("line longer than {MAX_LINE_BYTES} bytes");
+                    return Some((Err(reason), (data, buf, true)));
+                }
+                if let Some(end) = end {
+                    let line: Vec<u8> = buf.drain(..=end).collect();
+                    let line = decode(line);
+                    let done = line.is_err();
+                    return Some((line, (data, buf, done)));
+                }
+                match data.next().await {
+                    Some(Ok(chunk)) => buf.extend_from_slice(&chunk),
+                    Some(Err(err)) => {
+                        let reason = format!("failed to read body: {err}");
+                        return Some((Err(reason), (data, buf, true)));
+                    }
+                    None if buf.is_empty() => return None,
+                    None => {
+                        let line = decode(std::mem::take(&mut buf));
+                        return Some((line, (data, buf, true)));
+                    }
+                }
+            }
+        },
+    )
+}
+
+fn decode(line: Vec<u8>) -> Result<String, String> {
+    let line = String::from_utf8(line).map_err(|_| "line is not UTF-8".to_string())?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::http::Request;
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::test_support;
+
+    const MESSAGE: &str = r#"{"service": "smtp", "title": "newsletter", "body": "hello"}"#;
+
+    /// Posts `body` to `/notify/stream`, returning the status and a stream
+    /// of the response's lines.
+    async fn post(app: &axum::Router, body: Body) -> (StatusCode, impl Stream<Item = Value>) {
+        let request = Request::post("/notify/stream")
+            .header("authorization", format!("Bearer {}", test_support::API_KEY))
+            .body(body)
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let values = lines(response.into_body())
+            .map(|line| serde_json::from_str(&line.expect("a response line")).unwrap());
+        (status, values)
+    }
+
+    async fn post_all(app: &axum::Router, body: String) -> (StatusCode, Vec<Value>) {
+        let (status, values) = post(app, Body::from(body)).await;
+        (status, values.collect().await)
+    }
+
+    #[tokio::test]
+    async fn every_streamed_recipient_gets_a_copy() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let mut body = format!("{MESSAGE}\n");
+        for n in 0..300 {
+            body.push_str(&format!("\"user{n}@example.com\"\n"));
+        }
+        // Chunks that split lines, as a network would.
+        let chunks: Vec<Result<Vec<u8>, Infallible>> = body
+            .into_bytes()
+            .chunks(7)
+            .map(|chunk| Ok(chunk.to_vec()))
+            .collect();
+        let (status, values) = post(&app, Body::from_stream(stream::iter(chunks))).await;
+        assert_eq!(status, StatusCode::OK);
+        let values: Vec<Value> = values.collect().await;
+
+        assert_eq!(values.len(), 301);
+        let (summary, results) = values.split_last().unwrap();
+        assert_eq!(
+            *summary,
+            json!({"done": true, "ok": true, "sent": 300, "failed": 0})
+        );
+        let mut lines: Vec<u64> = results
+            .iter()
+            .map(|r| r["line"].as_u64().unwrap())
+            .collect();
+        lines.sort_unstable();
+        assert_eq!(lines, (2..=301).collect::<Vec<_>>());
+        assert!(results
+            .iter()
+            .all(|r| r["ok"] == true && r["message"] == "sent"));
+        assert_eq!(test_support::sent(&app).await.len(), 300);
+    }
+
+    #[tokio::test]
+    async fn results_are_streamed_before_the_body_ends() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let (tx, rx) = mpsc::channel::<Result<String, Infallible>>(4);
+        let body = Body::from_stream(stream::poll_fn({
+            let mut rx = rx;
+            move |cx| rx.poll_recv(cx)
+        }));
+        tx.send(Ok(format!("{MESSAGE}\n\"first@example.com\"\n")))
+            .await
+            .unwrap();
+        let (status, values) = post(&app, body).await;
+        assert_eq!(status, StatusCode::OK);
+        let mut values = Box::pin(values);
+
+        // The first result comes back while the body is still open.
+        let first = tokio::time::timeout(Duration::from_secs(3), values.next())
+            .await
+            .expect("a result before the body ends")
+            .unwrap();
+        assert_eq!(first["to"], "first@example.com");
+        tx.send(Ok("\"second@example.com\"\n".to_string()))
+            .await
+            .unwrap();
+        drop(tx);
+        let rest: Vec<Value> = values.collect().await;
+        assert_eq!(rest[0]["to"], "second@example.com");
+        assert_eq!(rest[1]["sent"], 2);
+    }
+
+    #[tokio::test]
+    async fn a_bad_recipient_line_fails_alone() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let body = format!(
+            "{MESSAGE}\n\"a@example.com\"\n42\n\n{{\"name\": \"B\", \"address\": \"b@example.com\"}}\n"
+        );
+        let (status, values) = post_all(&app, body).await;
+        assert_eq!(status, StatusCode::OK);
+        let failed: Vec<&Value> = values.iter().filter(|v| v["ok"] == false).collect();
+        assert_eq!(failed.len(), 2, "{values:?}");
+        assert_eq!(failed[0]["line"], 3);
+        assert_eq!(failed[0]["message"], "invalid recipient line");
+        assert_eq!(
+            values.last().unwrap(),
+            &json!({"done": true, "ok": false, "sent": 2, "failed": 1})
+        );
+        assert_eq!(test_support::sent(&app).await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn the_message_line_cannot_carry_recipients() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let (status, values) = post_all(
+            &app,
+            r#"{"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b"}
+"a@example.com"
+"#
+            .to_string(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            values[0]["message"],
+            "recipients go on the lines after the message"
+        );
+
+        let (status, values) = post_all(&app, String::new()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(values[0]["message"], "body is empty");
+        assert!(test_support::sent(&app).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn an_overlong_line_ends_the_body() {
+        let body = format!("short\n{}\nnever read\n", "x".repeat(MAX_LINE_BYTES + 1));
+        let lines: Vec<_> = lines(Body::from(body)).collect().await;
+        assert_eq!(
+            lines,
+            [
+                Ok("short".to_string()),
+                Err(format!("line longer than {MAX_LINE_BYTES} bytes"))
+            ]
+        );
+    }
+}
@@ -0,0 +1,156 @@
+use base64::{prelude::BASE64_STANDARD, Engine};
+use lettre::message::header::{HeaderName, HeaderValue};
+
+/// Longest raw chunk per encoded-word: 45 bytes become 60 base64 chars,
+/// which with the `=?utf-8?b?...?=` wrapper stays under the 75-char limit of
+/// RFC 2047.
+const MAX_WORD_BYTES: usize = 45;
+
+/// Longest encoded text per `Q` encoded-word: 75 chars minus the 12 of the
+/// `=?utf-8?q?...?=` wrapper.
+const MAX_Q_WORD_CHARS: usize = 63;
+
+/// Bidi controls, which can make a subject display differently from what
+/// it says, and invisible zero-width characters. Joiners are left alone as
+/// emoji sequences and some scripts need them.
+pub fn is_dangerous_char(ch: char) -> bool {
+    matches!(
+        ch,
+        '\u{061c}'
+            | '\u{200e}'
+            | '\u{200f}'
+            | '\u{202a}'..='\u{202e}'
+            | '\u{2066}'..='\u{2069}'
+            | '\u{200b}'
+            | '\u{2060}'
+            | '\u{feff}'
+    )
+}
+
+/// Builds a `Subject` header made of RFC 2047 base64 encoded-words, one per
+/// folded line, so no header line exceeds the RFC 5322 length limit however
+/// long the subject or its whitespace-free runs are.
+pub fn folded_base64_subject(subject: &str) -> HeaderValue {
+    let words: Vec<String> = chunk_utf8(subject, MAX_WORD_BYTES)
+        .into_iter()
+        .map(|chunk| format!("=?utf-8?b?{}?=", BASE64_STANDARD.encode(chunk)))
+        .collect();
+    subject_header(subject, &words)
+}
+
+/// Same as [`folded_base64_subject`] with RFC 2047 `Q` (quoted-printable)
+/// encoded-words, which keep ASCII text readable in the raw header.
+pub fn folded_q_subject(subject: &str) -> HeaderValue {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut buf = [0; 4];
+    for ch in subject.chars() {
+        let mut encoded = String::new();
+        for byte in ch.encode_utf8(&mut buf).bytes() {
+            match byte {
+                b' ' => encoded.push('_'),
+                byte if byte.is_ascii_alphanumeric() || b"!*+-/".contains(&byte) => {
+                    encoded.push(byte as char)
+                }
+                byte => encoded.push_str(&format!("={byte:02X}")),
+            }
+        }
+        // A character's bytes stay in one word; splitting them would leave
+        // each word invalid UTF-8 on its own.
+        if word.len() + encoded.len() > MAX_Q_WORD_CHARS {
+            words.push(format!("=?utf-8?q?{word}?="));
+            word.clear();
+        }
+        word.push_str(&encoded);
+    }
+    if !word.is_empty() {
+        words.push(format!("=?utf-8?q?{word}?="));
+    }
+    subject_header(subject, &words)
+}
+
+/// One encoded-word per folded line.
+fn subject_header(subject: &str, words: &[String]) -> HeaderValue {
+    HeaderValue::dangerous_new_pre_encoded(
+        HeaderName::new_from_ascii_str("Subject"),
+        subject.to_string(),
+        words.join("\r\n "),
+    )
+}
+
+/// Splits on char boundaries into pieces of at most `max_bytes` bytes.
+fn chunk_utf8(text: &str, max_bytes: usize) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    for (index, ch) in text.char_indices() {
+        if index + ch.len_utf8() - start > max_bytes {
+            chunks.push(&text[start..index]);
+            start = index;
+        }
+    }
+    if start < text.len() {
+        chunks.push(&text[start..]);
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use lettre::message::header::Headers;
+
+    use super::*;
+    use crate::test_support::decode_words;
+
+    const SUBJECT: &str = "🚀 デプロイ完了 — build 1234 is live on every region 🎉 服务器已上线";
+
+    /// The header's value as rendered, still folded.
+    fn encoded(header: HeaderValue) -> String {
+        let mut headers = Headers::new();
+        headers.insert_raw(header);
+        let rendered = headers.to_string();
+        let value = rendered.strip_prefix("Subject: ").expect("a subject");
+        value.trim_end().to_string()
+    }
+
+    #[test]
+    fn base64_subject_is_valid_rfc_2047() {
+        let encoded = encoded(folded_base64_subject(SUBJECT));
+        assert!(encoded.starts_with("=?utf-8?b?"), "{encoded}");
+        assert!(encoded.lines().count() > 1, "{encoded}");
+        assert_eq!(decode_words(&encoded), SUBJECT);
+    }
+
+    #[test]
+    fn q_subject_is_valid_rfc_2047() {
+        let encoded = encoded(folded_q_subject(SUBJECT));
+        assert!(encoded.starts_with("=?utf-8?q?=F0=9F=9A=80_"), "{encoded}");
+        assert!(encoded.contains("build_1234_is_live"), "{encoded}");
+        assert_eq!(decode_words(&encoded), SUBJECT);
+    }
+
+    #[test]
+    fn words_never_split_a_character() {
+        let subject = "日本語".repeat(40);
+        for encoded in [
+            encoded(folded_base64_subject(&subject)),
+            encoded(folded_q_subject(&subject)),
+        ] {
+            // Each word decodes on its own, so none holds part of a character.
+            assert_eq!(decode_words(&encoded), subject);
+        }
+        assert_eq!(chunk_utf8("aé日", 2), ["a", "é", "日"]);
+    }
+
+    #[test]
+    fn bidi_controls_and_zero_width_characters_are_dangerous() {
+        for ch in [
+            '\u{202e}', '\u{202a}', '\u{2066}', '\u{200f}', '\u{200b}', '\u{feff}',
+        ] {
+            assert!(is_dangerous_char(ch), "{:04x}", ch as u32);
+        }
+        // Joiners hold emoji sequences together.
+        for ch in ['a', 'é', '🚀', '\u{200d}', '\u{200c}', ' '] {
+            assert!(!is_dangerous_char(ch), "{:04x}", ch as u32);
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use anyhow::{Context, Result};
+use axum::http::HeaderMap;
+use opentelemetry::{global, propagation::Extractor, trace::TracerProvider as _};
+use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::SdkTracerProvider, Resource};
+use tracing::warn;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{
+    filter::filter_fn, layer::SubscriberExt, util::SubscriberInitExt, Layer as _,
+};
+
+use crate::smtp_debug::{is_smtp_wire, DialogLayer};
+
+/// Keeps the OTLP pipeline alive; call [`Telemetry::shutdown`] to flush
+/// buffered spans before exit.
+pub struct Telemetry {
+    provider: Option<SdkTracerProvider>,
+}
+
+impl Telemetry {
+    pub fn shutdown(self) {
+        if let Some(provider) = self.provider {
+            if let Err(err) = provider.shutdown() {
+                warn!(error = %err, "failed to flush trace exporter");
+            }
+        }
+    }
+}
+
+/// Installs the log subscriber and, when `OTEL_EXPORTER_OTLP_ENDPOINT` is
+/// set, an OTLP/HTTP span exporter alongside it. The exporter reads the
+/// endpoint and the other standard `OTEL_*` variables itself.
+///
+/// lettre's wire events only ever reach the `SMTP_DEBUG` dialog layer,
+/// which redacts them; no other layer sees credentials.
+pub fn init() -> Result<Telemetry> {
+    let mut filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| "notification_server=info,axum=info".into());
+    let smtp_debug = crate::parse_bool_env("SMTP_DEBUG").unwrap_or(false);
+    if smtp_debug {
+        for directive in [
+            "lettre::transport::smtp=debug",
+            "notification_server::smtp_debug=debug",
+        ] {
+            filter = filter.add_directive(directive.parse().expect("valid directive"));
+        }
+    }
+
+    let provider = if std::env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT").is_some() {
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .build()
+            .context("failed to build OTLP span exporter")?;
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(
+                Resource::builder()
+                    .with_service_name(env!("CARGO_PKG_NAME"))
+                    .build(),
+            )
+            .build();
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        Some(provider)
+    } else {
+        None
+    };
+
+    let otel_layer = provider.as_ref().map(|provider| {
+        tracing_opentelemetry::layer().with_tracer(provider.tracer(env!("CARGO_PKG_NAME")))
+    });
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_target(false)
+                .compact()
+                .with_filter(filter_fn(|meta| !is_smtp_wire(meta))),
+        )
+        .with(otel_layer.with_filter(filter_fn(|meta| !is_smtp_wire(meta))))
+        .with(smtp_debug.then(|| DialogLayer.with_filter(filter_fn(is_smtp_wire))))
+        .init();
+
+    Ok(Telemetry { provider })
+}
+
+/// Continues the caller's trace when the request carries a `traceparent`.
+pub fn link_parent(span: &tracing::Span, headers: &HeaderMap) {
+    let cx =
+        global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+    // Fails only when no OTel layer is installed, in which case there is
+    // nothing to link.
+    let _ = span.set_parent(cx);
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+    };
+    use opentelemetry::{trace::TraceId, Value};
+    use opentelemetry_sdk::trace::InMemorySpanExporter;
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::test_support;
+
+    #[tokio::test]
+    async fn send_span_carries_attributes_and_the_callers_trace() {
+        let exporter = InMemorySpanExporter::default();
+        let provider = SdkTracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let _subscriber = tracing::subscriber::set_default(
+            tracing_subscriber::registry()
+                .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test"))),
+        );
+
+        let state = test_support::state(&[]).await;
+        let request = Request::post("/notify")
+            .header("authorization", format!("Bearer {}", test_support::API_KEY))
+            .header("content-type", "application/json")
+            .header(
+                "traceparent",
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            )
+            .body(Body::from(
+                json!({"service": "smtp", "to": "ops@example.com", "title": "deploy", "body": "b"})
+                    .to_string(),
+            ))
+            .unwrap();
+        let response = test_support::app(&state).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let span = spans
+            .iter()
+            .find(|span| span.name == "send_email")
+            .expect("a send_email span");
+        let attribute = |key: &str| {
+            span.attributes
+                .iter()
+                .find(|kv| kv.key.as_str() == key)
+                .map(|kv| kv.value.clone())
+        };
+        assert_eq!(attribute("recipient"), Some(Value::from("ops@example.com")));
+        assert_eq!(attribute("subject_len"), Some(Value::I64(6)));
+        assert_eq!(attribute("retries"), Some(Value::I64(0)));
+        assert_eq!(attribute("outcome"), Some(Value::from("sent")));
+        assert_eq!(
+            span.span_context.trace_id(),
+            TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap()
+        );
+        assert_eq!(format!("{}", span.parent_span_id), "00f067aa0ba902b7");
+    }
+}
@@ -0,0 +1,754 @@
+use std::{
+    collections::HashMap,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use arc_swap::ArcSwap;
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use handlebars::{no_escape, template::TemplateElement, Handlebars};
+use serde::Serialize;
+use serde_json::Value;
+use tracing::{info, warn};
+
+use crate::{authenticate_admin, error_response, reload_guard, ApiError, AppState};
+
+/// Body templates loaded from `TEMPLATES_DIR`.
+///
+/// `welcome.de.hbs` is the German variant of `welcome`; a file without a
+/// locale suffix (`welcome.hbs`) belongs to the default locale. Templates are
+/// registered as `<name>.<locale>` so they can also be used as partials.
+/// The registry is swapped whole on `POST /admin/reload-templates`.
+pub struct Templates {
+    dir: PathBuf,
+    registry: ArcSwap<Handlebars<'static>>,
+    default_locale: String,
+    limits: RenderLimits,
+}
+
+/// Guards against templates whose partials recurse or blow up.
+#[derive(Debug, Clone, Copy)]
+pub struct RenderLimits {
+    /// Partials nested inside one another, counted from the template
+    /// rendered; a partial that includes itself, directly or through
+    /// others, always exceeds it.
+    pub max_depth: usize,
+    pub timeout: Option<Duration>,
+}
+
+/// A template file left out of a reload.
+#[derive(Debug, Serialize)]
+pub struct TemplateFailure {
+    file: String,
+    error: String,
+}
+
+#[derive(Serialize)]
+pub struct ReloadResponse {
+    ok: bool,
+    message: String,
+    loaded: usize,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    failed: Vec<TemplateFailure>,
+}
+
+#[derive(Debug)]
+pub enum TemplateError {
+    NotFound(String),
+    Render(String),
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateError::NotFound(name) => write!(f, "template not found: {name}"),
+            TemplateError::Render(reason) => write!(f, "template render failed: {reason}"),
+        }
+    }
+}
+
+impl Templates {
+    /// Fails on the first template that does not compile.
+    pub fn load(dir: &Path, default_locale: &str, limits: RenderLimits) -> Result<Self> {
+        let default_locale = normalize_locale(default_locale);
+        let (registry, failed) = scan(dir, &default_locale)?;
+        if let Some(failure) = failed.into_iter().next() {
+            anyhow::bail!(
+                "failed to compile template {}: {}",
+                failure.file,
+                failure.error
+            );
+        }
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            registry: ArcSwap::from_pointee(registry),
+            default_locale,
+            limits,
+        })
+    }
+
+    /// Templates currently loaded, each locale counted separately.
+    pub fn count(&self) -> usize {
+        self.registry.load().get_templates().len()
+    }
+
+    /// Re-reads the directory and swaps in every template that compiles.
+    /// When the directory cannot be read the current registry is kept.
+    fn reload(&self) -> Result<(usize, Vec<TemplateFailure>)> {
+        let (registry, failed) = scan(&self.dir, &self.default_locale)?;
+        let loaded = registry.get_templates().len();
+        self.registry.store(Arc::new(registry));
+        Ok((loaded, failed))
+    }
+
+    /// Renders `name` in the first of `locales` it exists in, each followed
+    /// by its bare language (`de-AT` → `de`), and then the default locale.
+    pub fn render(
+        &self,
+        name: &str,
+        locales: &[String],
+        data: &Value,
+    ) -> Result<String, TemplateError> {
+        let registry = self.registry.load();
+        let key = self
+            .candidates(locales)
+            .into_iter()
+            .map(|locale| format!("{name}.{locale}"))
+            .find(|key| registry.has_template(key))
+            .ok_or_else(|| TemplateError::NotFound(name.to_string()))?;
+        let template = registry
+            .get_template(&key)
+            .expect("template was just found");
+        // Checked before rendering: handlebars follows partials by recursion,
+        // so a cycle would overflow the stack rather than fail.
+        if partial_depth(
+            &registry,
+            &template.elements,
+            self.limits.max_depth,
+            &mut HashMap::new(),
+        )
+        .is_none()
+        {
+            return Err(TemplateError::Render("depth exceeded".to_string()));
+        }
+
+        let mut out = DeadlineWriter {
+            buf: Vec::new(),
+            deadline: self.limits.timeout.map(|timeout| Instant::now() + timeout),
+            expired: false,
+        };
+        if let Err(err) = registry.render_to_write(&key, data, &mut out) {
+            return Err(TemplateError::Render(match out.expired {
+                true => "timeout exceeded".to_string(),
+                false => err.to_string(),
+            }));
+        }
+        String::from_utf8(out.buf).map_err(|err| TemplateError::Render(err.to_string()))
+    }
+
+    fn candidates(&self, locales: &[String]) -> Vec<String> {
+        let mut candidates = Vec::with_capacity(locales.len() * 2 + 1);
+        for locale in locales.iter().map(|locale| normalize_locale(locale)) {
+            if let Some((language, _)) = locale.split_once('-') {
+                let language = language.to_string();
+                candidates.push(locale);
+                candidates.push(language);
+            } else {
+                candidates.push(locale);
+            }
+        }
+        candidates.push(self.default_locale.clone());
+        candidates
+    }
+}
+
+/// Render output that fails the render once `deadline` passes. Checked on
+/// each write, which a render that keeps expanding partials keeps making.
+struct DeadlineWriter {
+    buf: Vec<u8>,
+    deadline: Option<Instant>,
+    expired: bool,
+}
+
+impl io::Write for DeadlineWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self
+            .deadline
+            .is_some_and(|deadline| Instant::now() > deadline)
+        {
+            self.expired = true;
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "render timed out"));
+        }
+        self.buf.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// How deep `elements` nest registered partials, or `None` past `budget`.
+/// `known` holds the depth of partials already walked, so one included many
+/// times is walked once.
+fn partial_depth<'a>(
+    registry: &'a Handlebars<'static>,
+    elements: &'a [TemplateElement],
+    budget: usize,
+    known: &mut HashMap<&'a str, usize>,
+) -> Option<usize> {
+    let mut deepest = 0;
+    for element in elements {
+        let (partial, nested) = match element {
+            TemplateElement::PartialExpression(decorator)
+            | TemplateElement::PartialBlock(decorator) => (
+                decorator.name.as_name(),
+                [decorator.template.as_ref(), None],
+            ),
+            TemplateElement::DecoratorExpression(decorator)
+            | TemplateElement::DecoratorBlock(decorator) => {
+                (None, [decorator.template.as_ref(), None])
+            }
+            TemplateElement::HelperBlock(helper) => {
+                (None, [helper.template.as_ref(), helper.inverse.as_ref()])
+            }
+            _ => continue,
+        };
+        for template in nested.into_iter().flatten() {
+            deepest = deepest.max(partial_depth(registry, &template.elements, budget, known)?);
+        }
+        // Inline and missing partials are left to the render.
+        let Some((name, template)) =
+            partial.and_then(|name| Some((name, registry.get_template(name)?)))
+        else {
+            continue;
+        };
+        let depth = match known.get(name) {
+            Some(&depth) => depth,
+            None => {
+                let depth =
+                    1 + partial_depth(registry, &template.elements, budget.checked_sub(1)?, known)?;
+                known.insert(name, depth);
+                depth
+            }
+        };
+        if depth > budget {
+            return None;
+        }
+        deepest = deepest.max(depth);
+    }
+    Some(deepest)
+}
+
+/// Compiles every `.hbs` file in `dir`, collecting the ones that fail.
+fn scan(dir: &Path, default_locale: &str) -> Result<(Handlebars<'static>, Vec<TemplateFailure>)> {
+    let mut registry = Handlebars::new();
+    // Bodies are plain text, so HTML escaping would only mangle them.
+    registry.register_escape_fn(no_escape);
+    let mut failed = Vec::new();
+
+    let entries = fs::read_dir(dir)
+        .with_context(|| format!("failed to read templates dir {}", dir.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("hbs") {
+            continue;
+        }
+        let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+
+        let (name, locale) = match stem.split_once('.') {
+            Some((name, locale)) => (name, normalize_locale(locale)),
+            None => (stem, default_locale.to_string()),
+        };
+        if let Err(err) = registry.register_template_file(&format!("{name}.{locale}"), &path) {
+            failed.push(TemplateFailure {
+                file: path.display().to_string(),
+                error: err.to_string(),
+            });
+        }
+    }
+    Ok((registry, failed))
+}
+
+/// `POST /admin/reload-templates`: picks up changes in `TEMPLATES_DIR`
+/// without a restart.
+pub async fn reload_templates(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<ReloadResponse>, ApiError> {
+    authenticate_admin(&state, &headers).await?;
+    let Some(templates) = &state.templates else {
+        return Err(error_response(StatusCode::NOT_FOUND, "templates are not configured").into());
+    };
+
+    let _reloading = reload_guard(&state)?;
+    match templates.reload() {
+        Ok((loaded, failed)) => {
+            for failure in &failed {
+                warn!(file = %failure.file, error = %failure.error, "template failed to compile");
+            }
+            info!(loaded, failed = failed.len(), "templates reloaded");
+            let message = match failed.len() {
+                0 => format!("{loaded} templates loaded"),
+                n => format!("{loaded} templates loaded, {n} failed to compile"),
+            };
+            Ok(Json(ReloadResponse {
+                ok: failed.is_empty(),
+                message,
+                loaded,
+                failed,
+            }))
+        }
+        Err(err) => {
+            warn!(error = %format!("{err:#}"), "template reload failed, keeping previous templates");
+            Err(error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "failed to read templates dir, previous templates kept",
+            )
+            .into())
+        }
+    }
+}
+
+/// The language ranges of an `Accept-Language` value, most preferred first.
+/// Ranges with `q=0`, the `*` wildcard and malformed weights are dropped.
+pub fn preferred_locales(accept_language: &str) -> Vec<String> {
+    let mut ranges: Vec<(u16, String)> = accept_language
+        .split(',')
+        .filter_map(|range| {
+            let mut params = range.split(';');
+            let locale = params.next()?.trim();
+            if locale.is_empty() || locale == "*" {
+                return None;
+            }
+            let weight = match params.find_map(|param| param.trim().strip_prefix("q=")) {
+                Some(q) => q
+                    .trim()
+                    .parse::<f32>()
+                    .ok()
+                    .filter(|q| (0.0..=1.0).contains(q))?,
+                None => 1.0,
+            };
+            // Milli-units, so the sort below is on integers.
+            let weight = (weight * 1000.0).round() as u16;
+            (weight > 0).then(|| (weight, locale.to_string()))
+        })
+        .collect();
+    // Stable, so equal weights keep the order they were listed in.
+    ranges.sort_by_key(|(weight, _)| std::cmp::Reverse(*weight));
+    ranges.into_iter().map(|(_, locale)| locale).collect()
+}
+
+fn normalize_locale(locale: &str) -> String {
+    locale.trim().replace('_', "-").to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_support::{self, call, notify, TempDir};
+
+    fn load(files: &[(&str, &str)], limits: RenderLimits) -> Templates {
+        let dir = TempDir::new("templates");
+        for (file, source) in files {
+            fs::write(dir.path().join(file), source).expect("template written");
+        }
+        Templates::load(dir.path(), "en", limits).expect("templates compile")
+    }
+
+    const LIMITS: RenderLimits = RenderLimits {
+        max_depth: 3,
+        timeout: None,
+    };
+
+    fn render(templates: &Templates, name: &str) -> Result<String, TemplateError> {
+        templates.render(name, &[], &json!({"name": "Ada"}))
+    }
+
+    #[test]
+    fn self_recursive_partial_exceeds_the_depth() {
+        let templates = load(&[("loop.hbs", "again {{> loop.en}}")], LIMITS);
+        let err = render(&templates, "loop").expect_err("recursion is refused");
+        assert_eq!(err.to_string(), "template render failed: depth exceeded");
+    }
+
+    #[test]
+    fn mutually_recursive_partials_exceed_the_depth() {
+        let templates = load(
+            &[
+                ("ping.hbs", "{{> pong.en}}"),
+                ("pong.hbs", "{{#if name}}{{> ping.en}}{{/if}}"),
+            ],
+            LIMITS,
+        );
+        let err = render(&templates, "ping").expect_err("recursion is refused");
+        assert_eq!(err.to_string(), "template render failed: depth exceeded");
+    }
+
+    #[test]
+    fn partials_within_the_depth_render() {
+        let templates = load(
+            &[
+                ("welcome.hbs", "{{> header.en}}, welcome {{> name.en}}"),
+                ("header.hbs", "Hi"),
+                ("name.hbs", "{{name}}"),
+            ],
+            LIMITS,
+        );
+        assert_eq!(
+            render(&templates, "welcome").expect("renders"),
+            "Hi, welcome Ada"
+        );
+
+        let nested = load(
+            &[
+                ("a.hbs", "{{> b.en}}"),
+                ("b.hbs", "{{> c.en}}"),
+                ("c.hbs", "{{> d.en}}"),
+                ("d.hbs", "deep"),
+            ],
+            LIMITS,
+        );
+        assert_eq!(render(&nested, "a").expect("three levels render"), "deep");
+        let too_deep = load(
+            &[
+                ("a.hbs", "{{> b.en}}"),
+                ("b.hbs", "{{> c.en}}"),
+                ("c.hbs", "{{> d.en}}"),
+                ("d.hbs", "{{> e.en}}"),
+                ("e.hbs", "deeper"),
+            ],
+            LIMITS,
+        );
+        assert!(render(&too_deep, "a").is_err());
+    }
+
+    #[test]
+    fn slow_render_times_out() {
+        let templates = load(
+            &[("list.hbs", "{{#each items}}{{this}} {{/each}}")],
+            RenderLimits {
+                max_depth: 3,
+                timeout: Some(Duration::ZERO),
+            },
+        );
+        let items: Vec<u32> = (0..10_000).collect();
+        let err = templates
+            .render("list", &[], &json!({ "items": items }))
+            .expect_err("deadline passes mid-render");
+        assert_eq!(err.to_string(), "template render failed: timeout exceeded");
+    }
+
+    #[tokio::test]
+    async fn recursive_template_answers_400() {
+        let dir = TempDir::new("templates");
+        fs::write(dir.path().join("loop.hbs"), "{{> loop.en}}").expect("template written");
+        let state = test_support::state(&[(
+            "TEMPLATES_DIR",
+            dir.path().to_str().expect("utf-8 temp dir"),
+        )])
+        .await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "a@example.com", "title": "t", "template": "loop", "data": {}}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["message"], "template render failed: depth exceeded");
+    }
+
+    fn localized() -> Templates {
+        load(
+            &[
+                ("welcome.hbs", "Welcome {{name}}"),
+                ("welcome.de.hbs", "Willkommen {{name}}"),
+                ("welcome.pt_BR.hbs", "Bem-vindo {{name}}"),
+                ("farewell.de.hbs", "Tschüss {{name}}"),
+            ],
+            LIMITS,
+        )
+    }
+
+    fn render_in(
+        templates: &Templates,
+        name: &str,
+        locales: &[&str],
+    ) -> Result<String, TemplateError> {
+        let locales: Vec<String> = locales.iter().map(|locale| locale.to_string()).collect();
+        templates.render(name, &locales, &json!({"name": "Ada"}))
+    }
+
+    #[test]
+    fn requested_locale_is_selected() {
+        let templates = localized();
+        assert_eq!(
+            render_in(&templates, "welcome", &["de"]).unwrap(),
+            "Willkommen Ada"
+        );
+        assert_eq!(
+            render_in(&templates, "welcome", &["pt-br"]).unwrap(),
+            "Bem-vindo Ada"
+        );
+        assert_eq!(
+            render_in(&templates, "welcome", &["fr", "DE"]).unwrap(),
+            "Willkommen Ada"
+        );
+    }
+
+    #[test]
+    fn missing_locale_falls_back() {
+        let templates = localized();
+        // A region falls back to its language, then to the default locale.
+        assert_eq!(
+            render_in(&templates, "welcome", &["de-AT"]).unwrap(),
+            "Willkommen Ada"
+        );
+        assert_eq!(
+            render_in(&templates, "welcome", &["fr"]).unwrap(),
+            "Welcome Ada"
+        );
+        assert_eq!(
+            render_in(&templates, "welcome", &[]).unwrap(),
+            "Welcome Ada"
+        );
+    }
+
+    #[test]
+    fn not_found_only_without_the_requested_or_default_locale() {
+        let templates = localized();
+        assert_eq!(
+            render_in(&templates, "farewell", &["de"]).unwrap(),
+            "Tschüss Ada"
+        );
+        let err = render_in(&templates, "farewell", &["fr"]).expect_err("no fr or en farewell");
+        assert_eq!(err.to_string(), "template not found: farewell");
+    }
+
+    #[tokio::test]
+    async fn unknown_template_locale_answers_404() {
+        let dir = TempDir::new("templates");
+        fs::write(dir.path().join("farewell.de.hbs"), "Tschüss").expect("template written");
+        let state = test_support::state(&[(
+            "TEMPLATES_DIR",
+            dir.path().to_str().expect("utf-8 temp dir"),
+        )])
+        .await;
+        let app = test_support::app(&state);
+
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "a@example.com", "title": "t", "template": "farewell", "locale": "fr", "data": {}}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["message"], "template not found: farewell");
+    }
+
+    async fn reload(app: &axum::Router) -> (StatusCode, Value) {
+        call(
+            app,
+            axum::http::Method::POST,
+            "/admin/reload-templates",
+            None,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn reloading_picks_up_a_new_template() {
+        let dir = TempDir::new("templates");
+        fs::write(dir.path().join("welcome.hbs"), "Welcome {{name}}").expect("template written");
+        let state = test_support::state(&[(
+            "TEMPLATES_DIR",
+            dir.path().to_str().expect("utf-8 temp dir"),
+        )])
+        .await;
+        let app = test_support::app(&state);
+        let request = json!({"service": "smtp", "to": "a@example.com", "title": "t", "template": "farewell", "data": {"name": "Ada"}});
+
+        let (status, _) = notify(&app, request.clone()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        fs::write(dir.path().join("farewell.hbs"), "Bye {{name}}").expect("template written");
+        let (status, body) = reload(&app).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["message"], "2 templates loaded");
+        assert_eq!(body["loaded"], 2);
+        assert!(body.get("failed").is_none(), "{body}");
+
+        let (status, body) = notify(&app, request).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let sent = test_support::sent(&app).await;
+        assert!(
+            sent[0]["raw"].as_str().unwrap().contains("Bye Ada"),
+            "{}",
+            sent[0]["raw"]
+        );
+    }
+
+    #[tokio::test]
+    async fn templates_that_fail_to_compile_are_reported_and_skipped() {
+        let dir = TempDir::new("templates");
+        fs::write(dir.path().join("welcome.hbs"), "Welcome {{name}}").expect("template written");
+        let state = test_support::state(&[(
+            "TEMPLATES_DIR",
+            dir.path().to_str().expect("utf-8 temp dir"),
+        )])
+        .await;
+        let app = test_support::app(&state);
+
+        fs::write(dir.path().join("broken.hbs"), "{{#if name}}unclosed").expect("template written");
+        let (status, body) = reload(&app).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["ok"], false);
+        assert_eq!(body["message"], "1 templates loaded, 1 failed to compile");
+        assert_eq!(
+            body["failed"][0]["file"],
+            dir.path().join("broken.hbs").display().to_string()
+        );
+        assert_eq!(state.templates.as_ref().map(Templates::count), Some(1));
+    }
+
+    #[tokio::test]
+    async fn an_unreadable_dir_keeps_the_previous_templates() {
+        let dir = TempDir::new("templates");
+        let templates = dir.path().join("templates");
+        fs::create_dir(&templates).expect("dir created");
+        fs::write(templates.join("welcome.hbs"), "Welcome {{name}}").expect("template written");
+        let state =
+            test_support::state(&[("TEMPLATES_DIR", templates.to_str().expect("utf-8 temp dir"))])
+                .await;
+        let app = test_support::app(&state);
+
+        fs::remove_dir_all(&templates).expect("dir removed");
+        let (status, body) = reload(&app).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR, "{body}");
+        assert_eq!(
+            body["message"],
+            "failed to read templates dir, previous templates kept"
+        );
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "a@example.com", "title": "t", "template": "welcome", "data": {"name": "Ada"}}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+    }
+
+    #[tokio::test]
+    async fn reloading_needs_an_api_key_and_a_templates_dir() {
+        let state = test_support::state(&[]).await;
+        let app = test_support::app(&state);
+        let (status, body) = reload(&app).await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "{body}");
+        assert_eq!(body["message"], "templates are not configured");
+
+        let response = tower::ServiceExt::oneshot(
+            app,
+            axum::http::Request::post("/admin/reload-templates")
+                .body(axum::body::Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+        let status = response.status();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn accept_language_ranges_are_ordered_by_weight() {
+        assert_eq!(
+            preferred_locales("fr;q=0.5, de-AT,de;q=0.9,en;q=0.5"),
+            ["de-AT", "de", "fr", "en"]
+        );
+        assert_eq!(preferred_locales("*, it;q=0, es;q=2, nl;q=x, pt"), ["pt"]);
+        assert!(preferred_locales("").is_empty());
+    }
+
+    async fn welcome_app(detect: &str) -> axum::Router {
+        let dir = TempDir::new("templates");
+        fs::write(dir.path().join("welcome.hbs"), "Welcome {{name}}").expect("template written");
+        fs::write(dir.path().join("welcome.de.hbs"), "Willkommen {{name}}")
+            .expect("template written");
+        let state = test_support::state(&[
+            (
+                "TEMPLATES_DIR",
+                dir.path().to_str().expect("utf-8 temp dir"),
+            ),
+            ("DETECT_LOCALE", detect),
+        ])
+        .await;
+        test_support::app(&state)
+    }
+
+    async fn welcome_body(app: &axum::Router, hints: Value) -> String {
+        let mut request = json!({"service": "smtp", "to": "a@example.com", "title": "t",
+            "template": "welcome", "data": {"name": "Ada"}});
+        request
+            .as_object_mut()
+            .expect("an object")
+            .extend(hints.as_object().expect("an object").clone());
+        let (status, body) = notify(app, request).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let sent = test_support::sent(app).await;
+        let raw = sent.last().expect("a message")["raw"]
+            .as_str()
+            .expect("raw message");
+        raw.rsplit("\r\n\r\n")
+            .next()
+            .expect("a body")
+            .trim_end()
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn accept_language_picks_the_template_locale() {
+        let app = welcome_app("true").await;
+        assert_eq!(
+            welcome_body(
+                &app,
+                json!({"accept_language": "fr, de-AT;q=0.8, en;q=0.5"})
+            )
+            .await,
+            "Willkommen Ada"
+        );
+        assert_eq!(
+            welcome_body(&app, json!({"accept_language": "fr, it;q=0.5"})).await,
+            "Welcome Ada"
+        );
+        // An explicit locale wins over the hint.
+        assert_eq!(
+            welcome_body(&app, json!({"locale": "en", "accept_language": "de"})).await,
+            "Welcome Ada"
+        );
+    }
+
+    #[tokio::test]
+    async fn accept_language_without_detect_locale_answers_400() {
+        let app = welcome_app("false").await;
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "a@example.com", "title": "t", "template": "welcome",
+                "accept_language": "de", "data": {}}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["message"], "locale detection is not enabled");
+        assert!(test_support::sent(&app).await.is_empty());
+    }
+}
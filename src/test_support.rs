@@ -0,0 +1,565 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    time::Duration,
+};
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::State,
+    http::{
+        header::{CONTENT_TYPE, RETRY_AFTER},
+        Method, Request, StatusCode,
+    },
+    response::{IntoResponse, Response},
+    routing::post,
+    Json, Router,
+};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use serde_json::Value;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tower::ServiceExt;
+
+use crate::{build_state, router, AppState, Config};
+
+/// The api key every test state accepts.
+pub const API_KEY: &str = "test-key";
+
+/// Env vars are process-wide, so tests building a config take turns.
+static ENV: Mutex<()> = Mutex::new(());
+
+/// A fresh, empty directory under the system temp dir, removed on drop.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(label: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!(
+            "notification_server-{label}-{:016x}",
+            rand::random::<u64>()
+        ));
+        fs::create_dir_all(&dir).expect("temp dir is writable");
+        Self(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Log output written through [`Capture::subscriber`], for asserting on.
+#[derive(Clone, Default)]
+pub struct Capture(Arc<Mutex<Vec<u8>>>);
+
+impl std::io::Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Capture {
+    pub fn subscriber(&self) -> impl tracing::Subscriber {
+        tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer(self.writer())
+            .finish()
+    }
+
+    /// A `MakeWriter` for a layer of its own.
+    pub fn writer(&self) -> impl Fn() -> Capture + Clone {
+        let capture = self.clone();
+        move || capture.clone()
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        String::from_utf8_lossy(&self.0.lock().unwrap_or_else(PoisonError::into_inner))
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+}
+
+/// A `PGP_KEYS_DIR` holding a freshly generated, armored certificate for
+/// each of `emails`.
+pub fn pgp_keys(emails: &[&str]) -> TempDir {
+    use sequoia_openpgp::{cert::CertBuilder, serialize::SerializeInto};
+
+    let dir = TempDir::new("pgp-keys");
+    for (n, email) in emails.iter().enumerate() {
+        let (cert, _) = CertBuilder::general_purpose(Some(format!("Test <{email}>")))
+            .generate()
+            .expect("key generation succeeds");
+        let armored = cert.armored().to_vec().expect("cert serializes");
+        fs::write(dir.path().join(format!("key-{n}.asc")), armored).expect("key dir is writable");
+    }
+    dir
+}
+
+/// The config `vars` give on top of the minimum for the memory backend:
+/// `API_KEY` and `SMTP_FROM`. A var set to `""` is left unset.
+pub fn config(vars: &[(&str, &str)]) -> anyhow::Result<Config> {
+    let base = [
+        ("BACKEND", "memory"),
+        ("API_KEY", API_KEY),
+        ("SMTP_FROM", "Notifications <notify@example.com>"),
+    ];
+    let _env = ENV.lock().unwrap_or_else(PoisonError::into_inner);
+    for (name, value) in base.iter().chain(vars) {
+        match *value {
+            "" => std::env::remove_var(name),
+            value => std::env::set_var(name, value),
+        }
+    }
+    let cfg = Config::from_env();
+    for (name, _) in base.iter().chain(vars) {
+        std::env::remove_var(name);
+    }
+    cfg
+}
+
+/// A state built from [`config`]; nothing runs in the background.
+pub async fn state(vars: &[(&str, &str)]) -> Arc<AppState> {
+    let cfg = config(vars).expect("test config loads");
+    let (state, _) = build_state(cfg).await.expect("test state builds");
+    state
+}
+
+/// Every route over `state`, as served.
+pub fn app(state: &Arc<AppState>) -> Router {
+    router(state, state.max_upload_bytes)
+}
+
+/// Sends one request with the test api key and returns the status and the
+/// JSON body (`Null` when the body is empty or not JSON).
+pub async fn call(
+    app: &Router,
+    method: Method,
+    uri: &str,
+    body: Option<Value>,
+) -> (StatusCode, Value) {
+    let (status, text) = call_text(app, method, uri, body).await;
+    (status, serde_json::from_str(&text).unwrap_or(Value::Null))
+}
+
+/// [`call`], returning the body as text.
+pub async fn call_text(
+    app: &Router,
+    method: Method,
+    uri: &str,
+    body: Option<Value>,
+) -> (StatusCode, String) {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("authorization", format!("Bearer {API_KEY}"));
+    let request = match body {
+        Some(body) => request
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string())),
+        None => request.body(Body::empty()),
+    }
+    .expect("valid request");
+    let response = app.clone().oneshot(request).await.expect("infallible");
+    let status = response.status();
+    let bytes = to_bytes(response.into_body(), usize::MAX)
+        .await
+        .expect("body collects");
+    (status, String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// `POST /notify` with `body`.
+pub async fn notify(app: &Router, body: Value) -> (StatusCode, Value) {
+    call(app, Method::POST, "/notify", Some(body)).await
+}
+
+/// Messages the memory backend captured, as `GET /test/sent` lists them.
+pub async fn sent(app: &Router) -> Vec<Value> {
+    match call(app, Method::GET, "/test/sent", None).await {
+        (StatusCode::OK, Value::Array(sent)) => sent,
+        (status, body) => panic!("GET /test/sent answered {status}: {body}"),
+    }
+}
+
+/// Decodes a header value made of RFC 2047 `utf-8` encoded-words, folded
+/// or not, panicking on anything else: plain text, words over 75 characters
+/// or words that are not UTF-8 on their own.
+pub fn decode_words(value: &str) -> String {
+    let mut decoded = String::new();
+    for word in value.split_whitespace() {
+        assert!(word.len() <= 75, "encoded-word over 75 characters: {word}");
+        let inner = word
+            .strip_prefix("=?utf-8?")
+            .or_else(|| word.strip_prefix("=?UTF-8?"))
+            .and_then(|word| word.strip_suffix("?="))
+            .unwrap_or_else(|| panic!("not an encoded-word: {word}"));
+        let bytes = match inner.split_at(2) {
+            ("b?" | "B?", text) => BASE64_STANDARD.decode(text).expect("valid base64"),
+            ("q?" | "Q?", text) => {
+                let mut bytes = Vec::new();
+                let mut rest = text.as_bytes();
+                while let Some((&byte, tail)) = rest.split_first() {
+                    match byte {
+                        b'_' => bytes.push(b' '),
+                        b'=' => {
+                            let hex = std::str::from_utf8(&tail[..2]).expect("ascii");
+                            bytes.push(u8::from_str_radix(hex, 16).expect("hex escape"));
+                            rest = &tail[2..];
+                            continue;
+                        }
+                        byte if byte.is_ascii_graphic() && byte != b'?' => bytes.push(byte),
+                        byte => panic!("unencoded byte {byte:#04x} in {word}"),
+                    }
+                    rest = tail;
+                }
+                bytes
+            }
+            _ => panic!("unknown encoding in {word}"),
+        };
+        decoded.push_str(&String::from_utf8(bytes).expect("each word is UTF-8 on its own"));
+    }
+    decoded
+}
+
+/// The unfolded value of the `name` header in a raw message.
+pub fn header_value(raw: &str, name: &str) -> Option<String> {
+    let (headers, _) = raw.split_once("\r\n\r\n").unwrap_or((raw, ""));
+    let prefix = format!("{name}: ");
+    let mut lines = headers.split("\r\n");
+    let first = lines.find_map(|line| line.strip_prefix(&prefix))?;
+    let mut value = first.to_string();
+    for line in lines.take_while(|line| line.starts_with([' ', '\t'])) {
+        value.push_str(line);
+    }
+    Some(value)
+}
+
+/// A Slack Web API on localhost answering every `chat.postMessage` with the
+/// scripted body, unless rate limited, recording the posted messages.
+pub struct MockSlack {
+    url: String,
+    inner: Arc<MockSlackState>,
+}
+
+struct MockSlackState {
+    reply: Value,
+    /// `Retry-After` values to answer the next posts with, with a 429.
+    rate_limits: Mutex<VecDeque<String>>,
+    posts: Mutex<Vec<Value>>,
+}
+
+impl MockSlack {
+    pub async fn start(reply: Value) -> Self {
+        async fn post_message(
+            State(state): State<Arc<MockSlackState>>,
+            Json(body): Json<Value>,
+        ) -> Response {
+            state
+                .posts
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(body);
+            let rate_limit = state
+                .rate_limits
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .pop_front();
+            match rate_limit {
+                Some(retry_after) => {
+                    (StatusCode::TOO_MANY_REQUESTS, [(RETRY_AFTER, retry_after)]).into_response()
+                }
+                None => Json(state.reply.clone()).into_response(),
+            }
+        }
+
+        let inner = Arc::new(MockSlackState {
+            reply,
+            rate_limits: Mutex::default(),
+            posts: Mutex::default(),
+        });
+        let app = Router::new()
+            .route("/chat.postMessage", post(post_message))
+            .with_state(inner.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("localhost port free");
+        let url = format!("http://{}", listener.local_addr().expect("bound"));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        Self { url, inner }
+    }
+
+    /// The vars enabling the `slack` service against this server.
+    pub fn vars(&self) -> [(&'static str, &str); 2] {
+        [
+            ("SLACK_BOT_TOKEN", "xoxb-test"),
+            ("SLACK_API_URL", &self.url),
+        ]
+    }
+
+    /// Answers the next post with a 429 carrying `retry_after`.
+    pub fn rate_limit(&self, retry_after: &str) {
+        self.inner
+            .rate_limits
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push_back(retry_after.to_string());
+    }
+
+    /// Message bodies posted so far.
+    pub fn posts(&self) -> Vec<Value> {
+        self.inner
+            .posts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+/// A plaintext SMTP server on localhost that accepts every command unless a
+/// reply is scripted for it, and records the messages it is given.
+pub struct MockSmtp {
+    port: u16,
+    inner: Arc<MockSmtpState>,
+}
+
+#[derive(Default)]
+struct MockSmtpState {
+    connections: AtomicUsize,
+    /// Where each accepted connection came from.
+    peers: Mutex<Vec<SocketAddr>>,
+    /// Replies to use, in order, for the next commands with each verb.
+    script: Mutex<HashMap<String, VecDeque<String>>>,
+    /// How long to wait before answering each verb.
+    delays: Mutex<HashMap<String, Duration>>,
+    /// ESMTP keywords listed in the EHLO reply.
+    extensions: Mutex<Vec<String>>,
+    commands: Mutex<Vec<String>>,
+    messages: Mutex<Vec<String>>,
+}
+
+impl MockSmtp {
+    pub async fn start() -> Self {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("localhost port free");
+        let port = listener.local_addr().expect("bound").port();
+        let inner = Arc::new(MockSmtpState::default());
+        let state = inner.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, peer)) = listener.accept().await {
+                state.connections.fetch_add(1, Ordering::SeqCst);
+                state
+                    .peers
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .push(peer);
+                tokio::spawn(state.clone().session(stream));
+            }
+        });
+        Self { port, inner }
+    }
+
+    /// The port, as `SMTP_PORT` takes it.
+    pub fn port(&self) -> String {
+        self.port.to_string()
+    }
+
+    /// A state sending through this server, from `vars` on top of it.
+    pub async fn state(&self, vars: &[(&str, &str)]) -> Arc<AppState> {
+        let port = self.port();
+        let server = [
+            ("BACKEND", "smtp"),
+            ("SMTP_HOST", "127.0.0.1"),
+            ("SMTP_PORT", port.as_str()),
+            ("SMTP_TLS", "false"),
+        ];
+        let vars: Vec<_> = server.iter().chain(vars).copied().collect();
+        state(&vars).await
+    }
+
+    /// Answers the next `verb` command (`RCPT`, `DATA`, ...) with `reply`
+    /// instead of accepting it. For `DATA` the reply ends the message.
+    pub fn reply(&self, verb: &str, reply: &str) {
+        let mut script = self
+            .inner
+            .script
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        script
+            .entry(verb.to_string())
+            .or_default()
+            .push_back(reply.to_string());
+    }
+
+    /// Waits `delay` before answering every `verb` command, as a slow
+    /// server would.
+    pub fn delay(&self, verb: &str, delay: Duration) {
+        self.inner
+            .delays
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(verb.to_string(), delay);
+    }
+
+    /// Lists `keyword` (`CHUNKING`, `SMTPUTF8`, ...) in every EHLO reply.
+    pub fn offer(&self, keyword: &str) {
+        self.inner
+            .extensions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(keyword.to_string());
+    }
+
+    /// Connections accepted so far.
+    pub fn connections(&self) -> usize {
+        self.inner.connections.load(Ordering::SeqCst)
+    }
+
+    /// Source addresses of the connections accepted so far.
+    pub fn peers(&self) -> Vec<SocketAddr> {
+        self.inner
+            .peers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Commands received so far, in order, without their line endings.
+    pub fn commands(&self) -> Vec<String> {
+        self.inner
+            .commands
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Messages accepted so far, as sent after `DATA` or in `BDAT` chunks.
+    pub fn messages(&self) -> Vec<String> {
+        self.inner
+            .messages
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+impl MockSmtpState {
+    fn scripted(&self, verb: &str) -> Option<String> {
+        let mut script = self.script.lock().unwrap_or_else(PoisonError::into_inner);
+        script.get_mut(verb).and_then(VecDeque::pop_front)
+    }
+
+    async fn session(self: Arc<Self>, stream: tokio::net::TcpStream) -> std::io::Result<()> {
+        let (read, mut write) = stream.into_split();
+        let mut lines = BufReader::new(read).lines();
+        write.write_all(b"220 mock ESMTP\r\n").await?;
+        // The BDAT chunks received so far of the message being sent.
+        let mut chunks = Vec::new();
+        while let Some(line) = lines.next_line().await? {
+            self.commands
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(line.clone());
+            let verb = line
+                .split(' ')
+                .next()
+                .unwrap_or_default()
+                .to_ascii_uppercase();
+            let reply = match verb.as_str() {
+                "DATA" => {
+                    write.write_all(b"354 go ahead\r\n").await?;
+                    let mut message = String::new();
+                    while let Some(line) = lines.next_line().await? {
+                        if line == "." {
+                            break;
+                        }
+                        message.push_str(line.strip_prefix('.').unwrap_or(&line));
+                        message.push_str("\r\n");
+                    }
+                    match self.scripted("DATA") {
+                        Some(reply) => reply,
+                        None => {
+                            self.messages
+                                .lock()
+                                .unwrap_or_else(PoisonError::into_inner)
+                                .push(message);
+                            "250 2.0.0 queued".to_string()
+                        }
+                    }
+                }
+                "BDAT" => {
+                    let mut args = line.split(' ').skip(1);
+                    let size: usize = args
+                        .next()
+                        .and_then(|size| size.parse().ok())
+                        .unwrap_or_default();
+                    let last = args
+                        .next()
+                        .is_some_and(|arg| arg.eq_ignore_ascii_case("LAST"));
+                    let mut chunk = vec![0; size];
+                    lines.get_mut().read_exact(&mut chunk).await?;
+                    chunks.extend_from_slice(&chunk);
+                    match self.scripted("BDAT") {
+                        Some(reply) => reply,
+                        None if last => {
+                            let message = String::from_utf8_lossy(&chunks).into_owned();
+                            chunks.clear();
+                            self.messages
+                                .lock()
+                                .unwrap_or_else(PoisonError::into_inner)
+                                .push(message);
+                            "250 2.0.0 queued".to_string()
+                        }
+                        None => "250 2.0.0 chunk received".to_string(),
+                    }
+                }
+                "QUIT" => {
+                    write.write_all(b"221 bye\r\n").await?;
+                    return Ok(());
+                }
+                "EHLO" => self.scripted("EHLO").unwrap_or_else(|| {
+                    let extensions = self
+                        .extensions
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner);
+                    let mut lines = vec!["250-mock".to_string()];
+                    lines.extend(extensions.iter().map(|keyword| format!("250-{keyword}")));
+                    // The last line has a space where the others have a dash.
+                    let last = lines.len() - 1;
+                    lines[last].replace_range(3..4, " ");
+                    lines.join("\r\n")
+                }),
+                verb => self.scripted(verb).unwrap_or_else(|| "250 ok".to_string()),
+            };
+            let delay = self
+                .delays
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .get(&verb)
+                .copied();
+            if let Some(delay) = delay {
+                tokio::time::sleep(delay).await;
+            }
+            write.write_all(format!("{reply}\r\n").as_bytes()).await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,170 @@
+use std::{cell::RefCell, future::Future, time::Duration};
+
+use serde::Serialize;
+
+tokio::task_local! {
+    /// Phase timings of the `?timing=true` request running on this task.
+    static TIMINGS: RefCell<Timings>;
+}
+
+/// Per-phase SMTP timings in milliseconds, summed over every session the
+/// send used. Phases a transport cannot observe are left out: only sends
+/// that dial their own connection (`SMTP_LOCAL_BIND_ADDR`,
+/// `SMTP_USE_CHUNKING`, `SMTP_ACCEPT_PARTIAL_RCPT`) see every phase, so
+/// pooled sends report `total_ms` alone. `connect_ms` runs to the server's
+/// EHLO reply, so with implicit TLS it holds the handshake and `tls_ms` is
+/// left out.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Timings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dns_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connect_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data_ms: Option<u64>,
+    /// Wall time spent in the transport, summed over retry attempts.
+    pub total_ms: u64,
+}
+
+/// A timed phase.
+#[derive(Debug, Clone, Copy)]
+pub enum Phase {
+    Dns,
+    Connect,
+    Tls,
+    Auth,
+    Data,
+    Total,
+}
+
+/// Runs `fut` with timings collected for the sends it makes.
+pub async fn scope<F: Future>(fut: F) -> F::Output {
+    TIMINGS.scope(RefCell::new(Timings::default()), fut).await
+}
+
+/// Timings so far; `None` outside [`scope`].
+pub fn current() -> Option<Timings> {
+    TIMINGS.try_with(|timings| timings.borrow().clone()).ok()
+}
+
+/// Adds `elapsed` to `phase`; a no-op outside [`scope`].
+pub fn record(phase: Phase, elapsed: Duration) {
+    let ms = elapsed.as_millis() as u64;
+    let _ = TIMINGS.try_with(|timings| {
+        let mut timings = timings.borrow_mut();
+        let slot = match phase {
+            Phase::Dns => &mut timings.dns_ms,
+            Phase::Connect => &mut timings.connect_ms,
+            Phase::Tls => &mut timings.tls_ms,
+            Phase::Auth => &mut timings.auth_ms,
+            Phase::Data => &mut timings.data_ms,
+            Phase::Total => {
+                timings.total_ms += ms;
+                return;
+            }
+        };
+        *slot = Some(slot.unwrap_or_default() + ms);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        http::{header::CONTENT_TYPE, Request, StatusCode},
+    };
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::test_support;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    async fn post(app: axum::Router, uri: &str) -> (StatusCode, Value) {
+        let body = json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "b"});
+        let request = Request::post(uri)
+            .header("authorization", format!("Bearer {}", test_support::API_KEY))
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let status = response.status();
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap())
+    }
+
+    #[tokio::test]
+    async fn phases_add_up_within_a_scope_only() {
+        record(Phase::Data, 5 * MS);
+        assert!(current().is_none());
+
+        let timings = scope(async {
+            record(Phase::Connect, 3 * MS);
+            record(Phase::Data, 5 * MS);
+            record(Phase::Data, 7 * MS);
+            record(Phase::Total, 20 * MS);
+            record(Phase::Total, 2 * MS);
+            current()
+        })
+        .await
+        .expect("inside a scope");
+        assert_eq!(timings.connect_ms, Some(3));
+        assert_eq!(timings.data_ms, Some(12));
+        assert_eq!(timings.dns_ms, None);
+        assert_eq!(timings.total_ms, 22);
+    }
+
+    #[tokio::test]
+    async fn timing_true_reports_each_phase_of_a_dialed_send() {
+        let smtp = test_support::MockSmtp::start().await;
+        smtp.reply("EHLO", "250-mock\r\n250 AUTH PLAIN LOGIN");
+        smtp.reply("AUTH", "235 2.7.0 authenticated");
+        smtp.delay("EHLO", 60 * MS);
+        smtp.delay("AUTH", 40 * MS);
+        smtp.delay("DATA", 80 * MS);
+        let state = smtp
+            .state(&[
+                ("SMTP_LOCAL_BIND_ADDR", "127.0.0.1"),
+                ("SMTP_USERNAME", "relay"),
+                ("SMTP_PASSWORD", "secret"),
+            ])
+            .await;
+
+        let (status, body) = post(test_support::app(&state), "/notify?timing=true").await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let timings = &body["timings"];
+        let phase = |name: &str| {
+            timings[name]
+                .as_u64()
+                .unwrap_or_else(|| panic!("{name} missing: {timings}"))
+        };
+        assert!(phase("connect_ms") >= 60, "{timings}");
+        assert!(phase("auth_ms") >= 40, "{timings}");
+        assert!(phase("data_ms") >= 80, "{timings}");
+        assert!(timings.get("tls_ms").is_none(), "{timings}");
+
+        let phases = phase("dns_ms") + phase("connect_ms") + phase("auth_ms") + phase("data_ms");
+        let total = phase("total_ms");
+        assert!(
+            phases <= total && total <= phases + 50,
+            "phases {phases}ms, total {total}ms"
+        );
+    }
+
+    #[tokio::test]
+    async fn timings_are_left_out_unless_asked_for() {
+        let app = test_support::app(&test_support::state(&[]).await);
+        let (status, body) = post(app.clone(), "/notify").await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert!(body.get("timings").is_none(), "{body}");
+
+        let (_, body) = post(app, "/notify?timing=true").await;
+        assert!(body["timings"]["total_ms"].is_u64(), "{body}");
+        assert!(body["timings"].get("connect_ms").is_none(), "{body}");
+    }
+}
@@ -0,0 +1,356 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{
+        header::{CACHE_CONTROL, CONTENT_TYPE, LOCATION},
+        StatusCode,
+    },
+    response::{IntoResponse, Response},
+};
+use hmac::{Hmac, KeyInit, Mac};
+use lol_html::{element, rewrite_str, RewriteStrSettings};
+use serde::Deserialize;
+use sha2::Sha256;
+use tracing::{info, warn};
+
+use crate::{error_response, events::AuditEvent, AppState};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// 1x1 transparent GIF served for open tracking.
+const PIXEL_GIF: &[u8] = &[
+    0x47, 0x49, 0x46, 0x38, 0x39, 0x61, 0x01, 0x00, 0x01, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0xff, 0xff, 0xff, 0x21, 0xf9, 0x04, 0x01, 0x00, 0x00, 0x00, 0x00, 0x2c, 0x00, 0x00, 0x00, 0x00,
+    0x01, 0x00, 0x01, 0x00, 0x00, 0x02, 0x02, 0x44, 0x01, 0x00, 0x3b,
+];
+
+/// Open and click tracking for HTML bodies, enabled by `TRACKING_ENABLED`.
+#[derive(Clone)]
+pub struct Tracking {
+    /// Public URL of this service, without a trailing slash.
+    base_url: String,
+    /// Signs click redirects so `/click` cannot be used as an open redirect.
+    secret: Vec<u8>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ClickQuery {
+    url: String,
+    sig: String,
+}
+
+impl Tracking {
+    pub fn new(base_url: &str, secret: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            secret: secret.as_bytes().to_vec(),
+        }
+    }
+
+    /// Fresh id tying the tracking URLs of one message together.
+    pub fn new_message_id() -> String {
+        format!("{:032x}", rand::random::<u128>())
+    }
+
+    /// Adds the open pixel just before `</body>`, or at the end when the
+    /// HTML has no body tag.
+    pub fn inject_open_pixel(&self, html: &str, message_id: &str) -> String {
+        let pixel = format!(
+            r#"<img src="{}/open/{message_id}" width="1" height="1" alt="" style="display:none">"#,
+            self.base_url
+        );
+        let mut html = html.to_string();
+        match html.to_ascii_lowercase().rfind("</body>") {
+            Some(index) => html.insert_str(index, &pixel),
+            None => html.push_str(&pixel),
+        }
+        html
+    }
+
+    /// Points every http(s) link at the `/click` redirector. Other schemes
+    /// (`mailto:`, `tel:`, fragments, ...) are left alone.
+    pub fn rewrite_links(&self, html: &str, message_id: &str) -> String {
+        let rewritten = rewrite_str(
+            html,
+            RewriteStrSettings::new().append_element_content_handler(element!("a[href]", |el| {
+                let Some(href) = el.get_attribute("href") else {
+                    return Ok(());
+                };
+                let url = href.trim().replace("&amp;", "&");
+                if is_trackable(&url) {
+                    let tracked = self.click_url(message_id, &url).replace('&', "&amp;");
+                    el.set_attribute("href", &tracked)?;
+                }
+                Ok(())
+            })),
+        );
+        // The rewriter only fails on markup it cannot stream; sending the
+        // original links beats failing the message.
+        rewritten.unwrap_or_else(|err| {
+            warn!(error = %err, "failed to rewrite links for click tracking");
+            html.to_string()
+        })
+    }
+
+    fn click_url(&self, message_id: &str, url: &str) -> String {
+        let query = form_urlencoded::Serializer::new(String::new())
+            .append_pair("url", url)
+            .append_pair("sig", &self.sign(message_id, url))
+            .finish();
+        format!("{}/click/{message_id}?{query}", self.base_url)
+    }
+
+    fn mac(&self, message_id: &str, url: &str) -> HmacSha256 {
+        let mut mac =
+            HmacSha256::new_from_slice(&self.secret).expect("hmac accepts any key length");
+        mac.update(message_id.as_bytes());
+        mac.update(b"\n");
+        mac.update(url.as_bytes());
+        mac
+    }
+
+    fn sign(&self, message_id: &str, url: &str) -> String {
+        self.mac(message_id, url)
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+
+    fn verify(&self, message_id: &str, url: &str, sig: &str) -> bool {
+        let Some(sig) = decode_hex(sig) else {
+            return false;
+        };
+        // `verify_slice` compares in constant time.
+        self.mac(message_id, url).verify_slice(&sig).is_ok()
+    }
+}
+
+/// `GET /open/{id}`: records an open and returns the tracking pixel. Always
+/// answers with the image so mail clients never show a broken one.
+pub async fn open(State(state): State<Arc<AppState>>, Path(id): Path<String>) -> Response {
+    if is_message_id(&id) {
+        info!(message_id = %id, "message opened");
+        state
+            .events
+            .publish(AuditEvent::new("opened", "smtp", String::new(), id));
+    }
+
+    (
+        StatusCode::OK,
+        [
+            (CONTENT_TYPE, "image/gif"),
+            (CACHE_CONTROL, "no-store, max-age=0"),
+        ],
+        PIXEL_GIF,
+    )
+        .into_response()
+}
+
+fn is_message_id(id: &str) -> bool {
+    id.len() == 32 && id.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+/// `GET /click/{id}?url=...&sig=...`: records a click and redirects to the
+/// original link once the signature checks out.
+pub async fn click(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Query(query): Query<ClickQuery>,
+) -> Response {
+    let Some(tracking) = &state.tracking else {
+        return error_response(StatusCode::NOT_FOUND, "tracking disabled").into_response();
+    };
+    if !is_message_id(&id)
+        || !is_trackable(&query.url)
+        || !tracking.verify(&id, &query.url, &query.sig)
+    {
+        return error_response(StatusCode::FORBIDDEN, "invalid tracking link").into_response();
+    }
+
+    info!(message_id = %id, url = %query.url, "link clicked");
+    state.events.publish(AuditEvent::new(
+        "clicked",
+        "smtp",
+        String::new(),
+        query.url.clone(),
+    ));
+    (StatusCode::FOUND, [(LOCATION, query.url)]).into_response()
+}
+
+fn is_trackable(url: &str) -> bool {
+    let lower = url.to_ascii_lowercase();
+    lower.starts_with("http://") || lower.starts_with("https://")
+}
+
+pub fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(hex.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Method;
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_support::{self, call_text, notify};
+
+    const ID: &str = "0123456789abcdef0123456789abcdef";
+
+    fn tracking() -> Tracking {
+        Tracking::new("https://t.example.com/", "secret")
+    }
+
+    /// Undoes the quoted-printable soft breaks and `=` escapes of a raw part.
+    fn unfold_qp(raw: &str) -> String {
+        raw.replace("=\r\n", "").replace("=3D", "=")
+    }
+
+    #[test]
+    fn open_pixel_goes_before_the_body_end() {
+        let html = tracking().inject_open_pixel("<html><BODY><p>Hi</p></BODY></html>", ID);
+        assert_eq!(
+            html,
+            format!(
+                r#"<html><BODY><p>Hi</p><img src="https://t.example.com/open/{ID}" width="1" height="1" alt="" style="display:none"></BODY></html>"#
+            )
+        );
+        let html = tracking().inject_open_pixel("<p>Hi</p>", ID);
+        assert!(html.starts_with("<p>Hi</p><img src="), "{html}");
+    }
+
+    async fn tracked_state() -> Arc<AppState> {
+        test_support::state(&[
+            ("TRACKING_ENABLED", "true"),
+            ("TRACKING_BASE_URL", "https://t.example.com"),
+            ("TRACKING_SECRET", "secret"),
+        ])
+        .await
+    }
+
+    #[tokio::test]
+    async fn tracked_sends_carry_the_pixel_only_in_html() {
+        let app = test_support::app(&tracked_state().await);
+        let (status, body) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "plain", "html": "<p>Hi</p>", "track_opens": true}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let sent = test_support::sent(&app).await;
+        let raw = unfold_qp(sent[0]["raw"].as_str().unwrap());
+        assert_eq!(
+            raw.matches(r#"<img src="https://t.example.com/open/"#)
+                .count(),
+            1,
+            "{raw}"
+        );
+        assert!(!raw.contains("plain<img"), "{raw}");
+
+        // Not asked for: no pixel.
+        let (status, _) = notify(
+            &app,
+            json!({"service": "smtp", "to": "ops@example.com", "title": "t", "body": "", "html": "<p>Hi</p>"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let sent = test_support::sent(&app).await;
+        assert!(!sent[1]["raw"].as_str().unwrap().contains("/open/"));
+    }
+
+    #[tokio::test]
+    async fn opens_are_recorded_and_answered_with_a_gif() {
+        let state = tracked_state().await;
+        let app = test_support::app(&state);
+        let mut events = state.events.subscribe();
+
+        let (status, body) = call_text(&app, Method::GET, &format!("/open/{ID}"), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.starts_with("GIF89a"));
+        let event = events.try_recv().expect("open recorded");
+        assert_eq!(event.kind, "opened");
+        assert_eq!(event.message, ID);
+
+        // A malformed id still gets the image, but no record.
+        let (status, _) = call_text(&app, Method::GET, "/open/not-an-id", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn only_web_links_are_rewritten() {
+        let html = tracking().rewrite_links(
+            r##"<a href="https://example.com/a?x=1&amp;y=2">a</a> <a href="mailto:ops@example.com">m</a> <a href="tel:+100">t</a> <a href="#top">f</a>"##,
+            ID,
+        );
+        let url = "https://example.com/a?x=1&y=2";
+        let expected = format!(
+            "https://t.example.com/click/{ID}?url=https%3A%2F%2Fexample.com%2Fa%3Fx%3D1%26y%3D2&amp;sig={}",
+            tracking().sign(ID, url)
+        );
+        assert!(
+            html.contains(&format!(r#"<a href="{expected}">a</a>"#)),
+            "{html}"
+        );
+        assert!(
+            html.contains(r#"<a href="mailto:ops@example.com">m</a>"#),
+            "{html}"
+        );
+        assert!(html.contains(r#"<a href="tel:+100">t</a>"#), "{html}");
+        assert!(html.contains(r##"<a href="#top">f</a>"##), "{html}");
+    }
+
+    async fn get(app: &axum::Router, uri: &str) -> Response {
+        use tower::ServiceExt;
+
+        let request = axum::http::Request::get(uri)
+            .body(axum::body::Body::empty())
+            .unwrap();
+        app.clone().oneshot(request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn click_redirects_only_with_a_valid_signature() {
+        let state = tracked_state().await;
+        let app = test_support::app(&state);
+        let url = "https://example.com/a?x=1&y=2";
+        let link = tracking().click_url(ID, url);
+        let path = link.strip_prefix("https://t.example.com").unwrap();
+
+        let response = get(&app, path).await;
+        assert_eq!(response.status(), StatusCode::FOUND);
+        assert_eq!(response.headers()[LOCATION], url);
+
+        let tampered = path.replace("example.com%2Fa", "evil.example%2Fa");
+        let response = get(&app, &tampered).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let other_id = path.replace(ID, &"f".repeat(32));
+        let response = get(&app, &other_id).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let untracked = test_support::app(&test_support::state(&[]).await);
+        let response = get(&untracked, path).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn clicks_are_recorded() {
+        let state = tracked_state().await;
+        let app = test_support::app(&state);
+        let mut events = state.events.subscribe();
+        let link = tracking().click_url(ID, "https://example.com/");
+        get(&app, link.strip_prefix("https://t.example.com").unwrap()).await;
+        let event = events.try_recv().expect("click recorded");
+        assert_eq!(event.kind, "clicked");
+        assert_eq!(event.message, "https://example.com/");
+    }
+}
@@ -0,0 +1,1255 @@
+use std::{
+    cmp::Reverse,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use arc_swap::ArcSwap;
+use tracing::{info, warn};
+
+use aws_sdk_ses::{
+    error::{DisplayErrorContext, ProvideErrorMetadata, SdkError},
+    operation::send_raw_email::SendRawEmailError,
+    primitives::Blob,
+    types::RawMessage,
+};
+use futures::future::BoxFuture;
+use lettre::{
+    address::{Address, Envelope},
+    message::header,
+    transport::smtp::{
+        self,
+        authentication::{Credentials, Mechanism},
+        client::{AsyncSmtpConnection, TlsParameters},
+        commands::{Data, Ehlo, Mail, Rcpt, Rset},
+        extension::{ClientId, Extension, MailBodyParameter, MailParameter},
+        response::Severity,
+        AsyncSmtpTransport,
+    },
+    AsyncTransport, Message, Tokio1Executor,
+};
+use serde::Serialize;
+use tokio::sync::Semaphore;
+
+use crate::{
+    retry::parse_retry_after,
+    timing::{self, Phase},
+};
+
+/// Enhanced status codes (RFC 3463) greylisting servers pair with a 450/451.
+const GREYLIST_ENHANCED_CODES: &[&str] = &["4.2.0", "4.7.0", "4.7.1"];
+
+/// What a transport reports about a message it delivered.
+#[derive(Debug, Default)]
+pub struct Delivery {
+    /// Recipients the server refused for good while taking the message for
+    /// the rest (`SMTP_ACCEPT_PARTIAL_RCPT`).
+    pub rejected: Vec<Address>,
+    /// Backend that took the message when it was not the configured one,
+    /// i.e. the SMTP fallback of [`ApiFallbackTransport`].
+    pub via: Option<&'static str>,
+}
+
+/// Delivers a fully built message. Handlers only see this trait, so the
+/// backend is chosen once at startup by `BACKEND`.
+pub trait Transport: Send + Sync {
+    /// Short backend name used in logs and error messages.
+    fn name(&self) -> &'static str;
+
+    /// Delivers `email` to the recipients in `envelope`, which may be a
+    /// subset of the message's own envelope when a send is split.
+    fn send<'a>(
+        &'a self,
+        envelope: &'a Envelope,
+        email: &'a Message,
+    ) -> BoxFuture<'a, Result<Delivery, TransportError>>;
+
+    /// Drops pooled connections so later sends open fresh ones. Returns
+    /// `false` for backends that keep no pool.
+    fn flush_pool(&self) -> anyhow::Result<bool> {
+        Ok(false)
+    }
+}
+
+#[derive(Debug)]
+pub enum TransportError {
+    Smtp(smtp::Error),
+    Ses(Box<SdkError<SendRawEmailError>>),
+    /// The whole send attempt exceeded its time budget.
+    Timeout(Duration),
+}
+
+impl TransportError {
+    /// Whether a later attempt may succeed, i.e. the failure is worth retrying.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            TransportError::Smtp(err) => !(err.is_permanent() || err.is_client()),
+            TransportError::Ses(err) => match err.as_ref() {
+                SdkError::ServiceError(_) => {
+                    err.code() == Some("Throttling")
+                        || err
+                            .raw_response()
+                            .is_some_and(|raw| raw.status().as_u16() == 429)
+                }
+                SdkError::ConstructionFailure(_) => false,
+                _ => true,
+            },
+            TransportError::Timeout(_) => true,
+        }
+    }
+}
+
+impl TransportError {
+    /// SMTP reply code, or HTTP status for SES, when the server answered.
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            TransportError::Smtp(err) => err.status().map(u16::from),
+            TransportError::Ses(err) => err.raw_response().map(|raw| raw.status().as_u16()),
+            TransportError::Timeout(_) => None,
+        }
+    }
+
+    /// The delay a rate-limited HTTP provider asked for with `429` and
+    /// `Retry-After`. SMTP has no equivalent.
+    pub fn retry_after(&self) -> Option<Duration> {
+        let TransportError::Ses(err) = self else {
+            return None;
+        };
+        let raw = err.raw_response()?;
+        if raw.status().as_u16() != 429 {
+            return None;
+        }
+        parse_retry_after(raw.headers().get("retry-after")?, SystemTime::now())
+    }
+
+    /// Whether SES refused the message itself, which SMTP would not take
+    /// either.
+    pub fn is_message_rejected(&self) -> bool {
+        match self {
+            TransportError::Ses(err) => err.code() == Some("MessageRejected"),
+            TransportError::Smtp(_) | TransportError::Timeout(_) => false,
+        }
+    }
+
+    /// Whether an SMTP server is greylisting the message: a 450/451 reply
+    /// that either says so or carries one of the usual enhanced codes.
+    pub fn is_greylisting(&self) -> bool {
+        let TransportError::Smtp(err) = self else {
+            return false;
+        };
+        let Some(code) = err.status() else {
+            return false;
+        };
+        if code.severity != Severity::TransientNegativeCompletion
+            || !matches!(u16::from(code), 450 | 451)
+        {
+            return false;
+        }
+
+        let reply = err.to_string().to_ascii_lowercase();
+        reply.contains("greylist")
+            || reply.contains("graylist")
+            || GREYLIST_ENHANCED_CODES
+                .iter()
+                .any(|enhanced| reply.contains(enhanced))
+    }
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportError::Smtp(err) => err.fmt(f),
+            TransportError::Ses(err) => DisplayErrorContext(err.as_ref()).fmt(f),
+            TransportError::Timeout(limit) => write!(f, "send timed out after {limit:?}"),
+        }
+    }
+}
+
+/// Builds a mailer with an empty connection pool.
+pub type MailerFactory =
+    Box<dyn Fn() -> anyhow::Result<AsyncSmtpTransport<Tokio1Executor>> + Send + Sync>;
+
+pub struct SmtpTransport {
+    mailer: ArcSwap<AsyncSmtpTransport<Tokio1Executor>>,
+    build_mailer: MailerFactory,
+    /// Bound on one whole send attempt, TLS handshake and AUTH included.
+    send_timeout: Option<Duration>,
+}
+
+impl SmtpTransport {
+    pub fn new(
+        build_mailer: MailerFactory,
+        send_timeout: Option<Duration>,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            mailer: ArcSwap::from_pointee(build_mailer()?),
+            build_mailer,
+            send_timeout,
+        })
+    }
+
+    /// Handle sharing the current connection pool.
+    pub fn mailer(&self) -> AsyncSmtpTransport<Tokio1Executor> {
+        self.mailer.load().as_ref().clone()
+    }
+}
+
+impl Transport for SmtpTransport {
+    fn name(&self) -> &'static str {
+        "smtp"
+    }
+
+    fn send<'a>(
+        &'a self,
+        envelope: &'a Envelope,
+        email: &'a Message,
+    ) -> BoxFuture<'a, Result<Delivery, TransportError>> {
+        Box::pin(async move {
+            // Holding our own handle lets a flush swap the pool mid-send; the
+            // old pool closes once its last in-flight send finishes.
+            let mailer = self.mailer.load_full();
+            let raw = email.formatted();
+            let send = mailer.send_raw(envelope, &raw);
+            let result = match self.send_timeout {
+                Some(limit) => tokio::time::timeout(limit, send)
+                    .await
+                    .map_err(|_| TransportError::Timeout(limit))?,
+                None => send.await,
+            };
+            result
+                .map(|_| Delivery::default())
+                .map_err(TransportError::Smtp)
+        })
+    }
+
+    fn flush_pool(&self) -> anyhow::Result<bool> {
+        self.mailer.store((self.build_mailer)()?.into());
+        Ok(true)
+    }
+}
+
+/// Largest `BDAT` chunk; a message is sent in as few chunks as fit.
+const BDAT_CHUNK_BYTES: usize = 1 << 20;
+
+/// SMTP over connections this server dials itself, for what lettre's pooled
+/// transport cannot do: pick the source address (`SMTP_LOCAL_BIND_ADDR`),
+/// send with `BDAT` (`SMTP_USE_CHUNKING`) or skip refused recipients
+/// (`SMTP_ACCEPT_PARTIAL_RCPT`). Each send dials its own connection and
+/// closes it afterwards.
+pub struct BoundSmtpTransport {
+    pub host: String,
+    pub port: u16,
+    /// TLS parameters; `None` for plain connections.
+    pub tls: Option<TlsParameters>,
+    /// Upgrade a plain connection with STARTTLS instead of using implicit TLS.
+    pub starttls: bool,
+    /// `None` skips AUTH.
+    pub credentials: Option<Credentials>,
+    pub mechanisms: Vec<Mechanism>,
+    pub local_addr: Option<IpAddr>,
+    /// Send the message with `BDAT` when the server advertises `CHUNKING`.
+    pub chunking: bool,
+    /// Send to the recipients the server accepts when it refuses others.
+    pub partial_rcpt: bool,
+    pub connect_timeout: Option<Duration>,
+    pub send_timeout: Option<Duration>,
+}
+
+impl BoundSmtpTransport {
+    /// Dials the server and completes TLS, leaving EHLO and AUTH to the
+    /// caller.
+    async fn connect(&self) -> Result<AsyncSmtpConnection, smtp::Error> {
+        let implicit_tls = self.tls.clone().filter(|_| !self.starttls);
+        let started = Instant::now();
+        // Resolved here so the lookup is timed apart from the connect; on
+        // failure lettre resolves again and reports the error itself.
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host((self.host.as_str(), self.port))
+            .await
+            .map(Iterator::collect)
+            .unwrap_or_default();
+        timing::record(Phase::Dns, started.elapsed());
+
+        let started = Instant::now();
+        let mut conn = if addrs.is_empty() {
+            AsyncSmtpConnection::connect_tokio1(
+                (self.host.as_str(), self.port),
+                self.connect_timeout,
+                &ClientId::default(),
+                implicit_tls,
+                self.local_addr,
+            )
+            .await?
+        } else {
+            AsyncSmtpConnection::connect_tokio1(
+                addrs.as_slice(),
+                self.connect_timeout,
+                &ClientId::default(),
+                implicit_tls,
+                self.local_addr,
+            )
+            .await?
+        };
+        timing::record(Phase::Connect, started.elapsed());
+        if let Some(tls) = self.tls.clone().filter(|_| self.starttls) {
+            let started = Instant::now();
+            conn.starttls(tls, &ClientId::default()).await?;
+            timing::record(Phase::Tls, started.elapsed());
+        }
+        Ok(conn)
+    }
+
+    /// Logs in when credentials are set.
+    async fn auth(&self, conn: &mut AsyncSmtpConnection) -> Result<(), smtp::Error> {
+        match &self.credentials {
+            Some(credentials) => conn.auth(&self.mechanisms, credentials).await.map(drop),
+            None => Ok(()),
+        }
+    }
+
+    async fn deliver(&self, envelope: &Envelope, email: &Message) -> Result<Delivery, smtp::Error> {
+        let mut conn = self.connect().await?;
+        // lettre keeps only the EHLO keywords it knows, which CHUNKING is
+        // not, so ask again; EHLO before AUTH leaves the session as it was.
+        let chunking = self.chunking
+            && conn
+                .command(Ehlo::new(ClientId::default()))
+                .await?
+                .message()
+                .any(|line| {
+                    line.split_whitespace()
+                        .next()
+                        .is_some_and(|keyword| keyword.eq_ignore_ascii_case("CHUNKING"))
+                });
+        let started = Instant::now();
+        self.auth(&mut conn).await?;
+        timing::record(Phase::Auth, started.elapsed());
+        let started = Instant::now();
+        let formatted = email.formatted();
+        let delivery = if chunking || self.partial_rcpt {
+            send_transaction(&mut conn, envelope, &formatted, chunking, self.partial_rcpt).await?
+        } else {
+            conn.send(envelope, &formatted).await?;
+            Delivery::default()
+        };
+        timing::record(Phase::Data, started.elapsed());
+        // The message is accepted at this point; a failed QUIT changes nothing.
+        let _ = conn.quit().await;
+        Ok(delivery)
+    }
+
+    /// Dials and logs in to the server, then quits.
+    pub async fn test_connection(&self) -> Result<(), TransportError> {
+        let test = async {
+            let mut conn = self.connect().await?;
+            self.auth(&mut conn).await?;
+            conn.quit().await?;
+            Ok(())
+        };
+        let result = match self.send_timeout {
+            Some(limit) => tokio::time::timeout(limit, test)
+                .await
+                .map_err(|_| TransportError::Timeout(limit))?,
+            None => test.await,
+        };
+        result.map_err(TransportError::Smtp)
+    }
+
+    /// Asks the server whether it takes each of `envelope`'s recipients:
+    /// MAIL and RCPT with no DATA, then RSET and QUIT, so nothing is sent.
+    /// Returns the recipients refused with a 5xx; a 4xx fails the probe.
+    pub async fn probe(&self, envelope: &Envelope) -> Result<Vec<Address>, TransportError> {
+        let probe = async {
+            let mut conn = self.connect().await?;
+            self.auth(&mut conn).await?;
+            let non_ascii = |address: &Address| !address.to_string().is_ascii();
+            let mail_options =
+                if envelope.from().is_some_and(non_ascii) || envelope.to().iter().any(non_ascii) {
+                    vec![MailParameter::SmtpUtfEight]
+                } else {
+                    Vec::new()
+                };
+            conn.command(Mail::new(envelope.from().cloned(), mail_options))
+                .await?;
+            let mut refused = Vec::new();
+            for to in envelope.to() {
+                match conn.command(Rcpt::new(to.clone(), vec![])).await {
+                    Ok(_) => {}
+                    Err(err) if err.is_permanent() => refused.push(to.clone()),
+                    Err(err) => return Err(err),
+                }
+            }
+            // The connection is ours alone and closed right after, so a
+            // failed RSET or QUIT leaves nothing behind.
+            let _ = conn.command(Rset).await;
+            let _ = conn.quit().await;
+            Ok(refused)
+        };
+        let result = match self.send_timeout {
+            Some(limit) => tokio::time::timeout(limit, probe)
+                .await
+                .map_err(|_| TransportError::Timeout(limit))?,
+            None => probe.await,
+        };
+        result.map_err(TransportError::Smtp)
+    }
+}
+
+/// One `BDAT` command with its chunk; `command` writes it verbatim.
+struct Bdat<'a> {
+    chunk: &'a str,
+    last: bool,
+}
+
+impl fmt::Display for Bdat<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let last = if self.last { " LAST" } else { "" };
+        write!(f, "BDAT {}{last}\r\n{}", self.chunk.len(), self.chunk)
+    }
+}
+
+/// One mail transaction by hand, for what `AsyncSmtpConnection::send`
+/// cannot do: send the message as `BDAT` chunks with `chunking`, and with
+/// `partial` carry on past recipients refused with a 5xx. The send still
+/// fails when every recipient is refused.
+async fn send_transaction(
+    conn: &mut AsyncSmtpConnection,
+    envelope: &Envelope,
+    message: &[u8],
+    chunking: bool,
+    partial: bool,
+) -> Result<Delivery, smtp::Error> {
+    let mut mail_options = Vec::new();
+    let non_ascii = |address: &Address| !address.to_string().is_ascii();
+    if envelope.from().is_some_and(non_ascii) || envelope.to().iter().any(non_ascii) {
+        mail_options.push(MailParameter::SmtpUtfEight);
+    }
+    if !message.is_ascii() && conn.server_info().supports_feature(Extension::EightBitMime) {
+        mail_options.push(MailParameter::Body(MailBodyParameter::EightBitMime));
+    }
+    conn.command(Mail::new(envelope.from().cloned(), mail_options))
+        .await?;
+    let mut rejected = Vec::new();
+    for to in envelope.to() {
+        match conn.command(Rcpt::new(to.clone(), vec![])).await {
+            Ok(_) => {}
+            Err(err) if partial && err.is_permanent() => {
+                if rejected.len() + 1 == envelope.to().len() {
+                    return Err(err);
+                }
+                rejected.push(to.clone());
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    match std::str::from_utf8(message) {
+        Ok(message) if chunking => send_bdat(conn, message).await?,
+        _ => {
+            conn.command(Data).await?;
+            conn.message(message).await?;
+        }
+    }
+    Ok(Delivery {
+        rejected,
+        via: None,
+    })
+}
+
+/// The message as `BDAT` chunks (RFC 3030): sent as is, with no
+/// dot-stuffing or terminating dot.
+async fn send_bdat(conn: &mut AsyncSmtpConnection, message: &str) -> Result<(), smtp::Error> {
+    // DATA ends the last line before its dot; BDAT must carry that CRLF.
+    let terminated;
+    let mut message = if message.ends_with("\r\n") {
+        message
+    } else {
+        terminated = format!("{message}\r\n");
+        &terminated
+    };
+    loop {
+        let mut cut = message.len().min(BDAT_CHUNK_BYTES);
+        while !message.is_char_boundary(cut) {
+            cut -= 1;
+        }
+        let (chunk, rest) = message.split_at(cut);
+        let last = rest.is_empty();
+        conn.command(Bdat { chunk, last }).await?;
+        if last {
+            return Ok(());
+        }
+        message = rest;
+    }
+}
+
+impl Transport for BoundSmtpTransport {
+    fn name(&self) -> &'static str {
+        "smtp"
+    }
+
+    fn send<'a>(
+        &'a self,
+        envelope: &'a Envelope,
+        email: &'a Message,
+    ) -> BoxFuture<'a, Result<Delivery, TransportError>> {
+        Box::pin(async move {
+            let send = self.deliver(envelope, email);
+            let result = match self.send_timeout {
+                Some(limit) => tokio::time::timeout(limit, send)
+                    .await
+                    .map_err(|_| TransportError::Timeout(limit))?,
+                None => send.await,
+            };
+            result.map_err(TransportError::Smtp)
+        })
+    }
+}
+
+/// Caps simultaneous sends through one backend (`SMTP_MAX_CONNECTIONS`), so
+/// no more sessions are open to the provider than it allows however many
+/// requests are in flight.
+pub struct ConnectionLimit {
+    pub inner: Box<dyn Transport>,
+    pub permits: Semaphore,
+}
+
+impl Transport for ConnectionLimit {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn send<'a>(
+        &'a self,
+        envelope: &'a Envelope,
+        email: &'a Message,
+    ) -> BoxFuture<'a, Result<Delivery, TransportError>> {
+        Box::pin(async move {
+            let _permit = self
+                .permits
+                .acquire()
+                .await
+                .expect("connection semaphore is never closed");
+            self.inner.send(envelope, email).await
+        })
+    }
+
+    fn flush_pool(&self) -> anyhow::Result<bool> {
+        self.inner.flush_pool()
+    }
+}
+
+/// One server in a [`FailoverTransport`].
+pub struct FailoverBackend {
+    /// Server host, for logs.
+    pub host: String,
+    pub transport: Box<dyn Transport>,
+    /// Set after a transient failure; the server is skipped until then.
+    cooling_until: Mutex<Option<Instant>>,
+}
+
+impl FailoverBackend {
+    pub fn new(host: String, transport: Box<dyn Transport>) -> Self {
+        Self {
+            host,
+            transport,
+            cooling_until: Mutex::new(None),
+        }
+    }
+
+    fn is_cooling(&self, now: Instant) -> bool {
+        let cooling_until = self.cooling_until.lock().expect("failover lock poisoned");
+        cooling_until.is_some_and(|until| until > now)
+    }
+}
+
+/// SMTP servers tried in order (`SMTP_FALLBACK_<n>_*`): a transient or
+/// connection failure moves on to the next server, a permanent rejection is
+/// returned as is. A server that failed transiently is skipped for
+/// `cooldown` so a dead primary does not slow every send; when all are
+/// cooling down they are tried anyway.
+pub struct FailoverTransport {
+    pub backends: Vec<FailoverBackend>,
+    pub cooldown: Duration,
+}
+
+impl Transport for FailoverTransport {
+    fn name(&self) -> &'static str {
+        "smtp"
+    }
+
+    fn send<'a>(
+        &'a self,
+        envelope: &'a Envelope,
+        email: &'a Message,
+    ) -> BoxFuture<'a, Result<Delivery, TransportError>> {
+        Box::pin(async move {
+            let now = Instant::now();
+            let mut candidates: Vec<_> = self
+                .backends
+                .iter()
+                .enumerate()
+                .filter(|(_, backend)| !backend.is_cooling(now))
+                .collect();
+            if candidates.is_empty() {
+                candidates = self.backends.iter().enumerate().collect();
+            }
+
+            let mut last_error = None;
+            for (index, backend) in candidates {
+                match backend.transport.send(envelope, email).await {
+                    Ok(delivery) => {
+                        *backend
+                            .cooling_until
+                            .lock()
+                            .expect("failover lock poisoned") = None;
+                        if index > 0 {
+                            info!(host = %backend.host, backend = index, "sent via fallback smtp server");
+                        }
+                        return Ok(delivery);
+                    }
+                    // Greylisting comes from the recipient's side; another
+                    // relay would be greylisted just the same.
+                    Err(err) if err.is_transient() && !err.is_greylisting() => {
+                        warn!(host = %backend.host, backend = index, error = %err, "smtp server failed, trying next");
+                        *backend
+                            .cooling_until
+                            .lock()
+                            .expect("failover lock poisoned") =
+                            Some(Instant::now() + self.cooldown);
+                        last_error = Some(err);
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+            Err(last_error.expect("at least one backend is configured"))
+        })
+    }
+
+    fn flush_pool(&self) -> anyhow::Result<bool> {
+        let mut flushed = false;
+        for backend in &self.backends {
+            flushed |= backend.transport.flush_pool()?;
+        }
+        Ok(flushed)
+    }
+}
+
+/// The SES API with plain SMTP behind it (`SES_SMTP_FALLBACK`): any API
+/// failure other than SES refusing the message itself, such as missing
+/// credentials, a denied IAM policy or an outage, sends through SMTP
+/// instead. SES is tried first for every message.
+pub struct ApiFallbackTransport {
+    pub api: Box<dyn Transport>,
+    pub smtp: Box<dyn Transport>,
+}
+
+impl Transport for ApiFallbackTransport {
+    fn name(&self) -> &'static str {
+        self.api.name()
+    }
+
+    fn send<'a>(
+        &'a self,
+        envelope: &'a Envelope,
+        email: &'a Message,
+    ) -> BoxFuture<'a, Result<Delivery, TransportError>> {
+        Box::pin(async move {
+            match self.api.send(envelope, email).await {
+                Err(err) if !err.is_message_rejected() => {
+                    warn!(backend = self.api.name(), error = %err, "api send failed, falling back to smtp");
+                    let mut delivery = self.smtp.send(envelope, email).await?;
+                    info!("sent via smtp fallback");
+                    delivery.via = Some(self.smtp.name());
+                    Ok(delivery)
+                }
+                result => result,
+            }
+        })
+    }
+
+    fn flush_pool(&self) -> anyhow::Result<bool> {
+        self.smtp.flush_pool()
+    }
+}
+
+/// How [`PoolTransport`] picks the address for each message, from
+/// `SENDING_POOL_POLICY`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PoolPolicy {
+    /// Every address in turn, weights ignored.
+    RoundRobin,
+    /// Smooth weighted round-robin: each address gets its weight's share of
+    /// messages, interleaved rather than in runs.
+    Weighted,
+}
+
+/// One sending address in a [`PoolTransport`].
+pub struct PoolMember {
+    pub addr: IpAddr,
+    pub weight: u32,
+    /// Connections bound to `addr`.
+    pub transport: Box<dyn Transport>,
+}
+
+/// Spreads messages across several local sending addresses (`SENDING_POOL`)
+/// so volume builds reputation on all of them. A failed send is not moved
+/// to another address; a retry picks the next one like any new message.
+pub struct PoolTransport {
+    members: Vec<PoolMember>,
+    policy: PoolPolicy,
+    /// Round-robin position, or the weighted round-robin's running scores.
+    cursor: Mutex<(usize, Vec<i64>)>,
+}
+
+impl PoolTransport {
+    pub fn new(members: Vec<PoolMember>, policy: PoolPolicy) -> Self {
+        let scores = vec![0; members.len()];
+        Self {
+            members,
+            policy,
+            cursor: Mutex::new((0, scores)),
+        }
+    }
+
+    fn pick(&self) -> &PoolMember {
+        let mut cursor = self.cursor.lock().expect("pool lock poisoned");
+        let index = match self.policy {
+            PoolPolicy::RoundRobin => {
+                let index = cursor.0 % self.members.len();
+                cursor.0 = index + 1;
+                index
+            }
+            PoolPolicy::Weighted => {
+                let total: i64 = self.members.iter().map(|member| member.weight as i64).sum();
+                let scores = &mut cursor.1;
+                for (score, member) in scores.iter_mut().zip(&self.members) {
+                    *score += member.weight as i64;
+                }
+                let index = (0..scores.len())
+                    .max_by_key(|&index| (scores[index], Reverse(index)))
+                    .expect("the pool has a member");
+                scores[index] -= total;
+                index
+            }
+        };
+        &self.members[index]
+    }
+}
+
+impl Transport for PoolTransport {
+    fn name(&self) -> &'static str {
+        "smtp"
+    }
+
+    fn send<'a>(
+        &'a self,
+        envelope: &'a Envelope,
+        email: &'a Message,
+    ) -> BoxFuture<'a, Result<Delivery, TransportError>> {
+        let member = self.pick();
+        info!(local_addr = %member.addr, "sending from pool address");
+        member.transport.send(envelope, email)
+    }
+}
+
+/// Sends through the SES `SendRawEmail` API, so the MIME message is exactly
+/// what the SMTP backend would have sent.
+pub struct SesTransport {
+    client: aws_sdk_ses::Client,
+}
+
+impl SesTransport {
+    /// Uses the standard AWS credential and region chain (`AWS_REGION`,
+    /// `AWS_ACCESS_KEY_ID`, instance roles, ...), with `region` taking
+    /// precedence when set.
+    pub async fn from_env(region: Option<String>) -> Self {
+        let mut loader = aws_config::from_env();
+        if let Some(region) = region {
+            loader = loader.region(aws_config::Region::new(region));
+        }
+        Self {
+            client: aws_sdk_ses::Client::new(&loader.load().await),
+        }
+    }
+}
+
+impl Transport for SesTransport {
+    fn name(&self) -> &'static str {
+        "ses"
+    }
+
+    fn send<'a>(
+        &'a self,
+        envelope: &'a Envelope,
+        email: &'a Message,
+    ) -> BoxFuture<'a, Result<Delivery, TransportError>> {
+        Box::pin(async move {
+            let raw = RawMessage::builder()
+                .data(Blob::new(email.formatted()))
+                .build()
+                .map_err(|err| {
+                    TransportError::Ses(Box::new(SdkError::construction_failure(err)))
+                })?;
+
+            // Bcc is not in the formatted headers, so the envelope is the
+            // only complete recipient list.
+            let mut request = self.client.send_raw_email().raw_message(raw);
+            if let Some(from) = envelope.from() {
+                request = request.source(from.to_string());
+            }
+            for to in envelope.to() {
+                request = request.destinations(to.to_string());
+            }
+
+            request
+                .send()
+                .await
+                .map(|_| Delivery::default())
+                .map_err(|err| TransportError::Ses(Box::new(err)))
+        })
+    }
+}
+
+/// A message recorded by [`MemoryTransport`].
+#[derive(Debug, Clone, Serialize)]
+pub struct CapturedMessage {
+    pub from: Option<String>,
+    /// Envelope recipients, Bcc included.
+    pub to: Vec<String>,
+    pub subject: Option<String>,
+    /// The full message as SMTP would have carried it.
+    pub raw: String,
+}
+
+/// Messages captured so far, shared with the `/test/sent` endpoint.
+pub type SentLog = Arc<Mutex<Vec<CapturedMessage>>>;
+
+/// `BACKEND=memory`: records messages instead of delivering them, so the
+/// server can be exercised end to end without an SMTP server.
+#[derive(Default)]
+pub struct MemoryTransport {
+    sent: SentLog,
+}
+
+impl MemoryTransport {
+    pub fn sent_log(&self) -> SentLog {
+        self.sent.clone()
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn send<'a>(
+        &'a self,
+        envelope: &'a Envelope,
+        email: &'a Message,
+    ) -> BoxFuture<'a, Result<Delivery, TransportError>> {
+        let captured = CapturedMessage {
+            from: envelope.from().map(ToString::to_string),
+            to: envelope.to().iter().map(ToString::to_string).collect(),
+            subject: email
+                .headers()
+                .get::<header::Subject>()
+                .map(|subject| subject.as_ref().to_string()),
+            raw: String::from_utf8_lossy(&email.formatted()).into_owned(),
+        };
+        self.sent
+            .lock()
+            .expect("sent log lock poisoned")
+            .push(captured);
+        Box::pin(async { Ok(Delivery::default()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::test_support;
+    use aws_sdk_ses::config::{retry::RetryConfig, BehaviorVersion, Credentials, Region};
+    use axum::{extract::State, http::StatusCode, routing::post, Router};
+    use base64::{prelude::BASE64_STANDARD, Engine};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    /// What the mock SES endpoint answers, and the form bodies it was sent.
+    struct MockSes {
+        status: StatusCode,
+        body: &'static str,
+        requests: Mutex<Vec<String>>,
+    }
+
+    async fn answer(State(mock): State<Arc<MockSes>>, body: String) -> (StatusCode, String) {
+        mock.requests.lock().unwrap().push(body);
+        (mock.status, mock.body.to_string())
+    }
+
+    async fn ses(status: StatusCode, body: &'static str) -> (SesTransport, Arc<MockSes>) {
+        let mock = Arc::new(MockSes {
+            status,
+            body,
+            requests: Mutex::default(),
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let app = Router::new()
+            .route("/", post(answer))
+            .with_state(mock.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let config = aws_sdk_ses::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .endpoint_url(url)
+            .region(Region::new("us-east-1"))
+            .credentials_provider(Credentials::new("AKIDTEST", "secret", None, None, "test"))
+            .retry_config(RetryConfig::disabled())
+            .build();
+        let client = aws_sdk_ses::Client::from_conf(config);
+        (SesTransport { client }, mock)
+    }
+
+    fn message() -> Message {
+        Message::builder()
+            .from("Notifications <notify@example.com>".parse().unwrap())
+            .to("ops@example.com".parse().unwrap())
+            .bcc("hidden@example.com".parse().unwrap())
+            .subject("deploy finished")
+            .body("build 1234 is live".to_string())
+            .unwrap()
+    }
+
+    const SENT: &str = r#"<SendRawEmailResponse xmlns="http://ses.amazonaws.com/doc/2010-12-01/">
+  <SendRawEmailResult><MessageId>0100-test</MessageId></SendRawEmailResult>
+  <ResponseMetadata><RequestId>req-1</RequestId></ResponseMetadata>
+</SendRawEmailResponse>"#;
+
+    const THROTTLED: &str = r#"<ErrorResponse xmlns="http://ses.amazonaws.com/doc/2010-12-01/">
+  <Error><Type>Sender</Type><Code>Throttling</Code><Message>Maximum sending rate exceeded.</Message></Error>
+  <RequestId>req-2</RequestId>
+</ErrorResponse>"#;
+
+    const REJECTED: &str = r#"<ErrorResponse xmlns="http://ses.amazonaws.com/doc/2010-12-01/">
+  <Error><Type>Sender</Type><Code>MessageRejected</Code><Message>Email address is not verified.</Message></Error>
+  <RequestId>req-3</RequestId>
+</ErrorResponse>"#;
+
+    #[tokio::test]
+    async fn ses_sends_the_raw_message_to_every_envelope_recipient() {
+        let (transport, mock) = ses(StatusCode::OK, SENT).await;
+        let email = message();
+        transport
+            .send(email.envelope(), &email)
+            .await
+            .expect("SES accepted the message");
+
+        let requests = mock.requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        let form: Vec<(String, String)> = form_urlencoded::parse(requests[0].as_bytes())
+            .into_owned()
+            .collect();
+        let field = |name: &str| {
+            form.iter()
+                .filter(|(key, _)| key.starts_with(name))
+                .map(|(_, value)| value.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(field("Action"), ["SendRawEmail"]);
+        assert_eq!(field("Source"), ["notify@example.com"]);
+        assert_eq!(
+            field("Destinations.member."),
+            ["ops@example.com", "hidden@example.com"]
+        );
+        let raw = BASE64_STANDARD.decode(field("RawMessage.Data")[0]).unwrap();
+        let raw = String::from_utf8(raw).unwrap();
+        assert!(raw.contains("Subject: deploy finished"), "{raw}");
+        assert!(!raw.contains("hidden@example.com"), "{raw}");
+    }
+
+    #[tokio::test]
+    async fn ses_throttling_is_transient() {
+        let (transport, _) = ses(StatusCode::BAD_REQUEST, THROTTLED).await;
+        let email = message();
+        let err = transport
+            .send(email.envelope(), &email)
+            .await
+            .expect_err("throttled");
+        assert!(err.is_transient(), "{err}");
+        assert!(!err.is_message_rejected());
+        assert_eq!(err.status(), Some(400));
+    }
+
+    #[tokio::test]
+    async fn ses_rejection_is_permanent() {
+        let (transport, _) = ses(StatusCode::BAD_REQUEST, REJECTED).await;
+        let email = message();
+        let err = transport
+            .send(email.envelope(), &email)
+            .await
+            .expect_err("rejected");
+        assert!(!err.is_transient(), "{err}");
+        assert!(err.is_message_rejected());
+    }
+
+    /// Counts sends in flight and the most seen at once.
+    #[derive(Default)]
+    struct InFlight {
+        now: AtomicUsize,
+        peak: AtomicUsize,
+        sends: AtomicUsize,
+    }
+
+    struct Instrumented(Arc<InFlight>);
+
+    impl Transport for Instrumented {
+        fn name(&self) -> &'static str {
+            "instrumented"
+        }
+
+        fn send<'a>(
+            &'a self,
+            _envelope: &'a Envelope,
+            _email: &'a Message,
+        ) -> BoxFuture<'a, Result<Delivery, TransportError>> {
+            Box::pin(async move {
+                let now = self.0.now.fetch_add(1, Ordering::SeqCst) + 1;
+                self.0.peak.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                self.0.now.fetch_sub(1, Ordering::SeqCst);
+                self.0.sends.fetch_add(1, Ordering::SeqCst);
+                Ok(Delivery::default())
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn connection_limit_caps_concurrent_sends() {
+        let counts = Arc::new(InFlight::default());
+        let limited = ConnectionLimit {
+            inner: Box::new(Instrumented(counts.clone())),
+            permits: Semaphore::new(2),
+        };
+        let email = message();
+        let envelope = email.envelope().clone();
+
+        let sends = (0..6).map(|_| limited.send(&envelope, &email));
+        for result in futures::future::join_all(sends).await {
+            result.unwrap();
+        }
+        assert_eq!(counts.sends.load(Ordering::SeqCst), 6);
+        assert_eq!(counts.peak.load(Ordering::SeqCst), 2);
+        assert_eq!(limited.name(), "instrumented");
+    }
+
+    async fn chunked_send(smtp: &test_support::MockSmtp, body: &str) {
+        let state = smtp.state(&[("SMTP_USE_CHUNKING", "true")]).await;
+        let (status, response) = test_support::notify(
+            &test_support::app(&state),
+            json!({"service": "smtp", "to": "ops@example.com", "title": "report", "body": body}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{response}");
+    }
+
+    fn verbs(smtp: &test_support::MockSmtp) -> Vec<String> {
+        smtp.commands()
+            .iter()
+            .map(|command| command.split(' ').next().unwrap_or_default().to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn bdat_is_used_when_the_server_offers_chunking() {
+        let smtp = test_support::MockSmtp::start().await;
+        smtp.offer("CHUNKING");
+        chunked_send(&smtp, "line one\n.leading dot\nlast line").await;
+
+        let verbs = verbs(&smtp);
+        assert!(verbs.contains(&"BDAT".to_string()), "{verbs:?}");
+        assert!(!verbs.contains(&"DATA".to_string()), "{verbs:?}");
+        let message = &smtp.messages()[0];
+        // Sent as is: no dot-stuffing, ending in a CRLF.
+        assert!(message.contains("\r\n.leading dot\r\n"), "{message}");
+        assert!(message.ends_with("\r\n"), "{message}");
+        assert!(message.contains("Subject: report"), "{message}");
+    }
+
+    #[tokio::test]
+    async fn large_messages_go_in_several_chunks() {
+        let smtp = test_support::MockSmtp::start().await;
+        smtp.offer("CHUNKING");
+        let line = "x".repeat(70);
+        let body = vec![line.as_str(); BDAT_CHUNK_BYTES / 60].join("\n");
+        chunked_send(&smtp, &body).await;
+
+        let bdat: Vec<_> = smtp
+            .commands()
+            .into_iter()
+            .filter(|command| command.starts_with("BDAT"))
+            .collect();
+        assert_eq!(bdat.len(), 2, "{bdat:?}");
+        assert_eq!(bdat[0], format!("BDAT {BDAT_CHUNK_BYTES}"));
+        assert!(bdat[1].ends_with(" LAST"), "{bdat:?}");
+        let message = &smtp.messages()[0];
+        assert!(message.len() > BDAT_CHUNK_BYTES);
+        assert!(message.contains(&line));
+    }
+
+    #[tokio::test]
+    async fn data_is_used_when_chunking_is_not_offered() {
+        let smtp = test_support::MockSmtp::start().await;
+        chunked_send(&smtp, "plain").await;
+
+        let verbs = verbs(&smtp);
+        assert!(verbs.contains(&"DATA".to_string()), "{verbs:?}");
+        assert!(!verbs.contains(&"BDAT".to_string()), "{verbs:?}");
+        assert_eq!(smtp.messages().len(), 1);
+    }
+
+    /// Records which pool member took each send.
+    struct Labelled(&'static str, Arc<Mutex<Vec<&'static str>>>);
+
+    impl Transport for Labelled {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+
+        fn send<'a>(
+            &'a self,
+            _envelope: &'a Envelope,
+            _email: &'a Message,
+        ) -> BoxFuture<'a, Result<Delivery, TransportError>> {
+            self.1.lock().unwrap().push(self.0);
+            Box::pin(async { Ok(Delivery::default()) })
+        }
+    }
+
+    async fn pool_order(
+        members: &[(&'static str, u32)],
+        policy: PoolPolicy,
+        sends: usize,
+    ) -> Vec<&'static str> {
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let members = members
+            .iter()
+            .enumerate()
+            .map(|(n, &(label, weight))| PoolMember {
+                addr: IpAddr::from([127, 0, 0, n as u8 + 1]),
+                weight,
+                transport: Box::new(Labelled(label, order.clone())),
+            })
+            .collect();
+        let pool = PoolTransport::new(members, policy);
+        let email = message();
+        for _ in 0..sends {
+            pool.send(email.envelope(), &email).await.unwrap();
+        }
+        let order = order.lock().unwrap().clone();
+        order
+    }
+
+    #[tokio::test]
+    async fn round_robin_pool_takes_every_address_in_turn() {
+        let order = pool_order(&[("a", 5), ("b", 1), ("c", 1)], PoolPolicy::RoundRobin, 9).await;
+        assert_eq!(order, ["a", "b", "c", "a", "b", "c", "a", "b", "c"]);
+    }
+
+    #[tokio::test]
+    async fn weighted_pool_interleaves_by_weight() {
+        let order = pool_order(&[("a", 3), ("b", 1)], PoolPolicy::Weighted, 8).await;
+        assert_eq!(order, ["a", "a", "b", "a", "a", "a", "b", "a"]);
+
+        let order = pool_order(&[("a", 2), ("b", 3), ("c", 5)], PoolPolicy::Weighted, 1000).await;
+        for (label, share) in [("a", 200), ("b", 300), ("c", 500)] {
+            assert_eq!(
+                order.iter().filter(|&&l| l == label).count(),
+                share,
+                "{label}"
+            );
+        }
+        assert!(
+            !order.windows(3).any(|w| w.iter().all(|&l| l == "c")),
+            "runs of c"
+        );
+    }
+
+    const ACCESS_DENIED: &str = r#"<ErrorResponse xmlns="http://ses.amazonaws.com/doc/2010-12-01/">
+  <Error><Type>Sender</Type><Code>AccessDenied</Code><Message>User is not authorized to perform ses:SendRawEmail</Message></Error>
+  <RequestId>req-4</RequestId>
+</ErrorResponse>"#;
+
+    /// The SMTP transport the server would build for `smtp`.
+    fn smtp_transport(smtp: &test_support::MockSmtp) -> Box<dyn Transport> {
+        let port = smtp.port();
+        let cfg = test_support::config(&[
+            ("BACKEND", "smtp"),
+            ("SMTP_HOST", "127.0.0.1"),
+            ("SMTP_PORT", &port),
+            ("SMTP_TLS", "false"),
+        ])
+        .expect("config loads");
+        let crate::BackendConfig::Smtp(cfg) = cfg.backend else {
+            panic!("not an smtp backend");
+        };
+        crate::build_smtp_transport(&cfg)
+            .expect("transport builds")
+            .0
+    }
+
+    #[tokio::test]
+    async fn a_failing_api_falls_back_to_smtp() {
+        let (api, mock) = ses(StatusCode::FORBIDDEN, ACCESS_DENIED).await;
+        let smtp = test_support::MockSmtp::start().await;
+        let transport = ApiFallbackTransport {
+            api: Box::new(api),
+            smtp: smtp_transport(&smtp),
+        };
+        let email = message();
+
+        let delivery = transport.send(email.envelope(), &email).await.unwrap();
+        assert_eq!(delivery.via, Some("smtp"));
+        assert_eq!(mock.requests.lock().unwrap().len(), 1);
+        let messages = smtp.messages();
+        assert_eq!(messages.len(), 1);
+        assert!(
+            messages[0].contains("Subject: deploy finished"),
+            "{}",
+            messages[0]
+        );
+        assert_eq!(transport.name(), "ses");
+    }
+
+    #[tokio::test]
+    async fn a_working_api_is_not_bypassed() {
+        let (api, _) = ses(StatusCode::OK, SENT).await;
+        let smtp = test_support::MockSmtp::start().await;
+        let transport = ApiFallbackTransport {
+            api: Box::new(api),
+            smtp: smtp_transport(&smtp),
+        };
+        let email = message();
+
+        let delivery = transport.send(email.envelope(), &email).await.unwrap();
+        assert_eq!(delivery.via, None);
+        assert_eq!(smtp.connections(), 0);
+    }
+
+    #[tokio::test]
+    async fn a_message_the_api_rejects_is_not_resent_over_smtp() {
+        let (api, _) = ses(StatusCode::BAD_REQUEST, REJECTED).await;
+        let smtp = test_support::MockSmtp::start().await;
+        let transport = ApiFallbackTransport {
+            api: Box::new(api),
+            smtp: smtp_transport(&smtp),
+        };
+        let email = message();
+
+        let err = transport.send(email.envelope(), &email).await.unwrap_err();
+        assert!(err.is_message_rejected(), "{err}");
+        assert_eq!(smtp.connections(), 0);
+    }
+}
@@ -0,0 +1,294 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{
+        multipart::{Field, MultipartError},
+        Multipart, State,
+    },
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use sha2::{Digest, Sha256};
+use tokio::{fs::File, io::AsyncWriteExt};
+
+use crate::{
+    accept,
+    attachments::{AttachmentRequest, Disposition, SpooledFile, OCTET_STREAM},
+    authenticate, error_response, AppState,
+};
+
+/// Allowance for the `request` part and multipart framing on top of
+/// `MAX_UPLOAD_BYTES`.
+pub const OVERHEAD_BYTES: usize = 2 * 1024 * 1024;
+
+/// `POST /notify/upload`: `/notify` as multipart/form-data, for attachments
+/// too large to inline as base64. The `request` part holds the JSON body and
+/// every part with a filename becomes an attachment, streamed to a temp file
+/// as it arrives; their combined size is capped at `MAX_UPLOAD_BYTES`.
+pub async fn notify_upload(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Response {
+    let Some(caller) = authenticate(&state, &headers).await else {
+        return error_response(StatusCode::UNAUTHORIZED, "invalid api key").into_response();
+    };
+
+    let mut body = None;
+    let mut uploads = Vec::new();
+    let mut total = 0;
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(err) => return multipart_error(&err),
+        };
+        if let Some(filename) = field.file_name().map(str::to_string) {
+            match spool(field, filename, &mut total, state.max_upload_bytes).await {
+                Ok(upload) => uploads.push(upload),
+                Err(response) => return response,
+            }
+        } else if field.name() == Some("request") {
+            let text = match field.text().await {
+                Ok(text) => text,
+                Err(err) => return multipart_error(&err),
+            };
+            match serde_json::from_str(&text) {
+                Ok(value) => body = Some(value),
+                Err(err) => {
+                    return error_response(
+                        StatusCode::BAD_REQUEST,
+                        &format!("request part is not valid json: {err}"),
+                    )
+                    .into_response()
+                }
+            }
+        } else {
+            let name = field.name().unwrap_or_default();
+            return error_response(
+                StatusCode::BAD_REQUEST,
+                &format!("unexpected form field: {name}"),
+            )
+            .into_response();
+        }
+    }
+    let Some(body) = body else {
+        return error_response(StatusCode::BAD_REQUEST, "missing request part").into_response();
+    };
+
+    accept(&state, &caller, &headers, body, uploads).await
+}
+
+/// Writes one file part to disk chunk by chunk, adding its size to `total`.
+/// A part sent as `application/octet-stream` is left to content sniffing.
+async fn spool(
+    mut field: Field<'_>,
+    filename: String,
+    total: &mut usize,
+    max: usize,
+) -> Result<AttachmentRequest, Response> {
+    let content_type = field
+        .content_type()
+        .filter(|content_type| !content_type.eq_ignore_ascii_case(OCTET_STREAM))
+        .map(str::to_string);
+    let mut spooled = SpooledFile::new();
+    let mut file = File::create(spooled.path())
+        .await
+        .map_err(|err| spool_error(&filename, &err))?;
+    let mut hasher = Sha256::new();
+    loop {
+        let chunk = match field.chunk().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(err) => return Err(multipart_error(&err)),
+        };
+        *total += chunk.len();
+        if *total > max {
+            return Err(error_response(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                &format!("attachments exceed MAX_UPLOAD_BYTES ({max} bytes)"),
+            )
+            .into_response());
+        }
+        hasher.update(&chunk);
+        file.write_all(&chunk)
+            .await
+            .map_err(|err| spool_error(&filename, &err))?;
+    }
+    file.flush()
+        .await
+        .map_err(|err| spool_error(&filename, &err))?;
+    spooled.digest = hasher.finalize().into();
+
+    Ok(AttachmentRequest {
+        filename,
+        content: String::new(),
+        content_type,
+        disposition: Disposition::Attachment,
+        cid: None,
+        spooled: Some(Arc::new(spooled)),
+    })
+}
+
+/// Malformed bodies are 400, bodies past the route's limit 413.
+fn multipart_error(err: &MultipartError) -> Response {
+    error_response(err.status(), &err.body_text()).into_response()
+}
+
+fn spool_error(filename: &str, err: &std::io::Error) -> Response {
+    tracing::error!(filename, error = %err, "failed to spool upload");
+    error_response(StatusCode::INTERNAL_SERVER_ERROR, "failed to store upload").into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        http::{header::CONTENT_TYPE, Request},
+    };
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::test_support;
+
+    const BOUNDARY: &str = "upload-boundary";
+
+    /// A form part: `(name, filename, content type, contents)`.
+    type Part<'a> = (&'a str, Option<&'a str>, Option<&'a str>, &'a [u8]);
+
+    async fn upload(state: &Arc<AppState>, parts: &[Part<'_>]) -> (StatusCode, Value) {
+        let mut body = Vec::new();
+        for (name, filename, content_type, contents) in parts {
+            body.extend_from_slice(format!("--{BOUNDARY}\r\n").as_bytes());
+            let disposition = match filename {
+                Some(filename) => format!("form-data; name=\"{name}\"; filename=\"{filename}\""),
+                None => format!("form-data; name=\"{name}\""),
+            };
+            body.extend_from_slice(format!("Content-Disposition: {disposition}\r\n").as_bytes());
+            if let Some(content_type) = content_type {
+                body.extend_from_slice(format!("Content-Type: {content_type}\r\n").as_bytes());
+            }
+            body.extend_from_slice(b"\r\n");
+            body.extend_from_slice(contents);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(format!("--{BOUNDARY}--\r\n").as_bytes());
+
+        let request = Request::post("/notify/upload")
+            .header("authorization", format!("Bearer {}", test_support::API_KEY))
+            .header(
+                CONTENT_TYPE,
+                format!("multipart/form-data; boundary={BOUNDARY}"),
+            )
+            .body(Body::from(body))
+            .unwrap();
+        let response = test_support::app(state).oneshot(request).await.unwrap();
+        let status = response.status();
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (
+            status,
+            serde_json::from_slice(&bytes).unwrap_or(Value::Null),
+        )
+    }
+
+    fn request_part() -> Vec<u8> {
+        json!({"service": "smtp", "to": "ops@example.com", "title": "report", "body": "attached"})
+            .to_string()
+            .into_bytes()
+    }
+
+    #[tokio::test]
+    async fn multipart_attachment_is_sent() {
+        let state = test_support::state(&[]).await;
+        let request = request_part();
+
+        let (status, body) = upload(
+            &state,
+            &[
+                ("request", None, None, &request),
+                (
+                    "file",
+                    Some("report.csv"),
+                    Some("text/csv"),
+                    b"host,load\nweb-1,0.93\n",
+                ),
+                (
+                    "file",
+                    Some("blob.bin"),
+                    Some(OCTET_STREAM),
+                    b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR",
+                ),
+            ],
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        let sent = test_support::sent(&test_support::app(&state)).await;
+        assert_eq!(sent.len(), 1);
+        let raw = sent[0]["raw"].as_str().unwrap();
+        assert!(raw.contains("Content-Type: text/csv"), "{raw}");
+        assert!(raw.contains("filename=\"report.csv\""), "{raw}");
+        assert!(raw.contains("\nhost,load\nweb-1,0.93"), "{raw}");
+        // Sent as octet-stream, so left to sniffing.
+        assert!(raw.contains("Content-Type: image/png"), "{raw}");
+    }
+
+    #[tokio::test]
+    async fn uploads_over_the_cap_are_refused() {
+        let state = test_support::state(&[("MAX_UPLOAD_BYTES", "16")]).await;
+        let request = request_part();
+
+        let (status, body) = upload(
+            &state,
+            &[
+                ("request", None, None, &request),
+                ("file", Some("a.txt"), None, b"0123456789"),
+                ("file", Some("b.txt"), None, b"0123456789"),
+            ],
+        )
+        .await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(
+            body["message"],
+            "attachments exceed MAX_UPLOAD_BYTES (16 bytes)"
+        );
+        assert!(test_support::sent(&test_support::app(&state))
+            .await
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn malformed_forms_are_refused() {
+        let state = test_support::state(&[]).await;
+        let request = request_part();
+
+        for (parts, message) in [
+            (
+                vec![("file", Some("a.txt"), None, &b"x"[..])],
+                "missing request part".to_string(),
+            ),
+            (
+                vec![
+                    ("request", None, None, &request[..]),
+                    ("note", None, None, &b"hi"[..]),
+                ],
+                "unexpected form field: note".to_string(),
+            ),
+        ] {
+            let (status, body) = upload(&state, &parts).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{body}");
+            assert_eq!(body["message"], message);
+        }
+
+        let (status, body) = upload(&state, &[("request", None, None, b"{")]).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(
+            body["message"]
+                .as_str()
+                .unwrap()
+                .starts_with("request part is not valid json"),
+            "{body}"
+        );
+    }
+}
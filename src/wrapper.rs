@@ -0,0 +1,165 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use lol_html::{element, html_content::ContentType, rewrite_str, RewriteStrSettings};
+use tracing::warn;
+
+/// Fixed header and footer wrapped around every body, e.g. a company footer
+/// with legal text. Text and HTML parts each get their own version.
+#[derive(Debug, Default)]
+pub struct BodyWrapper {
+    text_header: Option<String>,
+    text_footer: Option<String>,
+    html_header: Option<String>,
+    html_footer: Option<String>,
+}
+
+/// `BODY_HEADER`, `BODY_FOOTER`, `BODY_HEADER_HTML` and `BODY_FOOTER_HTML`;
+/// any of them may be unset.
+#[derive(Debug, Default)]
+pub struct WrapperFiles {
+    pub text_header: Option<PathBuf>,
+    pub text_footer: Option<PathBuf>,
+    pub html_header: Option<PathBuf>,
+    pub html_footer: Option<PathBuf>,
+}
+
+impl BodyWrapper {
+    /// Reads the configured files, or `None` when none is set.
+    pub fn load(files: &WrapperFiles) -> Result<Option<Self>> {
+        let wrapper = Self {
+            text_header: files.text_header.as_deref().map(read).transpose()?,
+            text_footer: files.text_footer.as_deref().map(read).transpose()?,
+            html_header: files.html_header.as_deref().map(read).transpose()?,
+            html_footer: files.html_footer.as_deref().map(read).transpose()?,
+        };
+        let empty = wrapper.text_header.is_none()
+            && wrapper.text_footer.is_none()
+            && wrapper.html_header.is_none()
+            && wrapper.html_footer.is_none();
+        Ok((!empty).then_some(wrapper))
+    }
+
+    pub fn wrap_text(&self, body: &str) -> String {
+        let mut wrapped = String::new();
+        if let Some(header) = &self.text_header {
+            wrapped.push_str(header);
+            wrapped.push('\n');
+        }
+        wrapped.push_str(body);
+        if let Some(footer) = &self.text_footer {
+            wrapped.push('\n');
+            wrapped.push_str(footer);
+        }
+        wrapped
+    }
+
+    /// Places the header and footer inside `<body>` when the document has
+    /// one, and around the fragment otherwise.
+    pub fn wrap_html(&self, html: &str) -> String {
+        let header = self.html_header.as_deref().unwrap_or_default();
+        let footer = self.html_footer.as_deref().unwrap_or_default();
+        if !html.to_ascii_lowercase().contains("<body") {
+            return format!("{header}{html}{footer}");
+        }
+
+        let rewritten = rewrite_str(
+            html,
+            RewriteStrSettings::new().append_element_content_handler(element!("body", |el| {
+                el.prepend(header, ContentType::Html);
+                el.append(footer, ContentType::Html);
+                Ok(())
+            })),
+        );
+        rewritten.unwrap_or_else(|err| {
+            warn!(error = %err, "failed to insert html body wrapper");
+            format!("{header}{html}{footer}")
+        })
+    }
+}
+
+fn read(path: &Path) -> Result<String> {
+    let raw = fs::read_to_string(path)
+        .with_context(|| format!("failed to read body wrapper {}", path.display()))?;
+    Ok(raw.trim_end_matches(['\r', '\n']).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn write(dir: &TempDir, name: &str, contents: &str) -> Option<PathBuf> {
+        let path = dir.path().join(name);
+        fs::write(&path, contents).unwrap();
+        Some(path)
+    }
+
+    fn wrapper(dir: &TempDir) -> BodyWrapper {
+        BodyWrapper::load(&WrapperFiles {
+            text_header: write(dir, "header.txt", "ACME Corp\n"),
+            text_footer: write(dir, "footer.txt", "Legal text.\r\n\n"),
+            html_header: write(dir, "header.html", "<p>ACME Corp</p>\n"),
+            html_footer: write(dir, "footer.html", "<p>Legal text.</p>"),
+        })
+        .unwrap()
+        .expect("a wrapper")
+    }
+
+    #[test]
+    fn no_files_means_no_wrapper() {
+        assert!(BodyWrapper::load(&WrapperFiles::default())
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn missing_file_fails_to_load() {
+        let dir = TempDir::new("wrapper");
+        let err = BodyWrapper::load(&WrapperFiles {
+            text_footer: Some(dir.path().join("missing.txt")),
+            ..WrapperFiles::default()
+        })
+        .unwrap_err();
+        assert!(
+            err.to_string().starts_with("failed to read body wrapper"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn text_is_wrapped_on_their_own_lines() {
+        let dir = TempDir::new("wrapper");
+        assert_eq!(
+            wrapper(&dir).wrap_text("build 1234 is live"),
+            "ACME Corp\nbuild 1234 is live\nLegal text."
+        );
+
+        let footer_only = BodyWrapper::load(&WrapperFiles {
+            text_footer: write(&dir, "only.txt", "Legal text."),
+            ..WrapperFiles::default()
+        })
+        .unwrap()
+        .unwrap();
+        assert_eq!(footer_only.wrap_text("body"), "body\nLegal text.");
+        assert_eq!(footer_only.wrap_html("<p>body</p>"), "<p>body</p>");
+    }
+
+    #[test]
+    fn html_is_wrapped_inside_the_body_element() {
+        let dir = TempDir::new("wrapper");
+        let wrapper = wrapper(&dir);
+
+        assert_eq!(
+            wrapper.wrap_html("<p>live</p>"),
+            "<p>ACME Corp</p><p>live</p><p>Legal text.</p>"
+        );
+        assert_eq!(
+            wrapper.wrap_html("<html><BODY class=\"x\"><p>live</p></BODY></html>"),
+            "<html><BODY class=\"x\"><p>ACME Corp</p><p>live</p><p>Legal text.</p></BODY></html>"
+        );
+    }
+}
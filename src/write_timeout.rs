@@ -0,0 +1,208 @@
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::serve::Listener;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+    time::Sleep,
+};
+use tracing::info;
+
+/// HTTP listener whose connections give up on a client that stops reading
+/// (`HTTP_WRITE_TIMEOUT_SECS`). Responses are written by the connection's
+/// own task, so a slow reader never holds up other requests; without the
+/// timeout it would still keep its connection and the unsent response
+/// buffered for as long as it stays connected.
+pub struct WriteTimeoutListener {
+    inner: TcpListener,
+    timeout: Option<Duration>,
+}
+
+impl WriteTimeoutListener {
+    pub fn new(inner: TcpListener, timeout: Option<Duration>) -> Self {
+        Self { inner, timeout }
+    }
+}
+
+impl Listener for WriteTimeoutListener {
+    type Io = WriteTimeoutStream;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        let (stream, peer) = Listener::accept(&mut self.inner).await;
+        let stream = WriteTimeoutStream {
+            inner: stream,
+            peer,
+            timeout: self.timeout,
+            stalled: None,
+        };
+        (stream, peer)
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Listener::local_addr(&self.inner)
+    }
+}
+
+/// A connection whose writes fail once the client has accepted no bytes
+/// for the timeout.
+pub struct WriteTimeoutStream {
+    inner: TcpStream,
+    peer: SocketAddr,
+    timeout: Option<Duration>,
+    /// Started when a write first finds the socket full; reset on progress.
+    stalled: Option<Pin<Box<Sleep>>>,
+}
+
+impl WriteTimeoutStream {
+    fn guard<T>(&mut self, cx: &mut Context<'_>, poll: Poll<io::Result<T>>) -> Poll<io::Result<T>> {
+        let Some(timeout) = self.timeout else {
+            return poll;
+        };
+        if poll.is_ready() {
+            self.stalled = None;
+            return poll;
+        }
+        let stalled = self
+            .stalled
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+        match stalled.as_mut().poll(cx) {
+            Poll::Ready(()) => {
+                info!(peer = %self.peer, "client stopped reading the response, closing connection");
+                Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "response write timed out",
+                )))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl AsyncRead for WriteTimeoutStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for WriteTimeoutStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        self.guard(cx, poll)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write_vectored(cx, bufs);
+        self.guard(cx, poll)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{routing::get, Router};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    /// Far more than the socket buffers hold, so a client that reads none
+    /// of it stalls the write.
+    const LARGE: usize = 32 << 20;
+
+    async fn server(timeout: Option<Duration>) -> SocketAddr {
+        let app = Router::new()
+            .route("/large", get(|| async { "x".repeat(LARGE) }))
+            .route("/small", get(|| async { "ok" }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let listener = WriteTimeoutListener::new(listener, timeout);
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        addr
+    }
+
+    async fn request(addr: SocketAddr, path: &str) -> TcpStream {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET {path} HTTP/1.1\r\nhost: test\r\nconnection: close\r\n\r\n");
+        stream.write_all(request.as_bytes()).await.unwrap();
+        stream
+    }
+
+    async fn read_all(mut stream: TcpStream) -> Vec<u8> {
+        let mut received = Vec::new();
+        // A reset after the timeout ends the read like a close does.
+        let _ = stream.read_to_end(&mut received).await;
+        received
+    }
+
+    #[tokio::test]
+    async fn a_slow_reader_does_not_block_other_requests() {
+        let addr = server(Some(Duration::from_secs(30))).await;
+        let _stalled = request(addr, "/large").await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let response = tokio::time::timeout(
+            Duration::from_secs(5),
+            read_all(request(addr, "/small").await),
+        )
+        .await
+        .expect("other requests are answered");
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(response.ends_with("\r\n\r\nok"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn a_client_that_stops_reading_is_disconnected() {
+        let addr = server(Some(Duration::from_millis(200))).await;
+        let stalled = request(addr, "/large").await;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let received = tokio::time::timeout(Duration::from_secs(5), read_all(stalled))
+            .await
+            .expect("connection closed");
+        assert!(received.len() < LARGE, "{} bytes", received.len());
+    }
+
+    #[tokio::test]
+    async fn a_reading_client_gets_the_whole_response() {
+        let addr = server(Some(Duration::from_millis(200))).await;
+        let received = tokio::time::timeout(
+            Duration::from_secs(10),
+            read_all(request(addr, "/large").await),
+        )
+        .await
+        .expect("response read");
+        assert!(received.len() > LARGE, "{} bytes", received.len());
+        assert!(received.starts_with(b"HTTP/1.1 200 OK"));
+    }
+}